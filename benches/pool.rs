@@ -0,0 +1,60 @@
+//! Compares recording events into freshly allocated requests against requests checked out of a
+//! [`Pool`].
+//!
+//! Requests are dropped as soon as they are sent, as they would be once a [`Service`] completes,
+//! so that pooled requests are returned to the pool.
+//!
+//! [`Service`]: tower::Service
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tracing_service::{EventSink, Pool, Pooled, ServiceLayer, TrySendError};
+use tracing_subscriber::{fmt::format::JsonVisitor, layer::SubscriberExt};
+
+/// An [`EventSink`] which drops each request immediately.
+struct Discard;
+
+impl<Request> EventSink<Request> for Discard {
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        drop(request);
+        Ok(())
+    }
+}
+
+fn make_visitor(request: &mut String) -> JsonVisitor<'_> {
+    JsonVisitor::new(request)
+}
+
+fn make_pooled_visitor(request: &mut Pooled<String>) -> JsonVisitor<'_> {
+    JsonVisitor::new(&mut **request)
+}
+
+fn emit() {
+    tracing::info!(
+        user_id = 42,
+        path = "/api/v1/orders",
+        status = 200,
+        "handled request in the order service"
+    );
+}
+
+fn bench_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("on_event");
+
+    let layer = ServiceLayer::from_parts(Discard, make_visitor);
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        group.bench_function("default", |b| b.iter(emit));
+    });
+
+    let layer =
+        ServiceLayer::from_parts(Discard, make_pooled_visitor).with_request_factory(Pool::new(64));
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        group.bench_function("pooled", |b| b.iter(emit));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_pool);
+criterion_main!(benches);
@@ -2,31 +2,25 @@ use futures_util::StreamExt;
 use hyper::{client::Client, Body, Request};
 use tower::ServiceExt;
 use tracing::{info, Level};
-use tracing_service::ServiceLayer;
-use tracing_subscriber::{
-    filter, fmt::format::JsonVisitor, layer::SubscriberExt, util::SubscriberInitExt, Layer,
-};
+use tracing_service::logfmt::{self, Line};
+use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 // nc -l 8080
 const SERVER: &str = "http://127.0.0.1:8080";
 
-fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
-    JsonVisitor::new(value)
-}
-
 #[tokio::main]
 async fn main() {
     // Construct the `Service`
-    let client = Client::new().map_request(|json_str: String| {
+    let client = Client::new().map_request(|line: Line| {
         Request::builder()
             .method("GET")
             .uri(SERVER)
-            .body(Body::from(json_str))
+            .body(Body::from(line.into_string()))
             .unwrap()
     });
 
     // Create the layer
-    let (layer, mut responses) = ServiceLayer::new(client, make_visitor);
+    let (layer, mut responses) = logfmt::layer(client);
     let layer = layer.with_filter(filter::Targets::new().with_target("basic", Level::INFO));
 
     // Spawn the driver
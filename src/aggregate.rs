@@ -0,0 +1,228 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{self, Write},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing_core::{
+    field::{Field, Visit},
+    Event, Level,
+};
+
+type RecordCount<Request> = Box<dyn Fn(&mut Request, u64) + Send + Sync>;
+
+/// Coalesces identical events within a window, see
+/// [`ServiceLayer::with_aggregation`](crate::ServiceLayer::with_aggregation).
+///
+/// Events are identical if they come from the same callsite, so have the same target, level and
+/// message format, and have the same field values. The first event in a window is sent as normal.
+/// Its duplicates are coalesced into a single request, constructed from the first duplicate,
+/// which carries their count and is sent once the window closes.
+///
+/// A window therefore results in at most two requests: the first event, sent immediately so
+/// nothing is delayed when an event is not repeated, followed by the coalesced request once the
+/// window has elapsed, if there were any duplicates.
+///
+/// The coalesced request is sent by the [`ResponseStream`](crate::ResponseStream) once its window
+/// has elapsed, using the stream's timer, see
+/// [`ResponseStream::with_timer`](crate::ResponseStream::with_timer). Without a timer it is sent
+/// alongside the next event recorded after its window has elapsed. Coalesced requests are also
+/// sent when the stream is closed and when the layer is dropped, so they are not lost at shutdown.
+pub struct Aggregation<Request> {
+    window: Duration,
+    record_count: RecordCount<Request>,
+    state: Mutex<State<Request>>,
+}
+
+struct State<Request> {
+    windows: HashMap<u64, Window<Request>>,
+    // The earliest time any window closes
+    next_close: Option<Instant>,
+}
+
+struct Window<Request> {
+    opened: Instant,
+    duplicates: u64,
+    coalesced: Option<(Request, Level)>,
+}
+
+/// The outcome of [`Aggregation::observe`].
+pub(crate) enum Observe {
+    /// The event opens a new window and should be sent.
+    Send,
+    /// The event is the first duplicate in its window, its request is held until the window
+    /// closes.
+    Hold,
+    /// The event has been counted and should be discarded.
+    Discard,
+    /// The event is the first duplicate in its window, but its request could not be constructed,
+    /// so it is neither held nor counted.
+    Invalid,
+}
+
+impl<Request> Aggregation<Request> {
+    /// Constructs an `Aggregation` which coalesces identical events within `window`, using
+    /// `record_count` to record the number of duplicates into the coalesced request.
+    pub fn new<F>(window: Duration, record_count: F) -> Self
+    where
+        F: Fn(&mut Request, u64) + Send + Sync + 'static,
+    {
+        Self {
+            window,
+            record_count: Box::new(record_count),
+            state: Mutex::new(State {
+                windows: HashMap::new(),
+                next_close: None,
+            }),
+        }
+    }
+
+    /// Counts an event against its window, returning the coalesced requests, and the level of
+    /// their events, of any windows which have closed.
+    ///
+    /// The request of the first duplicate in a window is constructed using `hold` while the window
+    /// is locked, so it cannot close before the request is held. If `hold` returns `None` the
+    /// next duplicate is held instead.
+    pub(crate) fn observe(
+        &self,
+        event: &Event<'_>,
+        hold: impl FnOnce() -> Option<Request>,
+    ) -> (Observe, Vec<(Request, Level)>) {
+        let key = key(event);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+
+        let closed = match state.next_close {
+            Some(next_close) if next_close <= now => self.close_windows(&mut state, now),
+            _ => Vec::new(),
+        };
+
+        let observe = match state.windows.get_mut(&key) {
+            Some(window) if window.coalesced.is_some() => {
+                window.duplicates += 1;
+                Observe::Discard
+            }
+            Some(window) => match hold() {
+                Some(request) => {
+                    window.duplicates += 1;
+                    window.coalesced = Some((request, *event.metadata().level()));
+                    Observe::Hold
+                }
+                None => Observe::Invalid,
+            },
+            None => {
+                state.windows.insert(
+                    key,
+                    Window {
+                        opened: now,
+                        duplicates: 0,
+                        coalesced: None,
+                    },
+                );
+                let close = now + self.window;
+                state.next_close = Some(state.next_close.map_or(close, |next| next.min(close)));
+                Observe::Send
+            }
+        };
+        (observe, closed)
+    }
+
+    /// Closes the windows which have elapsed, or every window if `force` is `true`, returning
+    /// their coalesced requests and the level of their events.
+    pub(crate) fn release(&self, force: bool) -> Vec<(Request, Level)> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        match state.next_close {
+            Some(_) if force => self.close_windows(&mut state, now + self.window),
+            Some(next_close) if next_close <= now => self.close_windows(&mut state, now),
+            _ => Vec::new(),
+        }
+    }
+
+    /// When the next window closes, if any are open.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .next_close
+    }
+
+    /// Removes the windows which have closed, returning their coalesced requests.
+    fn close_windows(&self, state: &mut State<Request>, now: Instant) -> Vec<(Request, Level)> {
+        let mut closed = Vec::new();
+        let mut next_close = None;
+        state.windows.retain(|_, window| {
+            let close = window.opened + self.window;
+            if close > now {
+                next_close = Some(next_close.map_or(close, |next: Instant| next.min(close)));
+                return true;
+            }
+            if let Some((mut request, level)) = window.coalesced.take() {
+                (self.record_count)(&mut request, window.duplicates);
+                closed.push((request, level));
+            }
+            false
+        });
+        state.next_close = next_close;
+        closed
+    }
+}
+
+impl<Request> fmt::Debug for Aggregation<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aggregation")
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Hashes the callsite and field values of an event.
+fn key(event: &Event<'_>) -> u64 {
+    let mut hasher = KeyHasher(DefaultHasher::new());
+    event.metadata().callsite().hash(&mut hasher.0);
+    event.record(&mut hasher);
+    hasher.0.finish()
+}
+
+struct KeyHasher(DefaultHasher);
+
+impl Write for KeyHasher {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Visit for KeyHasher {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        field.name().hash(&mut self.0);
+        value.to_bits().hash(&mut self.0);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        field.name().hash(&mut self.0);
+        value.hash(&mut self.0);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        field.name().hash(&mut self.0);
+        value.hash(&mut self.0);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        field.name().hash(&mut self.0);
+        value.hash(&mut self.0);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        field.name().hash(&mut self.0);
+        value.hash(&mut self.0);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        field.name().hash(&mut self.0);
+        let _ = write!(self, "{value:?}");
+    }
+}
@@ -0,0 +1,503 @@
+//! A builder which configures a [`ServiceLayer`] and its [`ResponseStream`] together, see
+//! [`ServiceLayer::builder`].
+
+use std::{
+    borrow::Cow,
+    future::Future,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tower::Service;
+use tracing_core::{Level, Metadata};
+
+use crate::{
+    channel::{self, Receiver, Sender},
+    priority_channel, Aggregation, CircuitBreaker, DefaultRequest, ErrorClass, EventSink,
+    EventSource, NewRequest, PriorityReceiver, PrioritySender, RateLimit, RecordResource, Resource,
+    ResponseStream, RetryPolicy, SampleRate, ScopeSpan, ServiceLayer, SpanTiming, Spool,
+    Termination, TrySendError,
+};
+
+/// The queue between a [`ServiceLayer`] and its [`ResponseStream`], see [`Builder::queue`].
+pub trait Queue<Request> {
+    /// The sending half of the queue.
+    type Sink: EventSink<Request>;
+    /// The receiving half of the queue.
+    type Source: EventSource<Request>;
+
+    /// Constructs the queue.
+    fn channel(self) -> (Self::Sink, Self::Source);
+}
+
+/// A [`channel::bounded`] queue, see [`Builder::buffer`].
+#[derive(Debug, Clone, Copy)]
+pub struct Bounded {
+    buffer: usize,
+}
+
+impl<Request> Queue<Request> for Bounded {
+    type Sink = Sender<Request>;
+    type Source = Receiver<Request>;
+
+    fn channel(self) -> (Self::Sink, Self::Source) {
+        channel::bounded(self.buffer)
+    }
+}
+
+/// A [`channel::unbounded`] queue, see [`Builder::unbounded`].
+#[derive(Debug, Clone, Copy)]
+pub struct Unbounded;
+
+impl<Request> Queue<Request> for Unbounded {
+    type Sink = Sender<Request>;
+    type Source = Receiver<Request>;
+
+    fn channel(self) -> (Self::Sink, Self::Source) {
+        channel::unbounded()
+    }
+}
+
+/// A [`priority_channel`], see [`Builder::priority`].
+#[derive(Debug, Clone, Copy)]
+pub struct Priority {
+    buffer: usize,
+    reserved: usize,
+    threshold: Level,
+}
+
+impl<Request> Queue<Request> for Priority {
+    type Sink = PrioritySender<Request>;
+    type Source = PriorityReceiver<Request>;
+
+    fn channel(self) -> (Self::Sink, Self::Source) {
+        priority_channel(self.buffer, self.reserved, self.threshold)
+    }
+}
+
+/// Stands in for the queue until the builder is built.
+#[derive(Debug)]
+pub(crate) struct Detached;
+
+impl<Request> EventSink<Request> for Detached {
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        Err(TrySendError::Closed(request))
+    }
+}
+
+impl<Request> EventSource<Request> for Detached {
+    fn poll_recv(&mut self, _cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        Poll::Ready(None)
+    }
+
+    fn close(&mut self) {}
+}
+
+/// Configures a [`ServiceLayer`] and its [`ResponseStream`], see [`ServiceLayer::builder`].
+///
+/// Each setter corresponds to a `with_*` method on [`ServiceLayer`] or [`ResponseStream`]. The
+/// queue defaults to a bounded channel with a capacity of 32.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tracing_core::Level;
+/// # use tracing_service::{RetryPolicy, SampleRate, ServiceLayer};
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let (layer, responses) = ServiceLayer::builder(service)
+///     .make_visitor(make_visitor)
+///     .buffer(1024)
+///     .sample_rate(Level::TRACE, SampleRate::OneIn(100))
+///     .concurrency(4)
+///     .retry(RetryPolicy::new(3))
+///     .on_error(|err| eprintln!("failed to send event: {err:?}"))
+///     .build();
+/// ```
+#[must_use = "the builder does nothing until it is built"]
+pub struct Builder<Request, Svc, MakeVisitor = (), New = DefaultRequest, Q = Bounded>
+where
+    Svc: Service<Request>,
+{
+    layer: ServiceLayer<Request, MakeVisitor, New, Detached>,
+    stream: ResponseStream<Request, Svc, Detached>,
+    queue: Q,
+}
+
+impl<Request, Svc> Builder<Request, Svc>
+where
+    Svc: Service<Request>,
+{
+    pub(crate) fn new(service: Svc) -> Self {
+        let layer = ServiceLayer::from_parts(Detached, ());
+        let stream = ResponseStream::new(service, Detached, layer.metrics());
+        Self {
+            layer,
+            stream,
+            queue: Bounded {
+                buffer: ServiceLayer::<Request, ()>::DEFAULT_BUFFER,
+            },
+        }
+    }
+}
+
+impl<Request, Svc, MakeVisitor, New, Q> Builder<Request, Svc, MakeVisitor, New, Q>
+where
+    Svc: Service<Request>,
+{
+    /// Constructs the `Request` from each event's fields using `make_visitor`.
+    pub fn make_visitor<M>(self, make_visitor: M) -> Builder<Request, Svc, M, New, Q> {
+        Builder {
+            layer: self.layer.map_parts(|_, sink| (make_visitor, sink)),
+            stream: self.stream,
+            queue: self.queue,
+        }
+    }
+
+    /// See [`ServiceLayer::with_request_factory`].
+    pub fn request_factory<F>(self, new_request: F) -> Builder<Request, Svc, MakeVisitor, F, Q>
+    where
+        F: NewRequest<Request>,
+    {
+        Builder {
+            layer: self.layer.with_request_factory(new_request),
+            stream: self.stream,
+            queue: self.queue,
+        }
+    }
+
+    /// Uses a bounded queue with capacity `buffer`.
+    pub fn buffer(self, buffer: usize) -> Builder<Request, Svc, MakeVisitor, New, Bounded> {
+        self.queue(Bounded { buffer })
+    }
+
+    /// Uses an unbounded queue, so events are never dropped because the queue is full.
+    ///
+    /// Memory use grows without limit while the [`Service`] falls behind.
+    pub fn unbounded(self) -> Builder<Request, Svc, MakeVisitor, New, Unbounded> {
+        self.queue(Unbounded)
+    }
+
+    /// Uses a bounded queue, of capacity `buffer`, in which `reserved` slots are kept for `WARN`
+    /// and `ERROR` events, see [`priority_channel`].
+    pub fn priority(
+        self,
+        buffer: usize,
+        reserved: usize,
+    ) -> Builder<Request, Svc, MakeVisitor, New, Priority> {
+        self.queue(Priority {
+            buffer,
+            reserved,
+            threshold: Level::WARN,
+        })
+    }
+
+    /// Uses `queue` between the [`ServiceLayer`] and [`ResponseStream`].
+    pub fn queue<Q2>(self, queue: Q2) -> Builder<Request, Svc, MakeVisitor, New, Q2>
+    where
+        Q2: Queue<Request>,
+    {
+        Builder {
+            layer: self.layer,
+            stream: self.stream,
+            queue,
+        }
+    }
+
+    /// See [`ServiceLayer::with_metadata`].
+    pub fn metadata<F>(mut self, record_metadata: F) -> Self
+    where
+        F: Fn(&mut Request, &Metadata<'_>, SystemTime) + Send + Sync + 'static,
+    {
+        self.layer = self.layer.with_metadata(record_metadata);
+        self
+    }
+
+    /// See [`ServiceLayer::with_span_scope`].
+    pub fn span_scope<F>(mut self, record_span: F) -> Self
+    where
+        F: Fn(&mut Request, &ScopeSpan<'_>) + Send + Sync + 'static,
+    {
+        self.layer = self.layer.with_span_scope(record_span);
+        self
+    }
+
+    /// See [`ServiceLayer::with_span_timing`].
+    pub fn span_timing<F>(mut self, record_timing: F) -> Self
+    where
+        F: Fn(&mut Request, &SpanTiming<'_>) + Send + Sync + 'static,
+    {
+        self.layer = self.layer.with_span_timing(record_timing);
+        self
+    }
+
+    /// See [`ServiceLayer::with_request_init`].
+    pub fn request_init<F>(mut self, init_request: F) -> Self
+    where
+        F: Fn(&mut Request) + Send + Sync + 'static,
+    {
+        self.layer = self.layer.with_request_init(init_request);
+        self
+    }
+
+    /// See [`ServiceLayer::with_resource`].
+    pub fn resource(mut self, resource: Resource) -> Self
+    where
+        Request: RecordResource,
+    {
+        self.layer = self.layer.with_resource(resource);
+        self
+    }
+
+    /// See [`ServiceLayer::exclude_target`].
+    pub fn exclude_target(mut self, target: impl Into<Cow<'static, str>>) -> Self {
+        self.layer = self.layer.exclude_target(target);
+        self
+    }
+
+    /// See [`ServiceLayer::drop_visitor_errors`].
+    pub fn drop_visitor_errors(mut self) -> Self {
+        self.layer = self.layer.drop_visitor_errors();
+        self
+    }
+
+    /// See [`ServiceLayer::with_sample_rate`].
+    pub fn sample_rate(mut self, level: Level, rate: SampleRate) -> Self {
+        self.layer = self.layer.with_sample_rate(level, rate);
+        self
+    }
+
+    /// See [`ServiceLayer::with_rate_limit`].
+    pub fn rate_limit(mut self, rate_limit: RateLimit<Request>) -> Self {
+        self.layer = self.layer.with_rate_limit(rate_limit);
+        self
+    }
+
+    /// See [`ServiceLayer::with_aggregation`].
+    pub fn aggregation(mut self, aggregation: Aggregation<Request>) -> Self {
+        self.layer = self.layer.with_aggregation(aggregation);
+        self
+    }
+
+    /// Writes events which overflow the queue, or fail to send, to the [`Spool`] and replays them
+    /// once the [`Service`] next succeeds.
+    ///
+    /// See [`ServiceLayer::with_spool`] and [`ResponseStream::with_spool`].
+    pub fn spool(mut self, spool: Spool<Request>) -> Self
+    where
+        Request: Clone,
+    {
+        self.layer = self.layer.with_spool(spool.clone());
+        self.stream = self.stream.with_spool(spool);
+        self
+    }
+
+    /// See [`ResponseStream::with_concurrency`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.stream = self.stream.with_concurrency(concurrency);
+        self
+    }
+
+    /// See [`ResponseStream::with_retry`].
+    pub fn retry(mut self, policy: RetryPolicy<Svc::Error>) -> Self
+    where
+        Request: Clone,
+    {
+        self.stream = self.stream.with_retry(policy);
+        self
+    }
+
+    /// See [`ResponseStream::with_error_classifier`].
+    pub fn error_classifier<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&Svc::Error) -> ErrorClass + Send + Sync + 'static,
+    {
+        self.stream = self.stream.with_error_classifier(classify);
+        self
+    }
+
+    /// See [`ResponseStream::with_circuit_breaker`].
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.stream = self.stream.with_circuit_breaker(circuit_breaker);
+        self
+    }
+
+    /// See [`ResponseStream::with_timer`].
+    pub fn timer<F, Fut>(mut self, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.stream = self.stream.with_timer(sleep);
+        self
+    }
+
+    /// See [`ResponseStream::with_final_request`].
+    pub fn final_request<F>(mut self, final_request: F) -> Self
+    where
+        F: FnOnce(&Termination) -> Request + Send + 'static,
+    {
+        self.stream = self.stream.with_final_request(final_request);
+        self
+    }
+
+    /// See [`ResponseStream::on_response`].
+    pub fn on_response<F>(mut self, on_response: F) -> Self
+    where
+        F: FnMut(&Svc::Response) + Send + 'static,
+    {
+        self.stream = self.stream.on_response(on_response);
+        self
+    }
+
+    /// See [`ResponseStream::on_error`].
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(&Svc::Error) + Send + 'static,
+    {
+        self.stream = self.stream.on_error(on_error);
+        self
+    }
+
+    /// Constructs the [`ServiceLayer`] and the [`ResponseStream`] which drives it.
+    #[allow(clippy::type_complexity)]
+    pub fn build(
+        self,
+    ) -> (
+        ServiceLayer<Request, MakeVisitor, New, Q::Sink>,
+        ResponseStream<Request, Svc, Q::Source>,
+    )
+    where
+        Q: Queue<Request>,
+    {
+        let (sink, source) = self.queue.channel();
+        let layer = self.layer.map_parts(|make_visitor, _| (make_visitor, sink));
+        (layer, self.stream.with_source(source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{fmt::format::JsonVisitor, layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+        JsonVisitor::new(value)
+    }
+
+    /// A service which records the requests delivered to it, failing the first `failures` calls.
+    fn recorder(
+        failures: usize,
+    ) -> (
+        impl Service<String, Response = (), Error = &'static str>,
+        Arc<Mutex<Vec<String>>>,
+    ) {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let recorded = delivered.clone();
+        let calls = Arc::new(Mutex::new(0));
+        let service = tower::service_fn(move |request: String| {
+            let mut calls = calls.lock().unwrap();
+            *calls += 1;
+            if *calls <= failures {
+                return future::ready(Err("unavailable"));
+            }
+            recorded.lock().unwrap().push(request);
+            future::ready(Ok(()))
+        });
+        (service, delivered)
+    }
+
+    #[tokio::test]
+    async fn layer_and_stream_share_metrics() {
+        let (service, delivered) = recorder(0);
+        let (layer, responses) = ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .buffer(1)
+            .build();
+        let metrics = responses.metrics();
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for i in 0..3 {
+                tracing::info!(i);
+            }
+        });
+        responses.for_each(|_| async {}).await;
+
+        assert_eq!(*delivered.lock().unwrap(), ["{\"i\":0}"]);
+        assert_eq!(metrics.enqueued(), 1);
+        assert_eq!(metrics.dropped_full(), 2);
+    }
+
+    #[tokio::test]
+    async fn unbounded_queue_never_drops_events() {
+        let (service, delivered) = recorder(0);
+        let (layer, responses) = ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .unbounded()
+            .build();
+        let metrics = responses.metrics();
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for i in 0..100 {
+                tracing::info!(i);
+            }
+        });
+        responses.for_each(|_| async {}).await;
+
+        assert_eq!(delivered.lock().unwrap().len(), 100);
+        assert_eq!(metrics.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn configures_the_layer_and_the_stream() {
+        let (service, delivered) = recorder(1);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let recorded = errors.clone();
+        let (layer, responses) = ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .metadata(|request: &mut String, metadata, _| request.push_str(metadata.name()))
+            .sample_rate(Level::DEBUG, SampleRate::Probability(0.0))
+            .retry(RetryPolicy::new(2).with_backoff(Duration::ZERO, Duration::ZERO))
+            .on_error(move |err| recorded.lock().unwrap().push(*err))
+            .build();
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::debug!("sampled out");
+            tracing::info!(name: "event", "sent");
+        });
+        responses.for_each(|_| async {}).await;
+
+        assert_eq!(*delivered.lock().unwrap(), ["event{\"message\":\"sent\"}"]);
+        assert!(errors.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn priority_queue_reserves_slots_for_errors() {
+        let (service, delivered) = recorder(0);
+        let (layer, responses) = ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .priority(2, 1)
+            .build();
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info!(i = 0);
+            tracing::info!(i = 1);
+            tracing::error!(i = 2);
+        });
+        responses.for_each(|_| async {}).await;
+
+        assert_eq!(*delivered.lock().unwrap(), ["{\"i\":2}", "{\"i\":0}"]);
+    }
+}
@@ -0,0 +1,467 @@
+//! The queue between a [`ServiceLayer`](crate::ServiceLayer) and its
+//! [`ResponseStream`](crate::ResponseStream).
+//!
+//! This is a multi-producer, single-consumer channel which does not depend on any async runtime,
+//! so the [`ResponseStream`](crate::ResponseStream) can be driven by tokio, `async-std`, `smol`,
+//! or an embedded executor.
+//!
+//! ```
+//! # use futures_util::FutureExt;
+//! use tracing_service::channel;
+//!
+//! let (sender, mut receiver) = channel::bounded(2);
+//! sender.try_send("a").unwrap();
+//! sender.try_send("b").unwrap();
+//! assert!(sender.try_send("c").is_err());
+//! drop(sender);
+//!
+//! assert_eq!(receiver.recv().now_or_never(), Some(Some("a")));
+//! assert_eq!(receiver.recv().now_or_never(), Some(Some("b")));
+//! assert_eq!(receiver.recv().now_or_never(), Some(None));
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::poll_fn,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use crate::{metrics::EMPTY, EventSink, EventSource, LayerMetrics, TrySendError};
+
+/// Constructs a queue which holds at most `buffer` requests.
+///
+/// # Panics
+///
+/// Panics if `buffer` is zero.
+pub fn bounded<Request>(buffer: usize) -> (Sender<Request>, Receiver<Request>) {
+    assert!(buffer > 0, "buffer must be greater than zero");
+    with_metrics(Some(buffer), LayerMetrics::default())
+}
+
+/// Constructs a queue without a maximum size.
+///
+/// Memory use grows without limit while the [`Service`](tower::Service) falls behind.
+pub fn unbounded<Request>() -> (Sender<Request>, Receiver<Request>) {
+    with_metrics(None, LayerMetrics::default())
+}
+
+/// Constructs a queue which counts the requests it accepts in `metrics`, so several queues can
+/// share them.
+pub(crate) fn with_metrics<Request>(
+    capacity: Option<usize>,
+    metrics: LayerMetrics,
+) -> (Sender<Request>, Receiver<Request>) {
+    let shared = Arc::new(Shared {
+        oldest: metrics.add_queue(),
+        metrics,
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            capacity,
+            senders: 1,
+            closed: false,
+            waker: None,
+        }),
+    });
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+struct Shared<Request> {
+    state: Mutex<State<Request>>,
+    metrics: LayerMetrics,
+    // The sequence number of the oldest queued request, see `LayerMetrics::add_queue`
+    oldest: Arc<AtomicU64>,
+}
+
+struct State<Request> {
+    // The requests along with their sequence numbers, see `LayerMetrics::record_sent`
+    queue: VecDeque<(u64, Request)>,
+    capacity: Option<usize>,
+    senders: usize,
+    // Whether the receiver has been closed or dropped
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl<Request> Shared<Request> {
+    fn lock(&self) -> MutexGuard<'_, State<Request>> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Counts and queues a request, while the state is locked.
+    fn push(&self, state: &mut State<Request>, request: Request) {
+        // The oldest request is never later than the one being counted, so a flush which observes
+        // the count cannot miss it
+        if state.queue.is_empty() {
+            self.oldest
+                .store(self.metrics.enqueued(), Ordering::Release);
+        }
+        let sequence = self.metrics.record_sent();
+        state.queue.push_back((sequence, request));
+        self.update_oldest(state);
+    }
+
+    /// Records the sequence number of the oldest request, while the state is locked.
+    fn update_oldest(&self, state: &State<Request>) {
+        let oldest = state.queue.front().map_or(EMPTY, |(sequence, _)| *sequence);
+        self.oldest.store(oldest, Ordering::Release);
+    }
+}
+
+/// The sending half of a queue, see [`bounded`] and [`unbounded`].
+///
+/// Sending never blocks, so it is safe to call from within
+/// [`Layer::on_event`](tracing_subscriber::Layer::on_event). The requests it accepts are counted
+/// in the queue's [`LayerMetrics`], which are shared by a [`ServiceLayer`](crate::ServiceLayer)
+/// and [`ResponseStream`](crate::ResponseStream) constructed from its halves.
+pub struct Sender<Request> {
+    shared: Arc<Shared<Request>>,
+}
+
+impl<Request> Sender<Request> {
+    /// Attempts to immediately send a request.
+    pub fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(TrySendError::Closed(request));
+        }
+        if state
+            .capacity
+            .is_some_and(|capacity| state.queue.len() >= capacity)
+        {
+            return Err(TrySendError::Full(request));
+        }
+        self.shared.push(&mut state, request);
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Attempts to immediately send a request, evicting the oldest queued request for which
+    /// `evict` returns `true` if the queue is full.
+    ///
+    /// Returns the evicted request, if any, or [`TrySendError::Full`] if the queue is full and
+    /// none could be evicted. The evicted request is counted as having left the queue, see
+    /// [`LayerMetrics::queue_depth`].
+    pub(crate) fn try_send_evicting(
+        &self,
+        request: Request,
+        evict: impl Fn(&Request) -> bool,
+    ) -> Result<Option<Request>, TrySendError<Request>> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(TrySendError::Closed(request));
+        }
+        let mut evicted = None;
+        if state
+            .capacity
+            .is_some_and(|capacity| state.queue.len() >= capacity)
+        {
+            let Some(index) = state.queue.iter().position(|(_, queued)| evict(queued)) else {
+                return Err(TrySendError::Full(request));
+            };
+            evicted = state.queue.remove(index).map(|(_, evicted)| evicted);
+            self.shared.metrics.record_evicted();
+        }
+        self.shared.push(&mut state, request);
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(evicted)
+    }
+
+    /// Returns `true` if the [`Receiver`] has been closed or dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl<Request> Clone for Sender<Request> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Request> Drop for Sender<Request> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        let waker = match state.senders {
+            0 => state.waker.take(),
+            _ => None,
+        };
+        drop(state);
+
+        // The receiver is woken to observe that the queue has ended
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<Request> fmt::Debug for Sender<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.lock();
+        f.debug_struct("Sender")
+            .field("len", &state.queue.len())
+            .field("capacity", &state.capacity)
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+impl<Request> EventSink<Request> for Sender<Request> {
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        Sender::try_send(self, request)
+    }
+
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        Some(&self.shared.metrics)
+    }
+}
+
+/// The receiving half of a queue, see [`bounded`] and [`unbounded`].
+pub struct Receiver<Request> {
+    shared: Arc<Shared<Request>>,
+}
+
+impl<Request> Receiver<Request> {
+    /// Polls for the next request, returning `None` once the queue is closed, or every
+    /// [`Sender`] has been dropped, and it is empty.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        let mut state = self.shared.lock();
+        if let Some((_, request)) = state.queue.pop_front() {
+            self.shared.update_oldest(&state);
+            return Poll::Ready(Some(request));
+        }
+        if state.closed || state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        match &mut state.waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            waker => *waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+
+    /// Waits for the next request, returning `None` once the queue is closed, or every
+    /// [`Sender`] has been dropped, and it is empty.
+    pub async fn recv(&mut self) -> Option<Request> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Stops the queue from accepting new requests, those already queued are still received.
+    pub fn close(&mut self) {
+        self.shared.lock().closed = true;
+    }
+
+    /// The number of requests in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.lock().queue.len()
+    }
+
+    /// Returns `true` if there are no requests in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<Request> Drop for Receiver<Request> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.closed = true;
+        let queue = mem::take(&mut state.queue);
+        self.shared.update_oldest(&state);
+        drop(state);
+
+        // Requests are dropped outside of the lock, in case they hold a sender
+        drop(queue);
+    }
+}
+
+impl<Request> fmt::Debug for Receiver<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.lock();
+        f.debug_struct("Receiver")
+            .field("len", &state.queue.len())
+            .field("capacity", &state.capacity)
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+impl<Request> EventSource<Request> for Receiver<Request> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        Receiver::poll_recv(self, cx)
+    }
+
+    fn close(&mut self) {
+        Receiver::close(self)
+    }
+
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        Some(&self.shared.metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl CountingWaker {
+        fn wakes(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll_recv<Request>(
+        receiver: &mut Receiver<Request>,
+        waker: &Arc<CountingWaker>,
+    ) -> Poll<Option<Request>> {
+        let waker = Waker::from(waker.clone());
+        receiver.poll_recv(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn receives_requests_in_order() {
+        let (sender, mut receiver) = unbounded();
+        let waker = Arc::default();
+        for i in 0..3 {
+            sender.try_send(i).unwrap();
+        }
+        assert_eq!(receiver.len(), 3);
+
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(0)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(1)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(2)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Pending);
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn rejects_requests_once_full() {
+        let (sender, mut receiver) = bounded(2);
+        let waker = Arc::default();
+        sender.try_send(0).unwrap();
+        sender.try_send(1).unwrap();
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+
+        // Receiving a request makes room for another
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(0)));
+        sender.try_send(2).unwrap();
+        assert_eq!(receiver.len(), 2);
+    }
+
+    #[test]
+    fn evicts_the_oldest_matching_request_once_full() {
+        let (sender, mut receiver) = bounded(3);
+        let waker = Arc::default();
+        for i in 0..3 {
+            sender.try_send(i).unwrap();
+        }
+        assert_eq!(sender.try_send_evicting(3, |i| i % 2 == 1), Ok(Some(1)));
+        assert_eq!(
+            sender.try_send_evicting(4, |i| *i > 5),
+            Err(TrySendError::Full(4))
+        );
+
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(0)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(2)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(3)));
+    }
+
+    #[test]
+    #[should_panic = "buffer must be greater than zero"]
+    fn rejects_empty_buffers() {
+        let _ = bounded::<()>(0);
+    }
+
+    #[test]
+    fn receives_queued_requests_after_closing() {
+        let (sender, mut receiver) = bounded(2);
+        let waker = Arc::default();
+        sender.try_send(0).unwrap();
+        receiver.close();
+
+        assert!(sender.is_closed());
+        assert_eq!(sender.try_send(1), Err(TrySendError::Closed(1)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(0)));
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(None));
+    }
+
+    #[test]
+    fn closes_when_the_receiver_is_dropped() {
+        let (sender, receiver) = bounded(2);
+        drop(receiver);
+
+        assert!(sender.is_closed());
+        assert_eq!(sender.try_send(0), Err(TrySendError::Closed(0)));
+    }
+
+    #[test]
+    fn wakes_the_receiver_when_a_request_is_sent() {
+        let (sender, mut receiver) = bounded(2);
+        let waker = Arc::<CountingWaker>::default();
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Pending);
+        assert_eq!(waker.wakes(), 0);
+
+        sender.try_send(0).unwrap();
+        assert_eq!(waker.wakes(), 1);
+
+        // The waker is only used once
+        sender.try_send(1).unwrap();
+        assert_eq!(waker.wakes(), 1);
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(Some(0)));
+    }
+
+    #[test]
+    fn wakes_the_receiver_when_the_last_sender_is_dropped() {
+        let (sender, mut receiver) = bounded::<()>(2);
+        let waker = Arc::<CountingWaker>::default();
+        let clone = sender.clone();
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Pending);
+
+        drop(sender);
+        assert_eq!(waker.wakes(), 0);
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Pending);
+
+        drop(clone);
+        assert_eq!(waker.wakes(), 1);
+        assert_eq!(poll_recv(&mut receiver, &waker), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant};
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent as normal.
+    Closed,
+    /// Requests are shed until the cool-down elapses.
+    Open,
+    /// A single probe request is sent, the circuit closes if it succeeds and opens otherwise.
+    HalfOpen,
+}
+
+/// A circuit breaker which stops calling a failing [`Service`](tower::Service), see
+/// [`ResponseStream::with_circuit_breaker`](crate::ResponseStream::with_circuit_breaker).
+///
+/// The circuit opens after `failure_threshold` consecutive errors, whether returned by calls or
+/// by [`Service::poll_ready`](tower::Service::poll_ready). While it is open requests are
+/// shed, written to the [`Spool`](crate::Spool) if one is configured and dropped otherwise. Once
+/// the cool-down has elapsed the circuit is half-open, and a single request is sent to probe the
+/// [`Service`](tower::Service) before traffic resumes. Calls which were already in flight when the
+/// circuit opened do not affect it once they complete.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    failures: u32,
+    state: State,
+    // Incremented each time the circuit opens, so the results of calls admitted before then are
+    // ignored
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+/// The outcome of [`CircuitBreaker::admit`].
+pub(crate) enum Admit {
+    /// The request may be sent.
+    Call,
+    /// The request should be shed.
+    Shed,
+    /// The request should wait for the probe to complete.
+    Wait,
+}
+
+impl CircuitBreaker {
+    /// Constructs a `CircuitBreaker` which opens after `failure_threshold` consecutive errors, and
+    /// sheds requests for `cool_down` before probing the [`Service`](tower::Service).
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cool_down,
+            failures: 0,
+            state: State::Closed,
+            generation: 0,
+        }
+    }
+
+    /// Returns the current state of the circuit.
+    pub fn state(&self) -> CircuitState {
+        match self.state {
+            State::Closed => CircuitState::Closed,
+            State::Open { until } if until <= Instant::now() => CircuitState::HalfOpen,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Decides whether a request may be sent, moving from open to half-open once the cool-down
+    /// has elapsed.
+    pub(crate) fn admit(&mut self) -> Admit {
+        match self.state {
+            State::Closed | State::HalfOpen { probing: false } => Admit::Call,
+            State::Open { until } if until > Instant::now() => Admit::Shed,
+            State::Open { .. } => {
+                self.state = State::HalfOpen { probing: false };
+                Admit::Call
+            }
+            State::HalfOpen { probing: true } => Admit::Wait,
+        }
+    }
+
+    /// Records that a request was sent, while half-open this is the probe, returning the
+    /// generation it was admitted under.
+    pub(crate) fn record_call(&mut self) -> u64 {
+        if let State::HalfOpen { probing } = &mut self.state {
+            *probing = true;
+        }
+        self.generation
+    }
+
+    /// The generation of the circuit, which is incremented each time it opens.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Records the outcome of a call admitted under `generation`, or of the
+    /// [`Service`](tower::Service) failing to become ready.
+    ///
+    /// Calls admitted before the circuit last opened may still be completing, their outcomes are
+    /// ignored so they cannot close it again or count towards it reopening.
+    pub(crate) fn record(&mut self, generation: u64, success: bool) {
+        if generation != self.generation {
+            return;
+        }
+        if success {
+            self.failures = 0;
+            self.state = State::Closed;
+            return;
+        }
+
+        self.failures = self.failures.saturating_add(1);
+        let open = match self.state {
+            State::Closed => self.failures >= self.failure_threshold,
+            State::Open { .. } => false,
+            State::HalfOpen { .. } => true,
+        };
+        if open {
+            self.state = State::Open {
+                until: Instant::now() + self.cool_down,
+            };
+            self.generation += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn fail(circuit_breaker: &mut CircuitBreaker) {
+        let generation = circuit_breaker.record_call();
+        circuit_breaker.record(generation, false);
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let mut circuit_breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        fail(&mut circuit_breaker);
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+        fail(&mut circuit_breaker);
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+        assert!(matches!(circuit_breaker.admit(), Admit::Shed));
+    }
+
+    #[test]
+    fn successes_reset_the_failure_count() {
+        let mut circuit_breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        fail(&mut circuit_breaker);
+        let generation = circuit_breaker.record_call();
+        circuit_breaker.record(generation, true);
+        fail(&mut circuit_breaker);
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn probes_once_the_cool_down_elapses() {
+        let mut circuit_breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        fail(&mut circuit_breaker);
+        assert!(matches!(circuit_breaker.admit(), Admit::Shed));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(circuit_breaker.state(), CircuitState::HalfOpen);
+        assert!(matches!(circuit_breaker.admit(), Admit::Call));
+        let probe = circuit_breaker.record_call();
+        // Other requests wait for the probe to complete
+        assert!(matches!(circuit_breaker.admit(), Admit::Wait));
+
+        circuit_breaker.record(probe, true);
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+        assert!(matches!(circuit_breaker.admit(), Admit::Call));
+    }
+
+    #[test]
+    fn reopens_when_the_probe_fails() {
+        let mut circuit_breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        fail(&mut circuit_breaker);
+        thread::sleep(Duration::from_millis(20));
+        assert!(matches!(circuit_breaker.admit(), Admit::Call));
+        fail(&mut circuit_breaker);
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn ignores_calls_admitted_before_it_opened() {
+        let mut circuit_breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        let stale = circuit_breaker.record_call();
+        fail(&mut circuit_breaker);
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+
+        // A call which was already in flight cannot close the circuit
+        circuit_breaker.record(stale, true);
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn records_poll_ready_errors() {
+        let mut circuit_breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        circuit_breaker.record(circuit_breaker.generation(), false);
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::ready;
+use pin_project_lite::pin_project;
+use tower::{Layer, Service};
+
+/// How a [`ResponseStream`](crate::ResponseStream) handles a failed request, see
+/// [`ResponseStream::with_error_classifier`](crate::ResponseStream::with_error_classifier).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorClass {
+    /// The failure is transient. The request is retried according to the
+    /// [`RetryPolicy`](crate::RetryPolicy), and written to the [`Spool`](crate::Spool) once
+    /// retries are exhausted.
+    Retryable,
+    /// The [`Service`] shed the request without processing it, for example an error from
+    /// `tower::load_shed`. The request is written to the [`Spool`](crate::Spool), to be replayed
+    /// once the [`Service`] recovers, or retried according to the
+    /// [`RetryPolicy`](crate::RetryPolicy) if there is no spool.
+    Overloaded,
+    /// The request can never succeed, it is neither retried nor spooled.
+    Fatal,
+}
+
+/// An error returned by a [`Service`] along with the request which caused it, if it could be
+/// recovered.
+///
+/// A [`ResponseStream`](crate::ResponseStream) whose [`Service`] returns this error can retry or
+/// spool the request without cloning it first, see
+/// [`ResponseStream::with_request_recovery`](crate::ResponseStream::with_request_recovery).
+pub struct RequestError<Request, E> {
+    request: Option<Request>,
+    error: E,
+}
+
+impl<Request, E> RequestError<Request, E> {
+    /// Constructs a `RequestError` which recovers `request`.
+    pub fn new(request: Request, error: E) -> Self {
+        Self {
+            request: Some(request),
+            error,
+        }
+    }
+
+    /// Constructs a `RequestError` for which the request was lost.
+    pub fn lost(error: E) -> Self {
+        Self {
+            request: None,
+            error,
+        }
+    }
+
+    /// Returns a reference to the error.
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    /// Takes the request, if it has not already been taken.
+    pub fn take_request(&mut self) -> Option<Request> {
+        self.request.take()
+    }
+
+    /// Consumes the `RequestError`, returning the error.
+    pub fn into_error(self) -> E {
+        self.error
+    }
+}
+
+impl<Request, E> fmt::Debug for RequestError<Request, E>
+where
+    E: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestError")
+            .field("recovered", &self.request.is_some())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<Request, E> fmt::Display for RequestError<Request, E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<Request, E> Error for RequestError<Request, E>
+where
+    E: Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A [`Service`] which attaches a copy of each request to the errors returned by the inner
+/// [`Service`], as a [`RequestError`].
+///
+/// This allows requests to be recovered from middleware which drops them on failure, such as
+/// `tower::load_shed` or `tower::timeout`. Every request is cloned before it is called, whether or
+/// not the call fails, which costs the same as letting the
+/// [`ResponseStream`](crate::ResponseStream) clone it for a [`RetryPolicy`](crate::RetryPolicy) or
+/// [`Spool`](crate::Spool). Only a [`Service`] which returns the request itself avoids the clone,
+/// either in its own [`RequestError`], constructed with [`RequestError::new`], or alongside its
+/// error, see [`RecoverRequest`].
+#[derive(Debug, Clone)]
+pub struct ReturnRequest<Svc> {
+    inner: Svc,
+}
+
+impl<Svc> ReturnRequest<Svc> {
+    /// Wraps the inner [`Service`].
+    pub fn new(inner: Svc) -> Self {
+        Self { inner }
+    }
+
+    /// Consumes the `ReturnRequest`, returning the inner [`Service`].
+    pub fn into_inner(self) -> Svc {
+        self.inner
+    }
+}
+
+impl<Request, Svc> Service<Request> for ReturnRequest<Svc>
+where
+    Request: Clone,
+    Svc: Service<Request>,
+{
+    type Response = Svc::Response;
+    type Error = RequestError<Request, Svc::Error>;
+    type Future = ReturnRequestFuture<Request, Svc::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(RequestError::lost)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        ReturnRequestFuture {
+            request: Some(request.clone()),
+            future: self.inner.call(request),
+        }
+    }
+}
+
+pin_project! {
+    /// The [`Future`] returned by [`ReturnRequest`].
+    pub struct ReturnRequestFuture<Request, Fut> {
+        #[pin]
+        future: Fut,
+        request: Option<Request>,
+    }
+}
+
+impl<Request, Fut> fmt::Debug for ReturnRequestFuture<Request, Fut> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReturnRequestFuture")
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Request, Fut, Response, E> Future for ReturnRequestFuture<Request, Fut>
+where
+    Fut: Future<Output = Result<Response, E>>,
+{
+    type Output = Result<Response, RequestError<Request, E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = ready!(this.future.poll(cx));
+        Poll::Ready(output.map_err(|error| RequestError {
+            request: this.request.take(),
+            error,
+        }))
+    }
+}
+
+/// A [`Layer`] which wraps a [`Service`] in [`ReturnRequest`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReturnRequestLayer;
+
+impl<Svc> Layer<Svc> for ReturnRequestLayer {
+    type Service = ReturnRequest<Svc>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        ReturnRequest::new(inner)
+    }
+}
+
+/// A [`Service`] which converts the `(Request, E)` errors of an inner [`Service`], which returns
+/// the request it failed to process alongside its error, into [`RequestError`]s.
+///
+/// Unlike [`ReturnRequest`], requests are not cloned, the [`ResponseStream`](crate::ResponseStream)
+/// takes them back from the errors, see
+/// [`ResponseStream::with_request_recovery`](crate::ResponseStream::with_request_recovery).
+#[derive(Debug, Clone)]
+pub struct RecoverRequest<Svc> {
+    inner: Svc,
+}
+
+impl<Svc> RecoverRequest<Svc> {
+    /// Wraps the inner [`Service`].
+    pub fn new(inner: Svc) -> Self {
+        Self { inner }
+    }
+
+    /// Consumes the `RecoverRequest`, returning the inner [`Service`].
+    pub fn into_inner(self) -> Svc {
+        self.inner
+    }
+}
+
+impl<Request, Svc, E> Service<Request> for RecoverRequest<Svc>
+where
+    Svc: Service<Request, Error = (Request, E)>,
+{
+    type Response = Svc::Response;
+    type Error = RequestError<Request, E>;
+    type Future = RecoverRequestFuture<Svc::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_ready(cx)
+            .map_err(|(request, error)| RequestError::new(request, error))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        RecoverRequestFuture {
+            future: self.inner.call(request),
+        }
+    }
+}
+
+pin_project! {
+    /// The [`Future`] returned by [`RecoverRequest`].
+    pub struct RecoverRequestFuture<Fut> {
+        #[pin]
+        future: Fut,
+    }
+}
+
+impl<Fut> fmt::Debug for RecoverRequestFuture<Fut> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoverRequestFuture")
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Fut, Request, Response, E> Future for RecoverRequestFuture<Fut>
+where
+    Fut: Future<Output = Result<Response, (Request, E)>>,
+{
+    type Output = Result<Response, RequestError<Request, E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let output = ready!(self.project().future.poll(cx));
+        Poll::Ready(output.map_err(|(request, error)| RequestError::new(request, error)))
+    }
+}
+
+/// A [`Layer`] which wraps a [`Service`] in [`RecoverRequest`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecoverRequestLayer;
+
+impl<Svc> Layer<Svc> for RecoverRequestLayer {
+    type Service = RecoverRequest<Svc>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        RecoverRequest::new(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn return_request_attaches_a_copy_of_failed_requests() {
+        let service = tower::service_fn(|_: &'static str| async { Err::<(), _>("unavailable") });
+        let mut err = ReturnRequest::new(service)
+            .oneshot("request")
+            .await
+            .unwrap_err();
+        assert_eq!(*err.error(), "unavailable");
+        assert_eq!(err.take_request(), Some("request"));
+        assert_eq!(err.take_request(), None);
+    }
+
+    #[tokio::test]
+    async fn recover_request_takes_the_request_from_the_error() {
+        let service =
+            tower::service_fn(|request: &'static str| async move { Err::<(), _>((request, 7)) });
+        let mut err = RecoverRequest::new(service)
+            .oneshot("request")
+            .await
+            .unwrap_err();
+        assert_eq!(err.take_request(), Some("request"));
+        assert_eq!(err.into_error(), 7);
+    }
+}
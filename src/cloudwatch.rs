@@ -0,0 +1,928 @@
+//! An adapter which batches events into AWS CloudWatch Logs [`PutLogEvents`] requests.
+//!
+//! Each [`Entry`] is formatted as a JSON object holding the event's `level`, `target` and fields.
+//! The [`PutLogEvents`] service buffers entries, using a [`Batcher`], until a batch reaches the
+//! limits imposed by CloudWatch, and then passes a [`PutLogEventsRequest`] to the inner
+//! [`Service`], which is expected to call the API, for example using the AWS SDK:
+//!
+//! ```no_run
+//! use tracing_service::cloudwatch::{self, PutLogEventsRequest, PutLogEventsResponse};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! let client = tower::service_fn(|request: PutLogEventsRequest| async move {
+//!     // Call `PutLogEvents` using `request.log_events` and `request.sequence_token`
+//!     Ok::<_, std::io::Error>(PutLogEventsResponse::default())
+//! });
+//! let (layer, responses) = cloudwatch::layer(client, "my-group", "my-stream");
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+//!
+//! Calls are sequenced using the token returned by the previous call, so [`PutLogEvents`] is not
+//! ready while a batch is being sent. Buffered entries are sent once a batch is full, use
+//! [`PutLogEvents::with_max_delay`] to bound their latency. The [`ResponseStream`] returned by
+//! [`layer`] sends them once the maximum delay passes, when
+//! [`Handle::flush`](crate::Handle::flush) is called and once its queue closes, see
+//! [`ResponseStream::with_buffer_flush`].
+//!
+//! A batch which fails to send is kept by [`PutLogEvents`] and sent again, see
+//! [`PutLogEvents::with_max_attempts`], so the [`ResponseStream`] should not retry or spool
+//! failed entries.
+//!
+//! [`PutLogEvents`]: https://docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutLogEvents.html
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::{self, Future},
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures_core::ready;
+use pin_project_lite::pin_project;
+use serde_json::{Map, Value};
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{Flush, RecordResource, Reset, Resource, ResponseStream, ServiceLayer};
+
+/// The maximum number of events in a single `PutLogEvents` call.
+pub const MAX_BATCH_EVENTS: usize = 10_000;
+
+/// The maximum size of a single `PutLogEvents` call, in bytes.
+pub const MAX_BATCH_BYTES: usize = 1_048_576;
+
+/// The maximum size of a single event, in bytes, including [`EVENT_OVERHEAD`].
+pub const MAX_EVENT_BYTES: usize = 262_144;
+
+/// The number of bytes CloudWatch adds to the size of each event's message.
+pub const EVENT_OVERHEAD: usize = 26;
+
+/// The maximum time spanned by the events in a single `PutLogEvents` call.
+const MAX_BATCH_SPAN_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Constructs a [`ServiceLayer`] which sends events to `log_stream_name`, in `log_group_name`,
+/// using `service`.
+pub fn layer<Svc>(
+    service: Svc,
+    log_group_name: impl Into<String>,
+    log_stream_name: impl Into<String>,
+) -> (
+    ServiceLayer<Entry, CloudWatch>,
+    ResponseStream<Entry, PutLogEvents<Svc>>,
+)
+where
+    Svc: Service<PutLogEventsRequest, Response = PutLogEventsResponse>,
+{
+    let service = PutLogEvents::new(service, log_group_name, log_stream_name);
+    let (layer, responses) = ServiceLayer::new(service, CloudWatch);
+    (
+        layer.with_metadata(record_metadata),
+        responses.with_buffer_flush(),
+    )
+}
+
+/// Records the timestamp, `level` and `target` of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(entry: &mut Entry, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    entry.timestamp = unix_millis(timestamp);
+    entry
+        .fields
+        .insert("level".into(), metadata.level().as_str().into());
+    entry
+        .fields
+        .insert("target".into(), metadata.target().into());
+}
+
+fn unix_millis(timestamp: SystemTime) -> i64 {
+    let millis = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    millis.try_into().unwrap_or(i64::MAX)
+}
+
+/// An event recorded as a JSON object, along with its timestamp.
+///
+/// The [`fmt::Display`] implementation formats the object as a single line of JSON, which is used
+/// as the message of the [`InputLogEvent`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    timestamp: i64,
+    fields: Map<String, Value>,
+}
+
+impl Entry {
+    /// Returns the timestamp, in milliseconds since the Unix epoch.
+    pub fn timestamp_millis(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Returns the fields of the entry.
+    pub fn as_map(&self) -> &Map<String, Value> {
+        &self.fields
+    }
+}
+
+impl RecordResource for Entry {
+    /// Records the attributes under `resource`.
+    fn record_resource(&mut self, resource: &Resource) {
+        let attributes = resource
+            .attributes()
+            .map(|(key, value)| (key.to_owned(), value.into()))
+            .collect();
+        self.fields
+            .insert("resource".into(), Value::Object(attributes));
+    }
+}
+
+impl Reset for Entry {
+    fn reset(&mut self) {
+        self.timestamp = 0;
+        self.fields.clear();
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(&self.fields).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+impl From<Entry> for InputLogEvent {
+    fn from(entry: Entry) -> Self {
+        InputLogEvent::new(entry.timestamp, entry.to_string())
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into an [`Entry`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloudWatch;
+
+impl<'a> field::MakeVisitor<&'a mut Entry> for CloudWatch {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, entry: &'a mut Entry) -> Self::Visitor {
+        Visitor { entry }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`CloudWatch`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    entry: &'a mut Entry,
+}
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.entry.fields.insert(field.name().into(), value);
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+/// A single log event within a [`PutLogEventsRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputLogEvent {
+    /// The time of the event, in milliseconds since the Unix epoch.
+    pub timestamp: i64,
+    /// The message of the event.
+    pub message: String,
+}
+
+impl InputLogEvent {
+    /// Constructs an `InputLogEvent`, truncating `message` so the event is at most
+    /// [`MAX_EVENT_BYTES`].
+    pub fn new(timestamp: i64, mut message: String) -> Self {
+        let max_len = MAX_EVENT_BYTES - EVENT_OVERHEAD;
+        if message.len() > max_len {
+            let mut len = max_len;
+            while !message.is_char_boundary(len) {
+                len -= 1;
+            }
+            message.truncate(len);
+        }
+        Self { timestamp, message }
+    }
+
+    /// The size of the event, as counted towards [`MAX_BATCH_BYTES`].
+    pub fn size(&self) -> usize {
+        self.message.len() + EVENT_OVERHEAD
+    }
+}
+
+/// The request passed to the [`Service`] wrapped by [`PutLogEvents`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PutLogEventsRequest {
+    /// The name of the log group.
+    pub log_group_name: String,
+    /// The name of the log stream.
+    pub log_stream_name: String,
+    /// The events, in chronological order.
+    pub log_events: Vec<InputLogEvent>,
+    /// The sequence token returned by the previous call, if any.
+    pub sequence_token: Option<String>,
+}
+
+/// The response expected from the [`Service`] wrapped by [`PutLogEvents`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PutLogEventsResponse {
+    /// The sequence token to pass to the next call, if any.
+    pub next_sequence_token: Option<String>,
+}
+
+/// Accumulates [`InputLogEvent`]s into batches which satisfy the constraints of `PutLogEvents`.
+///
+/// A batch holds at most [`MAX_BATCH_EVENTS`] events totalling at most [`MAX_BATCH_BYTES`], all
+/// within 24 hours of each other, and is sorted chronologically when taken.
+#[derive(Debug, Clone)]
+pub struct Batcher {
+    events: Vec<InputLogEvent>,
+    bytes: usize,
+    max_events: usize,
+    max_bytes: usize,
+    // The earliest and latest timestamps in the batch
+    span: Option<(i64, i64)>,
+}
+
+impl Default for Batcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batcher {
+    /// Constructs an empty `Batcher` using the limits imposed by CloudWatch.
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            bytes: 0,
+            max_events: MAX_BATCH_EVENTS,
+            max_bytes: MAX_BATCH_BYTES,
+            span: None,
+        }
+    }
+
+    /// Limits each batch to `max_events`, which is clamped to [`MAX_BATCH_EVENTS`].
+    pub fn with_max_events(mut self, max_events: usize) -> Self {
+        self.max_events = max_events.clamp(1, MAX_BATCH_EVENTS);
+        self
+    }
+
+    /// Limits each batch to `max_bytes`, which is clamped to [`MAX_BATCH_BYTES`].
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes.clamp(MAX_EVENT_BYTES, MAX_BATCH_BYTES);
+        self
+    }
+
+    /// The number of events in the current batch.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if the current batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Adds `event` to the current batch.
+    ///
+    /// If `event` does not fit in the current batch, the current batch is returned and `event`
+    /// starts the next one.
+    pub fn push(&mut self, event: InputLogEvent) -> Option<Vec<InputLogEvent>> {
+        let (earliest, latest) = match self.span {
+            Some((earliest, latest)) => {
+                (earliest.min(event.timestamp), latest.max(event.timestamp))
+            }
+            None => (event.timestamp, event.timestamp),
+        };
+        let fits = self.events.len() < self.max_events
+            && self.bytes + event.size() <= self.max_bytes
+            && latest - earliest <= MAX_BATCH_SPAN_MS;
+
+        let full = if fits || self.events.is_empty() {
+            self.span = Some((earliest, latest));
+            None
+        } else {
+            let full = self.take();
+            self.span = Some((event.timestamp, event.timestamp));
+            full
+        };
+        self.bytes += event.size();
+        self.events.push(event);
+        full
+    }
+
+    /// Takes the current batch, sorted chronologically, if it is not empty.
+    pub fn take(&mut self) -> Option<Vec<InputLogEvent>> {
+        if self.events.is_empty() {
+            return None;
+        }
+        let mut events = mem::take(&mut self.events);
+        events.sort_by_key(|event| event.timestamp);
+        self.bytes = 0;
+        self.span = None;
+        Some(events)
+    }
+}
+
+/// A [`Service`] which batches events, using a [`Batcher`], before passing them to the inner
+/// [`Service`] as a [`PutLogEventsRequest`].
+///
+/// Responds with `None` when the event was buffered, and with the inner response when a batch was
+/// sent. Each call sends at most one batch, and only one batch is sent at a time, so it is not
+/// ready while a batch is being sent. A batch which fails is sent again ahead of any later batch,
+/// by the next call once the maximum delay has passed, or when flushed, until it has been
+/// attempted [`PutLogEvents::with_max_attempts`] times.
+pub struct PutLogEvents<Svc> {
+    inner: Svc,
+    log_group_name: String,
+    log_stream_name: String,
+    batcher: Batcher,
+    max_delay: Option<Duration>,
+    max_attempts: u32,
+    // When the first event of the current batch was buffered
+    started: Option<Instant>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// The state shared by a [`PutLogEvents`] and the futures of its calls.
+#[derive(Debug, Default)]
+struct Shared {
+    sequence_token: Option<String>,
+    // Batches waiting to be sent, along with the number of times each has been attempted
+    unsent: VecDeque<(Vec<InputLogEvent>, u32)>,
+    // When the failed batch at the front of `unsent` is due to be sent again
+    retry_at: Option<Instant>,
+    // Whether a batch is being sent, and the task waiting for it to finish
+    sending: bool,
+    ready_waker: Option<Waker>,
+}
+
+impl<Svc> fmt::Debug for PutLogEvents<Svc>
+where
+    Svc: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PutLogEvents")
+            .field("inner", &self.inner)
+            .field("log_group_name", &self.log_group_name)
+            .field("log_stream_name", &self.log_stream_name)
+            .field("batcher", &self.batcher)
+            .field("max_delay", &self.max_delay)
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Svc> PutLogEvents<Svc> {
+    /// Constructs a `PutLogEvents` which sends to `log_stream_name`, in `log_group_name`, using
+    /// the limits imposed by CloudWatch, without a maximum delay, and which attempts each batch up
+    /// to three times.
+    pub fn new(
+        inner: Svc,
+        log_group_name: impl Into<String>,
+        log_stream_name: impl Into<String>,
+    ) -> Self {
+        Self {
+            inner,
+            log_group_name: log_group_name.into(),
+            log_stream_name: log_stream_name.into(),
+            batcher: Batcher::new(),
+            max_delay: None,
+            max_attempts: 3,
+            started: None,
+            shared: Arc::default(),
+        }
+    }
+
+    /// Uses `batcher` to accumulate events, see [`Batcher::with_max_events`] and
+    /// [`Batcher::with_max_bytes`].
+    pub fn with_batcher(mut self, batcher: Batcher) -> Self {
+        self.batcher = batcher;
+        self
+    }
+
+    /// Sends the current batch once `max_delay` has passed since its first event was buffered,
+    /// and a failed batch once `max_delay` has passed since it failed.
+    ///
+    /// The batch is sent by the next call, or by the [`ResponseStream`] using its timer if it was
+    /// configured using [`ResponseStream::with_buffer_flush`], see [`Flush::flush_deadline`].
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Sends each batch at most `max_attempts` times, dropping it if every attempt fails.
+    ///
+    /// Failed batches are kept in memory until they are sent again, a `max_attempts` of one
+    /// drops them immediately, without copying each batch before it is sent.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Starts the sequence with `sequence_token`, which is otherwise taken from each response.
+    pub fn with_sequence_token(self, sequence_token: impl Into<String>) -> Self {
+        self.lock().sequence_token = Some(sequence_token.into());
+        self
+    }
+
+    /// Returns a reference to the inner [`Service`].
+    pub fn get_ref(&self) -> &Svc {
+        &self.inner
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn is_overdue(&self) -> bool {
+        match (self.started, self.max_delay) {
+            (Some(started), Some(max_delay)) => started.elapsed() >= max_delay,
+            _ => false,
+        }
+    }
+
+    /// Moves the current batch, if it is not empty, behind the batches waiting to be sent.
+    fn take_batch(&mut self) {
+        self.started = None;
+        if let Some(log_events) = self.batcher.take() {
+            self.lock().unsent.push_back((log_events, 0));
+        }
+    }
+}
+
+impl<Svc> PutLogEvents<Svc>
+where
+    Svc: Service<PutLogEventsRequest, Response = PutLogEventsResponse>,
+{
+    /// Sends the current batch, along with any batches waiting to be sent again.
+    ///
+    /// This should be called once the [`ResponseStream`] has finished, using
+    /// [`ResponseStream::into_inner`], so buffered events are not lost, unless the stream sends
+    /// them itself, see [`ResponseStream::with_buffer_flush`]. Failed batches are sent
+    /// without waiting for the maximum delay. Returns the response to the last batch sent,
+    /// stopping at the first which fails.
+    pub async fn flush(&mut self) -> Result<Option<PutLogEventsResponse>, Svc::Error> {
+        self.take_batch();
+        let mut response = None;
+        loop {
+            future::poll_fn(|cx| self.poll_sender(cx)).await?;
+            let Some(future) = self.send_next(true) else {
+                return Ok(response);
+            };
+            response = future.await?;
+        }
+    }
+
+    /// Waits for the batch being sent, if any, and then for the inner [`Service`].
+    fn poll_sender(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Svc::Error>> {
+        {
+            let mut shared = self.lock();
+            if shared.sending {
+                shared.ready_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    /// Sends the batch at the front of those waiting to be sent, if any, unless it failed and is
+    /// not yet due to be sent again. A `force` send ignores when it is due.
+    fn send_next(&mut self, force: bool) -> Option<ResponseFuture<Svc::Future>> {
+        let (log_events, attempts, sequence_token) = {
+            let mut shared = self.lock();
+            let due = shared
+                .retry_at
+                .map_or(true, |retry_at| retry_at <= Instant::now());
+            if shared.sending || !(due || force) {
+                return None;
+            }
+            let (log_events, attempts) = shared.unsent.pop_front()?;
+            shared.retry_at = None;
+            shared.sending = true;
+            (log_events, attempts, shared.sequence_token.clone())
+        };
+        // The batch is copied so it can be sent again if this attempt fails
+        let attempts = attempts + 1;
+        let retry = (attempts < self.max_attempts).then(|| (log_events.clone(), attempts));
+        let request = PutLogEventsRequest {
+            log_group_name: self.log_group_name.clone(),
+            log_stream_name: self.log_stream_name.clone(),
+            log_events,
+            sequence_token,
+        };
+        Some(ResponseFuture {
+            inner: Some(self.inner.call(request)),
+            retry,
+            retry_delay: self.max_delay,
+            sending: Some(Sending(self.shared.clone())),
+        })
+    }
+}
+
+impl<Request, Svc> Service<Request> for PutLogEvents<Svc>
+where
+    Request: Into<InputLogEvent>,
+    Svc: Service<PutLogEventsRequest, Response = PutLogEventsResponse>,
+{
+    type Response = Option<PutLogEventsResponse>;
+    type Error = Svc::Error;
+    type Future = ResponseFuture<Svc::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_sender(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let overdue = self.is_overdue();
+        match self.batcher.push(request.into()) {
+            Some(log_events) => {
+                self.started = Some(Instant::now());
+                self.lock().unsent.push_back((log_events, 0));
+            }
+            None if overdue => self.take_batch(),
+            None => {
+                self.started.get_or_insert_with(Instant::now);
+            }
+        }
+
+        self.send_next(false).unwrap_or(ResponseFuture {
+            inner: None,
+            retry: None,
+            retry_delay: None,
+            sending: None,
+        })
+    }
+}
+
+impl<Request, Svc> Flush<Request> for PutLogEvents<Svc>
+where
+    Request: Into<InputLogEvent>,
+    Svc: Service<PutLogEventsRequest, Response = PutLogEventsResponse>,
+{
+    /// Sends the next batch waiting to be sent, or the current batch, without waiting for a
+    /// failed batch to be due.
+    fn flush_buffered(&mut self) -> Option<Self::Future> {
+        if self.lock().unsent.is_empty() {
+            self.take_batch();
+        }
+        self.send_next(true)
+    }
+
+    /// When the current batch reaches the maximum delay, or a failed batch is due to be sent
+    /// again, see [`PutLogEvents::with_max_delay`].
+    fn flush_deadline(&self) -> Option<Instant> {
+        let batch = self
+            .started
+            .zip(self.max_delay)
+            .map(|(started, max_delay)| started + max_delay);
+        match (batch, self.lock().retry_at) {
+            (Some(batch), Some(retry_at)) => Some(batch.min(retry_at)),
+            (deadline, None) | (None, deadline) => deadline,
+        }
+    }
+}
+
+/// Marks the batch of a [`ResponseFuture`] as being sent until it is dropped, waking the task
+/// waiting for [`PutLogEvents`] to be ready.
+struct Sending(Arc<Mutex<Shared>>);
+
+impl Drop for Sending {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = self.0.lock().unwrap_or_else(|err| err.into_inner());
+            shared.sending = false;
+            shared.ready_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pin_project! {
+    /// The [`Future`] returned by [`PutLogEvents`], which records the next sequence token, and
+    /// keeps the batch to be sent again if it fails.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: Option<F>,
+        retry: Option<(Vec<InputLogEvent>, u32)>,
+        retry_delay: Option<Duration>,
+        sending: Option<Sending>,
+    }
+}
+
+impl<F> fmt::Debug for ResponseFuture<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseFuture")
+            .field("sent", &self.inner.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<PutLogEventsResponse, E>>,
+{
+    type Output = Result<Option<PutLogEventsResponse>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let Some(inner) = this.inner.as_pin_mut() else {
+            return Poll::Ready(Ok(None));
+        };
+        let output = ready!(inner.poll(cx));
+        if let Some(Sending(shared)) = this.sending.as_ref() {
+            let mut shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+            match &output {
+                Ok(response) => shared.sequence_token = response.next_sequence_token.clone(),
+                Err(_) => {
+                    if let Some(retry) = this.retry.take() {
+                        shared.unsent.push_front(retry);
+                        shared.retry_at = this.retry_delay.map(|delay| Instant::now() + delay);
+                    }
+                }
+            }
+        }
+        // The next batch may be sent
+        this.sending.take();
+        Poll::Ready(output.map(Some))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn event(timestamp: i64, message: &str) -> InputLogEvent {
+        InputLogEvent::new(timestamp, message.into())
+    }
+
+    fn messages(log_events: &[InputLogEvent]) -> Vec<&str> {
+        log_events
+            .iter()
+            .map(|event| event.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn batcher_starts_a_new_batch_once_full() {
+        let mut batcher = Batcher::new().with_max_events(2);
+        assert_eq!(batcher.push(event(1, "a")), None);
+        assert_eq!(batcher.push(event(2, "b")), None);
+        let full = batcher.push(event(3, "c")).unwrap();
+        assert_eq!(messages(&full), ["a", "b"]);
+        assert_eq!(batcher.len(), 1);
+    }
+
+    #[test]
+    fn batcher_limits_bytes() {
+        let mut batcher = Batcher::new().with_max_bytes(MAX_EVENT_BYTES);
+        let large = "x".repeat(MAX_EVENT_BYTES / 2);
+        assert_eq!(batcher.push(event(1, &large)), None);
+        assert!(batcher.push(event(2, &large)).is_some());
+    }
+
+    #[test]
+    fn batcher_limits_the_time_spanned() {
+        let mut batcher = Batcher::new();
+        batcher.push(event(0, "a"));
+        assert_eq!(batcher.push(event(MAX_BATCH_SPAN_MS, "b")), None);
+        let full = batcher.push(event(MAX_BATCH_SPAN_MS + 1, "c")).unwrap();
+        assert_eq!(messages(&full), ["a", "b"]);
+    }
+
+    #[test]
+    fn batcher_sorts_batches_chronologically() {
+        let mut batcher = Batcher::new();
+        batcher.push(event(3, "c"));
+        batcher.push(event(1, "a"));
+        batcher.push(event(2, "b"));
+        assert_eq!(messages(&batcher.take().unwrap()), ["a", "b", "c"]);
+        assert_eq!(batcher.take(), None);
+    }
+
+    #[test]
+    fn truncates_large_events() {
+        let event = event(0, &"é".repeat(MAX_EVENT_BYTES));
+        assert!(event.size() <= MAX_EVENT_BYTES);
+    }
+
+    type Sent = Arc<Mutex<Vec<(Vec<String>, Option<String>)>>>;
+
+    /// A client which fails the calls whose index is in `failures`, recording the batches of
+    /// those which succeed.
+    struct Client {
+        calls: usize,
+        failures: &'static [usize],
+        sent: Sent,
+    }
+
+    impl Service<PutLogEventsRequest> for Client {
+        type Response = PutLogEventsResponse;
+        type Error = &'static str;
+        type Future = future::Ready<Result<PutLogEventsResponse, &'static str>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: PutLogEventsRequest) -> Self::Future {
+            let call = self.calls;
+            self.calls += 1;
+            if self.failures.contains(&call) {
+                return future::ready(Err("unavailable"));
+            }
+            let messages = request
+                .log_events
+                .into_iter()
+                .map(|event| event.message)
+                .collect();
+            self.sent
+                .lock()
+                .unwrap()
+                .push((messages, request.sequence_token));
+            future::ready(Ok(PutLogEventsResponse {
+                next_sequence_token: Some(format!("token {call}")),
+            }))
+        }
+    }
+
+    fn client(failures: &'static [usize]) -> (Client, Sent) {
+        let sent = Sent::default();
+        let client = Client {
+            calls: 0,
+            failures,
+            sent: sent.clone(),
+        };
+        (client, sent)
+    }
+
+    fn batches(sent: &Sent) -> Vec<Vec<String>> {
+        let sent = sent.lock().unwrap();
+        sent.iter().map(|(messages, _)| messages.clone()).collect()
+    }
+
+    async fn call(
+        service: &mut PutLogEvents<Client>,
+        message: &str,
+    ) -> Result<Option<PutLogEventsResponse>, &'static str> {
+        ServiceExt::<InputLogEvent>::ready(service).await?;
+        Service::<InputLogEvent>::call(service, event(0, message)).await
+    }
+
+    #[tokio::test]
+    async fn sequences_calls() {
+        let (client, sent) = client(&[]);
+        let mut service = PutLogEvents::new(client, "group", "stream")
+            .with_batcher(Batcher::new().with_max_events(1))
+            .with_sequence_token("first");
+        call(&mut service, "a").await.unwrap();
+        call(&mut service, "b").await.unwrap();
+        call(&mut service, "c").await.unwrap();
+        let tokens: Vec<_> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, token)| token.clone())
+            .collect();
+        assert_eq!(tokens, [Some("first".into()), Some("token 0".into())]);
+    }
+
+    #[tokio::test]
+    async fn is_not_ready_while_a_batch_is_being_sent() {
+        let (client, sent) = client(&[]);
+        let mut service = PutLogEvents::new(client, "group", "stream")
+            .with_batcher(Batcher::new().with_max_events(1));
+        call(&mut service, "a").await.unwrap();
+        let sending = Service::<InputLogEvent>::call(&mut service, event(0, "b"));
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Service::<InputLogEvent>::poll_ready(&mut service, &mut cx).is_pending());
+        sending.await.unwrap();
+        assert!(Service::<InputLogEvent>::poll_ready(&mut service, &mut cx).is_ready());
+
+        // A dropped call no longer blocks the next
+        drop(Service::<InputLogEvent>::call(&mut service, event(0, "c")));
+        call(&mut service, "d").await.unwrap();
+        assert_eq!(batches(&sent), [vec!["a"], vec!["b"], vec!["c"]]);
+    }
+
+    #[tokio::test]
+    async fn resends_failed_batches() {
+        let (client, sent) = client(&[0]);
+        let mut service = PutLogEvents::new(client, "group", "stream")
+            .with_batcher(Batcher::new().with_max_events(2));
+        call(&mut service, "a").await.unwrap();
+        call(&mut service, "b").await.unwrap();
+        // The batch of "a" and "b" is sent, and fails
+        assert!(call(&mut service, "c").await.is_err());
+        // The failed batch is sent again, ahead of the rest
+        assert!(call(&mut service, "d").await.unwrap().is_some());
+        service.flush().await.unwrap();
+
+        assert_eq!(batches(&sent), [vec!["a", "b"], vec!["c", "d"]]);
+    }
+
+    #[tokio::test]
+    async fn resends_failed_batches_once_the_max_delay_passes() {
+        let (client, sent) = client(&[0]);
+        let mut service = PutLogEvents::new(client, "group", "stream")
+            .with_batcher(Batcher::new().with_max_events(1))
+            .with_max_delay(Duration::from_secs(60));
+        call(&mut service, "a").await.unwrap();
+        assert!(call(&mut service, "b").await.is_err());
+        // The failed batch is not yet due, so the later batch waits behind it
+        assert!(call(&mut service, "c").await.unwrap().is_none());
+        assert!(batches(&sent).is_empty());
+
+        service.lock().retry_at = Some(Instant::now());
+        assert!(call(&mut service, "d").await.unwrap().is_some());
+        assert_eq!(batches(&sent), [vec!["a"]]);
+        // Flushing does not wait for the maximum delay
+        service.flush().await.unwrap();
+        assert_eq!(batches(&sent), [vec!["a"], vec!["b"], vec!["c"], vec!["d"]]);
+    }
+
+    #[tokio::test]
+    async fn drops_batches_once_attempts_are_exhausted() {
+        let (client, sent) = client(&[0, 1]);
+        let mut service = PutLogEvents::new(client, "group", "stream")
+            .with_batcher(Batcher::new().with_max_events(1))
+            .with_max_attempts(2);
+        call(&mut service, "a").await.unwrap();
+        assert!(call(&mut service, "b").await.is_err());
+        assert!(call(&mut service, "c").await.is_err());
+        call(&mut service, "d").await.unwrap();
+        service.flush().await.unwrap();
+
+        assert_eq!(batches(&sent), [vec!["b"], vec!["c"], vec!["d"]]);
+    }
+
+    #[tokio::test]
+    async fn flush_deadline_follows_the_max_delay() {
+        let (client, _) = client(&[0]);
+        let max_delay = Duration::from_secs(5);
+        let mut service = PutLogEvents::new(client, "group", "stream").with_max_delay(max_delay);
+        assert_eq!(Flush::<InputLogEvent>::flush_deadline(&service), None);
+
+        let before = Instant::now();
+        call(&mut service, "a").await.unwrap();
+        let deadline = Flush::<InputLogEvent>::flush_deadline(&service).unwrap();
+        assert!(deadline >= before + max_delay && deadline <= Instant::now() + max_delay);
+
+        // A failed batch is due to be sent again once the maximum delay passes
+        let flush = Flush::<InputLogEvent>::flush_buffered(&mut service).unwrap();
+        let failed = Instant::now();
+        assert!(flush.await.is_err());
+        let deadline = Flush::<InputLogEvent>::flush_deadline(&service).unwrap();
+        assert!(deadline >= failed + max_delay);
+    }
+}
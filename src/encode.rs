@@ -0,0 +1,567 @@
+//! Binary requests, encoded from the fields of an event by a pluggable [`Encoder`].
+//!
+//! Each [`Encoded`] request collects the event's fields, along with its metadata and span scope,
+//! and encodes them once the event has been visited. This suits backends which expect framed
+//! binary payloads, such as Kafka-style services, Vector or the fluentd forward protocol.
+//!
+//! ```no_run
+//! use tracing_service::encode::{self, Encoded, LengthDelimited, MessagePack};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! # let service = tower::service_fn(|_: Encoded| async { Ok::<_, ()>(()) });
+//! let (layer, responses) = encode::layer(service, LengthDelimited::new(MessagePack));
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+//!
+//! Other formats, such as protobuf, can be supported by implementing [`Encoder`].
+
+use std::{borrow::Cow, error::Error, fmt, mem, time::SystemTime};
+
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{
+    timestamp::rfc3339, EventVisitor, RecordResource, Reset, Resource, ResponseStream, ScopeSpan,
+    ServiceLayer,
+};
+
+/// Constructs a [`ServiceLayer`] which sends each event to `service` as an [`Encoded`] request,
+/// including its metadata and span scope, encoded using `encoder`.
+///
+/// Events which fail to encode are dropped, see [`ServiceLayer::drop_visitor_errors`].
+pub fn layer<Svc, E>(
+    service: Svc,
+    encoder: E,
+) -> (
+    ServiceLayer<Encoded, Encode<E>>,
+    ResponseStream<Encoded, Svc>,
+)
+where
+    Svc: Service<Encoded>,
+{
+    let (layer, responses) = ServiceLayer::new(service, Encode::new(encoder));
+    let layer = layer
+        .with_metadata(record_metadata)
+        .with_span_scope(record_span)
+        .drop_visitor_errors();
+    (layer, responses)
+}
+
+/// Records the `timestamp`, `level` and `target` of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(request: &mut Encoded, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    request.push("timestamp", Value::Str(rfc3339(timestamp)));
+    request.push("level", Value::Str(metadata.level().as_str().into()));
+    request.push("target", Value::Str(metadata.target().into()));
+}
+
+/// Records the fields of a span in the scope of an event, prefixed by the span's name.
+///
+/// This is intended to be passed to [`ServiceLayer::with_span_scope`].
+pub fn record_span(request: &mut Encoded, span: &ScopeSpan<'_>) {
+    span.record(&mut Visitor {
+        request,
+        prefix: Some(span.name()),
+        encoder: &(),
+    });
+}
+
+/// A field value, see [`Encoder`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Value {
+    /// A floating point number.
+    F64(f64),
+    /// A signed integer.
+    I64(i64),
+    /// An unsigned integer.
+    U64(u64),
+    /// A signed 128-bit integer.
+    I128(i128),
+    /// An unsigned 128-bit integer.
+    U128(u128),
+    /// A boolean.
+    Bool(bool),
+    /// A string, values recorded using their [`fmt::Debug`] implementation are formatted into
+    /// strings.
+    Str(String),
+}
+
+/// A field name and its value.
+pub type Fields = [(Cow<'static, str>, Value)];
+
+/// The error returned by an [`Encoder`].
+#[derive(Debug)]
+pub struct EncodeError {
+    message: Cow<'static, str>,
+}
+
+impl EncodeError {
+    /// Constructs an `EncodeError` with a message.
+    pub fn new(message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for EncodeError {}
+
+/// Encodes the fields of an event into bytes.
+pub trait Encoder {
+    /// Appends the encoding of `fields` to `buf`.
+    fn encode(&self, fields: &Fields, buf: &mut Vec<u8>) -> Result<(), EncodeError>;
+}
+
+impl Encoder for () {
+    fn encode(&self, _fields: &Fields, _buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        Ok(())
+    }
+}
+
+/// An [`Encoder`] which encodes the fields as a [MessagePack] map.
+///
+/// 128-bit integers which do not fit in 64 bits are encoded as strings.
+///
+/// [MessagePack]: https://msgpack.org
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl MessagePack {
+    fn write_len(buf: &mut Vec<u8>, len: usize, fix: (u8, usize), markers: [u8; 3]) {
+        let (fix_marker, fix_max) = fix;
+        if len <= fix_max {
+            buf.push(fix_marker | len as u8);
+        } else if markers[0] != 0 && len <= u8::MAX as usize {
+            buf.extend_from_slice(&[markers[0], len as u8]);
+        } else if len <= u16::MAX as usize {
+            buf.push(markers[1]);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            buf.push(markers[2]);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+
+    fn write_str(buf: &mut Vec<u8>, value: &str) {
+        Self::write_len(buf, value.len(), (0xa0, 31), [0xd9, 0xda, 0xdb]);
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn write_u64(buf: &mut Vec<u8>, value: u64) {
+        if value < 0x80 {
+            buf.push(value as u8);
+        } else if let Ok(value) = u8::try_from(value) {
+            buf.extend_from_slice(&[0xcc, value]);
+        } else if let Ok(value) = u16::try_from(value) {
+            buf.push(0xcd);
+            buf.extend_from_slice(&value.to_be_bytes());
+        } else if let Ok(value) = u32::try_from(value) {
+            buf.push(0xce);
+            buf.extend_from_slice(&value.to_be_bytes());
+        } else {
+            buf.push(0xcf);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn write_i64(buf: &mut Vec<u8>, value: i64) {
+        if let Ok(value) = u64::try_from(value) {
+            Self::write_u64(buf, value);
+        } else if value >= -32 {
+            buf.push(value as u8);
+        } else if let Ok(value) = i8::try_from(value) {
+            buf.extend_from_slice(&[0xd0, value as u8]);
+        } else if let Ok(value) = i16::try_from(value) {
+            buf.push(0xd1);
+            buf.extend_from_slice(&value.to_be_bytes());
+        } else if let Ok(value) = i32::try_from(value) {
+            buf.push(0xd2);
+            buf.extend_from_slice(&value.to_be_bytes());
+        } else {
+            buf.push(0xd3);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn write_value(buf: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::F64(value) => {
+                buf.push(0xcb);
+                buf.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::I64(value) => Self::write_i64(buf, *value),
+            Value::U64(value) => Self::write_u64(buf, *value),
+            Value::I128(value) => match i64::try_from(*value) {
+                Ok(value) => Self::write_i64(buf, value),
+                Err(_) => Self::write_str(buf, &value.to_string()),
+            },
+            Value::U128(value) => match u64::try_from(*value) {
+                Ok(value) => Self::write_u64(buf, value),
+                Err(_) => Self::write_str(buf, &value.to_string()),
+            },
+            Value::Bool(value) => buf.push(if *value { 0xc3 } else { 0xc2 }),
+            Value::Str(value) => Self::write_str(buf, value),
+        }
+    }
+}
+
+impl Encoder for MessagePack {
+    fn encode(&self, fields: &Fields, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        if u32::try_from(fields.len()).is_err() {
+            return Err(EncodeError::new("too many fields for a MessagePack map"));
+        }
+        Self::write_len(buf, fields.len(), (0x80, 15), [0, 0xde, 0xdf]);
+        for (name, value) in fields {
+            if u32::try_from(name.len()).is_err() {
+                return Err(EncodeError::new("field name too long for MessagePack"));
+            }
+            Self::write_str(buf, name);
+            if matches!(value, Value::Str(value) if u32::try_from(value.len()).is_err()) {
+                return Err(EncodeError::new("field value too long for MessagePack"));
+            }
+            Self::write_value(buf, value);
+        }
+        Ok(())
+    }
+}
+
+/// An [`Encoder`] which frames the output of another [`Encoder`], prefixing it with its length as
+/// a big-endian `u32`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthDelimited<E> {
+    inner: E,
+}
+
+impl<E> LengthDelimited<E> {
+    /// Constructs a `LengthDelimited` which frames the output of `inner`.
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+}
+
+impl<E> Encoder for LengthDelimited<E>
+where
+    E: Encoder,
+{
+    fn encode(&self, fields: &Fields, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        let len = self.inner.encode(fields, buf).and_then(|()| {
+            u32::try_from(buf.len() - start - 4)
+                .map_err(|_| EncodeError::new("frame longer than u32::MAX bytes"))
+        });
+        match len {
+            Ok(len) => {
+                buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+                Ok(())
+            }
+            // Remove the partial frame, so it cannot be mistaken for a complete one
+            Err(err) => {
+                buf.truncate(start);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// An event encoded as bytes, see [`Encoder`].
+///
+/// Fields are collected as the event is recorded, and encoded once every field has been visited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Encoded {
+    fields: Vec<(Cow<'static, str>, Value)>,
+    bytes: Vec<u8>,
+}
+
+impl Encoded {
+    /// Returns the encoded bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Consumes the request, returning the encoded bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn push(&mut self, name: impl Into<Cow<'static, str>>, value: Value) {
+        self.fields.push((name.into(), value));
+    }
+}
+
+impl RecordResource for Encoded {
+    fn record_resource(&mut self, resource: &Resource) {
+        for (key, value) in resource.attributes() {
+            self.push(key.to_owned(), Value::Str(value.into()));
+        }
+    }
+}
+
+impl Reset for Encoded {
+    fn reset(&mut self) {
+        self.fields.clear();
+        self.bytes.clear();
+    }
+}
+
+impl From<Encoded> for Vec<u8> {
+    fn from(request: Encoded) -> Self {
+        request.bytes
+    }
+}
+
+impl AsRef<[u8]> for Encoded {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into an [`Encoded`] request
+/// and encodes them using an [`Encoder`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encode<E> {
+    encoder: E,
+}
+
+impl<E> Encode<E> {
+    /// Constructs an `Encode` which encodes requests using `encoder`.
+    pub fn new(encoder: E) -> Self {
+        Self { encoder }
+    }
+}
+
+impl<'a, E> field::MakeVisitor<&'a mut Encoded> for Encode<E>
+where
+    E: Encoder + Clone,
+{
+    type Visitor = Visitor<'a, E>;
+
+    fn make_visitor(&self, request: &'a mut Encoded) -> Self::Visitor {
+        Visitor {
+            request,
+            prefix: None,
+            encoder: self.encoder.clone(),
+        }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Encode`].
+///
+/// Encoding errors are returned from [`VisitOutput::finish`] and counted by
+/// [`LayerMetrics::visitor_errors`](crate::LayerMetrics::visitor_errors). The partial frame is
+/// removed, so [`layer`] drops requests which fail to encode.
+#[derive(Debug)]
+pub struct Visitor<'a, E> {
+    request: &'a mut Encoded,
+    prefix: Option<&'static str>,
+    encoder: E,
+}
+
+impl<E> Visitor<'_, E> {
+    fn push(&mut self, field: &Field, value: Value) {
+        let name = match self.prefix {
+            Some(prefix) => Cow::Owned(format!("{prefix}.{}", field.name())),
+            None => Cow::Borrowed(field.name()),
+        };
+        self.request.push(name, value);
+    }
+}
+
+impl<E> Visit for Visitor<'_, E> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, Value::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Value::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, Value::U64(value));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.push(field, Value::I128(value));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.push(field, Value::U128(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, Value::Str(value.into()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, Value::Str(format!("{value:?}")));
+    }
+}
+
+impl<E> VisitOutput<Result<(), EncodeError>> for Visitor<'_, E>
+where
+    E: Encoder,
+{
+    fn finish(self) -> Result<(), EncodeError> {
+        let fields = mem::take(&mut self.request.fields);
+        let start = self.request.bytes.len();
+        let result = self.encoder.encode(&fields, &mut self.request.bytes);
+        if result.is_err() {
+            self.request.bytes.truncate(start);
+        }
+        // Keep the allocation for pooled requests
+        self.request.fields = fields;
+        self.request.fields.clear();
+        result
+    }
+}
+
+impl<E> EventVisitor for Visitor<'_, E>
+where
+    E: Encoder,
+{
+    type Error = EncodeError;
+
+    fn finish_request(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(encoder: impl Encoder, fields: &[(&'static str, Value)]) -> Vec<u8> {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| (Cow::Borrowed(*name), value.clone()))
+            .collect();
+        let mut buf = Vec::new();
+        encoder.encode(&fields, &mut buf).unwrap();
+        buf
+    }
+
+    struct Failing;
+
+    impl Encoder for Failing {
+        fn encode(&self, _fields: &Fields, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+            buf.extend_from_slice(b"partial");
+            Err(EncodeError::new("failed"))
+        }
+    }
+
+    #[test]
+    fn message_pack_encodes_fixmap_of_fixstr() {
+        let bytes = encode(MessagePack, &[("a", Value::Str("bc".into()))]);
+        assert_eq!(bytes, [0x81, 0xa1, b'a', 0xa2, b'b', b'c']);
+    }
+
+    #[test]
+    fn message_pack_encodes_integers_in_the_smallest_format() {
+        let cases: [(Value, &[u8]); 10] = [
+            (Value::U64(0x7f), &[0x7f]),
+            (Value::U64(0x80), &[0xcc, 0x80]),
+            (Value::U64(0x100), &[0xcd, 0x01, 0x00]),
+            (Value::U64(0x1_0000), &[0xce, 0x00, 0x01, 0x00, 0x00]),
+            (
+                Value::U64(u64::MAX),
+                &[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (Value::I64(5), &[0x05]),
+            (Value::I64(-32), &[0xe0]),
+            (Value::I64(-33), &[0xd0, 0xdf]),
+            (Value::I64(-129), &[0xd1, 0xff, 0x7f]),
+            (Value::I128(-1), &[0xff]),
+        ];
+        for (value, expected) in cases {
+            let bytes = encode(MessagePack, &[("n", value.clone())]);
+            assert_eq!(&bytes[3..], expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn message_pack_encodes_large_128_bit_integers_as_strings() {
+        let bytes = encode(MessagePack, &[("n", Value::U128(u128::from(u64::MAX) + 1))]);
+        let expected = "18446744073709551616";
+        assert_eq!(bytes[3], 0xa0 | expected.len() as u8);
+        assert_eq!(&bytes[4..], expected.as_bytes());
+    }
+
+    #[test]
+    fn message_pack_encodes_floats_and_bools() {
+        let bytes = encode(
+            MessagePack,
+            &[
+                ("f", Value::F64(1.5)),
+                ("t", Value::Bool(true)),
+                ("u", Value::Bool(false)),
+            ],
+        );
+        let mut expected = vec![0x83, 0xa1, b'f', 0xcb];
+        expected.extend_from_slice(&1.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0xa1, b't', 0xc3, 0xa1, b'u', 0xc2]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn message_pack_encodes_long_strings_with_length_prefixes() {
+        let value = "x".repeat(300);
+        let bytes = encode(MessagePack, &[("s", Value::Str(value.clone()))]);
+        assert_eq!(&bytes[3..6], [0xda, 0x01, 0x2c]);
+        assert_eq!(&bytes[6..], value.as_bytes());
+
+        let value = "x".repeat(32);
+        let bytes = encode(MessagePack, &[("s", Value::Str(value))]);
+        assert_eq!(&bytes[3..5], [0xd9, 32]);
+    }
+
+    #[test]
+    fn message_pack_encodes_large_maps() {
+        let fields: Vec<_> = (0..16).map(|_| ("k", Value::Bool(true))).collect();
+        let bytes = encode(MessagePack, &fields);
+        assert_eq!(&bytes[..3], [0xde, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn length_delimited_prefixes_frames_with_their_length() {
+        let encoder = LengthDelimited::new(MessagePack);
+        let bytes = encode(encoder, &[("a", Value::Bool(true))]);
+        assert_eq!(bytes, [0, 0, 0, 4, 0x81, 0xa1, b'a', 0xc3]);
+    }
+
+    #[test]
+    fn length_delimited_appends_to_existing_frames() {
+        let encoder = LengthDelimited::new(MessagePack);
+        let fields = [(Cow::Borrowed("a"), Value::Bool(false))];
+        let mut buf = Vec::new();
+        encoder.encode(&fields, &mut buf).unwrap();
+        encoder.encode(&fields, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 4, 0x81, 0xa1, b'a', 0xc2].repeat(2));
+    }
+
+    #[test]
+    fn length_delimited_removes_partial_frames() {
+        let mut buf = b"previous".to_vec();
+        let result = LengthDelimited::new(Failing).encode(&[], &mut buf);
+        assert!(result.is_err());
+        assert_eq!(buf, b"previous");
+    }
+}
@@ -0,0 +1,50 @@
+use std::{error::Error, fmt, time::Instant};
+
+use tower::Service;
+
+/// A [`Service`] which buffers requests, such as to send them in batches.
+///
+/// A [`ResponseStream`](crate::ResponseStream) configured using
+/// [`ResponseStream::with_buffer_flush`](crate::ResponseStream::with_buffer_flush) sends the
+/// buffered requests when [`Handle::flush`](crate::Handle::flush) is called, once its queue
+/// closes, so they are not lost at shutdown, and once the [`Flush::flush_deadline`] passes.
+pub trait Flush<Request>: Service<Request> {
+    /// Sends the buffered requests, returning `None` if there are none.
+    ///
+    /// This is only called once [`Service::poll_ready`] has returned `Ok`, and the returned
+    /// future counts towards the concurrency of the [`ResponseStream`](crate::ResponseStream). It
+    /// is called again each time the returned future succeeds, until it returns `None`, so a
+    /// service holding several batches can send one per call.
+    fn flush_buffered(&mut self) -> Option<Self::Future>;
+
+    /// When the buffered requests should be sent, without waiting for a flush or another
+    /// request, using the timer of the [`ResponseStream`](crate::ResponseStream), see
+    /// [`ResponseStream::with_timer`](crate::ResponseStream::with_timer).
+    ///
+    /// Returns `None` by default, so buffered requests are only sent when flushed.
+    fn flush_deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// The error returned by [`Handle::flush`](crate::Handle::flush) when no
+/// [`ResponseStream`](crate::ResponseStream) is draining the queue, or it was dropped before the
+/// flush completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushError {
+    _private: (),
+}
+
+impl FlushError {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no stream is draining the queue")
+    }
+}
+
+impl Error for FlushError {}
@@ -0,0 +1,238 @@
+//! Typed requests, constructed from the fields and metadata of an event.
+
+use std::fmt;
+
+use tracing_core::{
+    field::{Field, Visit},
+    Event, Level, Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+/// A `Request` which is constructed from the fields and metadata of an event.
+///
+/// This can be derived, with the `derive` feature enabled, for structs with named fields. Each
+/// struct field is set from the event field of the same name, and fields missing from the event
+/// keep their [`Default`] value:
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// use tracing_service::FromEvent;
+///
+/// #[derive(Debug, Default, FromEvent)]
+/// struct AuditRecord {
+///     user_id: u64,
+///     action: String,
+///     #[from_event(rename = "message")]
+///     description: Option<String>,
+///     #[from_event(level)]
+///     level: String,
+///     #[from_event(target)]
+///     target: String,
+/// }
+/// # }
+/// ```
+///
+/// The `#[from_event(...)]` attribute accepts `rename = "..."`, to read a differently named event
+/// field, or one of `level`, `target`, `module_path`, `file` and `line` to read the event's
+/// [`Metadata`]. Fields are converted using [`FromFieldValue`].
+///
+/// Requests are constructed by a [`ServiceLayer`](crate::ServiceLayer) using
+/// [`ServiceLayer::typed`](crate::ServiceLayer::typed), or directly using
+/// [`FromEvent::from_event`].
+pub trait FromEvent: Default {
+    /// Records the [`Metadata`] of the event, this is called before any fields are recorded.
+    fn record_metadata(&mut self, metadata: &Metadata<'_>) {
+        let _ = metadata;
+    }
+
+    /// Records a field of the event.
+    fn record_field(&mut self, field: &Field, value: FieldValue<'_>);
+
+    /// Constructs a `Request` from an event.
+    fn from_event(event: &Event<'_>) -> Self {
+        let mut request = Self::default();
+        request.record_metadata(event.metadata());
+        event.record(&mut Visitor {
+            request: &mut request,
+        });
+        request
+    }
+}
+
+/// The value of a field recorded by [`FromEvent::record_field`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum FieldValue<'a> {
+    /// A floating point number.
+    F64(f64),
+    /// A signed integer.
+    I64(i64),
+    /// An unsigned integer.
+    U64(u64),
+    /// A signed 128-bit integer.
+    I128(i128),
+    /// An unsigned 128-bit integer.
+    U128(u128),
+    /// A boolean.
+    Bool(bool),
+    /// A string.
+    Str(&'a str),
+    /// A value recorded using its [`fmt::Debug`] implementation.
+    Debug(&'a dyn fmt::Debug),
+}
+
+/// Converts a [`FieldValue`] into the type of a struct field, when deriving [`FromEvent`].
+pub trait FromFieldValue: Sized {
+    /// Converts the value, returning `None` if it has the wrong type or is out of range.
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self>;
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl FromFieldValue for $ty {
+                fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+                    match value {
+                        FieldValue::I64(value) => value.try_into().ok(),
+                        FieldValue::U64(value) => value.try_into().ok(),
+                        FieldValue::I128(value) => value.try_into().ok(),
+                        FieldValue::U128(value) => value.try_into().ok(),
+                        FieldValue::Str(value) => value.parse().ok(),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl FromFieldValue for f64 {
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        match value {
+            FieldValue::F64(value) => Some(value),
+            FieldValue::I64(value) => Some(value as f64),
+            FieldValue::U64(value) => Some(value as f64),
+            FieldValue::Str(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromFieldValue for f32 {
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        f64::from_field_value(value).map(|value| value as f32)
+    }
+}
+
+impl FromFieldValue for bool {
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        match value {
+            FieldValue::Bool(value) => Some(value),
+            FieldValue::Str(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromFieldValue for String {
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        Some(match value {
+            FieldValue::F64(value) => value.to_string(),
+            FieldValue::I64(value) => value.to_string(),
+            FieldValue::U64(value) => value.to_string(),
+            FieldValue::I128(value) => value.to_string(),
+            FieldValue::U128(value) => value.to_string(),
+            FieldValue::Bool(value) => value.to_string(),
+            FieldValue::Str(value) => value.to_owned(),
+            FieldValue::Debug(value) => format!("{value:?}"),
+        })
+    }
+}
+
+impl FromFieldValue for Level {
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        match value {
+            FieldValue::Str(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl<T> FromFieldValue for Option<T>
+where
+    T: FromFieldValue,
+{
+    fn from_field_value(value: FieldValue<'_>) -> Option<Self> {
+        T::from_field_value(value).map(Some)
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into a [`FromEvent`]
+/// request, see [`ServiceLayer::typed`](crate::ServiceLayer::typed).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FromEventVisitor;
+
+impl<'a, Request> field::MakeVisitor<&'a mut Request> for FromEventVisitor
+where
+    Request: FromEvent,
+{
+    type Visitor = Visitor<'a, Request>;
+
+    fn make_visitor(&self, request: &'a mut Request) -> Self::Visitor {
+        Visitor { request }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`FromEventVisitor`].
+#[derive(Debug)]
+pub struct Visitor<'a, Request> {
+    request: &'a mut Request,
+}
+
+impl<Request> Visit for Visitor<'_, Request>
+where
+    Request: FromEvent,
+{
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.request.record_field(field, FieldValue::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.request.record_field(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.request.record_field(field, FieldValue::U64(value));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.request.record_field(field, FieldValue::I128(value));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.request.record_field(field, FieldValue::U128(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.request.record_field(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.request.record_field(field, FieldValue::Str(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.request.record_field(field, FieldValue::Debug(value));
+    }
+}
+
+impl<Request> VisitOutput<Result<(), fmt::Error>> for Visitor<'_, Request>
+where
+    Request: FromEvent,
+{
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
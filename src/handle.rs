@@ -0,0 +1,358 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use tracing_core::{Level, LevelFilter, Metadata};
+
+use crate::{sample::Sampler, FlushError, LayerMetrics, SampleRate, Spool};
+
+/// What a [`ServiceLayer`](crate::ServiceLayer) does with events which overflow its queue, or are
+/// rate limited, see [`Handle::set_overflow_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum OverflowPolicy {
+    /// Events are written to the [`Spool`](crate::Spool), if one is configured, and dropped
+    /// otherwise.
+    #[default]
+    Spool,
+    /// Events are dropped.
+    Drop,
+}
+
+/// A handle which reconfigures a [`ServiceLayer`](crate::ServiceLayer) while it is running, see
+/// [`ServiceLayer::handle`](crate::ServiceLayer::handle).
+///
+/// This allows the verbosity of the events sent to be changed on a live service, in the same way
+/// as [`tracing_subscriber::reload`].
+///
+/// ```
+/// # use tracing_core::{Level, LevelFilter};
+/// # use tracing_service::{SampleRate, ServiceLayer};
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+/// let handle = layer.handle();
+/// handle.set_max_level(LevelFilter::INFO);
+///
+/// // While investigating an incident
+/// handle.set_target_level("app::payments", LevelFilter::DEBUG);
+/// handle.set_sample_rate(Level::DEBUG, Some(SampleRate::OneIn(10)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Handle {
+    config: Arc<Config>,
+    metrics: LayerMetrics,
+}
+
+/// The configuration shared between a [`ServiceLayer`](crate::ServiceLayer) and its [`Handle`]s.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    sampler: Sampler,
+    filter: RwLock<Filter>,
+    drop_overflow: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Filter {
+    excluded: Vec<Cow<'static, str>>,
+    max_level: LevelFilter,
+    target_levels: Vec<(Cow<'static, str>, LevelFilter)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            excluded: Vec::new(),
+            max_level: LevelFilter::TRACE,
+            target_levels: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns `true` if events and spans with `metadata` are sent, according to the target
+    /// exclusions and level filters.
+    pub(crate) fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let filter = self.filter.read().unwrap_or_else(|err| err.into_inner());
+        let target = metadata.target();
+        if filter
+            .excluded
+            .iter()
+            .any(|excluded| is_within(target, excluded))
+        {
+            return false;
+        }
+
+        // The most specific target level applies, falling back to the maximum level
+        let max_level = filter
+            .target_levels
+            .iter()
+            .filter(|(prefix, _)| is_within(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(filter.max_level, |(_, level)| *level);
+        metadata.level() <= &max_level
+    }
+
+    /// Returns `true` if an event at `level` should be sent, see [`SampleRate`].
+    pub(crate) fn sample(&self, level: &Level) -> bool {
+        self.sampler.sample(level)
+    }
+
+    pub(crate) fn overflow_policy(&self) -> OverflowPolicy {
+        if self.drop_overflow.load(Ordering::Relaxed) {
+            OverflowPolicy::Drop
+        } else {
+            OverflowPolicy::Spool
+        }
+    }
+
+    /// Returns the [`Spool`] events which overflow the queue, or are rate limited, are written to,
+    /// which is `None` if the overflow policy drops them.
+    pub(crate) fn spool_to<'a, Request>(
+        &self,
+        spool: Option<&'a Spool<Request>>,
+    ) -> Option<&'a Spool<Request>> {
+        match self.overflow_policy() {
+            OverflowPolicy::Spool => spool,
+            OverflowPolicy::Drop => None,
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Filter)) {
+        f(&mut self.filter.write().unwrap_or_else(|err| err.into_inner()));
+    }
+}
+
+/// Returns `true` if `target` is `prefix`, or a module within it.
+fn is_within(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+impl Handle {
+    pub(crate) fn new(config: Arc<Config>, metrics: LayerMetrics) -> Self {
+        Self { config, metrics }
+    }
+
+    /// Sets the [`SampleRate`] of events at `level`, `None` sends every event.
+    pub fn set_sample_rate(&self, level: Level, rate: Option<SampleRate>) {
+        self.config.sampler.set(level, rate);
+    }
+
+    /// Only sends events and spans at or above `max_level`, unless a more specific level is set
+    /// for their target using [`Handle::set_target_level`].
+    pub fn set_max_level(&self, max_level: LevelFilter) {
+        self.config.update(|filter| filter.max_level = max_level);
+    }
+
+    /// Only sends events and spans, whose target is `target` or a module within it, at or above
+    /// `level`.
+    ///
+    /// This replaces any level previously set for `target`, the most specific target applies.
+    pub fn set_target_level(&self, target: impl Into<Cow<'static, str>>, level: LevelFilter) {
+        let target = target.into();
+        self.config.update(|filter| {
+            match filter
+                .target_levels
+                .iter_mut()
+                .find(|(existing, _)| *existing == target)
+            {
+                Some((_, existing)) => *existing = level,
+                None => filter.target_levels.push((target, level)),
+            }
+        });
+    }
+
+    /// Removes the level set for `target` using [`Handle::set_target_level`].
+    pub fn clear_target_level(&self, target: &str) {
+        self.config.update(|filter| {
+            filter
+                .target_levels
+                .retain(|(existing, _)| existing != target)
+        });
+    }
+
+    /// Ignores events and spans whose target is `target`, or a module within it, see
+    /// [`ServiceLayer::exclude_target`](crate::ServiceLayer::exclude_target).
+    pub fn exclude_target(&self, target: impl Into<Cow<'static, str>>) {
+        let target = target.into();
+        self.config.update(|filter| filter.excluded.push(target));
+    }
+
+    /// Removes a target previously excluded using [`Handle::exclude_target`].
+    pub fn include_target(&self, target: &str) {
+        self.config
+            .update(|filter| filter.excluded.retain(|existing| existing != target));
+    }
+
+    /// Sets what happens to events which overflow the queue, or are rate limited, see
+    /// [`ServiceLayer::with_rate_limit`](crate::ServiceLayer::with_rate_limit).
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.config
+            .drop_overflow
+            .store(policy == OverflowPolicy::Drop, Ordering::Relaxed);
+    }
+
+    /// Returns what happens to events which overflow the queue, or are rate limited.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.config.overflow_policy()
+    }
+
+    /// Waits for the events enqueued before this is called to be sent, and for every
+    /// [`Service`](tower::Service) call in flight to complete.
+    ///
+    /// Requests held back by the layer, such as those being aggregated, are enqueued first. Each
+    /// queue behind a [`Tee`](crate::Tee) or [`Keyed`](crate::Keyed) sink is waited for, and
+    /// requests buffered within the [`Service`](tower::Service) itself are sent if its stream is
+    /// configured using [`ResponseStream::with_buffer_flush`](crate::ResponseStream::with_buffer_flush).
+    /// The stream must still be polled for this to make progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`FlushError`] if no [`ResponseStream`](crate::ResponseStream) is recording into
+    /// the layer's [`LayerMetrics`], or it is dropped before the flush completes. A stream which
+    /// has terminated has nothing left to flush. For sinks which do not track their metrics, see
+    /// [`EventSink::metrics`](crate::EventSink::metrics), the layer's metrics must be passed to
+    /// [`ResponseStream::with_metrics`](crate::ResponseStream::with_metrics).
+    ///
+    /// ```no_run
+    /// # use tracing_service::ServiceLayer;
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # async fn run() {
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    /// let handle = layer.handle();
+    /// tokio::spawn(responses.drain());
+    ///
+    /// // At the end of the run
+    /// handle.flush().await.expect("the stream is running");
+    /// # }
+    /// ```
+    pub async fn flush(&self) -> Result<(), FlushError> {
+        self.metrics.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{fmt::format::JsonVisitor, layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::ServiceLayer;
+
+    fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+        JsonVisitor::new(value)
+    }
+
+    /// Returns the requests constructed from the events emitted by `f`, which is passed the
+    /// [`Handle`] of the layer.
+    async fn requests(f: impl FnOnce(&Handle)) -> Vec<String> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let service = tower::service_fn(move |request: String| {
+            recorded.lock().unwrap().push(request);
+            future::ready(Ok::<_, ()>(()))
+        });
+        let (layer, responses) = ServiceLayer::new(service, make_visitor);
+        let handle = layer.handle();
+        tracing::subscriber::with_default(Registry::default().with(layer), || f(&handle));
+        responses.for_each(|_| async {}).await;
+        let requests = requests.lock().unwrap().clone();
+        requests
+    }
+
+    mod payments {
+        pub(super) fn emit(n: u8) {
+            tracing::debug!(n);
+        }
+
+        pub(super) mod refunds {
+            pub(crate) fn emit(n: u8) {
+                tracing::debug!(n);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_by_level_while_running() {
+        let requests = requests(|handle| {
+            tracing::debug!(n = 0);
+            handle.set_max_level(LevelFilter::INFO);
+            tracing::debug!(n = 1);
+            tracing::info!(n = 2);
+            handle.set_max_level(LevelFilter::TRACE);
+            tracing::debug!(n = 3);
+        })
+        .await;
+        assert_eq!(requests, ["{\"n\":0}", "{\"n\":2}", "{\"n\":3}"]);
+    }
+
+    #[tokio::test]
+    async fn the_most_specific_target_level_applies() {
+        const PAYMENTS: &str = "tracing_service::handle::tests::payments";
+
+        let requests = requests(|handle| {
+            handle.set_max_level(LevelFilter::INFO);
+            handle.set_target_level(PAYMENTS, LevelFilter::DEBUG);
+            handle.set_target_level(format!("{PAYMENTS}::refunds"), LevelFilter::OFF);
+            payments::emit(0);
+            payments::refunds::emit(1);
+            tracing::debug!(n = 2);
+
+            handle.clear_target_level(&format!("{PAYMENTS}::refunds"));
+            payments::refunds::emit(3);
+            handle.set_target_level(PAYMENTS, LevelFilter::WARN);
+            payments::emit(4);
+        })
+        .await;
+        assert_eq!(requests, ["{\"n\":0}", "{\"n\":3}"]);
+    }
+
+    #[tokio::test]
+    async fn excludes_and_includes_targets() {
+        let requests = requests(|handle| {
+            handle.exclude_target("tracing_service::handle");
+            tracing::info!(n = 0);
+            handle.include_target("tracing_service::handle");
+            tracing::info!(n = 1);
+            // Only whole modules are excluded
+            handle.exclude_target("tracing_service::hand");
+            tracing::info!(n = 2);
+        })
+        .await;
+        assert_eq!(requests, ["{\"n\":1}", "{\"n\":2}"]);
+    }
+
+    #[tokio::test]
+    async fn sets_and_clears_sample_rates() {
+        let requests = requests(|handle| {
+            handle.set_sample_rate(Level::INFO, Some(SampleRate::Probability(0.0)));
+            tracing::info!(n = 0);
+            handle.set_sample_rate(Level::INFO, None);
+            tracing::info!(n = 1);
+        })
+        .await;
+        assert_eq!(requests, ["{\"n\":1}"]);
+    }
+
+    #[test]
+    fn sets_the_overflow_policy() {
+        let handle = Handle::new(Arc::new(Config::default()), LayerMetrics::default());
+        assert_eq!(handle.overflow_policy(), OverflowPolicy::Spool);
+        handle.set_overflow_policy(OverflowPolicy::Drop);
+        assert_eq!(handle.overflow_policy(), OverflowPolicy::Drop);
+    }
+}
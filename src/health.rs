@@ -0,0 +1,153 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::LayerMetrics;
+
+/// The most recent error returned by the [`Service`](tower::Service), see [`Health::last_error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LastError {
+    /// The error, formatted using its [`fmt::Debug`] implementation.
+    pub message: String,
+    /// When the error was returned.
+    pub at: SystemTime,
+}
+
+/// A cheap, clonable handle reporting the health of a [`ResponseStream`](crate::ResponseStream),
+/// see [`ResponseStream::health`](crate::ResponseStream::health).
+///
+/// This is intended for readiness probes and dashboards, to detect a stalled or backlogged
+/// pipeline.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tracing_service::ServiceLayer;
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let (layer, mut responses) = ServiceLayer::new(service, make_visitor);
+/// let health = responses.health();
+///
+/// // In a readiness probe
+/// let ready = health.is_alive()
+///     && health
+///         .oldest_queued_age()
+///         .map_or(true, |age| age < Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Health {
+    state: Arc<State>,
+    metrics: LayerMetrics,
+}
+
+/// The state shared between a [`Health`] handle and its stream.
+pub(crate) struct State {
+    alive: AtomicBool,
+    created: Instant,
+    // Nanoseconds since `created`
+    last_polled: AtomicU64,
+    last_error: Mutex<Option<LastError>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            alive: AtomicBool::new(true),
+            created: Instant::now(),
+            last_polled: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State")
+            .field("alive", &self.alive)
+            .field("last_polled", &self.last_polled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl State {
+    pub(crate) fn record_polled(&self) {
+        let now = self.created.elapsed().as_nanos();
+        self.last_polled
+            .store(now.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, message: String) {
+        let error = LastError {
+            message,
+            at: SystemTime::now(),
+        };
+        *self
+            .last_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(error);
+    }
+
+    /// Marks the stream as terminated or dropped.
+    pub(crate) fn finish(&self) {
+        self.alive.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Marks the stream as no longer alive when it is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Liveness(pub(crate) Arc<State>);
+
+impl Drop for Liveness {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+impl Health {
+    pub(crate) fn new(state: Arc<State>, metrics: LayerMetrics) -> Self {
+        Self { state, metrics }
+    }
+
+    /// Returns `true` until the stream terminates or is dropped.
+    ///
+    /// A stream which is alive but no longer being polled is stalled, see
+    /// [`Health::since_last_poll`].
+    pub fn is_alive(&self) -> bool {
+        self.state.alive.load(Ordering::Relaxed)
+    }
+
+    /// Returns the time since the stream was last polled, or since it was constructed if it has
+    /// never been polled.
+    pub fn since_last_poll(&self) -> Duration {
+        let last_polled = Duration::from_nanos(self.state.last_polled.load(Ordering::Relaxed));
+        self.state.created.elapsed().saturating_sub(last_polled)
+    }
+
+    /// Returns the number of events queued which have not yet been received by the stream.
+    pub fn queue_depth(&self) -> u64 {
+        self.metrics.queue_depth()
+    }
+
+    /// Returns the approximate age of the oldest queued event, or `None` if the queue is empty.
+    ///
+    /// This assumes events are received in the order they were queued, which does not hold for a
+    /// [`priority_channel`](crate::priority_channel).
+    pub fn oldest_queued_age(&self) -> Option<Duration> {
+        self.metrics.oldest_queued_age()
+    }
+
+    /// Returns the most recent error returned by the [`Service`](tower::Service).
+    pub fn last_error(&self) -> Option<LastError> {
+        self.state
+            .last_error
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+}
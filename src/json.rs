@@ -0,0 +1,316 @@
+//! A [`MakeVisitor`](field::MakeVisitor) which records events as JSON objects.
+//!
+//! Each [`Entry`] holds the event's `timestamp`, `level` and `target`, its fields under `fields`,
+//! and the spans in its scope, from the root to the leaf, under `spans`. Attributes added using
+//! [`ServiceLayer::with_resource`] are held under `resource`:
+//!
+//! ```json
+//! {
+//!   "timestamp": "2022-06-01T12:00:00.000000000Z",
+//!   "level": "INFO",
+//!   "target": "app::handler",
+//!   "fields": { "message": "handled request", "status": 200 },
+//!   "spans": [{ "name": "request", "method": "GET" }]
+//! }
+//! ```
+//!
+//! ```no_run
+//! use tracing_service::json::{self, Entry};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! # let service = tower::service_fn(|_: Entry| async { Ok::<_, ()>(()) });
+//! let (layer, responses) = json::layer(service);
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use serde_json::{Map, Value};
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{
+    timestamp::rfc3339, RecordResource, Reset, Resource, ResponseStream, ScopeSpan, ServiceLayer,
+    SpanTiming,
+};
+
+/// Constructs a [`ServiceLayer`] which sends each event to `service` as an [`Entry`], including
+/// its metadata and span scope.
+pub fn layer<Svc>(service: Svc) -> (ServiceLayer<Entry, Json>, ResponseStream<Entry, Svc>)
+where
+    Svc: Service<Entry>,
+{
+    let (layer, responses) = ServiceLayer::new(service, Json);
+    let layer = layer
+        .with_metadata(record_metadata)
+        .with_span_scope(record_span);
+    (layer, responses)
+}
+
+/// Records the `timestamp`, `level` and `target` of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(entry: &mut Entry, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    entry
+        .0
+        .insert("timestamp".into(), rfc3339(timestamp).into());
+    entry
+        .0
+        .insert("level".into(), metadata.level().as_str().into());
+    entry.0.insert("target".into(), metadata.target().into());
+}
+
+/// Records the name and fields of a span in the scope of an event, appending it to `spans`.
+///
+/// This is intended to be passed to [`ServiceLayer::with_span_scope`].
+pub fn record_span(entry: &mut Entry, span: &ScopeSpan<'_>) {
+    let mut fields = Map::new();
+    fields.insert("name".into(), span.name().into());
+    span.record(&mut Visitor {
+        fields: &mut fields,
+    });
+
+    let spans = entry
+        .0
+        .entry("spans")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    if let Value::Array(spans) = spans {
+        spans.push(fields.into());
+    }
+}
+
+/// Records the name and fields of a closed span under `span`, along with `busy_ns` and `idle_ns`.
+///
+/// This is intended to be passed to [`ServiceLayer::with_span_timing`].
+pub fn record_span_timing(entry: &mut Entry, timing: &SpanTiming<'_>) {
+    let span = timing.span();
+    let mut fields = Map::new();
+    fields.insert("name".into(), span.name().into());
+    span.record(&mut Visitor {
+        fields: &mut fields,
+    });
+    entry.0.insert("span".into(), fields.into());
+    entry
+        .0
+        .insert("busy_ns".into(), nanos(timing.busy()).into());
+    entry
+        .0
+        .insert("idle_ns".into(), nanos(timing.idle()).into());
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+/// An event recorded as a JSON object, formatted as a single line of JSON by its
+/// [`fmt::Display`] implementation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry(Map<String, Value>);
+
+impl Entry {
+    /// Returns the entry as a JSON object.
+    pub fn as_map(&self) -> &Map<String, Value> {
+        &self.0
+    }
+
+    /// Returns the fields of the event.
+    pub fn fields(&self) -> Option<&Map<String, Value>> {
+        self.0.get("fields").and_then(Value::as_object)
+    }
+
+    /// Consumes the entry, returning the JSON object.
+    pub fn into_map(self) -> Map<String, Value> {
+        self.0
+    }
+}
+
+impl RecordResource for Entry {
+    /// Records the attributes under `resource`.
+    fn record_resource(&mut self, resource: &Resource) {
+        let attributes = resource
+            .attributes()
+            .map(|(key, value)| (key.to_owned(), value.into()))
+            .collect();
+        self.0.insert("resource".into(), Value::Object(attributes));
+    }
+}
+
+impl Reset for Entry {
+    fn reset(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl From<Entry> for Value {
+    fn from(entry: Entry) -> Self {
+        Value::Object(entry.0)
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(&self.0).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into the `fields` of an
+/// [`Entry`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl<'a> field::MakeVisitor<&'a mut Entry> for Json {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, entry: &'a mut Entry) -> Self::Visitor {
+        let fields = entry
+            .0
+            .entry("fields")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !fields.is_object() {
+            *fields = Value::Object(Map::new());
+        }
+        let Value::Object(fields) = fields else {
+            unreachable!("`fields` is an object");
+        };
+        Visitor { fields }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Json`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    fields: &'a mut Map<String, Value>,
+}
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.fields.insert(field.name().into(), value);
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    /// Returns the entries constructed from the events emitted by `f`.
+    async fn entries(f: impl FnOnce()) -> Vec<Entry> {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = entries.clone();
+        let service = tower::service_fn(move |entry: Entry| {
+            recorded.lock().unwrap().push(entry);
+            future::ready(Ok::<_, ()>(()))
+        });
+        let (layer, responses) = layer(service);
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+        let entries = entries.lock().unwrap().clone();
+        entries
+    }
+
+    #[test]
+    fn records_resource_attributes() {
+        let mut entry = Entry::default();
+        entry.record_resource(
+            &Resource::new()
+                .with_service_name("checkout")
+                .with_environment("production"),
+        );
+        assert_eq!(
+            entry.as_map()["resource"],
+            json!({"service.name": "checkout", "deployment.environment": "production"})
+        );
+    }
+
+    #[tokio::test]
+    async fn records_metadata_and_fields() {
+        let entries = entries(|| tracing::warn!(status = 200, ok = true, "handled")).await;
+        let entry = entries[0].as_map();
+        assert_eq!(entry["level"], "WARN");
+        assert_eq!(entry["target"], "tracing_service::json::tests");
+        assert!(entry["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(
+            entries[0].fields().unwrap(),
+            json!({"status": 200, "ok": true, "message": "handled"})
+                .as_object()
+                .unwrap()
+        );
+        assert!(!entry.contains_key("spans"));
+    }
+
+    #[tokio::test]
+    async fn records_the_span_scope_from_the_root() {
+        let entries = entries(|| {
+            let _request = tracing::info_span!("request", method = "GET").entered();
+            let _handler = tracing::info_span!("handler").entered();
+            tracing::info!("handled");
+        })
+        .await;
+        assert_eq!(
+            entries[0].as_map()["spans"],
+            json!([{"name": "request", "method": "GET"}, {"name": "handler"}])
+        );
+    }
+
+    #[test]
+    fn displays_entries_as_a_line_of_json() {
+        let Value::Object(map) = json!({"level": "INFO", "fields": {"n": 1}}) else {
+            unreachable!()
+        };
+        assert_eq!(
+            Entry(map).to_string(),
+            r#"{"fields":{"n":1},"level":"INFO"}"#
+        );
+    }
+}
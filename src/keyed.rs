@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{Mutex, MutexGuard},
+};
+
+#[cfg(feature = "tokio")]
+use tower::Service;
+use tracing_core::Level;
+
+#[cfg(feature = "tokio")]
+use crate::ResponseStream;
+use crate::{channel::Sender, EventSink, LayerMetrics, TrySendError};
+
+type Extract<Key, Request> = Box<dyn Fn(&Request) -> Key + Send + Sync>;
+type MakeSink<Key, Sink> = Box<dyn Fn(&Key) -> Sink + Send + Sync>;
+
+/// An [`EventSink`] which routes each request to a sink chosen by a key extracted from it.
+///
+/// The sink for a key is created the first time the key is seen, and cached until it is closed.
+/// This allows, for example, each tenant of a multi-tenant service to have its own endpoint and
+/// queue, so a slow tenant does not hold up the others.
+///
+/// The key is extracted from the constructed `Request`, so the fields of the spans in an event's
+/// scope are available if they are recorded using
+/// [`ServiceLayer::with_span_scope`](crate::ServiceLayer::with_span_scope). Keys are never
+/// evicted while their sink is open, so they should have a bounded cardinality.
+///
+/// The [`LayerMetrics`] of each sink's queue are tracked alongside the `Keyed`'s own, see
+/// [`EventSink::metrics`], so [`Handle::flush`](crate::Handle::flush) waits for every queue to
+/// drain.
+///
+/// ```no_run
+/// # #[cfg(feature = "json")] {
+/// use tracing_service::{json, Keyed, ServiceLayer};
+///
+/// # async fn run() {
+/// # let endpoint = |_: &Option<String>| tower::service_fn(|_: json::Entry| async { Ok::<_, ()>(()) });
+/// let tenants = Keyed::spawn(
+///     |entry: &json::Entry| {
+///         let tenant_id = entry.fields()?.get("tenant_id")?.as_str()?;
+///         Some(tenant_id.to_owned())
+///     },
+///     32,
+///     move |tenant_id: &Option<String>| endpoint(tenant_id),
+/// );
+/// let layer = ServiceLayer::from_parts(tenants, json::Json)
+///     .with_metadata(json::record_metadata)
+///     .with_span_scope(json::record_span);
+/// # }
+/// # }
+/// ```
+pub struct Keyed<Key, Request, Sink = Sender<Request>> {
+    extract: Extract<Key, Request>,
+    make_sink: MakeSink<Key, Sink>,
+    sinks: Mutex<HashMap<Key, Sink>>,
+    metrics: LayerMetrics,
+}
+
+impl<Key, Request, Sink> Keyed<Key, Request, Sink>
+where
+    Key: Eq + Hash + Clone,
+{
+    /// Constructs a `Keyed` which routes each request using the key returned by `extract`,
+    /// creating the sink for a new key using `make_sink`.
+    pub fn new<E, M>(extract: E, make_sink: M) -> Self
+    where
+        E: Fn(&Request) -> Key + Send + Sync + 'static,
+        M: Fn(&Key) -> Sink + Send + Sync + 'static,
+    {
+        Self {
+            extract: Box::new(extract),
+            make_sink: Box::new(make_sink),
+            sinks: Mutex::new(HashMap::new()),
+            metrics: LayerMetrics::default(),
+        }
+    }
+
+    /// The number of keys with a cached sink.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns `true` if no keys have a cached sink.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Key, Sink>> {
+        self.sinks.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Sends the request to the sink for its key using `send`, creating the sink if needed.
+    ///
+    /// A sink which is closed is removed, so it is recreated for the next request with its key.
+    fn send(
+        &self,
+        request: Request,
+        send: impl Fn(&Sink, Request) -> Result<(), TrySendError<Request>>,
+    ) -> Result<(), TrySendError<Request>>
+    where
+        Sink: EventSink<Request>,
+    {
+        let key = (self.extract)(&request);
+        let mut sinks = self.lock();
+        let sink = sinks.entry(key.clone()).or_insert_with(|| {
+            let sink = (self.make_sink)(&key);
+            if let Some(metrics) = sink.metrics() {
+                self.metrics.add_branch(metrics.clone());
+            }
+            sink
+        });
+        let result = send(sink, request);
+        match &result {
+            Ok(()) => {
+                self.metrics.record_sent();
+            }
+            Err(TrySendError::Closed(_)) => {
+                if let Some(metrics) = sinks.remove(&key).as_ref().and_then(Sink::metrics) {
+                    self.metrics.remove_branch(metrics);
+                }
+            }
+            Err(TrySendError::Full(_)) => {}
+        }
+        result
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Key, Request> Keyed<Key, Request>
+where
+    Key: Eq + Hash + Clone,
+    Request: Send + 'static,
+{
+    /// Constructs a `Keyed` which routes each request using the key returned by `extract`, to a
+    /// [`Service`] created by `make_service` the first time its key is seen.
+    ///
+    /// Each [`Service`] is drained from its own bounded queue, of capacity `buffer`, by a task
+    /// spawned on the current tokio runtime. Responses are discarded, and the tasks exit once the
+    /// `Keyed` is dropped and their queues are empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn spawn<E, M, Svc>(extract: E, buffer: usize, make_service: M) -> Self
+    where
+        E: Fn(&Request) -> Key + Send + Sync + 'static,
+        M: Fn(&Key) -> Svc + Send + Sync + 'static,
+        Svc: Service<Request> + Send + 'static,
+        Svc::Future: Send + 'static,
+    {
+        let runtime = tokio::runtime::Handle::current();
+        Self::new(extract, move |key| {
+            let (sender, receiver) = crate::channel::bounded(buffer);
+            let responses = ResponseStream::from_source(make_service(key), receiver);
+            runtime.spawn(responses.drain());
+            sender
+        })
+    }
+}
+
+impl<Key, Request, Sink> fmt::Debug for Keyed<Key, Request, Sink> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = self
+            .sinks
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .len();
+        f.debug_struct("Keyed")
+            .field("keys", &keys)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Key, Request, Sink> EventSink<Request> for Keyed<Key, Request, Sink>
+where
+    Key: Eq + Hash + Clone,
+    Sink: EventSink<Request>,
+{
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        self.send(request, Sink::try_send)
+    }
+
+    fn try_send_with_level(
+        &self,
+        request: Request,
+        level: &Level,
+    ) -> Result<(), TrySendError<Request>> {
+        self.send(request, |sink, request| {
+            sink.try_send_with_level(request, level)
+        })
+    }
+
+    /// The `Keyed`'s own metrics, which track those of each sink.
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        Some(&self.metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+    use crate::channel::{self, Receiver};
+
+    type Receivers = Arc<Mutex<Vec<(char, Receiver<String>)>>>;
+
+    /// Returns a `Keyed` routing by the first character of each request, along with the receiver
+    /// of each sink it creates.
+    fn keyed() -> (Keyed<char, String>, Receivers) {
+        let receivers = Receivers::default();
+        let created = receivers.clone();
+        let keyed = Keyed::new(
+            |request: &String| request.chars().next().unwrap(),
+            move |key: &char| {
+                let (sender, receiver) = channel::bounded(1);
+                created.lock().unwrap().push((*key, receiver));
+                sender
+            },
+        );
+        (keyed, receivers)
+    }
+
+    fn received(receivers: &Receivers) -> Vec<(char, Vec<String>)> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut receivers = receivers.lock().unwrap();
+        receivers
+            .iter_mut()
+            .map(|(key, receiver)| {
+                let mut requests = Vec::new();
+                while let Poll::Ready(Some(request)) = receiver.poll_recv(&mut cx) {
+                    requests.push(request);
+                }
+                (*key, requests)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn creates_a_sink_the_first_time_a_key_is_seen() {
+        let (keyed, receivers) = keyed();
+        assert!(keyed.is_empty());
+        keyed.try_send("a1".into()).unwrap();
+        keyed.try_send("b1".into()).unwrap();
+        assert_eq!(keyed.len(), 2);
+        assert_eq!(
+            received(&receivers),
+            [('a', vec!["a1".to_owned()]), ('b', vec!["b1".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn caches_the_sink_of_each_key() {
+        let (keyed, receivers) = keyed();
+        keyed.try_send("a1".into()).unwrap();
+        // The sink of `a` is full, which does not affect `b`
+        assert!(matches!(
+            keyed.try_send("a2".into()),
+            Err(TrySendError::Full(_))
+        ));
+        keyed.try_send("b1".into()).unwrap();
+        assert_eq!(keyed.len(), 2);
+        assert_eq!(receivers.lock().unwrap().len(), 2);
+        assert_eq!(
+            received(&receivers),
+            [('a', vec!["a1".to_owned()]), ('b', vec!["b1".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn removes_closed_sinks_so_they_are_recreated() {
+        let (keyed, receivers) = keyed();
+        keyed.try_send("a1".into()).unwrap();
+        keyed.try_send("b1".into()).unwrap();
+        receivers.lock().unwrap().retain(|(key, _)| *key != 'a');
+
+        assert!(matches!(
+            keyed.try_send("a2".into()),
+            Err(TrySendError::Closed(_))
+        ));
+        assert_eq!(keyed.len(), 1);
+        keyed.try_send("a3".into()).unwrap();
+        assert_eq!(keyed.len(), 2);
+        assert_eq!(
+            received(&receivers),
+            [('b', vec!["b1".to_owned()]), ('a', vec!["a3".to_owned()])]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn spawn_drains_each_key_with_its_own_service() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let keyed = Keyed::spawn(|request: &String| request.chars().next().unwrap(), 8, {
+            let delivered = delivered.clone();
+            move |key: &char| {
+                let (key, delivered) = (*key, delivered.clone());
+                tower::service_fn(move |request: String| {
+                    delivered.lock().unwrap().push((key, request));
+                    std::future::ready(Ok::<_, ()>(()))
+                })
+            }
+        });
+        keyed.try_send("a1".into()).unwrap();
+        keyed.try_send("b1".into()).unwrap();
+        keyed.try_send("a2".into()).unwrap();
+        drop(keyed);
+
+        for _ in 0..100 {
+            if delivered.lock().unwrap().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let mut delivered = delivered.lock().unwrap().clone();
+        delivered.sort();
+        assert_eq!(
+            delivered,
+            [('a', "a1".into()), ('a', "a2".into()), ('b', "b1".into())]
+        );
+    }
+}
@@ -1,24 +1,172 @@
+mod aggregate;
+pub mod builder;
+pub mod channel;
+mod circuit_breaker;
+mod classify;
+#[cfg(feature = "cloudwatch")]
+pub mod cloudwatch;
+pub mod encode;
+mod flush;
+pub mod from_event;
+mod handle;
+mod health;
+#[cfg(feature = "json")]
+pub mod json;
+mod keyed;
+#[cfg(feature = "log-compat")]
+mod log_compat;
+pub mod logfmt;
+#[cfg(feature = "loki")]
+pub mod loki;
+mod metrics;
+mod new_request;
+mod notify;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod pool;
+mod priority;
+mod rate_limit;
+mod reentrancy;
+mod resource;
 mod response_stream;
+mod retry;
+mod route;
+mod sample;
+mod shutdown;
+mod sink;
+mod span_scope;
+mod span_timing;
+mod spool;
+#[cfg(feature = "stackdriver")]
+pub mod stackdriver;
+mod tee;
+mod termination;
+#[cfg(feature = "test-util")]
+pub mod test;
+mod timestamp;
+mod visit;
+mod worker;
 
+pub use aggregate::Aggregation;
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
+pub use classify::{
+    ErrorClass, RecoverRequest, RecoverRequestFuture, RecoverRequestLayer, RequestError,
+    ReturnRequest, ReturnRequestFuture, ReturnRequestLayer,
+};
+pub use flush::{Flush, FlushError};
+pub use from_event::FromEvent;
+pub use handle::{Handle, OverflowPolicy};
+pub use health::{Health, LastError};
+pub use keyed::Keyed;
+pub use metrics::LayerMetrics;
+pub use new_request::{DefaultRequest, NewRequest};
+pub use pool::{Pool, Pooled, Reset};
+pub use priority::{priority_channel, PriorityReceiver, PrioritySender};
+pub use rate_limit::RateLimit;
+pub use resource::{RecordResource, Resource};
 pub use response_stream::*;
+pub use retry::RetryPolicy;
+pub use route::Router;
+pub use sample::SampleRate;
+pub use shutdown::{ShutdownHandle, ShutdownReport};
+pub use sink::{EventSink, EventSource, StreamSource, TrySendError};
+pub use span_scope::ScopeSpan;
+pub use span_timing::SpanTiming;
+pub use spool::{Spool, SpoolCodec, Utf8Codec};
+pub use tee::{Tee, TeeBranch};
+pub use termination::{ExitReason, Termination};
+pub use timestamp::Timestamped;
+#[cfg(feature = "derive")]
+pub use tracing_service_macros::FromEvent;
+pub use visit::EventVisitor;
+pub use worker::WorkerGuard;
+
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-use std::fmt;
+#[doc(hidden)]
+pub mod __private {
+    pub use tracing_core::{field::Field, Metadata};
+}
 
-use tokio::sync::mpsc::{channel, Sender};
+use aggregate::Observe;
+use builder::Builder;
+use channel::Sender;
+use from_event::FromEventVisitor;
+use handle::Config;
+use metrics::Release;
+use rate_limit::Acquire;
+use span_scope::SpanFields;
+use span_timing::Timings;
 use tower::Service;
-use tracing_core::{Event, Subscriber};
-use tracing_subscriber::{
-    field::{self, VisitOutput},
-    layer::Context as LayerContext,
-    registry::LookupSpan,
-    Layer,
+use tracing_core::{
+    span::{Attributes, Id, Record},
+    Event, Level, Metadata, Subscriber,
 };
+use tracing_subscriber::{field, layer::Context as LayerContext, registry::LookupSpan, Layer};
 
 /// A [`Layer`] which uses a [`MakeVisitor`](field::MakeVisitor) to construct a `Request` and then
 /// sends it to a [`Service<Request>`].
-pub struct ServiceLayer<Request, MakeVisitor> {
+///
+/// Each `Request` is constructed by a [`NewRequest`], which defaults to the [`Default`]
+/// implementation of `Request`, see [`ServiceLayer::with_request_factory`]. Requests are sent to
+/// an [`EventSink`], which defaults to a [`channel::Sender`], see [`ServiceLayer::from_parts`].
+///
+/// The [`channel`] does not depend on an async runtime, so the [`ResponseStream`] can be driven by
+/// any executor, such as `async-std` or `smol`. Disabling the default `tokio` feature removes the
+/// tokio dependency along with the parts which use its runtime, [`ServiceLayer::spawn`] and the
+/// default timer used by [`RetryPolicy`].
+/// Applications without an async runtime can use [`ServiceLayer::spawn_blocking_thread`].
+///
+/// With the `log-compat` feature enabled, events converted from `log` records by `tracing-log`
+/// are filtered and recorded using the record's target, module path, file and line, rather than
+/// the shared metadata of the `log` callsite, and their `log.*` fields are not visited.
+pub struct ServiceLayer<Request, MakeVisitor, New = DefaultRequest, Sink = Sender<Request>> {
+    new_request: Arc<New>,
     make_visitor: MakeVisitor,
-    sender: Sender<Request>,
+    init_request: Option<InitRequest<Request>>,
+    record_metadata: Option<RecordMetadata<Request>>,
+    record_span: Option<RecordSpan<Request>>,
+    record_timing: Option<RecordTiming<Request>>,
+    spool: Option<Spool<Request>>,
+    config: Arc<Config>,
+    drop_visitor_errors: bool,
+    rate_limit: Option<Arc<RateLimit<Request>>>,
+    aggregation: Option<Arc<Aggregation<Request>>>,
+    sender: Arc<Sink>,
+    metrics: LayerMetrics,
+    // Sends held requests to the queue, the metrics only hold a weak reference
+    held: Option<HeldGuard>,
+}
+
+type InitRequest<Request> = Arc<dyn Fn(&mut Request) + Send + Sync>;
+type RecordMetadata<Request> = Box<dyn Fn(&mut Request, &Metadata<'_>, SystemTime) + Send + Sync>;
+type RecordSpan<Request> = Box<dyn Fn(&mut Request, &ScopeSpan<'_>) + Send + Sync>;
+type RecordTiming<Request> = Box<dyn Fn(&mut Request, &SpanTiming<'_>) + Send + Sync>;
+
+impl<Request> ServiceLayer<Request, ()> {
+    /// Returns a [`Builder`] which configures a `ServiceLayer` sending to `service`, along with
+    /// its [`ResponseStream`].
+    ///
+    /// ```
+    /// # use tracing_service::ServiceLayer;
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::builder(service)
+    ///     .make_visitor(make_visitor)
+    ///     .unbounded()
+    ///     .build();
+    /// ```
+    pub fn builder<Svc>(service: Svc) -> Builder<Request, Svc>
+    where
+        Svc: Service<Request>,
+    {
+        Builder::new(service)
+    }
 }
 
 impl<Request, MakeVisitor> ServiceLayer<Request, MakeVisitor> {
@@ -35,14 +183,10 @@ impl<Request, MakeVisitor> ServiceLayer<Request, MakeVisitor> {
     where
         Svc: Service<Request>,
     {
-        let (sender, receiver) = channel(buffer);
-        let layer = Self {
-            sender,
-            make_visitor,
-        };
-        let handle = ResponseStream::new(service, receiver);
-
-        (layer, handle)
+        ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .buffer(buffer)
+            .build()
     }
 
     /// Constructs a `ServiceLayer` with an bounded queue being drained into the [`Service`].
@@ -55,39 +199,800 @@ impl<Request, MakeVisitor> ServiceLayer<Request, MakeVisitor> {
     {
         Self::new_with_buffer(service, visitor, Self::DEFAULT_BUFFER)
     }
+
+    /// Constructs a `ServiceLayer` with a bounded queue, using the default capacity (32), and
+    /// spawns a task on the current tokio runtime which drains it into the [`Service`].
+    ///
+    /// Responses from the [`Service`] are discarded. When the returned [`WorkerGuard`] is dropped
+    /// the queue stops accepting events and the events already queued are sent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn<Svc>(service: Svc, make_visitor: MakeVisitor) -> (Self, WorkerGuard)
+    where
+        Request: Send + 'static,
+        Svc: Service<Request> + Send + 'static,
+        Svc::Future: Send + 'static,
+    {
+        let (layer, responses) = Self::new(service, make_visitor);
+        (layer, responses.spawn())
+    }
+
+    /// Constructs a `ServiceLayer` with a bounded queue, using the default capacity (32), and
+    /// spawns a dedicated thread which drains it into the [`Service`].
+    ///
+    /// This is intended for applications without an async runtime. With the `tokio` feature
+    /// enabled the thread runs its own current-thread tokio runtime, so the [`Service`] may use
+    /// tokio's I/O and timers. Responses from the [`Service`] are discarded. When the returned
+    /// [`WorkerGuard`] is dropped the queue stops accepting events and the events already queued
+    /// are sent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread cannot be spawned.
+    pub fn spawn_blocking_thread<Svc>(
+        service: Svc,
+        make_visitor: MakeVisitor,
+    ) -> (Self, WorkerGuard)
+    where
+        Request: Send + 'static,
+        Svc: Service<Request> + Send + 'static,
+        Svc::Future: Send + 'static,
+    {
+        let (layer, responses) = Self::new(service, make_visitor);
+        (layer, worker::spawn_thread(responses))
+    }
+}
+
+impl<Request> ServiceLayer<Request, FromEventVisitor>
+where
+    Request: FromEvent + 'static,
+{
+    /// Constructs a `ServiceLayer`, with the default queue capacity (32), which constructs each
+    /// `Request` from the fields and metadata of an event using its [`FromEvent`] implementation.
+    ///
+    /// ```
+    /// # #[cfg(feature = "derive")] {
+    /// # use tracing_service::{FromEvent, ServiceLayer};
+    /// #[derive(Default, FromEvent)]
+    /// struct AuditRecord {
+    ///     user_id: u64,
+    ///     action: String,
+    /// }
+    ///
+    /// # let service = tower::service_fn(|_: AuditRecord| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::typed(service);
+    /// # }
+    /// ```
+    pub fn typed<Svc>(service: Svc) -> (Self, ResponseStream<Request, Svc>)
+    where
+        Svc: Service<Request>,
+    {
+        ServiceLayer::builder(service)
+            .make_visitor(FromEventVisitor)
+            .metadata(|request: &mut Request, metadata, _| request.record_metadata(metadata))
+            .build()
+    }
+}
+
+impl<Request, MakeVisitor, Sink> ServiceLayer<Request, MakeVisitor, DefaultRequest, Sink> {
+    /// Constructs a `ServiceLayer` which sends requests to an [`EventSink`].
+    ///
+    /// This allows the queue to be backed by a channel other than the [`channel`] module's, such
+    /// as tokio's with the `tokio` feature enabled. The receiving half can be drained into a
+    /// [`Service`] using [`ResponseStream::from_source`].
+    ///
+    /// The layer records into the [`LayerMetrics`] of the sink, see [`EventSink::metrics`], which
+    /// are shared with a stream constructed from the receiving half. The layer's metrics must be
+    /// passed to [`ResponseStream::with_metrics`] for sinks which do not track their metrics, such
+    /// as tokio's, for [`Handle::flush`] to wait for the queue.
+    pub fn from_parts(sink: Sink, make_visitor: MakeVisitor) -> Self
+    where
+        Sink: EventSink<Request>,
+    {
+        let metrics = sink.metrics().cloned().unwrap_or_default();
+        Self {
+            new_request: Arc::new(DefaultRequest),
+            make_visitor,
+            init_request: None,
+            record_metadata: None,
+            record_span: None,
+            record_timing: None,
+            spool: None,
+            config: Arc::default(),
+            drop_visitor_errors: false,
+            rate_limit: None,
+            aggregation: None,
+            sender: Arc::new(sink),
+            metrics,
+            held: None,
+        }
+    }
+}
+
+impl<Request, MakeVisitor>
+    ServiceLayer<Request, MakeVisitor, DefaultRequest, PrioritySender<Request>>
+{
+    /// Constructs a `ServiceLayer` with a bounded queue, of capacity `buffer`, in which `reserved`
+    /// slots are kept for `WARN` and `ERROR` events, see [`priority_channel`].
+    ///
+    /// This ensures a burst of low-severity events does not cause errors to be dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reserved` is zero or greater than `buffer`.
+    pub fn new_with_priority<Svc>(
+        service: Svc,
+        make_visitor: MakeVisitor,
+        buffer: usize,
+        reserved: usize,
+    ) -> (
+        Self,
+        ResponseStream<Request, Svc, PriorityReceiver<Request>>,
+    )
+    where
+        Svc: Service<Request>,
+    {
+        ServiceLayer::builder(service)
+            .make_visitor(make_visitor)
+            .priority(buffer, reserved)
+            .build()
+    }
+}
+
+impl<Request, MakeVisitor, New, Sink> ServiceLayer<Request, MakeVisitor, New, Sink> {
+    /// Constructs each `Request` using `new_request` rather than its [`Default`] implementation.
+    ///
+    /// This allows for `Request` types which require arguments, such as URLs or API keys, to
+    /// construct.
+    pub fn with_request_factory<F>(
+        self,
+        new_request: F,
+    ) -> ServiceLayer<Request, MakeVisitor, F, Sink>
+    where
+        F: NewRequest<Request>,
+    {
+        ServiceLayer {
+            new_request: Arc::new(new_request),
+            make_visitor: self.make_visitor,
+            init_request: self.init_request,
+            record_metadata: self.record_metadata,
+            record_span: self.record_span,
+            record_timing: self.record_timing,
+            spool: self.spool,
+            config: self.config,
+            drop_visitor_errors: self.drop_visitor_errors,
+            rate_limit: self.rate_limit,
+            aggregation: self.aggregation,
+            sender: self.sender,
+            metrics: self.metrics,
+            held: self.held,
+        }
+    }
+
+    /// Replaces the [`MakeVisitor`](field::MakeVisitor) and [`EventSink`], keeping the rest of the
+    /// configuration, and records into the metrics of the new sink if it tracks them.
+    pub(crate) fn map_parts<M, S>(
+        self,
+        f: impl FnOnce(MakeVisitor, Sink) -> (M, S),
+    ) -> ServiceLayer<Request, M, New, S>
+    where
+        S: EventSink<Request>,
+    {
+        let Ok(sender) = Arc::try_unwrap(self.sender) else {
+            unreachable!("the sink is only shared once the layer is registered")
+        };
+        let (make_visitor, sender) = f(self.make_visitor, sender);
+        let metrics = sender.metrics().cloned().unwrap_or(self.metrics);
+        ServiceLayer {
+            new_request: self.new_request,
+            make_visitor,
+            init_request: self.init_request,
+            record_metadata: self.record_metadata,
+            record_span: self.record_span,
+            record_timing: self.record_timing,
+            spool: self.spool,
+            config: self.config,
+            drop_visitor_errors: self.drop_visitor_errors,
+            rate_limit: self.rate_limit,
+            aggregation: self.aggregation,
+            sender: Arc::new(sender),
+            metrics,
+            held: None,
+        }
+    }
+
+    /// Returns a [`Handle`] which reconfigures this layer while it is running.
+    pub fn handle(&self) -> Handle {
+        Handle::new(self.config.clone(), self.metrics.clone())
+    }
+
+    /// Returns a handle to the [`LayerMetrics`] recorded by this layer.
+    pub fn metrics(&self) -> LayerMetrics {
+        self.metrics.clone()
+    }
+
+    /// Writes events which overflow the queue to the [`Spool`], rather than dropping them.
+    ///
+    /// This performs blocking file I/O within [`Layer::on_event`] while the queue is full. The
+    /// same [`Spool`] should be passed to [`ResponseStream::with_spool`] so that the events are
+    /// replayed.
+    pub fn with_spool(mut self, spool: Spool<Request>) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Ignores events whose target is `target`, or a module within it.
+    ///
+    /// Events emitted while the [`ResponseStream`] is being polled are always ignored, however a
+    /// [`Service`] may emit events from tasks it spawns, such as `hyper`'s connection tasks. These
+    /// should be excluded to prevent a feedback loop.
+    pub fn exclude_target(self, target: impl Into<Cow<'static, str>>) -> Self {
+        self.handle().exclude_target(target);
+        self
+    }
+
+    /// Drops the requests of events whose visitor returned an error, rather than sending them.
+    ///
+    /// By default such requests are sent as they are, which may be incomplete, and counted by
+    /// [`LayerMetrics::visitor_errors`]. This should be used when an incomplete request cannot be
+    /// sent, such as one which failed to [`encode`].
+    pub fn drop_visitor_errors(mut self) -> Self {
+        self.drop_visitor_errors = true;
+        self
+    }
+
+    /// Sends only a fraction of the events at `level`, all events are sent by default.
+    ///
+    /// Sampling happens before the `Request` is constructed, so events which are sampled out do
+    /// not pay the cost of visiting their fields or queueing.
+    ///
+    /// ```
+    /// # use tracing_core::Level;
+    /// # use tracing_service::{SampleRate, ServiceLayer};
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    /// let layer = layer
+    ///     .with_sample_rate(Level::TRACE, SampleRate::OneIn(100))
+    ///     .with_sample_rate(Level::DEBUG, SampleRate::Probability(0.1));
+    /// ```
+    pub fn with_sample_rate(self, level: Level, rate: SampleRate) -> Self {
+        self.handle().set_sample_rate(level, Some(rate));
+        self
+    }
+
+    /// Limits the rate at which events are sent, see [`RateLimit`].
+    ///
+    /// This prevents a runaway loop from flooding the [`Service`]. Events which are rate limited
+    /// are handled like those which overflow the queue, they are written to the [`Spool`], if one
+    /// is configured, and dropped otherwise, see [`Handle::set_overflow_policy`].
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit<Request>) -> Self {
+        self.rate_limit = Some(Arc::new(rate_limit));
+        self
+    }
+
+    /// Coalesces identical events within a window into a single request carrying their count,
+    /// see [`Aggregation`].
+    ///
+    /// This cuts the traffic from tight error loops, which may emit thousands of identical events
+    /// per second. Duplicates are counted before the rate limit is applied, so are never rate
+    /// limited themselves.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tracing_service::{Aggregation, ServiceLayer};
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    /// let aggregation = Aggregation::new(Duration::from_secs(10), |request: &mut String, count| {
+    ///     request.push_str(&format!(" (repeated {count} times)"));
+    /// });
+    /// let layer = layer.with_aggregation(aggregation);
+    /// ```
+    pub fn with_aggregation(mut self, aggregation: Aggregation<Request>) -> Self {
+        self.aggregation = Some(Arc::new(aggregation));
+        self
+    }
+
+    /// Runs `init_request` on each newly constructed `Request`, before the event is recorded into
+    /// it.
+    ///
+    /// This allows fields common to every request to be populated, see
+    /// [`ServiceLayer::with_resource`].
+    pub fn with_request_init<F>(mut self, init_request: F) -> Self
+    where
+        F: Fn(&mut Request) + Send + Sync + 'static,
+    {
+        self.init_request = Some(Arc::new(init_request));
+        self
+    }
+
+    /// Records the attributes of the [`Resource`] into every `Request`.
+    ///
+    /// This replaces any closure passed to [`ServiceLayer::with_request_init`].
+    ///
+    /// ```
+    /// # use tracing_service::{logfmt, Resource};
+    /// # let service = tower::service_fn(|_: logfmt::Line| async { Ok::<_, ()>(()) });
+    /// let resource = Resource::new()
+    ///     .with_service_name("checkout")
+    ///     .with_service_version(env!("CARGO_PKG_VERSION"))
+    ///     .with_environment("production");
+    /// let (layer, responses) = logfmt::layer(service);
+    /// let layer = layer.with_resource(resource);
+    /// ```
+    pub fn with_resource(self, resource: Resource) -> Self
+    where
+        Request: RecordResource,
+    {
+        self.with_request_init(move |request: &mut Request| request.record_resource(&resource))
+    }
+
+    /// Records the [`Metadata`] of each event into the `Request` before its fields are visited.
+    ///
+    /// `record_metadata` is also passed the time the event was recorded, captured within
+    /// [`Layer::on_event`] before the `Request` is constructed, so it is unaffected by time spent
+    /// waiting to be sent.
+    pub fn with_metadata<F>(mut self, record_metadata: F) -> Self
+    where
+        F: Fn(&mut Request, &Metadata<'_>, SystemTime) + Send + Sync + 'static,
+    {
+        self.record_metadata = Some(Box::new(record_metadata));
+        self
+    }
+
+    /// Records each span in the scope of an event into the `Request`, from the root span to the
+    /// leaf, before the event's fields are visited.
+    ///
+    /// Enabling this stores the fields of every span in its registry extensions, so they can be
+    /// visited using [`ScopeSpan::record`].
+    pub fn with_span_scope<F>(mut self, record_span: F) -> Self
+    where
+        F: Fn(&mut Request, &ScopeSpan<'_>) + Send + Sync + 'static,
+    {
+        self.record_span = Some(Box::new(record_span));
+        self
+    }
+
+    /// Sends a `Request` as each span closes, recording how long it spent entered and idle.
+    ///
+    /// The `Request` is constructed as it is for an event, the [`Metadata`] recorded is that of
+    /// the span, and then `record_timing` records the [`SpanTiming`]. This stores the fields and
+    /// timings of every span in its registry extensions.
+    ///
+    /// ```
+    /// # use tracing_service::logfmt;
+    /// # let service = tower::service_fn(|_: logfmt::Line| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = logfmt::layer(service);
+    /// let layer = layer.with_span_timing(logfmt::record_span_timing);
+    /// ```
+    pub fn with_span_timing<F>(mut self, record_timing: F) -> Self
+    where
+        F: Fn(&mut Request, &SpanTiming<'_>) + Send + Sync + 'static,
+    {
+        self.record_timing = Some(Box::new(record_timing));
+        self
+    }
 }
 
-impl<S, Request, MakeVisitor> Layer<S> for ServiceLayer<Request, MakeVisitor>
+impl<S, Request, MakeVisitor, New, Sink> Layer<S> for ServiceLayer<Request, MakeVisitor, New, Sink>
 where
     S: Subscriber,
     for<'a> S: LookupSpan<'a>,
 
-    Request: Default + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+
+    New: NewRequest<Request> + Send + Sync + 'static,
+    Sink: EventSink<Request> + Send + Sync + 'static,
 
     for<'a> MakeVisitor: field::MakeVisitor<&'a mut Request>,
     MakeVisitor: 'static,
-    for<'a> <MakeVisitor as field::MakeVisitor<&'a mut Request>>::Visitor:
-        VisitOutput<Result<(), fmt::Error>>,
+    for<'a> <MakeVisitor as field::MakeVisitor<&'a mut Request>>::Visitor: EventVisitor,
 {
-    // TODO: Add spans
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        // Coalesced requests and rate limit summaries are sent to the queue by the stream once
+        // they are due
+        if self.aggregation.is_none() && self.rate_limit.is_none() {
+            return;
+        }
+        let held: Arc<dyn Release> = Arc::new(Held {
+            new_request: self.new_request.clone(),
+            init_request: self.init_request.clone(),
+            aggregation: self.aggregation.clone(),
+            rate_limit: self.rate_limit.clone(),
+            sender: self.sender.clone(),
+            spool: self.spool.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+        });
+        self.metrics.set_release(&held);
+        self.held = Some(HeldGuard(held));
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        if self.record_span.is_none() && self.record_timing.is_none() {
+            return;
+        }
+
+        // The fields and timings are shared with any other `ServiceLayer` recording them
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if extensions.get_mut::<SpanFields>().is_none() {
+                extensions.insert(SpanFields::new(attrs));
+            }
+            if self.record_timing.is_some() && extensions.get_mut::<Timings>().is_none() {
+                extensions.insert(Timings::new());
+            }
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        if self.record_span.is_none() && self.record_timing.is_none() {
+            return;
+        }
+
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.update(values);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        if self.record_timing.is_none() {
+            return;
+        }
+
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                timings.enter();
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        if self.record_timing.is_none() {
+            return;
+        }
+
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                timings.exit();
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(record_timing) = &self.record_timing else {
+            return;
+        };
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let metadata = span.metadata();
+        if reentrancy::is_entered() || !self.config.enabled(metadata) {
+            return;
+        }
+        let _guard = reentrancy::Guard::enter();
+        let timestamp = SystemTime::now();
+
+        let timings = span
+            .extensions_mut()
+            .get_mut::<Timings>()
+            .map(Timings::close);
+        let Some((busy, idle)) = timings else {
+            return;
+        };
+
+        let mut request = self.new_request.new_request_at(timestamp);
+        if let Some(init_request) = &self.init_request {
+            init_request(&mut request);
+        }
+        if let Some(record_metadata) = &self.record_metadata {
+            record_metadata(&mut request, metadata, timestamp);
+        }
+        let extensions = span.extensions();
+        let timing = SpanTiming {
+            span: ScopeSpan {
+                id,
+                metadata,
+                fields: extensions.get::<SpanFields>(),
+            },
+            busy,
+            idle,
+        };
+        record_timing(&mut request, &timing);
+        drop(extensions);
+
+        self.enqueue(request, metadata.level());
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        // Events converted from `log` records carry their metadata in `log.*` fields
+        #[cfg(feature = "log-compat")]
+        let normalized = log_compat::normalized_metadata(event);
+        #[cfg(feature = "log-compat")]
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        #[cfg(not(feature = "log-compat"))]
+        let metadata = event.metadata();
+
+        // Ignore events emitted by the pipeline itself, they would otherwise loop back around
+        if reentrancy::is_entered() || !self.config.enabled(metadata) {
+            return;
+        }
+        if !self.config.sample(metadata.level()) {
+            return;
+        }
+        let _guard = reentrancy::Guard::enter();
+        let timestamp = SystemTime::now();
 
-    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
         // Construct the request using the visitor implementation
-        let mut request = Request::default();
-        let mut visitor = self.make_visitor.make_visitor(&mut request);
-        event.record(&mut visitor);
-
-        // There needs to be some consideration on what to do with these errors. Logging them
-        // naively might make the situation worse.
-        //
-        // Allowing the user to provide a backup subscriber to log this might be an avenue.
-        if visitor.finish().is_err() {
-            // TODO
+        let new_request = || {
+            let mut request = self.new_request.new_request_at(timestamp);
+            if let Some(init_request) = &self.init_request {
+                init_request(&mut request);
+            }
+            if let Some(record_metadata) = &self.record_metadata {
+                record_metadata(&mut request, metadata, timestamp);
+            }
+            if let (Some(record_span), Some(scope)) = (&self.record_span, ctx.event_scope(event)) {
+                for span in scope.from_root() {
+                    let extensions = span.extensions();
+                    let scope_span = ScopeSpan {
+                        id: span.id(),
+                        metadata: span.metadata(),
+                        fields: extensions.get::<SpanFields>(),
+                    };
+                    record_span(&mut request, &scope_span);
+                }
+            }
+            let mut visitor = self.make_visitor.make_visitor(&mut request);
+            #[cfg(feature = "log-compat")]
+            log_compat::record(event, &mut visitor);
+            #[cfg(not(feature = "log-compat"))]
+            event.record(&mut visitor);
+
+            // There needs to be some consideration on what to do with these errors. Logging them
+            // naively might make the situation worse.
+            //
+            // Allowing the user to provide a backup subscriber to log this might be an avenue.
+            if visitor.finish_request().is_err() {
+                self.metrics.record_visitor_error();
+                if self.drop_visitor_errors {
+                    return None;
+                }
+            }
+            Some(request)
+        };
+
+        // Duplicates within an aggregation window are counted, only the first is constructed
+        if let Some(aggregation) = &self.aggregation {
+            let (observe, closed) = aggregation.observe(event, new_request);
+            for (request, level) in closed {
+                self.enqueue(request, &level);
+            }
+            match observe {
+                Observe::Send => {}
+                Observe::Hold => {
+                    // The stream sends the held request once its window closes
+                    self.metrics.record_aggregated();
+                    self.metrics.wake_streams();
+                    return;
+                }
+                Observe::Discard => {
+                    self.metrics.record_aggregated();
+                    return;
+                }
+                Observe::Invalid => return,
+            }
+        }
+
+        // Events beyond the rate limit are only constructed if they can be spooled, according to
+        // the overflow policy
+        let rate_limited = match &self.rate_limit {
+            Some(rate_limit) => match rate_limit.acquire(*metadata.level()) {
+                Acquire::Admitted { summary } => {
+                    if let Some((limited, level)) = summary {
+                        let summary = summary_request(
+                            &*self.new_request,
+                            self.init_request.as_ref(),
+                            rate_limit,
+                            limited,
+                        );
+                        self.enqueue(summary, &level);
+                    }
+                    false
+                }
+                Acquire::Limited { first } => {
+                    // The stream sends the summary once the bucket refills
+                    if first {
+                        self.metrics.wake_streams();
+                    }
+                    if self.spool_to().is_none() {
+                        self.metrics.record_dropped_rate_limited();
+                        return;
+                    }
+                    true
+                }
+            },
+            None => false,
         };
 
-        if self.sender.try_send(request).is_err() {
-            // TODO: This can error in two ways, receiver dropped and receiver full (in the case of
-            // a bounded sender).
+        let Some(request) = new_request() else {
+            return;
         };
+        if rate_limited {
+            if !self.spool(&request) {
+                self.metrics.record_dropped_rate_limited();
+            }
+            return;
+        }
+        self.enqueue(request, metadata.level());
+    }
+}
+
+impl<Request, MakeVisitor, New, Sink> ServiceLayer<Request, MakeVisitor, New, Sink>
+where
+    Sink: EventSink<Request>,
+{
+    /// Sends a request, for an event at `level`, to the queue, spooling it if the queue is full.
+    fn enqueue(&self, request: Request, level: &Level) {
+        enqueue(
+            &*self.sender,
+            self.spool_to(),
+            &self.metrics,
+            request,
+            level,
+        );
+    }
+
+    /// Writes a request to the [`Spool`], returning `false` if there is none, it is full or the
+    /// overflow policy drops events.
+    fn spool(&self, request: &Request) -> bool {
+        spool(self.spool_to(), &self.metrics, request)
+    }
+
+    /// The [`Spool`] overflowing events are written to, according to the [`OverflowPolicy`].
+    fn spool_to(&self) -> Option<&Spool<Request>> {
+        self.config.spool_to(self.spool.as_ref())
+    }
+}
+
+/// Sends a request, for an event at `level`, to the queue, spooling it if the queue is full.
+fn enqueue<Request, Sink>(
+    sender: &Sink,
+    spool_to: Option<&Spool<Request>>,
+    metrics: &LayerMetrics,
+    request: Request,
+    level: &Level,
+) where
+    Sink: EventSink<Request> + ?Sized,
+{
+    let mut overflowed = None;
+    let sent = sender.try_send_each(request, level, &mut |err| match err {
+        TrySendError::Full(request) if spool_to.is_some() && overflowed.is_none() => {
+            overflowed = Some(request);
+        }
+        TrySendError::Full(_) => metrics.record_dropped_full(),
+        TrySendError::Closed(_) => metrics.record_dropped_closed(),
+    });
+    if sent {
+        // Sinks which track their metrics count the requests they accept themselves
+        if sender.metrics().is_none() {
+            metrics.record_sent();
+        }
+        metrics.record_enqueued();
+    }
+
+    // The spool is replayed into a single queue, so the request is only spooled if no queue
+    // accepted it, the branches of a `Tee` spool their own overflow
+    if let Some(request) = overflowed {
+        if sent || !spool(spool_to, metrics, &request) {
+            metrics.record_dropped_full();
+        }
+    }
+}
+
+/// Writes a request to the [`Spool`], returning `false` if there is none or it is full.
+fn spool<Request>(
+    spool: Option<&Spool<Request>>,
+    metrics: &LayerMetrics,
+    request: &Request,
+) -> bool {
+    let spooled = spool.is_some_and(|spool| matches!(spool.push(request), Ok(true)));
+    if spooled {
+        metrics.record_spooled();
+    }
+    spooled
+}
+
+/// The requests held back by a [`ServiceLayer`], which are sent to its queue when they are due,
+/// see [`Release`].
+struct Held<Request, New, Sink> {
+    new_request: Arc<New>,
+    init_request: Option<InitRequest<Request>>,
+    aggregation: Option<Arc<Aggregation<Request>>>,
+    rate_limit: Option<Arc<RateLimit<Request>>>,
+    sender: Arc<Sink>,
+    spool: Option<Spool<Request>>,
+    config: Arc<Config>,
+    metrics: LayerMetrics,
+}
+
+impl<Request, New, Sink> Release for Held<Request, New, Sink>
+where
+    Request: Send,
+    New: NewRequest<Request> + Send + Sync,
+    Sink: EventSink<Request> + Send + Sync,
+{
+    fn release(&self, force: bool) {
+        let mut released = self
+            .aggregation
+            .as_ref()
+            .map(|aggregation| aggregation.release(force))
+            .unwrap_or_default();
+        if let Some(rate_limit) = &self.rate_limit {
+            if let Some((limited, level)) = rate_limit.summary_due(force) {
+                let summary = summary_request(
+                    &*self.new_request,
+                    self.init_request.as_ref(),
+                    rate_limit,
+                    limited,
+                );
+                released.push((summary, level));
+            }
+        }
+        for (request, level) in released {
+            enqueue(
+                &*self.sender,
+                self.config.spool_to(self.spool.as_ref()),
+                &self.metrics,
+                request,
+                &level,
+            );
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let aggregation = self.aggregation.as_ref().and_then(|aggr| aggr.deadline());
+        let rate_limit = self.rate_limit.as_ref().and_then(|limit| limit.deadline());
+        match (aggregation, rate_limit) {
+            (Some(aggregation), Some(rate_limit)) => Some(aggregation.min(rate_limit)),
+            (deadline, None) | (None, deadline) => deadline,
+        }
+    }
+}
+
+/// Constructs the summary of the events limited by the [`RateLimit`], with the fields common to
+/// every request.
+fn summary_request<Request, New>(
+    new_request: &New,
+    init_request: Option<&InitRequest<Request>>,
+    rate_limit: &RateLimit<Request>,
+    limited: u64,
+) -> Request
+where
+    New: NewRequest<Request> + ?Sized,
+{
+    let mut request = new_request.new_request_at(SystemTime::now());
+    if let Some(init_request) = init_request {
+        init_request(&mut request);
+    }
+    rate_limit.record_summary(&mut request, limited);
+    request
+}
+
+/// Sends the requests held back by a [`ServiceLayer`] to its queue when the layer is dropped, so
+/// they are not lost at shutdown.
+struct HeldGuard(Arc<dyn Release>);
+
+impl Drop for HeldGuard {
+    fn drop(&mut self) {
+        self.0.release(true);
     }
 }
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use tracing_core::{
+    field::{Field, Visit},
+    Event, Metadata,
+};
+use tracing_log::NormalizeEvent;
+
+/// The prefix of the fields `tracing-log` uses to carry the metadata of a `log` record.
+const LOG_FIELD_PREFIX: &str = "log.";
+
+/// Returns the metadata of the `log` record an event was converted from, if it was.
+///
+/// Events converted by `tracing-log` share a callsite per level, so their own metadata has the
+/// target `log` and no source location.
+pub(crate) fn normalized_metadata<'a>(event: &'a Event<'_>) -> Option<Metadata<'a>> {
+    event.normalized_metadata()
+}
+
+/// Records the fields of an event into `visitor`, skipping the `log.*` fields of events converted
+/// from `log` records, as these are recorded as metadata instead.
+pub(crate) fn record(event: &Event<'_>, visitor: &mut dyn Visit) {
+    if event.is_log() {
+        event.record(&mut SkipLogFields(visitor));
+    } else {
+        event.record(visitor);
+    }
+}
+
+struct SkipLogFields<'a>(&'a mut dyn Visit);
+
+impl SkipLogFields<'_> {
+    fn is_log_field(field: &Field) -> bool {
+        field.name().starts_with(LOG_FIELD_PREFIX)
+    }
+}
+
+impl Visit for SkipLogFields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if !Self::is_log_field(field) {
+            self.0.record_f64(field, value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if !Self::is_log_field(field) {
+            self.0.record_i64(field, value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if !Self::is_log_field(field) {
+            self.0.record_u64(field, value);
+        }
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        if !Self::is_log_field(field) {
+            self.0.record_i128(field, value);
+        }
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        if !Self::is_log_field(field) {
+            self.0.record_u128(field, value);
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if !Self::is_log_field(field) {
+            self.0.record_bool(field, value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !Self::is_log_field(field) {
+            self.0.record_str(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if !Self::is_log_field(field) {
+            self.0.record_error(field, value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !Self::is_log_field(field) {
+            self.0.record_debug(field, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{fmt::format::JsonVisitor, layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::ServiceLayer;
+
+    fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+        JsonVisitor::new(value)
+    }
+
+    /// Records the metadata passed to the layer ahead of the event's fields.
+    fn record_metadata(request: &mut String, metadata: &Metadata<'_>, _: SystemTime) {
+        request.push_str(&format!(
+            "{} {} {:?} {:?} {:?} ",
+            metadata.level(),
+            metadata.target(),
+            metadata.module_path(),
+            metadata.file(),
+            metadata.line()
+        ));
+    }
+
+    /// Returns the requests constructed from the events emitted by `f`.
+    async fn requests(f: impl FnOnce()) -> Vec<String> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let service = tower::service_fn(move |request: String| {
+            recorded.lock().unwrap().push(request);
+            std::future::ready(Ok::<_, ()>(()))
+        });
+        let (layer, responses) = ServiceLayer::new(service, make_visitor);
+        let layer = layer.with_metadata(record_metadata);
+        let handle = layer.handle();
+        handle.exclude_target("excluded");
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+        let requests = requests.lock().unwrap().clone();
+        requests
+    }
+
+    fn log(target: &str, message: &str) {
+        tracing_log::format_trace(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target(target)
+                .module_path(Some("app::db"))
+                .file(Some("src/db.rs"))
+                .line(Some(42))
+                .args(format_args!("{message}"))
+                .build(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_the_metadata_of_log_records() {
+        let requests = requests(|| log("app::db", "slow query")).await;
+        assert_eq!(
+            requests,
+            [
+                "WARN app::db Some(\"app::db\") Some(\"src/db.rs\") Some(42) \
+              {\"message\":\"slow query\"}"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn filters_log_records_by_their_target() {
+        let requests = requests(|| {
+            log("excluded", "dropped");
+            log("app::db", "sent");
+        })
+        .await;
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].ends_with("{\"message\":\"sent\"}"));
+    }
+
+    #[tokio::test]
+    async fn records_tracing_events_unchanged() {
+        let requests = requests(|| tracing::info!(log.target = "kept", "event")).await;
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("INFO tracing_service::log_compat::tests"));
+        assert!(requests[0].ends_with("{\"log.target\":\"kept\",\"message\":\"event\"}"));
+    }
+}
@@ -0,0 +1,347 @@
+//! A [`MakeVisitor`](field::MakeVisitor) which records events as [logfmt] lines.
+//!
+//! Each [`Line`] starts with the event's `ts`, `level` and `target`, followed by the fields of the
+//! spans in its scope, prefixed by the span's name, and then the event's fields. The `message`
+//! field is written as `msg`:
+//!
+//! ```text
+//! ts=2022-06-01T12:00:00.000000000Z level=info target=app::handler request.method=GET msg="handled request" status=200
+//! ```
+//!
+//! ```no_run
+//! use tracing_service::logfmt::{self, Line};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! # let service = tower::service_fn(|_: Line| async { Ok::<_, ()>(()) });
+//! let (layer, responses) = logfmt::layer(service);
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+//!
+//! [logfmt]: https://brandur.org/logfmt
+
+use std::{
+    fmt::{self, Write},
+    time::SystemTime,
+};
+
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{
+    timestamp::rfc3339, RecordResource, Reset, Resource, ResponseStream, ScopeSpan, ServiceLayer,
+    SpanTiming,
+};
+
+/// Constructs a [`ServiceLayer`] which sends each event to `service` as a [`Line`], including its
+/// metadata and span scope.
+pub fn layer<Svc>(service: Svc) -> (ServiceLayer<Line, Logfmt>, ResponseStream<Line, Svc>)
+where
+    Svc: Service<Line>,
+{
+    let (layer, responses) = ServiceLayer::new(service, Logfmt);
+    let layer = layer
+        .with_metadata(record_metadata)
+        .with_span_scope(record_span);
+    (layer, responses)
+}
+
+/// Records the `ts`, `level` and `target` of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(line: &mut Line, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    line.push("ts", &rfc3339(timestamp));
+    line.push("level", &metadata.level().as_str().to_lowercase());
+    line.push("target", metadata.target());
+}
+
+/// Records the fields of a span in the scope of an event, prefixed by the span's name.
+///
+/// This is intended to be passed to [`ServiceLayer::with_span_scope`].
+pub fn record_span(line: &mut Line, span: &ScopeSpan<'_>) {
+    span.record(&mut Visitor {
+        line,
+        prefix: Some(span.name()),
+    });
+}
+
+/// Records the name and fields of a closed span, prefixed by its name, along with `busy_ns` and
+/// `idle_ns`.
+///
+/// This is intended to be passed to [`ServiceLayer::with_span_timing`].
+pub fn record_span_timing(line: &mut Line, timing: &SpanTiming<'_>) {
+    let span = timing.span();
+    line.push("span", span.name());
+    record_span(line, span);
+    line.push("busy_ns", &timing.busy().as_nanos().to_string());
+    line.push("idle_ns", &timing.idle().as_nanos().to_string());
+}
+
+/// An event recorded as a single logfmt line, without a trailing newline.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line(String);
+
+impl Line {
+    /// Returns the line.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Consumes the line, returning the underlying [`String`].
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    fn push(&mut self, key: &str, value: &str) {
+        self.push_key(key, None);
+        self.push_value(value);
+    }
+
+    fn push_key(&mut self, key: &str, prefix: Option<&str>) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if let Some(prefix) = prefix {
+            self.0.push_str(prefix);
+            self.0.push('.');
+        }
+        self.0.push_str(key);
+        self.0.push('=');
+    }
+
+    /// Appends a value, quoting it if it is empty or contains whitespace, `=` or `"`.
+    fn push_value(&mut self, value: &str) {
+        let needs_quotes = value.is_empty()
+            || value
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == '=' || c == '"');
+        if !needs_quotes {
+            self.0.push_str(value);
+            return;
+        }
+
+        self.0.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                c => self.0.push(c),
+            }
+        }
+        self.0.push('"');
+    }
+}
+
+impl RecordResource for Line {
+    fn record_resource(&mut self, resource: &Resource) {
+        for (key, value) in resource.attributes() {
+            self.push(key, value);
+        }
+    }
+}
+
+impl Reset for Line {
+    fn reset(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Line> for String {
+    fn from(line: Line) -> Self {
+        line.0
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which appends event fields to a [`Line`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Logfmt;
+
+impl<'a> field::MakeVisitor<&'a mut Line> for Logfmt {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, line: &'a mut Line) -> Self::Visitor {
+        Visitor { line, prefix: None }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Logfmt`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    line: &'a mut Line,
+    prefix: Option<&'static str>,
+}
+
+impl Visitor<'_> {
+    fn push_key(&mut self, field: &Field) {
+        let key = match field.name() {
+            "message" if self.prefix.is_none() => "msg",
+            name => name,
+        };
+        self.line.push_key(key, self.prefix);
+    }
+
+    fn push_display(&mut self, field: &Field, value: impl fmt::Display) {
+        self.push_key(field);
+        let _ = write!(self.line.0, "{value}");
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push_display(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push_display(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push_display(field, value);
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.push_display(field, value);
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.push_display(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push_display(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push_key(field);
+        self.line.push_value(value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push_key(field);
+        self.line.push_value(&format!("{value:?}"));
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    /// Returns the lines constructed from the events emitted by `f`, without their timestamps.
+    async fn lines(f: impl FnOnce()) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let recorded = lines.clone();
+        let service = tower::service_fn(move |line: Line| {
+            recorded.lock().unwrap().push(line);
+            future::ready(Ok::<_, ()>(()))
+        });
+        let (layer, responses) = layer(service);
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+
+        let lines = lines.lock().unwrap();
+        lines
+            .iter()
+            .map(|line| {
+                let (ts, rest) = line.as_str().split_once(' ').unwrap();
+                assert!(ts.starts_with("ts=") && ts.ends_with('Z'));
+                rest.to_owned()
+            })
+            .collect()
+    }
+
+    fn line(f: impl FnOnce(&mut Line)) -> String {
+        let mut line = Line::default();
+        f(&mut line);
+        line.into_string()
+    }
+
+    #[tokio::test]
+    async fn records_metadata_spans_and_fields() {
+        let lines = lines(|| {
+            let _span = tracing::info_span!("request", method = "GET").entered();
+            tracing::warn!(status = 200, "handled request");
+        })
+        .await;
+        assert_eq!(
+            lines,
+            [
+                "level=warn target=tracing_service::logfmt::tests request.method=GET \
+                 msg=\"handled request\" status=200"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_renames_the_message_of_the_event() {
+        let lines = lines(|| {
+            let _span = tracing::info_span!("job", message = "queued").entered();
+            tracing::info!(message = "done");
+        })
+        .await;
+        assert_eq!(
+            lines,
+            ["level=info target=tracing_service::logfmt::tests job.message=queued msg=done"]
+        );
+    }
+
+    #[test]
+    fn quotes_values_which_need_it() {
+        assert_eq!(line(|line| line.push("k", "plain")), "k=plain");
+        assert_eq!(line(|line| line.push("k", "")), "k=\"\"");
+        assert_eq!(line(|line| line.push("k", "a b")), "k=\"a b\"");
+        assert_eq!(line(|line| line.push("k", "a=b")), "k=\"a=b\"");
+        assert_eq!(
+            line(|line| line.push("k", "say \"hi\"")),
+            r#"k="say \"hi\"""#
+        );
+    }
+
+    #[test]
+    fn escapes_quoted_values() {
+        assert_eq!(
+            line(|line| line.push("k", "a\\b\nc\td")),
+            r#"k="a\\b\nc\td""#
+        );
+        // Backslashes alone do not need quoting
+        assert_eq!(line(|line| line.push("k", "a\\b")), r"k=a\b");
+    }
+
+    #[test]
+    fn separates_pairs_with_spaces() {
+        let line = line(|line| {
+            line.push("a", "1");
+            line.push_key("b", Some("span"));
+            line.push_value("2");
+        });
+        assert_eq!(line, "a=1 span.b=2");
+    }
+}
@@ -0,0 +1,315 @@
+//! An adapter which maps events onto [Grafana Loki push API] payloads.
+//!
+//! Each [`Entry`] is labelled with the event's `level` and `target`, along with any configured
+//! span fields, and its line is the event's fields formatted as JSON. The [`fmt::Display`]
+//! implementation of [`Entry`] formats the JSON body of a push request.
+//!
+//! ```no_run
+//! use hyper::{client::Client, Body, Request};
+//! use tower::ServiceExt;
+//! use tracing_service::loki::{self, Entry, Loki};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! let client = Client::new().map_request(|entry: Entry| {
+//!     Request::post("http://127.0.0.1:3100/loki/api/v1/push")
+//!         .header("content-type", "application/json")
+//!         .body(Body::from(entry.to_string()))
+//!         .unwrap()
+//! });
+//! let (layer, responses) = loki::layer(client, Loki::new().with_span_label("tenant_id"));
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+//!
+//! [Grafana Loki push API]: https://grafana.com/docs/loki/latest/reference/loki-http-api/#ingest-logs
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{RecordResource, Reset, Resource, ResponseStream, ServiceLayer};
+
+/// Constructs a [`ServiceLayer`] which sends each event to `service` as an [`Entry`].
+pub fn layer<Svc>(
+    service: Svc,
+    loki: Loki,
+) -> (ServiceLayer<Entry, Loki>, ResponseStream<Entry, Svc>)
+where
+    Svc: Service<Entry>,
+{
+    let span_labels = loki.span_labels.clone();
+    let (layer, responses) = ServiceLayer::new(service, loki);
+    let mut layer = layer.with_metadata(record_metadata);
+    if !span_labels.is_empty() {
+        layer = layer.with_span_scope(move |entry, span| {
+            span.record(&mut LabelVisitor {
+                entry,
+                span_labels: &span_labels,
+            })
+        });
+    }
+    (layer, responses)
+}
+
+/// Records the `level` and `target` labels, and the timestamp, of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(entry: &mut Entry, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    entry.timestamp = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    entry
+        .labels
+        .insert("level".into(), metadata.level().as_str().to_lowercase());
+    entry
+        .labels
+        .insert("target".into(), metadata.target().into());
+}
+
+/// A single Loki log entry, formatted as the JSON body of a push request by its [`fmt::Display`]
+/// implementation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    labels: BTreeMap<String, String>,
+    timestamp: u128,
+    line: Map<String, Value>,
+}
+
+impl Entry {
+    /// Returns the stream labels.
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// Returns the timestamp, in nanoseconds since the Unix epoch.
+    pub fn timestamp_nanos(&self) -> u128 {
+        self.timestamp
+    }
+
+    /// Returns the fields making up the log line.
+    pub fn line(&self) -> &Map<String, Value> {
+        &self.line
+    }
+}
+
+impl RecordResource for Entry {
+    /// Records the attributes as labels, with `.` replaced by `_` as Loki label names may not
+    /// contain `.`.
+    fn record_resource(&mut self, resource: &Resource) {
+        for (key, value) in resource.attributes() {
+            self.labels.insert(key.replace('.', "_"), value.into());
+        }
+    }
+}
+
+impl Reset for Entry {
+    fn reset(&mut self) {
+        self.labels.clear();
+        self.timestamp = 0;
+        self.line.clear();
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = serde_json::to_string(&self.line).map_err(|_| fmt::Error)?;
+        let body = json!({
+            "streams": [{
+                "stream": self.labels,
+                "values": [[self.timestamp.to_string(), line]],
+            }]
+        });
+        write!(f, "{body}")
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into the line of an
+/// [`Entry`].
+#[derive(Debug, Clone, Default)]
+pub struct Loki {
+    span_labels: Arc<[&'static str]>,
+}
+
+impl Loki {
+    /// Constructs a `Loki` without any span labels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the span field `name` as a label, when [`layer`] is used.
+    ///
+    /// Labels should have a low cardinality, Loki indexes each unique set of labels as a stream.
+    pub fn with_span_label(mut self, name: &'static str) -> Self {
+        let mut span_labels = self.span_labels.to_vec();
+        span_labels.push(name);
+        self.span_labels = span_labels.into();
+        self
+    }
+}
+
+impl<'a> field::MakeVisitor<&'a mut Entry> for Loki {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, entry: &'a mut Entry) -> Self::Visitor {
+        Visitor { entry }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Loki`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    entry: &'a mut Entry,
+}
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.entry.line.insert(field.name().into(), value);
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+/// Records the span fields configured by [`Loki::with_span_label`] as labels.
+struct LabelVisitor<'a> {
+    entry: &'a mut Entry,
+    span_labels: &'a [&'static str],
+}
+
+impl Visit for LabelVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.span_labels.contains(&field.name()) {
+            self.entry.labels.insert(field.name().into(), value.into());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.span_labels.contains(&field.name()) {
+            self.entry
+                .labels
+                .insert(field.name().into(), format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    /// Returns the entries constructed from the events emitted by `f`.
+    async fn entries(loki: Loki, f: impl FnOnce()) -> Vec<Entry> {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = entries.clone();
+        let service = tower::service_fn(move |entry: Entry| {
+            recorded.lock().unwrap().push(entry);
+            future::ready(Ok::<_, fmt::Error>(()))
+        });
+        let (layer, responses) = layer(service, loki);
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+        let entries = entries.lock().unwrap().clone();
+        entries
+    }
+
+    #[tokio::test]
+    async fn labels_entries() {
+        let loki = Loki::new().with_span_label("tenant_id");
+        let entries = entries(loki, || {
+            let span = tracing::info_span!("request", tenant_id = "acme", path = "/");
+            span.in_scope(|| tracing::warn!(status = 503, "unavailable"));
+        })
+        .await;
+        let entry = &entries[0];
+        assert_eq!(
+            entry.labels().iter().collect::<Vec<_>>(),
+            [
+                (&"level".to_string(), &"warn".to_string()),
+                (
+                    &"target".to_string(),
+                    &"tracing_service::loki::tests".to_string()
+                ),
+                (&"tenant_id".to_string(), &"acme".to_string()),
+            ]
+        );
+        assert_eq!(entry.line()["status"], 503);
+        assert_eq!(entry.line()["message"], "unavailable");
+        assert!(entry.timestamp_nanos() > 0);
+    }
+
+    #[tokio::test]
+    async fn ignores_span_fields_which_are_not_labels() {
+        let entries = entries(Loki::new(), || {
+            let span = tracing::info_span!("request", tenant_id = "acme");
+            span.in_scope(|| tracing::info!("served"));
+        })
+        .await;
+        assert!(!entries[0].labels().contains_key("tenant_id"));
+        assert!(!entries[0].line().contains_key("tenant_id"));
+    }
+
+    #[test]
+    fn formats_push_requests() {
+        let entry = Entry {
+            labels: [("level".to_string(), "info".to_string())].into(),
+            timestamp: 1_500,
+            line: [("message".to_string(), Value::from("hi"))]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(
+            entry.to_string(),
+            r#"{"streams":[{"stream":{"level":"info"},"values":[["1500","{\"message\":\"hi\"}"]]}]}"#
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use futures_util::{
+    future::{join_all, BoxFuture},
+    task::AtomicWaker,
+    FutureExt,
+};
+
+use crate::{notify::Notify, FlushError};
+
+/// The requests held back by a [`ServiceLayer`](crate::ServiceLayer), such as those being
+/// aggregated, which are sent to its queue by the [`ResponseStream`](crate::ResponseStream).
+pub(crate) trait Release: Send + Sync {
+    /// Sends the held requests which are due, or all of them if `force` is `true`, to the queue.
+    fn release(&self, force: bool);
+
+    /// When the next held request is due, if any.
+    fn deadline(&self) -> Option<Instant>;
+}
+
+/// The number of recent enqueue times retained, used to estimate the age of the oldest queued
+/// event.
+const ENQUEUED_AT_LEN: usize = 256;
+
+/// The sequence number of the oldest request in an empty queue, see [`LayerMetrics::add_queue`].
+pub(crate) const EMPTY: u64 = u64::MAX;
+
+struct Counters {
+    enqueued: AtomicU64,
+    received: AtomicU64,
+    evicted: AtomicU64,
+    dropped_full: AtomicU64,
+    dropped_closed: AtomicU64,
+    dropped_rate_limited: AtomicU64,
+    dropped_circuit_open: AtomicU64,
+    spooled: AtomicU64,
+    aggregated: AtomicU64,
+    visitor_errors: AtomicU64,
+    service_errors: AtomicU64,
+    created: Instant,
+    // The time each recent event was enqueued, in nanoseconds since `created`, indexed by its
+    // sequence number
+    enqueued_at: [AtomicU64; ENQUEUED_AT_LEN],
+    // The sequence number of the oldest request in each queue counting into these metrics, which
+    // may not be received in the order they were enqueued, see `priority_channel`
+    queues: Mutex<Vec<Arc<AtomicU64>>>,
+    // The flushes requested from the stream, the number of requests enqueued before the most
+    // recent of them, and the most recent flush the stream has completed, see `Handle::flush`
+    flush_requested: AtomicU64,
+    flush_target: AtomicU64,
+    flushed: AtomicU64,
+    flushed_notify: Notify,
+    // The number of streams recording into these metrics, nothing is flushed without one
+    attached: AtomicUsize,
+    stream_waker: AtomicWaker,
+    // The metrics of the queues a sink fans requests out to, see `Tee` and `Keyed`
+    branches: Mutex<Vec<LayerMetrics>>,
+    // Registered by the layer, which holds the only strong reference so it is not kept alive
+    release: Mutex<Option<Weak<dyn Release>>>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            enqueued: AtomicU64::default(),
+            received: AtomicU64::default(),
+            evicted: AtomicU64::default(),
+            dropped_full: AtomicU64::default(),
+            dropped_closed: AtomicU64::default(),
+            dropped_rate_limited: AtomicU64::default(),
+            dropped_circuit_open: AtomicU64::default(),
+            spooled: AtomicU64::default(),
+            aggregated: AtomicU64::default(),
+            visitor_errors: AtomicU64::default(),
+            service_errors: AtomicU64::default(),
+            created: Instant::now(),
+            enqueued_at: std::array::from_fn(|_| AtomicU64::default()),
+            queues: Mutex::default(),
+            flush_requested: AtomicU64::default(),
+            flush_target: AtomicU64::default(),
+            flushed: AtomicU64::default(),
+            flushed_notify: Notify::new(),
+            attached: AtomicUsize::default(),
+            stream_waker: AtomicWaker::new(),
+            branches: Mutex::default(),
+            release: Mutex::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Counters")
+            .field("enqueued", &self.enqueued)
+            .field("received", &self.received)
+            .field("flushed", &self.flushed)
+            .field("attached", &self.attached)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle to the counters shared between a [`ServiceLayer`](crate::ServiceLayer) and its
+/// [`ResponseStream`](crate::ResponseStream).
+///
+/// With the `metrics` feature enabled, these are also emitted using the `metrics` crate:
+///
+/// - `tracing_service_events_enqueued` counter
+/// - `tracing_service_events_dropped` counter, labelled with `reason` (`full`, `closed`,
+///   `rate_limited` or `circuit_open`)
+/// - `tracing_service_events_spooled` counter
+/// - `tracing_service_events_aggregated` counter
+/// - `tracing_service_visitor_errors` counter
+/// - `tracing_service_service_errors` counter
+/// - `tracing_service_queue_depth` gauge
+#[derive(Debug, Clone, Default)]
+pub struct LayerMetrics {
+    counters: Arc<Counters>,
+}
+
+impl LayerMetrics {
+    /// The number of events successfully enqueued.
+    ///
+    /// This includes events later evicted from a [`priority_channel`](crate::priority_channel) to
+    /// admit a more severe event, which also count towards [`LayerMetrics::dropped_full`].
+    pub fn enqueued(&self) -> u64 {
+        self.counters.enqueued.load(Ordering::Relaxed)
+    }
+
+    /// The number of events dropped because the queue was full.
+    pub fn dropped_full(&self) -> u64 {
+        self.counters.dropped_full.load(Ordering::Relaxed)
+    }
+
+    /// The number of events dropped because the queue was closed.
+    pub fn dropped_closed(&self) -> u64 {
+        self.counters.dropped_closed.load(Ordering::Relaxed)
+    }
+
+    /// The number of events dropped because they were rate limited, see
+    /// [`ServiceLayer::with_rate_limit`](crate::ServiceLayer::with_rate_limit).
+    pub fn dropped_rate_limited(&self) -> u64 {
+        self.counters.dropped_rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests dropped because the circuit was open, see
+    /// [`ResponseStream::with_circuit_breaker`](crate::ResponseStream::with_circuit_breaker).
+    pub fn dropped_circuit_open(&self) -> u64 {
+        self.counters.dropped_circuit_open.load(Ordering::Relaxed)
+    }
+
+    /// The total number of events dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped_full()
+            + self.dropped_closed()
+            + self.dropped_rate_limited()
+            + self.dropped_circuit_open()
+    }
+
+    /// The number of requests written to a [`Spool`](crate::Spool).
+    pub fn spooled(&self) -> u64 {
+        self.counters.spooled.load(Ordering::Relaxed)
+    }
+
+    /// The number of duplicate events coalesced into another request, see
+    /// [`ServiceLayer::with_aggregation`](crate::ServiceLayer::with_aggregation).
+    pub fn aggregated(&self) -> u64 {
+        self.counters.aggregated.load(Ordering::Relaxed)
+    }
+
+    /// The number of events whose visitor returned an error, see
+    /// [`EventVisitor`](crate::EventVisitor).
+    pub fn visitor_errors(&self) -> u64 {
+        self.counters.visitor_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of errors returned by the [`Service`](tower::Service).
+    pub fn service_errors(&self) -> u64 {
+        self.counters.service_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of events enqueued which have not yet been received by the
+    /// [`ResponseStream`](crate::ResponseStream).
+    ///
+    /// For a sink which sends to several queues, such as a [`Tee`](crate::Tee), this is the total
+    /// across its queues.
+    pub fn queue_depth(&self) -> u64 {
+        let branches = self.branches();
+        if !branches.is_empty() {
+            return branches.iter().map(LayerMetrics::queue_depth).sum();
+        }
+        let left = self.received() + self.counters.evicted.load(Ordering::Relaxed);
+        self.enqueued().saturating_sub(left)
+    }
+
+    /// The approximate age of the oldest event which is enqueued but has not yet been received.
+    ///
+    /// While more than 256 events are queued this is the age of the 256th most recent.
+    pub(crate) fn oldest_queued_age(&self) -> Option<Duration> {
+        let oldest = self.oldest_queued()?;
+        let oldest = oldest.max(self.enqueued().saturating_sub(ENQUEUED_AT_LEN as u64));
+        let enqueued_at =
+            self.counters.enqueued_at[oldest as usize % ENQUEUED_AT_LEN].load(Ordering::Relaxed);
+        let now = self.counters.created.elapsed();
+        Some(now.saturating_sub(Duration::from_nanos(enqueued_at)))
+    }
+
+    /// The sequence number of the oldest request which is enqueued but has not yet been received.
+    ///
+    /// Queues which track these metrics report their oldest request, see
+    /// [`LayerMetrics::add_queue`], otherwise requests are assumed to be received in the order
+    /// they were enqueued.
+    fn oldest_queued(&self) -> Option<u64> {
+        let queues = self.lock_queues();
+        if !queues.is_empty() {
+            let oldest = queues
+                .iter()
+                .map(|queue| queue.load(Ordering::Acquire))
+                .min()
+                .unwrap_or(EMPTY);
+            return Some(oldest).filter(|oldest| *oldest != EMPTY);
+        }
+        let received = self.received();
+        Some(received).filter(|received| *received < self.enqueued())
+    }
+
+    /// Records that the [`ServiceLayer`](crate::ServiceLayer) enqueued an event, which is counted
+    /// by [`LayerMetrics::record_sent`] if the sink tracks these metrics itself.
+    pub(crate) fn record_enqueued(&self) {
+        #[cfg(feature = "metrics")]
+        {
+            ::metrics::counter!("tracing_service_events_enqueued").increment(1);
+            ::metrics::gauge!("tracing_service_queue_depth").set(self.queue_depth() as f64);
+        }
+    }
+
+    /// Counts a request accepted by the queue these metrics belong to, returning its sequence
+    /// number.
+    pub(crate) fn record_sent(&self) -> u64 {
+        let sequence = self.counters.enqueued.fetch_add(1, Ordering::AcqRel);
+        let now = self.counters.created.elapsed().as_nanos();
+        self.counters.enqueued_at[sequence as usize % ENQUEUED_AT_LEN]
+            .store(now.try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+        sequence
+    }
+
+    /// Counts a request evicted from the queue to admit another, see
+    /// [`priority_channel`](crate::priority_channel).
+    pub(crate) fn record_evicted(&self) {
+        self.counters.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Registers a queue counting into these metrics, returning the sequence number of its oldest
+    /// request, which the queue keeps up to date and sets to [`EMPTY`] while it is empty.
+    pub(crate) fn add_queue(&self) -> Arc<AtomicU64> {
+        let oldest = Arc::new(AtomicU64::new(EMPTY));
+        self.lock_queues().push(oldest.clone());
+        oldest
+    }
+
+    fn lock_queues(&self) -> MutexGuard<'_, Vec<Arc<AtomicU64>>> {
+        self.counters
+            .queues
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The number of events received by the [`ResponseStream`](crate::ResponseStream).
+    pub(crate) fn received(&self) -> u64 {
+        self.counters.received.load(Ordering::Relaxed)
+    }
+
+    /// Records that an event was received, returning the number received before it.
+    pub(crate) fn record_received(&self) -> u64 {
+        let received = self.counters.received.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::gauge!("tracing_service_queue_depth").set(self.queue_depth() as f64);
+
+        received
+    }
+
+    pub(crate) fn record_dropped_full(&self) {
+        self.counters.dropped_full.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_dropped", "reason" => "full").increment(1);
+    }
+
+    pub(crate) fn record_dropped_closed(&self) {
+        self.counters.dropped_closed.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_dropped", "reason" => "closed").increment(1);
+    }
+
+    pub(crate) fn record_dropped_rate_limited(&self) {
+        self.counters
+            .dropped_rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_dropped", "reason" => "rate_limited")
+            .increment(1);
+    }
+
+    pub(crate) fn record_dropped_circuit_open(&self) {
+        self.counters
+            .dropped_circuit_open
+            .fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_dropped", "reason" => "circuit_open")
+            .increment(1);
+    }
+
+    pub(crate) fn record_spooled(&self) {
+        self.counters.spooled.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_spooled").increment(1);
+    }
+
+    pub(crate) fn record_aggregated(&self) {
+        self.counters.aggregated.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_events_aggregated").increment(1);
+    }
+
+    pub(crate) fn record_visitor_error(&self) {
+        self.counters.visitor_errors.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_visitor_errors").increment(1);
+    }
+
+    pub(crate) fn record_service_error(&self) {
+        self.counters.service_errors.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("tracing_service_service_errors").increment(1);
+    }
+
+    /// Adds the metrics of a queue the sink sends to, which are flushed along with these.
+    pub(crate) fn add_branch(&self, branch: LayerMetrics) {
+        if let Some(release) = self.release() {
+            branch.set_release(&release);
+        }
+        self.lock_branches().push(branch);
+    }
+
+    /// Removes the metrics of a queue the sink no longer sends to.
+    pub(crate) fn remove_branch(&self, branch: &LayerMetrics) {
+        self.lock_branches()
+            .retain(|existing| !Arc::ptr_eq(&existing.counters, &branch.counters));
+    }
+
+    fn lock_branches(&self) -> MutexGuard<'_, Vec<LayerMetrics>> {
+        self.counters
+            .branches
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn branches(&self) -> Vec<LayerMetrics> {
+        self.lock_branches().clone()
+    }
+
+    /// Registers the layer's [`Release`], which is also used by the streams of any branches.
+    pub(crate) fn set_release(&self, release: &Arc<dyn Release>) {
+        *self
+            .counters
+            .release
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Arc::downgrade(release));
+        for branch in self.branches() {
+            branch.set_release(release);
+        }
+    }
+
+    fn release(&self) -> Option<Arc<dyn Release>> {
+        self.counters
+            .release
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .and_then(Weak::upgrade)
+    }
+
+    /// Sends the requests held back by the layer to the queue, see [`Release`].
+    pub(crate) fn release_held(&self, force: bool) {
+        if let Some(release) = self.release() {
+            release.release(force);
+        }
+    }
+
+    /// When the next request held back by the layer is due, see [`Release`].
+    pub(crate) fn release_deadline(&self) -> Option<Instant> {
+        self.release().and_then(|release| release.deadline())
+    }
+
+    /// Registers the task driving the [`ResponseStream`](crate::ResponseStream), which is woken
+    /// when a request is held back by the layer, or a flush is requested.
+    pub(crate) fn register_stream(&self, waker: &Waker) {
+        self.counters.stream_waker.register(waker);
+    }
+
+    /// Wakes the [`ResponseStream`](crate::ResponseStream), and those of any branches, so they
+    /// observe a new [`Release::deadline`].
+    pub(crate) fn wake_streams(&self) {
+        self.counters.stream_waker.wake();
+        for branch in self.branches() {
+            branch.wake_streams();
+        }
+    }
+
+    fn is_attached(&self) -> bool {
+        self.counters.attached.load(Ordering::Acquire) > 0
+    }
+
+    /// The most recent flush requested from the [`ResponseStream`](crate::ResponseStream), along
+    /// with the number of requests enqueued before it was requested.
+    pub(crate) fn flush_requested(&self) -> (u64, u64) {
+        let generation = self.counters.flush_requested.load(Ordering::Acquire);
+        let target = self.counters.flush_target.load(Ordering::Acquire);
+        (generation, target)
+    }
+
+    /// Returns `true` once every request enqueued before the first `target` has been received,
+    /// in whichever order the queue delivers them.
+    pub(crate) fn received_before(&self, target: u64) -> bool {
+        self.oldest_queued().map_or(true, |oldest| oldest >= target)
+    }
+
+    /// Records that the [`ResponseStream`](crate::ResponseStream) has completed the flushes
+    /// requested up to `flushed`.
+    pub(crate) fn record_flushed(&self, flushed: u64) {
+        let previous = self.counters.flushed.fetch_max(flushed, Ordering::AcqRel);
+        if flushed > previous {
+            self.counters.flushed_notify.notify_waiters();
+        }
+    }
+
+    /// Waits for the events enqueued before this is called to be sent, see
+    /// [`Handle::flush`](crate::Handle::flush).
+    pub(crate) async fn flush(&self) -> Result<(), FlushError> {
+        self.release_held(true);
+        self.flush_queues().await
+    }
+
+    /// Waits for the stream of this queue, or of each branch, to complete the events enqueued
+    /// before this is called and then flush its [`Service`](tower::Service).
+    fn flush_queues(&self) -> BoxFuture<'_, Result<(), FlushError>> {
+        let branches = self.branches();
+        if !branches.is_empty() {
+            return async move {
+                let flushed = join_all(branches.iter().map(LayerMetrics::flush_queues)).await;
+                flushed.into_iter().collect()
+            }
+            .boxed();
+        }
+        async move {
+            // Nothing records the completion of events in a queue without a stream
+            if !self.is_attached() {
+                return Err(FlushError::new());
+            }
+            let target = self.counters.enqueued.load(Ordering::Acquire);
+            self.counters
+                .flush_target
+                .fetch_max(target, Ordering::AcqRel);
+            let generation = self.counters.flush_requested.fetch_add(1, Ordering::AcqRel) + 1;
+            self.counters.stream_waker.wake();
+            loop {
+                let notified = self.counters.flushed_notify.notified();
+                if self.counters.flushed.load(Ordering::Acquire) >= generation {
+                    return Ok(());
+                }
+                if !self.is_attached() {
+                    return Err(FlushError::new());
+                }
+                notified.await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Marks [`LayerMetrics`] as being recorded by a [`ResponseStream`](crate::ResponseStream) until
+/// it is dropped, so [`Handle::flush`](crate::Handle::flush) fails rather than waiting forever.
+#[derive(Debug)]
+pub(crate) struct Attached(LayerMetrics);
+
+impl Attached {
+    pub(crate) fn new(metrics: LayerMetrics) -> Self {
+        metrics.counters.attached.fetch_add(1, Ordering::AcqRel);
+        Self(metrics)
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.0.counters.attached.fetch_sub(1, Ordering::AcqRel);
+        self.0.counters.flushed_notify.notify_waiters();
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::SystemTime;
+
+use crate::Timestamped;
+
+/// Constructs the `Request` which each event is recorded into.
+///
+/// This is implemented for closures of the form `Fn() -> Request`, see
+/// [`ServiceLayer::with_request_factory`](crate::ServiceLayer::with_request_factory).
+pub trait NewRequest<Request> {
+    /// Constructs a new `Request`.
+    fn new_request(&self) -> Request;
+
+    /// Constructs a new `Request` for an event recorded at `timestamp`.
+    ///
+    /// The timestamp is captured as the event is recorded, rather than when the request is sent,
+    /// so it is unaffected by time spent in the queue. It defaults to [`NewRequest::new_request`].
+    fn new_request_at(&self, timestamp: SystemTime) -> Request {
+        let _ = timestamp;
+        self.new_request()
+    }
+}
+
+impl<Request, F> NewRequest<Request> for F
+where
+    F: Fn() -> Request,
+{
+    fn new_request(&self) -> Request {
+        self()
+    }
+}
+
+/// A [`NewRequest`] which constructs requests using their [`Default`] implementation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRequest;
+
+impl<Request> NewRequest<Request> for DefaultRequest
+where
+    Request: Default,
+{
+    fn new_request(&self) -> Request {
+        Request::default()
+    }
+}
+
+impl<Request> NewRequest<Timestamped<Request>> for DefaultRequest
+where
+    Request: Default,
+{
+    fn new_request(&self) -> Timestamped<Request> {
+        self.new_request_at(SystemTime::now())
+    }
+
+    fn new_request_at(&self, timestamp: SystemTime) -> Timestamped<Request> {
+        Timestamped::new(timestamp, Request::default())
+    }
+}
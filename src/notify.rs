@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+/// Wakes every task waiting on it, without depending on an async runtime.
+///
+/// A [`Notified`] future completes once [`Notify::notify_waiters`] is called after it was
+/// constructed, so a condition can be checked between constructing and awaiting it without
+/// missing a notification.
+#[derive(Debug, Default)]
+pub(crate) struct Notify {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    generation: u64,
+    next_id: u64,
+    wakers: HashMap<u64, Waker>,
+}
+
+impl Notify {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Constructs a future which completes once [`Notify::notify_waiters`] is next called.
+    pub(crate) fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            generation: self.lock().generation,
+            id: None,
+        }
+    }
+
+    /// Wakes every [`Notified`] future constructed before this call.
+    pub(crate) fn notify_waiters(&self) {
+        let wakers = {
+            let mut state = self.lock();
+            state.generation += 1;
+            std::mem::take(&mut state.wakers)
+        };
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by [`Notify::notified`].
+#[derive(Debug)]
+pub(crate) struct Notified<'a> {
+    notify: &'a Notify,
+    generation: u64,
+    // The key of the waker registered with the `Notify`, if any
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut state = this.notify.lock();
+        if state.generation != this.generation {
+            this.id = None;
+            return Poll::Ready(());
+        }
+        let id = *this.id.get_or_insert_with(|| {
+            state.next_id += 1;
+            state.next_id
+        });
+        state.wakers.insert(id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.notify.lock().wakers.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::Wake,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll(notified: Pin<&mut Notified<'_>>, waker: &Arc<CountingWaker>) -> Poll<()> {
+        let waker = Waker::from(waker.clone());
+        notified.poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn wakes_every_waiter() {
+        let notify = Notify::default();
+        let (first, second) = (Arc::<CountingWaker>::default(), Arc::default());
+        let mut first_notified = pin!(notify.notified());
+        let mut second_notified = pin!(notify.notified());
+        assert_eq!(poll(first_notified.as_mut(), &first), Poll::Pending);
+        assert_eq!(poll(second_notified.as_mut(), &second), Poll::Pending);
+
+        notify.notify_waiters();
+        assert_eq!(first.0.load(Ordering::SeqCst), 1);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+        assert_eq!(poll(first_notified, &first), Poll::Ready(()));
+        assert_eq!(poll(second_notified, &second), Poll::Ready(()));
+    }
+
+    #[test]
+    fn completes_if_notified_before_being_polled() {
+        let notify = Notify::default();
+        let waker = Arc::default();
+        let notified = pin!(notify.notified());
+
+        // The notification is not missed between constructing and polling the future
+        notify.notify_waiters();
+        assert_eq!(poll(notified, &waker), Poll::Ready(()));
+    }
+
+    #[test]
+    fn ignores_earlier_notifications() {
+        let notify = Notify::default();
+        let waker = Arc::default();
+        notify.notify_waiters();
+
+        let notified = pin!(notify.notified());
+        assert_eq!(poll(notified, &waker), Poll::Pending);
+    }
+
+    #[test]
+    fn only_registers_one_waker_per_future() {
+        let notify = Notify::default();
+        let waker = Arc::<CountingWaker>::default();
+        let mut notified = pin!(notify.notified());
+        assert_eq!(poll(notified.as_mut(), &waker), Poll::Pending);
+        assert_eq!(poll(notified.as_mut(), &waker), Poll::Pending);
+        assert_eq!(notify.lock().wakers.len(), 1);
+
+        notify.notify_waiters();
+        assert_eq!(waker.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn removes_the_waker_when_dropped() {
+        let notify = Notify::default();
+        let waker = Arc::<CountingWaker>::default();
+        {
+            let notified = pin!(notify.notified());
+            assert_eq!(poll(notified, &waker), Poll::Pending);
+        }
+        assert!(notify.lock().wakers.is_empty());
+
+        notify.notify_waiters();
+        assert_eq!(waker.0.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,433 @@
+//! An adapter which maps events onto OpenTelemetry [`LogRecord`]s and sends them as
+//! [`ExportLogsServiceRequest`]s.
+//!
+//! The messages are those generated by [`opentelemetry_proto`], so the [`Service`] can be a `tonic`
+//! gRPC client or an OTLP/HTTP client encoding them with `prost`.
+//!
+//! ```no_run
+//! use opentelemetry_proto::tonic::collector::logs::v1::{
+//!     ExportLogsServiceRequest, ExportLogsServiceResponse,
+//! };
+//! use tracing_service::otlp;
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! let client = tower::service_fn(|request: ExportLogsServiceRequest| async move {
+//!     // Send the request to the collector
+//!     Ok::<_, std::io::Error>(ExportLogsServiceResponse::default())
+//! });
+//! let (layer, responses) = otlp::layer(client);
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub use opentelemetry_proto::tonic::{
+    collector::logs::v1::ExportLogsServiceRequest,
+    common::v1::InstrumentationScope,
+    logs::v1::{LogRecord, SeverityNumber},
+    resource::v1::Resource,
+};
+
+use opentelemetry_proto::tonic::{
+    common::v1::{any_value, AnyValue, KeyValue},
+    logs::v1::{ResourceLogs, ScopeLogs},
+};
+use tower::{Layer, Service};
+use tracing_core::{
+    field::{Field, Visit},
+    Level, Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{ResponseStream, ServiceLayer};
+
+/// Constructs a [`ServiceLayer`] which sends each event to `service` as an
+/// [`ExportLogsServiceRequest`].
+pub fn layer<Svc>(
+    service: Svc,
+) -> (
+    ServiceLayer<LogRecord, Otlp>,
+    ResponseStream<LogRecord, ExportLogs<Svc>>,
+)
+where
+    Svc: Service<ExportLogsServiceRequest>,
+{
+    let (layer, responses) = ServiceLayer::new(ExportLogs::new(service), Otlp);
+    (layer.with_metadata(record_metadata), responses)
+}
+
+/// Records the severity, timestamp, target and source location of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(record: &mut LogRecord, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    let timestamp = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    record.time_unix_nano = timestamp;
+    record.observed_time_unix_nano = timestamp;
+    record.severity_number = severity_number(metadata.level()) as i32;
+    record.severity_text = metadata.level().as_str().into();
+
+    record
+        .attributes
+        .push(key_value("target", string_value(metadata.target())));
+    if let Some(file) = metadata.file() {
+        record
+            .attributes
+            .push(key_value("code.file.path", string_value(file)));
+    }
+    if let Some(line) = metadata.line() {
+        record.attributes.push(key_value(
+            "code.line.number",
+            any_value::Value::IntValue(line.into()),
+        ));
+    }
+}
+
+fn severity_number(level: &Level) -> SeverityNumber {
+    match *level {
+        Level::TRACE => SeverityNumber::Trace,
+        Level::DEBUG => SeverityNumber::Debug,
+        Level::INFO => SeverityNumber::Info,
+        Level::WARN => SeverityNumber::Warn,
+        Level::ERROR => SeverityNumber::Error,
+    }
+}
+
+fn string_value(value: impl Into<String>) -> any_value::Value {
+    any_value::Value::StringValue(value.into())
+}
+
+fn key_value(key: impl Into<String>, value: any_value::Value) -> KeyValue {
+    KeyValue {
+        key: key.into(),
+        value: Some(AnyValue { value: Some(value) }),
+        ..Default::default()
+    }
+}
+
+/// Parses a hex encoded identifier of `N` bytes.
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into a [`LogRecord`].
+///
+/// The `message` field is recorded as the body, hex encoded `trace_id` and `span_id` fields are
+/// recorded as the trace context, and all other fields are recorded as attributes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Otlp;
+
+impl<'a> field::MakeVisitor<&'a mut LogRecord> for Otlp {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, record: &'a mut LogRecord) -> Self::Visitor {
+        Visitor { record }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Otlp`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    record: &'a mut LogRecord,
+}
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: any_value::Value) {
+        match field.name() {
+            "message" => {
+                self.record.body = Some(AnyValue { value: Some(value) });
+            }
+            name => self.record.attributes.push(key_value(name, value)),
+        }
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, any_value::Value::DoubleValue(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, any_value::Value::IntValue(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "span_id" => self.record.span_id = value.to_be_bytes().into(),
+            _ => match i64::try_from(value) {
+                Ok(value) => self.insert(field, any_value::Value::IntValue(value)),
+                Err(_) => self.insert(field, string_value(value.to_string())),
+            },
+        }
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        match field.name() {
+            "trace_id" => self.record.trace_id = value.to_be_bytes().into(),
+            _ => self.insert(field, string_value(value.to_string())),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, any_value::Value::BoolValue(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "trace_id" => match parse_hex::<16>(value) {
+                Some(trace_id) => self.record.trace_id = trace_id.into(),
+                None => self.insert(field, string_value(value)),
+            },
+            "span_id" => match parse_hex::<8>(value) {
+                Some(span_id) => self.record.span_id = span_id.into(),
+                None => self.insert(field, string_value(value)),
+            },
+            _ => self.insert(field, string_value(value)),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+/// A [`Service`] which wraps each [`LogRecord`] in an [`ExportLogsServiceRequest`] before passing
+/// it to the inner [`Service`].
+#[derive(Debug, Clone)]
+pub struct ExportLogs<Svc> {
+    inner: Svc,
+    resource: Option<Resource>,
+    scope: Option<InstrumentationScope>,
+}
+
+impl<Svc> ExportLogs<Svc> {
+    /// Constructs an `ExportLogs` without a [`Resource`] or [`InstrumentationScope`].
+    pub fn new(inner: Svc) -> Self {
+        Self {
+            inner,
+            resource: None,
+            scope: None,
+        }
+    }
+
+    /// Attaches a [`Resource`], describing the source of the logs, to each request.
+    pub fn with_resource(mut self, resource: Resource) -> Self {
+        self.resource = Some(resource);
+        self
+    }
+
+    /// Attaches an [`InstrumentationScope`] to each request.
+    pub fn with_scope(mut self, scope: InstrumentationScope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Wraps a single [`LogRecord`] in an [`ExportLogsServiceRequest`].
+    fn export_request(&self, record: LogRecord) -> ExportLogsServiceRequest {
+        ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: self.resource.clone(),
+                scope_logs: vec![ScopeLogs {
+                    scope: self.scope.clone(),
+                    log_records: vec![record],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+impl<Svc> Service<LogRecord> for ExportLogs<Svc>
+where
+    Svc: Service<ExportLogsServiceRequest>,
+{
+    type Response = Svc::Response;
+    type Error = Svc::Error;
+    type Future = Svc::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, record: LogRecord) -> Self::Future {
+        let request = self.export_request(record);
+        self.inner.call(request)
+    }
+}
+
+/// A [`Layer`] which wraps a [`Service`] in [`ExportLogs`].
+#[derive(Debug, Clone, Default)]
+pub struct ExportLogsLayer {
+    resource: Option<Resource>,
+    scope: Option<InstrumentationScope>,
+}
+
+impl ExportLogsLayer {
+    /// Constructs an `ExportLogsLayer` without a [`Resource`] or [`InstrumentationScope`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`ExportLogs::with_resource`].
+    pub fn with_resource(mut self, resource: Resource) -> Self {
+        self.resource = Some(resource);
+        self
+    }
+
+    /// See [`ExportLogs::with_scope`].
+    pub fn with_scope(mut self, scope: InstrumentationScope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+}
+
+impl<Svc> Layer<Svc> for ExportLogsLayer {
+    type Service = ExportLogs<Svc>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        ExportLogs {
+            inner,
+            resource: self.resource.clone(),
+            scope: self.scope.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_util::StreamExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    /// Returns the requests constructed from the events emitted by `f`.
+    async fn requests(f: impl FnOnce()) -> Vec<ExportLogsServiceRequest> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let service = tower::service_fn(move |request: ExportLogsServiceRequest| {
+            recorded.lock().unwrap().push(request);
+            future::ready(Ok::<_, fmt::Error>(()))
+        });
+        let (layer, responses) = layer(service);
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+        let requests = requests.lock().unwrap().clone();
+        requests
+    }
+
+    fn attribute<'a>(record: &'a LogRecord, key: &str) -> Option<&'a any_value::Value> {
+        record
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .and_then(|attribute| attribute.value.as_ref()?.value.as_ref())
+    }
+
+    #[tokio::test]
+    async fn records_events() {
+        let requests = requests(|| {
+            tracing::warn!(
+                trace_id = "0102030405060708090a0b0c0d0e0f10",
+                span_id = "0102030405060708",
+                attempt = 3,
+                "retrying"
+            );
+        })
+        .await;
+        let record = &requests[0].resource_logs[0].scope_logs[0].log_records[0];
+        assert_eq!(record.trace_id, (1..=16).collect::<Vec<u8>>());
+        assert_eq!(record.span_id, (1..=8).collect::<Vec<u8>>());
+        assert_eq!(
+            record.body.as_ref().unwrap().value,
+            Some(string_value("retrying"))
+        );
+        assert_eq!(
+            attribute(record, "attempt"),
+            Some(&any_value::Value::IntValue(3))
+        );
+        assert_eq!(record.severity_number, SeverityNumber::Warn as i32);
+        assert_eq!(record.severity_text, "WARN");
+        assert_eq!(
+            attribute(record, "target"),
+            Some(&string_value("tracing_service::otlp::tests"))
+        );
+        assert!(record.time_unix_nano > 0);
+    }
+
+    #[tokio::test]
+    async fn records_invalid_trace_context_as_attributes() {
+        let requests = requests(|| {
+            tracing::info!(trace_id = "not hex", span_id = 7_u64, large = u64::MAX);
+        })
+        .await;
+        let record = &requests[0].resource_logs[0].scope_logs[0].log_records[0];
+        assert!(record.trace_id.is_empty());
+        assert_eq!(
+            attribute(record, "trace_id"),
+            Some(&string_value("not hex"))
+        );
+        assert_eq!(record.span_id, 7_u64.to_be_bytes());
+        assert_eq!(
+            attribute(record, "large"),
+            Some(&string_value(u64::MAX.to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(parse_hex::<2>("0aff"), Some([0x0a, 0xff]));
+        assert_eq!(parse_hex::<2>("0af"), None);
+        assert_eq!(parse_hex::<2>("0afg"), None);
+    }
+
+    #[test]
+    fn export_logs_attaches_the_resource_and_scope() {
+        let resource = Resource {
+            attributes: vec![key_value("service.name", string_value("api"))],
+            ..Default::default()
+        };
+        let scope = InstrumentationScope {
+            name: "scope".into(),
+            ..Default::default()
+        };
+        let export_logs = ExportLogsLayer::new()
+            .with_resource(resource.clone())
+            .with_scope(scope.clone())
+            .layer(());
+        let request = export_logs.export_request(LogRecord::default());
+        assert_eq!(request.resource_logs[0].resource, Some(resource));
+        assert_eq!(request.resource_logs[0].scope_logs[0].scope, Some(scope));
+        assert_eq!(request.resource_logs[0].scope_logs[0].log_records.len(), 1);
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::{NewRequest, Timestamped};
+
+/// Clears a `Request` so that it can be reused, see [`Pool`].
+///
+/// Implementations should retain any allocated capacity, such as that of a [`String`], as this is
+/// what makes reuse worthwhile.
+pub trait Reset {
+    /// Clears the request, returning it to the state of a newly constructed request.
+    fn reset(&mut self);
+}
+
+impl Reset for String {
+    fn reset(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T> Reset for Vec<T> {
+    fn reset(&mut self) {
+        self.clear();
+    }
+}
+
+/// A pool of reusable requests, which avoids allocating a new `Request` for each event.
+///
+/// A `Pool` is a [`NewRequest`] which checks out [`Pooled`] requests. Once the [`Service`] has
+/// finished with a [`Pooled`] request and dropped it, it is [`Reset`] and returned to the pool.
+/// Requests are constructed using their [`Default`] implementation while the pool is empty.
+///
+/// ```
+/// # use tracing_service::{Pool, Pooled, ServiceLayer};
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut Pooled<String>) -> JsonVisitor<'_> { JsonVisitor::new(&mut **value) }
+/// let service = tower::service_fn(|request: Pooled<String>| async move {
+///     println!("{}", *request);
+///     Ok::<_, ()>(())
+/// });
+/// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+/// let layer = layer.with_request_factory(Pool::new(64));
+/// ```
+///
+/// [`Service`]: tower::Service
+pub struct Pool<Request> {
+    inner: Arc<Inner<Request>>,
+}
+
+struct Inner<Request> {
+    capacity: usize,
+    free: Mutex<Vec<Request>>,
+}
+
+impl<Request> Pool<Request> {
+    /// Constructs an empty `Pool` which retains up to `capacity` requests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                capacity,
+                free: Mutex::new(Vec::with_capacity(capacity)),
+            }),
+        }
+    }
+
+    /// Returns the number of requests waiting in the pool.
+    pub fn len(&self) -> usize {
+        self.inner.free().len()
+    }
+
+    /// Returns `true` if there are no requests waiting in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<Request> Pool<Request>
+where
+    Request: Reset + Default,
+{
+    /// Checks out a request, constructing a new one if the pool is empty.
+    pub fn get(&self) -> Pooled<Request> {
+        let request = self.inner.free().pop().unwrap_or_default();
+        Pooled {
+            request: Some(request),
+            pool: Some(self.inner.clone()),
+        }
+    }
+}
+
+impl<Request> Inner<Request> {
+    fn free(&self) -> MutexGuard<'_, Vec<Request>> {
+        self.free.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<Request> Clone for Pool<Request> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<Request> fmt::Debug for Pool<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("capacity", &self.inner.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<Request> NewRequest<Pooled<Request>> for Pool<Request>
+where
+    Request: Reset + Default,
+{
+    fn new_request(&self) -> Pooled<Request> {
+        self.get()
+    }
+}
+
+impl<Request> NewRequest<Timestamped<Pooled<Request>>> for Pool<Request>
+where
+    Request: Reset + Default,
+{
+    fn new_request(&self) -> Timestamped<Pooled<Request>> {
+        self.new_request_at(SystemTime::now())
+    }
+
+    fn new_request_at(&self, timestamp: SystemTime) -> Timestamped<Pooled<Request>> {
+        Timestamped::new(timestamp, self.get())
+    }
+}
+
+/// A request checked out of a [`Pool`], which is returned to the pool when dropped.
+///
+/// This dereferences to the underlying `Request`, so visitors and hooks written for `Request` can
+/// be used through it.
+pub struct Pooled<Request: Reset> {
+    request: Option<Request>,
+    pool: Option<Arc<Inner<Request>>>,
+}
+
+impl<Request> Pooled<Request>
+where
+    Request: Reset,
+{
+    /// Wraps a request which does not belong to a pool, and is dropped as normal.
+    pub fn detached(request: Request) -> Self {
+        Self {
+            request: Some(request),
+            pool: None,
+        }
+    }
+
+    /// Consumes the `Pooled`, returning the underlying request rather than returning it to the
+    /// pool.
+    pub fn into_inner(mut self) -> Request {
+        self.pool = None;
+        self.request
+            .take()
+            .expect("request is present until dropped")
+    }
+}
+
+impl<Request> Deref for Pooled<Request>
+where
+    Request: Reset,
+{
+    type Target = Request;
+
+    fn deref(&self) -> &Request {
+        self.request
+            .as_ref()
+            .expect("request is present until dropped")
+    }
+}
+
+impl<Request> DerefMut for Pooled<Request>
+where
+    Request: Reset,
+{
+    fn deref_mut(&mut self) -> &mut Request {
+        self.request
+            .as_mut()
+            .expect("request is present until dropped")
+    }
+}
+
+impl<Request> Drop for Pooled<Request>
+where
+    Request: Reset,
+{
+    fn drop(&mut self) {
+        let (Some(pool), Some(mut request)) = (self.pool.take(), self.request.take()) else {
+            return;
+        };
+        let mut free = pool.free();
+        if free.len() < pool.capacity {
+            request.reset();
+            free.push(request);
+        }
+    }
+}
+
+impl<Request> Default for Pooled<Request>
+where
+    Request: Reset + Default,
+{
+    fn default() -> Self {
+        Self::detached(Request::default())
+    }
+}
+
+impl<Request> fmt::Debug for Pooled<Request>
+where
+    Request: Reset + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<Request> fmt::Display for Pooled<Request>
+where
+    Request: Reset + fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_requests_once_they_are_dropped() {
+        let pool = Pool::<String>::new(2);
+        let mut request = pool.get();
+        request.push_str("event");
+        let capacity = request.capacity();
+        drop(request);
+        assert_eq!(pool.len(), 1);
+
+        let request = pool.get();
+        assert!(request.is_empty());
+        assert_eq!(request.capacity(), capacity);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn retains_at_most_capacity_requests() {
+        let pool = Pool::<String>::new(1);
+        let requests = [pool.get(), pool.get()];
+        drop(requests);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn detached_and_unwrapped_requests_are_not_returned() {
+        let pool = Pool::<String>::new(2);
+        drop(Pooled::detached(String::from("detached")));
+        assert_eq!(pool.get().into_inner(), "");
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn timestamps_pooled_requests() {
+        let pool = Pool::<String>::new(1);
+        let timestamp = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        let mut request: Timestamped<Pooled<String>> = pool.new_request_at(timestamp);
+        assert_eq!(request.timestamp(), timestamp);
+        request.push_str("event");
+        drop(request);
+        assert_eq!(pool.len(), 1);
+
+        let request: Timestamped<Pooled<String>> = pool.new_request();
+        assert!(request.timestamp() > timestamp);
+        assert!(request.is_empty());
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use std::task::{Context, Poll};
+
+use tracing_core::Level;
+
+use crate::{
+    channel::{self, Receiver, Sender},
+    EventSink, EventSource, LayerMetrics, TrySendError,
+};
+
+/// Constructs a bounded queue, of capacity `buffer`, in which `reserved` slots are only used by
+/// events at or above `threshold`.
+///
+/// Low-severity events are dropped once `buffer - reserved` of them are queued, so a burst of
+/// `TRACE` events cannot prevent an `ERROR` from being queued. High-severity events are received
+/// ahead of any low-severity events queued before them.
+///
+/// High-severity events overflow into the unreserved slots once the reserved slots are full. If
+/// those are full too, the oldest queued low-severity event is evicted to admit the high-severity
+/// event, see [`PrioritySender::try_send_with_level`]. High-severity events are only dropped once
+/// the whole queue holds high-severity events.
+///
+/// Requests which are not sent from an event, such as rate limit summaries, use the most severe
+/// level of the events they summarize.
+///
+/// # Panics
+///
+/// Panics if `reserved` is zero or greater than `buffer`.
+pub fn priority_channel<Request>(
+    buffer: usize,
+    reserved: usize,
+    threshold: Level,
+) -> (PrioritySender<Request>, PriorityReceiver<Request>) {
+    assert!(
+        0 < reserved && reserved <= buffer,
+        "reserved must be greater than zero and at most buffer"
+    );
+    // Both queues count into the same metrics, which track the oldest request of each as they
+    // are not received in the order they were sent
+    let metrics = LayerMetrics::default();
+    let (high_sender, high_receiver) = channel::with_metrics(Some(reserved), metrics.clone());
+    let (low_sender, low_receiver) = match buffer - reserved {
+        0 => (None, None),
+        low => {
+            let (sender, receiver) = channel::with_metrics(Some(low), metrics);
+            (Some(sender), Some(receiver))
+        }
+    };
+    let sender = PrioritySender {
+        high: high_sender,
+        low: low_sender,
+        threshold,
+    };
+    let receiver = PriorityReceiver {
+        high: high_receiver,
+        low: low_receiver,
+    };
+    (sender, receiver)
+}
+
+/// A request queued in the unreserved slots of a [`priority_channel`].
+#[derive(Debug)]
+struct Unreserved<Request> {
+    request: Request,
+    // Whether the request overflowed from the reserved slots, so must not be evicted
+    high: bool,
+}
+
+/// The sending half of a [`priority_channel`].
+#[derive(Debug)]
+pub struct PrioritySender<Request> {
+    high: Sender<Request>,
+    low: Option<Sender<Unreserved<Request>>>,
+    threshold: Level,
+}
+
+impl<Request> Clone for PrioritySender<Request> {
+    fn clone(&self) -> Self {
+        Self {
+            high: self.high.clone(),
+            low: self.low.clone(),
+            threshold: self.threshold,
+        }
+    }
+}
+
+impl<Request> EventSink<Request> for PrioritySender<Request> {
+    /// Sends the request as a low-severity event.
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        match &self.low {
+            Some(low) => low
+                .try_send(Unreserved {
+                    request,
+                    high: false,
+                })
+                .map_err(|err| err.map(|unreserved| unreserved.request)),
+            None => Err(TrySendError::Full(request)),
+        }
+    }
+
+    /// Sends high-severity events to the reserved slots, overflowing into the unreserved slots
+    /// once they are full.
+    ///
+    /// If the unreserved slots are full too, the oldest low-severity event queued is evicted to
+    /// admit the high-severity event. The evicted request is returned in [`TrySendError::Full`],
+    /// in place of the one sent, so the layer spools or counts it as the request which overflowed
+    /// the queue.
+    fn try_send_with_level(
+        &self,
+        request: Request,
+        level: &Level,
+    ) -> Result<(), TrySendError<Request>> {
+        if *level > self.threshold {
+            return self.try_send(request);
+        }
+        let request = match self.high.try_send(request) {
+            Err(TrySendError::Full(request)) => request,
+            result => return result,
+        };
+        let Some(low) = &self.low else {
+            return Err(TrySendError::Full(request));
+        };
+        let unreserved = Unreserved {
+            request,
+            high: true,
+        };
+        match low.try_send_evicting(unreserved, |queued| !queued.high) {
+            Ok(None) => Ok(()),
+            Ok(Some(evicted)) => Err(TrySendError::Full(evicted.request)),
+            Err(err) => Err(err.map(|unreserved| unreserved.request)),
+        }
+    }
+
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        EventSink::metrics(&self.high)
+    }
+}
+
+/// The receiving half of a [`priority_channel`], which receives high-severity events first.
+#[derive(Debug)]
+pub struct PriorityReceiver<Request> {
+    high: Receiver<Request>,
+    low: Option<Receiver<Unreserved<Request>>>,
+}
+
+impl<Request> EventSource<Request> for PriorityReceiver<Request> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        let high = self.high.poll_recv(cx);
+        if let Poll::Ready(Some(request)) = high {
+            return Poll::Ready(Some(request));
+        }
+        let low = match &mut self.low {
+            Some(low) => low
+                .poll_recv(cx)
+                .map(|unreserved| unreserved.map(|unreserved| unreserved.request)),
+            None => Poll::Ready(None),
+        };
+        match (high, low) {
+            (_, Poll::Ready(Some(request))) => Poll::Ready(Some(request)),
+            (Poll::Ready(None), Poll::Ready(None)) => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+
+    fn close(&mut self) {
+        self.high.close();
+        if let Some(low) = &mut self.low {
+            low.close();
+        }
+    }
+
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        EventSource::metrics(&self.high)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+
+    fn recv_all(receiver: &mut PriorityReceiver<&'static str>) -> Vec<&'static str> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut requests = Vec::new();
+        while let Poll::Ready(Some(request)) = receiver.poll_recv(&mut cx) {
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[test]
+    fn receives_high_severity_events_first() {
+        let (sender, mut receiver) = priority_channel(4, 2, Level::WARN);
+        sender.try_send_with_level("info", &Level::INFO).unwrap();
+        sender.try_send_with_level("debug", &Level::DEBUG).unwrap();
+        sender.try_send_with_level("error", &Level::ERROR).unwrap();
+        sender.try_send_with_level("warn", &Level::WARN).unwrap();
+        assert_eq!(recv_all(&mut receiver), ["error", "warn", "info", "debug"]);
+    }
+
+    #[test]
+    fn reserves_slots_for_high_severity_events() {
+        let (sender, mut receiver) = priority_channel(3, 1, Level::WARN);
+        sender.try_send_with_level("info 1", &Level::INFO).unwrap();
+        sender.try_send_with_level("info 2", &Level::INFO).unwrap();
+        assert!(matches!(
+            sender.try_send_with_level("info 3", &Level::INFO),
+            Err(TrySendError::Full("info 3"))
+        ));
+        sender.try_send_with_level("error", &Level::ERROR).unwrap();
+        assert_eq!(recv_all(&mut receiver), ["error", "info 1", "info 2"]);
+    }
+
+    #[test]
+    fn high_severity_events_overflow_into_unreserved_slots() {
+        let (sender, mut receiver) = priority_channel(2, 1, Level::WARN);
+        sender
+            .try_send_with_level("error 1", &Level::ERROR)
+            .unwrap();
+        sender
+            .try_send_with_level("error 2", &Level::ERROR)
+            .unwrap();
+        assert!(sender
+            .try_send_with_level("error 3", &Level::ERROR)
+            .is_err());
+        assert_eq!(recv_all(&mut receiver), ["error 1", "error 2"]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_low_severity_event_to_admit_high_severity_events() {
+        let (sender, mut receiver) = priority_channel(3, 1, Level::WARN);
+        sender.try_send_with_level("info 1", &Level::INFO).unwrap();
+        sender.try_send_with_level("info 2", &Level::INFO).unwrap();
+        sender
+            .try_send_with_level("error 1", &Level::ERROR)
+            .unwrap();
+        assert!(matches!(
+            sender.try_send_with_level("error 2", &Level::ERROR),
+            Err(TrySendError::Full("info 1"))
+        ));
+        assert!(matches!(
+            sender.try_send_with_level("error 3", &Level::ERROR),
+            Err(TrySendError::Full("info 2"))
+        ));
+        assert!(matches!(
+            sender.try_send_with_level("error 4", &Level::ERROR),
+            Err(TrySendError::Full("error 4"))
+        ));
+        assert_eq!(recv_all(&mut receiver), ["error 1", "error 2", "error 3"]);
+    }
+
+    #[test]
+    fn tracks_the_oldest_queued_event_across_both_queues() {
+        let (sender, mut receiver) = priority_channel(4, 2, Level::WARN);
+        let metrics = EventSink::metrics(&sender).unwrap().clone();
+        sender.try_send_with_level("info", &Level::INFO).unwrap();
+        sender.try_send_with_level("error", &Level::ERROR).unwrap();
+
+        // The error is received first, so the info queued before it is still outstanding
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(Some("error")));
+        assert!(!metrics.received_before(metrics.enqueued()));
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(Some("info")));
+        assert!(metrics.received_before(metrics.enqueued()));
+    }
+
+    #[test]
+    fn evictions_leave_the_queue_depth_consistent() {
+        let (sender, mut receiver) = priority_channel(2, 1, Level::WARN);
+        let metrics = EventSink::metrics(&sender).unwrap().clone();
+        sender.try_send_with_level("info", &Level::INFO).unwrap();
+        sender
+            .try_send_with_level("error 1", &Level::ERROR)
+            .unwrap();
+        assert!(sender
+            .try_send_with_level("error 2", &Level::ERROR)
+            .is_err());
+        assert_eq!(metrics.queue_depth(), 2);
+
+        assert_eq!(recv_all(&mut receiver), ["error 1", "error 2"]);
+        assert!(metrics.received_before(metrics.enqueued()));
+    }
+
+    #[test]
+    fn closes_both_queues() {
+        let (sender, mut receiver) = priority_channel::<&str>(2, 1, Level::WARN);
+        receiver.close();
+        assert!(matches!(
+            sender.try_send_with_level("error", &Level::ERROR),
+            Err(TrySendError::Closed("error"))
+        ));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fmt, mem,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use tracing_core::Level;
+
+type Summary<Request> = Box<dyn Fn(&mut Request, u64) + Send + Sync>;
+
+/// A token bucket limiting the rate at which events are sent, see
+/// [`ServiceLayer::with_rate_limit`](crate::ServiceLayer::with_rate_limit).
+///
+/// The bucket holds up to `burst` tokens and is refilled at `per_second` tokens per second. Each
+/// event takes a token, events arriving while the bucket is empty are rate limited. These are
+/// written to the [`Spool`](crate::Spool), if one is configured, and dropped otherwise.
+pub struct RateLimit<Request> {
+    per_second: f64,
+    burst: f64,
+    summary: Option<Summary<Request>>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    limited: u64,
+    // The most severe level of the events rate limited since the last summary
+    level: Option<Level>,
+}
+
+/// The outcome of [`RateLimit::acquire`].
+pub(crate) enum Acquire {
+    /// A token was taken, along with the number of events rate limited since the last summary,
+    /// and their most severe level, if a summary should be sent.
+    Admitted { summary: Option<(u64, Level)> },
+    /// The bucket is empty, `first` is `true` if no other events are waiting to be summarized.
+    Limited { first: bool },
+}
+
+impl<Request> RateLimit<Request> {
+    /// Constructs a `RateLimit` which allows `per_second` events per second on average, and bursts
+    /// of up to `burst` events.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_second.into(),
+            burst: burst.into(),
+            summary: None,
+            bucket: Mutex::new(Bucket {
+                tokens: burst.into(),
+                refilled: Instant::now(),
+                limited: 0,
+                level: None,
+            }),
+        }
+    }
+
+    /// Sends a summary request once events are admitted again, using `summary` to record the
+    /// number of events which were rate limited.
+    ///
+    /// The summary is constructed like any other request, using the
+    /// [`NewRequest`](crate::NewRequest) of the layer, and sent through the same queue. It is sent
+    /// ahead of the next event admitted, or by the [`ResponseStream`](crate::ResponseStream) once
+    /// the bucket has refilled, see [`ResponseStream::with_timer`](crate::ResponseStream::with_timer),
+    /// and when the layer is shut down. It is sent at the most severe level of the events it
+    /// summarizes.
+    pub fn with_summary<F>(mut self, summary: F) -> Self
+    where
+        F: Fn(&mut Request, u64) + Send + Sync + 'static,
+    {
+        self.summary = Some(Box::new(summary));
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.refilled = now;
+        bucket
+    }
+
+    /// Attempts to take a token from the bucket for an event at `level`.
+    pub(crate) fn acquire(&self, level: Level) -> Acquire {
+        let mut bucket = self.lock();
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Acquire::Admitted {
+                summary: self.take_summary(&mut bucket),
+            }
+        } else {
+            bucket.limited += 1;
+            bucket.level = Some(
+                bucket
+                    .level
+                    .map_or(level, |most_severe| most_severe.min(level)),
+            );
+            Acquire::Limited {
+                first: bucket.limited == 1,
+            }
+        }
+    }
+
+    /// Takes the number of events rate limited, and their most severe level, if a summary is due,
+    /// which is once the bucket holds a token again or if `force` is `true`.
+    pub(crate) fn summary_due(&self, force: bool) -> Option<(u64, Level)> {
+        let mut bucket = self.lock();
+        if force || bucket.tokens >= 1.0 {
+            self.take_summary(&mut bucket)
+        } else {
+            None
+        }
+    }
+
+    fn take_summary(&self, bucket: &mut Bucket) -> Option<(u64, Level)> {
+        let limited = mem::take(&mut bucket.limited);
+        let level = bucket.level.take()?;
+        self.summary.as_ref().map(|_| (limited, level))
+    }
+
+    /// When the bucket next holds a token, if a summary is waiting to be sent.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        let bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        if bucket.limited == 0 || self.summary.is_none() {
+            return None;
+        }
+        let missing = (1.0 - bucket.tokens).max(0.0);
+        let refill = Duration::try_from_secs_f64(missing / self.per_second).ok()?;
+        Some(bucket.refilled + refill)
+    }
+
+    /// Records the number of events which were rate limited into the summary request.
+    pub(crate) fn record_summary(&self, request: &mut Request, limited: u64) {
+        if let Some(summary) = &self.summary {
+            summary(request, limited);
+        }
+    }
+}
+
+impl<Request> fmt::Debug for RateLimit<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("per_second", &self.per_second)
+            .field("burst", &self.burst)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn rate_limit() -> RateLimit<String> {
+        RateLimit::new(1, 2).with_summary(|request: &mut String, limited| {
+            request.push_str(&format!("dropped {limited}"));
+        })
+    }
+
+    #[test]
+    fn admits_bursts() {
+        let rate_limit = rate_limit();
+        for _ in 0..2 {
+            assert!(matches!(
+                rate_limit.acquire(Level::INFO),
+                Acquire::Admitted { summary: None }
+            ));
+        }
+        assert!(matches!(
+            rate_limit.acquire(Level::INFO),
+            Acquire::Limited { first: true }
+        ));
+        assert!(matches!(
+            rate_limit.acquire(Level::INFO),
+            Acquire::Limited { first: false }
+        ));
+    }
+
+    #[test]
+    fn counts_the_events_limited_until_the_bucket_refills() {
+        let rate_limit = RateLimit::new(1_000, 1).with_summary(|_: &mut String, _| {});
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::INFO);
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert!(matches!(
+            rate_limit.acquire(Level::INFO),
+            Acquire::Admitted {
+                summary: Some((2, Level::INFO))
+            }
+        ));
+    }
+
+    #[test]
+    fn summarizes_at_the_most_severe_level() {
+        let rate_limit = rate_limit();
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::DEBUG);
+        rate_limit.acquire(Level::WARN);
+        rate_limit.acquire(Level::INFO);
+
+        assert_eq!(rate_limit.summary_due(false), None);
+        assert_eq!(rate_limit.summary_due(true), Some((3, Level::WARN)));
+        assert_eq!(rate_limit.summary_due(true), None);
+    }
+
+    #[test]
+    fn only_summarizes_with_a_summary() {
+        let rate_limit = RateLimit::<String>::new(1, 1);
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::INFO);
+        assert!(rate_limit.deadline().is_none());
+        assert_eq!(rate_limit.summary_due(true), None);
+    }
+
+    #[test]
+    fn records_the_summary() {
+        let mut request = String::new();
+        rate_limit().record_summary(&mut request, 5);
+        assert_eq!(request, "dropped 5");
+    }
+
+    #[test]
+    fn summary_is_due_once_the_bucket_refills() {
+        let rate_limit = RateLimit::new(1_000, 1).with_summary(|_: &mut String, _| {});
+        assert!(rate_limit.deadline().is_none());
+        rate_limit.acquire(Level::INFO);
+        rate_limit.acquire(Level::ERROR);
+        rate_limit.acquire(Level::INFO);
+
+        let deadline = rate_limit.deadline().unwrap();
+        assert!(deadline <= Instant::now() + Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(rate_limit.summary_due(false), Some((2, Level::ERROR)));
+        assert_eq!(rate_limit.summary_due(false), None);
+        assert!(rate_limit.deadline().is_none());
+    }
+
+    #[test]
+    fn forces_the_summary_while_the_bucket_is_empty() {
+        let rate_limit = rate_limit();
+        for _ in 0..3 {
+            rate_limit.acquire(Level::INFO);
+        }
+        assert_eq!(rate_limit.summary_due(false), None);
+        assert_eq!(rate_limit.summary_due(true), Some((1, Level::INFO)));
+    }
+}
@@ -0,0 +1,32 @@
+use std::cell::Cell;
+
+thread_local! {
+    static ENTERED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as being within the pipeline until dropped.
+///
+/// Events emitted while a guard is held, for example by the [`Service`](tower::Service) while the
+/// [`ResponseStream`](crate::ResponseStream) is polled, are ignored by the
+/// [`ServiceLayer`](crate::ServiceLayer) to prevent feedback loops.
+pub(crate) struct Guard {
+    previous: bool,
+}
+
+impl Guard {
+    pub(crate) fn enter() -> Self {
+        let previous = ENTERED.with(|entered| entered.replace(true));
+        Self { previous }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        ENTERED.with(|entered| entered.set(self.previous));
+    }
+}
+
+/// Returns `true` if the current thread is within the pipeline.
+pub(crate) fn is_entered() -> bool {
+    ENTERED.with(Cell::get)
+}
@@ -0,0 +1,107 @@
+use std::borrow::Cow;
+
+/// Attributes describing the entity producing events, such as the service name and version,
+/// which are attached to every request, see
+/// [`ServiceLayer::with_resource`](crate::ServiceLayer::with_resource).
+///
+/// The well-known keys follow the OpenTelemetry semantic conventions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Resource {
+    attributes: Vec<(Cow<'static, str>, String)>,
+}
+
+impl Resource {
+    /// Constructs a `Resource` without any attributes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the attribute `key` to `value`, replacing any existing value.
+    pub fn with_attribute(
+        mut self,
+        key: impl Into<Cow<'static, str>>,
+        value: impl Into<String>,
+    ) -> Self {
+        let (key, value) = (key.into(), value.into());
+        match self
+            .attributes
+            .iter_mut()
+            .find(|(existing, _)| *existing == key)
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.attributes.push((key, value)),
+        }
+        self
+    }
+
+    /// Sets the `service.name` attribute.
+    pub fn with_service_name(self, name: impl Into<String>) -> Self {
+        self.with_attribute("service.name", name)
+    }
+
+    /// Sets the `service.version` attribute.
+    pub fn with_service_version(self, version: impl Into<String>) -> Self {
+        self.with_attribute("service.version", version)
+    }
+
+    /// Sets the `deployment.environment` attribute.
+    pub fn with_environment(self, environment: impl Into<String>) -> Self {
+        self.with_attribute("deployment.environment", environment)
+    }
+
+    /// Sets the `host.name` attribute.
+    pub fn with_host_name(self, host_name: impl Into<String>) -> Self {
+        self.with_attribute("host.name", host_name)
+    }
+
+    /// Returns the attributes, in the order they were first set.
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_str()))
+    }
+}
+
+/// A `Request` which [`Resource`] attributes can be recorded into.
+///
+/// This is implemented by the requests of the `json`, `logfmt`, `loki` and `stackdriver` presets.
+/// OTLP resources are instead attached to each export request by `otlp::ExportLogs::with_resource`.
+pub trait RecordResource {
+    /// Records the attributes of `resource`.
+    fn record_resource(&mut self, resource: &Resource);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_existing_attributes_in_place() {
+        let resource = Resource::new()
+            .with_service_name("checkout")
+            .with_environment("staging")
+            .with_attribute("service.name", "payments")
+            .with_host_name("web-1");
+
+        assert_eq!(
+            resource.attributes().collect::<Vec<_>>(),
+            [
+                ("service.name", "payments"),
+                ("deployment.environment", "staging"),
+                ("host.name", "web-1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_the_well_known_keys() {
+        let resource = Resource::new()
+            .with_service_version("1.2.3")
+            .with_attribute(String::from("team"), "platform");
+
+        assert_eq!(
+            resource.attributes().collect::<Vec<_>>(),
+            [("service.version", "1.2.3"), ("team", "platform")]
+        );
+    }
+}
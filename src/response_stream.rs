@@ -1,106 +1,1009 @@
 use std::{
+    fmt,
     future::Future,
-    hint::unreachable_unchecked,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use futures_core::{ready, Stream};
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use pin_project_lite::pin_project;
-use tokio::sync::mpsc::Receiver;
 use tower::Service;
 
-pin_project! {
-    #[project = InnerProj]
-    #[project_replace = InnerProjReplace]
-    enum Inner<Request, Fut> {
-        WaitingStream,
-        WaitingService { request: Request },
-        Existing { #[pin] future: Fut },
-        Closed,
-    }
-}
+use crate::{
+    channel::Receiver,
+    circuit_breaker::Admit,
+    classify::{ErrorClass, RequestError},
+    health::{self, Liveness},
+    metrics::Attached,
+    reentrancy,
+    retry::{self, Backoff, Call, Sleep, Timer},
+    shutdown,
+    spool::Replayed,
+    termination::{ExitReason, Termination},
+    CircuitBreaker, CircuitState, EventSource, Flush, Health, LayerMetrics, RetryPolicy,
+    ShutdownHandle, Spool,
+};
+
+type FinalRequest<Request> = Box<dyn FnOnce(&Termination) -> Request + Send>;
+type Callback<T> = Box<dyn FnMut(&T) + Send>;
+type Classifier<Error> = Box<dyn Fn(&Error) -> ErrorClass + Send + Sync>;
 
 pin_project! {
     /// A [`Stream`] of [`Service::Response`]s returned by the [`Service`] as `Request`s are passed
     /// through it.
+    ///
+    /// By default a single [`Service::Future`] is driven at a time, see
+    /// [`ResponseStream::with_concurrency`] to allow more.
+    ///
+    /// Requests are received from an [`EventSource`], which defaults to a
+    /// [`channel::Receiver`](Receiver).
     #[must_use = "the underlying Service will not process requests unless this is being polled"]
-    pub struct ResponseStream<Request, Svc> where Svc: Service<Request> {
+    pub struct ResponseStream<Request, Svc, Source = Receiver<Request>> where Svc: Service<Request> {
         service: Svc,
-        #[pin]
-        receiver: Receiver<Request>,
-        #[pin]
-        inner: Inner<Request, Svc::Future>
+        receiver: Source,
+        // A request, the number of times it has been attempted, the spooled request it replays and
+        // its sequence number, which is waiting for the service to become ready
+        pending: Option<(Request, u32, Option<Replayed>, u64)>,
+        in_flight: FuturesUnordered<Call<Request, Svc::Future>>,
+        concurrency: usize,
+        // The flush being waited for, see `Handle::flush`
+        flush: Option<PendingFlush>,
+        // The most recent flush which has been started
+        flush_started: u64,
+        // Sends the requests buffered by the service, see `Flush`
+        flush_buffered: Option<fn(&mut Svc) -> Option<Svc::Future>>,
+        // When the service's buffered requests should be sent without waiting for a flush
+        flush_deadline: Option<fn(&Svc) -> Option<Instant>>,
+        flush_at: Option<(Instant, Sleep)>,
+        // The future sending the buffered requests, and the flush it completes
+        flushing: Option<(Pin<Box<Svc::Future>>, u64)>,
+        // Whether the buffered requests should be sent once the service is ready, and the flush
+        // this completes
+        flush_due: bool,
+        flush_due_for: u64,
+        flushed_on_close: bool,
+        // Clones requests before they are called, when they may be retried or spooled
+        clone_request: Option<fn(&Request) -> Request>,
+        // Takes failed requests back from errors, rather than cloning them before they are called
+        recover_request: Option<fn(&mut Svc::Error) -> Option<Request>>,
+        classify: Option<Classifier<Svc::Error>>,
+        retry: Option<RetryPolicy<Svc::Error>>,
+        retries: FuturesUnordered<Backoff<Request>>,
+        spool: Option<Spool<Request>>,
+        circuit_breaker: Option<CircuitBreaker>,
+        // Whether the most recent call succeeded, the spool is only replayed while it is healthy
+        healthy: bool,
+        receiver_closed: bool,
+        // Waits out retry backoffs and until the requests held back by the layer are due, see
+        // `LayerMetrics::release_held`
+        timer: Option<Timer>,
+        release_at: Option<(Instant, Sleep)>,
+        exit_reason: ExitReason,
+        final_request: Option<FinalRequest<Request>>,
+        on_response: Option<Callback<Svc::Response>>,
+        on_error: Option<Callback<Svc::Error>>,
+        metrics: LayerMetrics,
+        attached: Attached,
+        started: Instant,
+        shutdown: Arc<shutdown::State>,
+        health: Liveness,
+        // Formats errors for `Health::last_error`, set once a `Health` handle is requested
+        format_error: Option<fn(&Svc::Error) -> String>,
     }
 }
 
-impl<Request, Svc> Stream for ResponseStream<Request, Svc>
+impl<Request, Svc, Source> Stream for ResponseStream<Request, Svc, Source>
 where
     Svc: Service<Request>,
+    Source: EventSource<Request>,
 {
     type Item = Result<Svc::Response, Svc::Error>;
 
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Events emitted by the service should not be fed back into the pipeline
+        let _guard = reentrancy::Guard::enter();
+
+        let this = self.get_mut();
+        this.health.0.record_polled();
+        this.shutdown.register(cx.waker());
+        this.metrics.register_stream(cx.waker());
+        if this.shutdown.is_aborted() {
+            this.shutdown.finish();
+            this.health.0.finish();
+            this.metrics.record_flushed(u64::MAX);
+            return Poll::Ready(None);
+        }
+        if this.shutdown.take_close() {
+            this.close();
+        }
+        this.poll_release(cx);
+        this.poll_flush_deadline(cx);
+
+        let poll = Pin::new(&mut *this).poll_responses(cx);
+        // The requests passed to the service may have given it a new deadline
+        if poll.is_pending() && this.poll_flush_deadline(cx) {
+            cx.waker().wake_by_ref();
+        }
+        match &poll {
+            Poll::Ready(Some(output)) => {
+                match (output, &mut this.on_response, &mut this.on_error) {
+                    (Ok(response), Some(on_response), _) => on_response(response),
+                    (Err(err), _, Some(on_error)) => on_error(err),
+                    _ => {}
+                }
+                if this.exit_reason == ExitReason::Shutdown {
+                    this.shutdown.record_flushed();
+                }
+            }
+            Poll::Ready(None) => {
+                this.shutdown.finish();
+                this.health.0.finish();
+                this.metrics.record_flushed(u64::MAX);
+            }
+            Poll::Pending => {}
+        }
+        this.shutdown.set_outstanding(
+            this.in_flight.len()
+                + this.retries.len()
+                + usize::from(this.pending.is_some())
+                + usize::from(this.flushing.is_some()),
+        );
+        poll
+    }
+}
+
+impl<Request, Svc, Source> ResponseStream<Request, Svc, Source>
+where
+    Svc: Service<Request>,
+    Source: EventSource<Request>,
+{
     #[inline]
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.as_mut().project();
-
-        let inner = this.inner.as_mut().project();
-        match inner {
-            // Waiting for stream to yield a request
-            InnerProj::WaitingStream => {
-                let item = ready!(this.receiver.poll_recv(cx));
-
-                match item {
-                    Some(request) => {
-                        this.inner.set(Inner::WaitingService { request });
-                        self.poll_next(cx)
+    fn poll_responses(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Svc::Response, Svc::Error>>> {
+        let this = self.project();
+
+        loop {
+            // Once the requests enqueued before a flush have completed, the requests buffered by
+            // the service are sent
+            if let Some(generation) = poll_flush(
+                this.flush,
+                this.flush_started,
+                this.metrics,
+                this.in_flight,
+                this.retries,
+                this.pending.as_ref(),
+            ) {
+                match this.flush_buffered {
+                    Some(_) => {
+                        *this.flush_due = true;
+                        *this.flush_due_for = generation;
                     }
-                    None => {
-                        this.inner.set(Inner::Closed);
-                        Poll::Ready(None)
+                    None => this.metrics.record_flushed(generation),
+                }
+            }
+            if *this.flush_due
+                && this.flushing.is_none()
+                && this.in_flight.len() < *this.concurrency
+            {
+                if let Some(flush_buffered) = *this.flush_buffered {
+                    match this.service.poll_ready(cx) {
+                        Poll::Ready(Ok(())) => {
+                            *this.flush_due = false;
+                            match flush_buffered(this.service) {
+                                Some(future) => {
+                                    *this.flushing = Some((Box::pin(future), *this.flush_due_for));
+                                }
+                                None => this.metrics.record_flushed(*this.flush_due_for),
+                            }
+                        }
+                        Poll::Ready(Err(err)) => {
+                            *this.flush_due = false;
+                            this.metrics.record_flushed(*this.flush_due_for);
+                            this.metrics.record_service_error();
+                            record_error(&this.health.0, *this.format_error, &err);
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                        Poll::Pending => {}
                     }
                 }
             }
-            // Waiting for service to be ready, then call it
-            InnerProj::WaitingService { .. } => {
-                let result = ready!(this.service.poll_ready(cx));
-
-                // We can reuse the Inner::Closed state here as an intermediate state
-                let inner = this.inner.as_mut().project_replace(Inner::Closed);
-                if let InnerProjReplace::WaitingService { request } = inner {
-                    if let Err(err) = result {
-                        Poll::Ready(Some(Err(err)))
-                    } else {
+
+            // Call the service until the concurrency limit is reached
+            while this.in_flight.len() + usize::from(this.flushing.is_some()) < *this.concurrency {
+                // Requests which are due a retry, followed by spooled requests, take priority over
+                // new requests
+                let (request, attempts, replayed, sequence) = match this.pending.take() {
+                    Some(pending) => pending,
+                    None => match this.retries.poll_next_unpin(cx) {
+                        Poll::Ready(Some(retry)) => retry,
+                        _ if should_replay(*this.healthy, this.spool.as_ref()) => {
+                            match this.spool.as_ref().map(Spool::pop) {
+                                Some(Ok(Some((request, replayed)))) => {
+                                    (request, 0, Some(replayed), UNQUEUED)
+                                }
+                                // The spool cannot be read, stop replaying until the next success
+                                _ => {
+                                    *this.healthy = false;
+                                    continue;
+                                }
+                            }
+                        }
+                        _ if *this.receiver_closed => break,
+                        _ => match this.receiver.poll_recv(cx) {
+                            Poll::Ready(Some(request)) => {
+                                let sequence = this.metrics.record_received();
+                                (request, 0, None, sequence)
+                            }
+                            Poll::Ready(None) => {
+                                *this.receiver_closed = true;
+                                break;
+                            }
+                            Poll::Pending => break,
+                        },
+                    },
+                };
+
+                // Shed requests while the circuit is open, and hold them back while it is probed
+                match this.circuit_breaker.as_mut().map(CircuitBreaker::admit) {
+                    Some(Admit::Shed) => {
+                        match this.spool.as_ref().map(|spool| spool.push(&request)) {
+                            Some(Ok(true)) => this.metrics.record_spooled(),
+                            _ => this.metrics.record_dropped_circuit_open(),
+                        }
+                        // The replayed request was written back to the spool
+                        if let (Some(spool), Some(replayed)) = (this.spool.as_ref(), replayed) {
+                            let _ = spool.commit(replayed);
+                        }
+                        continue;
+                    }
+                    Some(Admit::Wait) => {
+                        *this.pending = Some((request, attempts, replayed, sequence));
+                        break;
+                    }
+                    Some(Admit::Call) | None => {}
+                }
+
+                match this.service.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        let copy = match this.recover_request {
+                            Some(_) => None,
+                            None => this
+                                .clone_request
+                                .map(|clone_request| clone_request(&request)),
+                        };
                         let future = this.service.call(request);
-                        this.inner.set(Inner::Existing { future });
-                        self.poll_next(cx)
+                        let generation = this
+                            .circuit_breaker
+                            .as_mut()
+                            .map_or(0, CircuitBreaker::record_call);
+                        let call = Call::new(future, copy, attempts + 1, replayed, sequence)
+                            .with_generation(generation);
+                        this.in_flight.push(call);
+                    }
+                    Poll::Ready(Err(err)) => {
+                        if let Some(circuit_breaker) = this.circuit_breaker.as_mut() {
+                            circuit_breaker.record(circuit_breaker.generation(), false);
+                        }
+                        this.metrics.record_service_error();
+                        record_error(&this.health.0, *this.format_error, &err);
+
+                        // The request is only recovered if errors are being classified
+                        if let Some(classify) = this.classify.as_ref() {
+                            let attempts = attempts + 1;
+                            let retried = recover(
+                                (request, attempts, replayed, sequence),
+                                classify(&err),
+                                || {
+                                    retry_sleep(
+                                        this.retry.as_ref(),
+                                        this.timer.as_ref(),
+                                        &err,
+                                        attempts,
+                                    )
+                                },
+                                this.retries,
+                                this.spool.as_ref(),
+                                this.metrics,
+                            );
+                            if retried {
+                                continue;
+                            }
+                            // The replayed request was dropped, or written back to the spool
+                            if let (Some(spool), Some(replayed)) = (this.spool.as_ref(), replayed) {
+                                let _ = spool.commit(replayed);
+                            }
+                        }
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    Poll::Pending => {
+                        *this.pending = Some((request, attempts, replayed, sequence));
+                        break;
+                    }
+                }
+            }
+
+            // Yield responses in the order they complete
+            if let Some((future, flush)) = this.flushing {
+                if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                    // The service is flushed again until it has nothing left to send, or fails
+                    match &output {
+                        Ok(_) => *this.flush_due = true,
+                        Err(err) => {
+                            this.metrics.record_flushed(*flush);
+                            this.metrics.record_service_error();
+                            record_error(&this.health.0, *this.format_error, err);
+                        }
                     }
-                } else {
-                    // The InnerProj and InnerProjReplace match paths should be identical
-                    unsafe { unreachable_unchecked() }
+                    *this.flushing = None;
+                    *this.healthy = output.is_ok();
+                    return Poll::Ready(Some(output));
                 }
             }
-            // Waiting for existing Svc::Future to resolve
-            InnerProj::Existing { future } => {
-                let output = ready!(future.poll(cx));
-                this.inner.set(Inner::WaitingStream);
-                Poll::Ready(Some(output))
+            match ready!(this.in_flight.poll_next_unpin(cx)) {
+                Some((mut output, copy, attempts, replayed, sequence, generation)) => {
+                    *this.healthy = output.is_ok();
+                    if let Some(circuit_breaker) = this.circuit_breaker.as_mut() {
+                        circuit_breaker.record(generation, output.is_ok());
+                    }
+                    if let Err(err) = &mut output {
+                        this.metrics.record_service_error();
+                        record_error(&this.health.0, *this.format_error, err);
+
+                        let request = copy.or_else(|| {
+                            this.recover_request
+                                .and_then(|recover_request| recover_request(err))
+                        });
+                        if let Some(request) = request {
+                            let class = this
+                                .classify
+                                .as_ref()
+                                .map_or(ErrorClass::Retryable, |classify| classify(err));
+                            let retried = recover(
+                                (request, attempts, replayed, sequence),
+                                class,
+                                || {
+                                    retry_sleep(
+                                        this.retry.as_ref(),
+                                        this.timer.as_ref(),
+                                        err,
+                                        attempts,
+                                    )
+                                },
+                                this.retries,
+                                this.spool.as_ref(),
+                                this.metrics,
+                            );
+                            if retried {
+                                continue;
+                            }
+                        }
+                    }
+                    // The replayed request was delivered, or written back to the spool
+                    if let (Some(spool), Some(replayed)) = (this.spool.as_ref(), replayed) {
+                        let _ = spool.commit(replayed);
+                    }
+                    return Poll::Ready(Some(output));
+                }
+                // The receiver is closed and there is no remaining work
+                None if *this.receiver_closed
+                    && this.pending.is_none()
+                    && this.retries.is_empty()
+                    && this.flushing.is_none()
+                    && !*this.flush_due
+                    && !should_replay(*this.healthy, this.spool.as_ref()) =>
+                {
+                    match this.final_request.take() {
+                        Some(final_request) => {
+                            let termination = Termination::new(
+                                *this.exit_reason,
+                                this.started.elapsed(),
+                                this.metrics,
+                            );
+                            *this.pending = Some((final_request(&termination), 0, None, UNQUEUED));
+                        }
+                        // The requests buffered by the service, including the final request, are
+                        // sent before terminating
+                        None if this.flush_buffered.is_some() && !*this.flushed_on_close => {
+                            *this.flushed_on_close = true;
+                            *this.flush_due = true;
+                        }
+                        // Terminal state
+                        None => return Poll::Ready(None),
+                    }
+                }
+                // Nothing in flight, the receiver, service or a retry has registered a wakeup
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<Request, Svc, Source> ResponseStream<Request, Svc, Source>
+where
+    Svc: Service<Request>,
+{
+    /// Sends the requests held back by the layer to the queue once they are due, arming the timer
+    /// for the next of them.
+    fn poll_release(&mut self, cx: &mut Context<'_>) {
+        while let Some(deadline) = self.metrics.release_deadline() {
+            if self
+                .release_at
+                .as_ref()
+                .map_or(true, |(at, _)| *at != deadline)
+            {
+                let duration = deadline.saturating_duration_since(Instant::now());
+                let Some(sleep) = retry::sleep(self.timer.as_ref(), duration) else {
+                    return;
+                };
+                self.release_at = Some((deadline, sleep));
+            }
+            if let Some((_, sleep)) = &mut self.release_at {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return;
+                }
+            }
+            self.release_at = None;
+            self.metrics.release_held(false);
+        }
+        self.release_at = None;
+    }
+
+    /// Sets `flush_due` once the deadline of the requests buffered by the service passes, arming
+    /// the timer for it otherwise. Returns `true` if the deadline passed.
+    fn poll_flush_deadline(&mut self, cx: &mut Context<'_>) -> bool {
+        let deadline = match self.flush_deadline {
+            Some(flush_deadline) if !self.flush_due && self.flushing.is_none() => {
+                flush_deadline(&self.service)
+            }
+            _ => None,
+        };
+        let Some(deadline) = deadline else {
+            self.flush_at = None;
+            return false;
+        };
+        if self
+            .flush_at
+            .as_ref()
+            .map_or(true, |(at, _)| *at != deadline)
+        {
+            let duration = deadline.saturating_duration_since(Instant::now());
+            let Some(sleep) = retry::sleep(self.timer.as_ref(), duration) else {
+                return false;
+            };
+            self.flush_at = Some((deadline, sleep));
+        }
+        if let Some((_, sleep)) = &mut self.flush_at {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return false;
+            }
+        }
+        self.flush_at = None;
+        self.flush_due = true;
+        true
+    }
+}
+
+/// The sequence number of requests which were not received from the queue, such as those replayed
+/// from the spool, so they do not hold up [`Handle::flush`](crate::Handle::flush).
+const UNQUEUED: u64 = u64::MAX;
+
+/// A flush requested using [`Handle::flush`](crate::Handle::flush).
+struct PendingFlush {
+    generation: u64,
+    // The number of requests enqueued before the flush was requested
+    enqueued: u64,
+    // The number of requests received once every request enqueued before the flush had been
+    received: Option<u64>,
+}
+
+/// Starts the most recently requested flush, if there is none in progress, and returns its
+/// generation once every request enqueued before it has completed.
+///
+/// Requests may not be received in the order they were enqueued, so this first waits for every
+/// one of them to be received, and then for every request received up to that point to complete.
+fn poll_flush<Request, Fut>(
+    flush: &mut Option<PendingFlush>,
+    flush_started: &mut u64,
+    metrics: &LayerMetrics,
+    in_flight: &FuturesUnordered<Call<Request, Fut>>,
+    retries: &FuturesUnordered<Backoff<Request>>,
+    pending: Option<&(Request, u32, Option<Replayed>, u64)>,
+) -> Option<u64> {
+    if flush.is_none() {
+        let (generation, enqueued) = metrics.flush_requested();
+        if generation > *flush_started {
+            *flush_started = generation;
+            *flush = Some(PendingFlush {
+                generation,
+                enqueued,
+                received: None,
+            });
+        }
+    }
+    let pending_flush = flush.as_mut()?;
+    if pending_flush.received.is_none() && metrics.received_before(pending_flush.enqueued) {
+        pending_flush.received = Some(metrics.received());
+    }
+    let received = pending_flush.received?;
+
+    // The oldest request which is still waiting to be called, in flight, or waiting to be retried
+    let in_flight = Pin::new(in_flight)
+        .iter_pin_ref()
+        .map(|call| call.sequence());
+    let retries = retries.iter().map(Backoff::sequence);
+    let pending = pending.map(|(_, _, _, sequence)| *sequence);
+    let oldest = in_flight.chain(retries).chain(pending).min();
+    if oldest.is_some_and(|oldest| oldest < received) {
+        return None;
+    }
+    flush.take().map(|flush| flush.generation)
+}
+
+/// Retries or spools a failed request, along with the number of times it has been attempted, the
+/// spooled request it replays and its sequence number, according to its [`ErrorClass`]. `sleep`
+/// returns the backoff to wait out, if the request may be retried. Returns `true` if it will be
+/// retried.
+fn recover<Request>(
+    (request, attempts, replayed, sequence): (Request, u32, Option<Replayed>, u64),
+    class: ErrorClass,
+    sleep: impl FnOnce() -> Option<Sleep>,
+    retries: &mut FuturesUnordered<Backoff<Request>>,
+    spool: Option<&Spool<Request>>,
+    metrics: &LayerMetrics,
+) -> bool {
+    // Overloaded requests are spooled rather than adding to the load with retries
+    let spool_first = class == ErrorClass::Overloaded && spool.is_some();
+    let sleep = match class {
+        ErrorClass::Fatal => return false,
+        _ if spool_first => None,
+        _ => sleep(),
+    };
+    match sleep {
+        Some(sleep) => {
+            retries.push(Backoff::new(sleep, request, attempts, replayed, sequence));
+            true
+        }
+        None => {
+            if let Some(Ok(true)) = spool.map(|spool| spool.push(&request)) {
+                metrics.record_spooled();
             }
-            // Terminal closed state
-            InnerProj::Closed => Poll::Ready(None),
+            false
         }
     }
 }
 
-impl<Request, Svc> ResponseStream<Request, Svc>
+/// The backoff before retrying a request which failed with `err`, if the [`RetryPolicy`] allows
+/// it to be retried and there is a timer to wait with.
+fn retry_sleep<E>(
+    retry: Option<&RetryPolicy<E>>,
+    timer: Option<&Timer>,
+    err: &E,
+    attempts: u32,
+) -> Option<Sleep> {
+    retry
+        .filter(|retry| retry.should_retry(err, attempts))
+        .and_then(|retry| retry.sleep(attempts, timer))
+}
+
+fn record_error<E>(health: &health::State, format_error: Option<fn(&E) -> String>, err: &E) {
+    if let Some(format_error) = format_error {
+        health.record_error(format_error(err));
+    }
+}
+
+fn should_replay<Request>(healthy: bool, spool: Option<&Spool<Request>>) -> bool {
+    healthy && spool.is_some_and(|spool| !spool.is_empty())
+}
+
+impl<Request, Svc, Source> ResponseStream<Request, Svc, Source>
 where
     Svc: Service<Request>,
+    Source: EventSource<Request>,
 {
-    pub(crate) fn new(service: Svc, receiver: Receiver<Request>) -> Self {
+    /// Constructs a `ResponseStream` which drains an [`EventSource`] into the [`Service`].
+    ///
+    /// This is intended to be used alongside
+    /// [`ServiceLayer::from_parts`](crate::ServiceLayer::from_parts). The stream records into the
+    /// [`LayerMetrics`] of the source, see [`EventSource::metrics`], which are shared with a layer
+    /// constructed from the sending half, otherwise see [`ResponseStream::with_metrics`].
+    pub fn from_source(service: Svc, source: Source) -> Self {
+        let metrics = source.metrics().cloned().unwrap_or_default();
+        Self::new(service, source, metrics)
+    }
+
+    pub(crate) fn new(service: Svc, receiver: Source, metrics: LayerMetrics) -> Self {
         Self {
             service,
             receiver,
-            inner: Inner::WaitingStream,
+            pending: None,
+            in_flight: FuturesUnordered::new(),
+            concurrency: 1,
+            flush: None,
+            flush_started: 0,
+            flush_buffered: None,
+            flush_deadline: None,
+            flush_at: None,
+            flushing: None,
+            flush_due: false,
+            flush_due_for: 0,
+            flushed_on_close: false,
+            clone_request: None,
+            recover_request: None,
+            classify: None,
+            retry: None,
+            retries: FuturesUnordered::new(),
+            spool: None,
+            circuit_breaker: None,
+            healthy: true,
+            receiver_closed: false,
+            timer: None,
+            release_at: None,
+            exit_reason: ExitReason::Closed,
+            final_request: None,
+            on_response: None,
+            on_error: None,
+            attached: Attached::new(metrics.clone()),
+            metrics,
+            started: Instant::now(),
+            shutdown: Arc::default(),
+            health: Liveness::default(),
+            format_error: None,
         }
     }
+
+    /// Replaces the [`EventSource`], keeping the rest of the configuration, and records into the
+    /// metrics of the new source if it tracks them.
+    pub(crate) fn with_source<S>(self, receiver: S) -> ResponseStream<Request, Svc, S>
+    where
+        S: EventSource<Request>,
+    {
+        let (metrics, attached) = match receiver.metrics() {
+            Some(metrics) => (metrics.clone(), Attached::new(metrics.clone())),
+            None => (self.metrics, self.attached),
+        };
+        ResponseStream {
+            service: self.service,
+            receiver,
+            pending: self.pending,
+            in_flight: self.in_flight,
+            concurrency: self.concurrency,
+            flush: self.flush,
+            flush_started: self.flush_started,
+            flush_buffered: self.flush_buffered,
+            flush_deadline: self.flush_deadline,
+            flush_at: self.flush_at,
+            flushing: self.flushing,
+            flush_due: self.flush_due,
+            flush_due_for: self.flush_due_for,
+            flushed_on_close: self.flushed_on_close,
+            clone_request: self.clone_request,
+            recover_request: self.recover_request,
+            classify: self.classify,
+            retry: self.retry,
+            retries: self.retries,
+            spool: self.spool,
+            circuit_breaker: self.circuit_breaker,
+            healthy: self.healthy,
+            receiver_closed: self.receiver_closed,
+            timer: self.timer,
+            release_at: self.release_at,
+            exit_reason: self.exit_reason,
+            final_request: self.final_request,
+            on_response: self.on_response,
+            on_error: self.on_error,
+            metrics,
+            attached,
+            started: self.started,
+            shutdown: self.shutdown,
+            health: self.health,
+            format_error: self.format_error,
+        }
+    }
+
+    /// Returns a handle to the [`LayerMetrics`] recorded by this stream.
+    pub fn metrics(&self) -> LayerMetrics {
+        self.metrics.clone()
+    }
+
+    /// Records into the given [`LayerMetrics`], typically those of the
+    /// [`ServiceLayer`](crate::ServiceLayer) sending to this stream.
+    ///
+    /// This is only needed if the [`EventSource`] does not track the metrics of its queue, see
+    /// [`EventSource::metrics`].
+    pub fn with_metrics(mut self, metrics: LayerMetrics) -> Self {
+        self.attached = Attached::new(metrics.clone());
+        self.metrics = metrics;
+        self
+    }
+
+    /// Returns a [`ShutdownHandle`] which closes the queue and waits for this stream to drain.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown.clone(), self.metrics.clone())
+    }
+
+    /// Returns a [`Health`] handle reporting whether this stream is alive, the state of its queue,
+    /// and the last error returned by the [`Service`].
+    pub fn health(&mut self) -> Health
+    where
+        Svc::Error: fmt::Debug,
+    {
+        self.format_error = Some(|err| format!("{err:?}"));
+        Health::new(self.health.0.clone(), self.metrics.clone())
+    }
+
+    /// Returns a reference to the [`Service`].
+    pub fn get_ref(&self) -> &Svc {
+        &self.service
+    }
+
+    /// Returns a mutable reference to the [`Service`].
+    pub fn get_mut(&mut self) -> &mut Svc {
+        &mut self.service
+    }
+
+    /// Returns a reference to the [`EventSource`].
+    pub fn source_ref(&self) -> &Source {
+        &self.receiver
+    }
+
+    /// Consumes the stream, returning the [`Service`].
+    ///
+    /// Requests which are in flight, waiting to be retried, or still queued are dropped, see
+    /// [`ResponseStream::close`] to send them first.
+    pub fn into_inner(self) -> Svc {
+        self.service
+    }
+
+    /// Consumes the stream, returning the [`Service`] and [`EventSource`].
+    ///
+    /// Requests which are in flight or waiting to be retried are dropped.
+    pub fn into_parts(self) -> (Svc, Source) {
+        (self.service, self.receiver)
+    }
+
+    /// Stops the queue from accepting new requests.
+    ///
+    /// The requests already queued, in flight, or waiting to be retried are still sent as the
+    /// stream is polled, after which it terminates with [`ExitReason::Shutdown`].
+    pub fn close(&mut self) {
+        self.exit_reason = ExitReason::Shutdown;
+        self.metrics.release_held(true);
+        self.receiver.close();
+    }
+
+    /// Waits out retry backoffs, and for the requests held back by the
+    /// [`ServiceLayer`](crate::ServiceLayer) to be due, such as those being aggregated, using the
+    /// future returned by `sleep`, for example `async_io::Timer` when running on `smol`.
+    ///
+    /// The tokio timer is used by default if the `tokio` feature is enabled. Otherwise, without a
+    /// timer, held requests are only sent as later events are recorded and at shutdown, and
+    /// failed calls are not retried unless the [`RetryPolicy`] has its own timer.
+    pub fn with_timer<F, Fut>(mut self, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.timer = Some(Box::new(move |duration| Box::pin(sleep(duration))));
+        self
+    }
+
+    /// Allows up to `concurrency` [`Service::Future`]s to be in flight at once.
+    ///
+    /// Responses are yielded in the order they complete, rather than the order the requests were
+    /// received.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "concurrency must be greater than zero");
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Sends a final request, constructed from a [`Termination`] summary, once the queue has closed
+    /// and every other request has completed.
+    ///
+    /// The final request acts as a terminator marker, downstream consumers which do not receive it
+    /// can assume the pipeline did not terminate cleanly.
+    pub fn with_final_request<F>(mut self, final_request: F) -> Self
+    where
+        F: FnOnce(&Termination) -> Request + Send + 'static,
+    {
+        self.final_request = Some(Box::new(final_request));
+        self
+    }
+
+    /// Calls `on_response` with each [`Service::Response`] before it is yielded.
+    ///
+    /// Along with [`ResponseStream::on_error`], this allows responses to be handled while the
+    /// stream is driven by [`ResponseStream::drain`] or [`ResponseStream::spawn`].
+    pub fn on_response<F>(mut self, on_response: F) -> Self
+    where
+        F: FnMut(&Svc::Response) + Send + 'static,
+    {
+        self.on_response = Some(Box::new(on_response));
+        self
+    }
+
+    /// Calls `on_error` with each [`Service::Error`] before it is yielded.
+    ///
+    /// Errors which are retried are not passed to `on_error`, see [`ResponseStream::with_retry`].
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(&Svc::Error) + Send + 'static,
+    {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    /// Returns a future which drives the stream to completion, calling `handler` with each
+    /// response.
+    pub async fn for_each_response<F>(mut self, mut handler: F)
+    where
+        F: FnMut(Result<Svc::Response, Svc::Error>),
+    {
+        while let Some(output) = self.next().await {
+            handler(output);
+        }
+    }
+
+    /// Returns a future which drives the stream to completion, discarding its responses.
+    ///
+    /// Responses can still be observed using [`ResponseStream::on_response`] and
+    /// [`ResponseStream::on_error`].
+    pub async fn drain(self) {
+        self.for_each_response(|_| {}).await
+    }
+
+    /// Spawns a task, on the current tokio runtime, which drives the stream to completion and
+    /// discards its responses.
+    ///
+    /// When the returned [`WorkerGuard`](crate::WorkerGuard) is dropped the queue stops accepting
+    /// events and the events already queued are sent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn(self) -> crate::WorkerGuard
+    where
+        Request: Send + 'static,
+        Svc: Send + 'static,
+        Svc::Future: Send + 'static,
+        Source: Send + 'static,
+    {
+        crate::worker::spawn(self)
+    }
+
+    /// Retries failed [`Service`] calls according to the [`RetryPolicy`].
+    ///
+    /// Requests are cloned before each call, so they can be retried once the backoff has elapsed.
+    /// Errors which are retried are not yielded by the stream, but are still recorded in the
+    /// [`LayerMetrics`]. Errors returned by [`Service::poll_ready`] are never retried.
+    pub fn with_retry(mut self, policy: RetryPolicy<Svc::Error>) -> Self
+    where
+        Request: Clone,
+    {
+        self.clone_request = Some(Request::clone);
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Classifies the errors returned by the [`Service`], deciding whether the failed request is
+    /// retried, spooled or dropped, see [`ErrorClass`].
+    ///
+    /// Errors are [`ErrorClass::Retryable`] by default. Once a classifier is set, errors returned
+    /// by [`Service::poll_ready`] are also handled, as the request which was waiting for the
+    /// [`Service`] to become ready is recovered. Requests are otherwise recovered by cloning them
+    /// before each call, see [`ResponseStream::with_retry`] and [`ResponseStream::with_spool`], or
+    /// from a [`RequestError`], see [`ResponseStream::with_request_recovery`].
+    ///
+    /// ```
+    /// # use tracing_service::{ErrorClass, RetryPolicy, ServiceLayer};
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # #[derive(Debug)]
+    /// # enum Error { Overloaded, BadRequest, Unavailable }
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, Error>(()) });
+    /// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    /// let responses = responses
+    ///     .with_retry(RetryPolicy::new(3))
+    ///     .with_error_classifier(|err| match err {
+    ///         Error::Overloaded => ErrorClass::Overloaded,
+    ///         Error::BadRequest => ErrorClass::Fatal,
+    ///         Error::Unavailable => ErrorClass::Retryable,
+    ///     });
+    /// ```
+    pub fn with_error_classifier<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&Svc::Error) -> ErrorClass + Send + Sync + 'static,
+    {
+        self.classify = Some(Box::new(classify));
+        self
+    }
+
+    /// Stops calling the [`Service`] while it is failing, according to the [`CircuitBreaker`].
+    ///
+    /// While the circuit is open requests are written to the [`Spool`], if one is configured, and
+    /// dropped otherwise. Spooled requests are replayed once the circuit closes.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tracing_service::{CircuitBreaker, ServiceLayer};
+    /// # use tracing_subscriber::fmt::format::JsonVisitor;
+    /// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+    /// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+    /// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    /// let responses =
+    ///     responses.with_circuit_breaker(CircuitBreaker::new(5, Duration::from_secs(30)));
+    /// ```
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Returns the state of the [`CircuitBreaker`], if one is configured.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit_breaker.as_ref().map(CircuitBreaker::state)
+    }
+
+    /// Writes requests which fail, once any retries are exhausted, to the [`Spool`] and replays
+    /// them once the [`Service`] next succeeds.
+    ///
+    /// Replayed requests are only removed from the [`Spool`] once they have been delivered, or
+    /// written back to it after failing again.
+    ///
+    /// The same [`Spool`] is typically shared with the
+    /// [`ServiceLayer`](crate::ServiceLayer::with_spool), so that events overflowing the queue are
+    /// also replayed.
+    pub fn with_spool(mut self, spool: Spool<Request>) -> Self
+    where
+        Request: Clone,
+    {
+        self.clone_request = Some(Request::clone);
+        self.spool = Some(spool);
+        self
+    }
+}
+
+impl<Request, Svc, Source> ResponseStream<Request, Svc, Source>
+where
+    Svc: Flush<Request>,
+{
+    /// Sends the requests buffered by the [`Service`], see [`Flush`], when
+    /// [`Handle::flush`](crate::Handle::flush) is called, once the queue closes and once their
+    /// [`Flush::flush_deadline`] passes.
+    ///
+    /// The buffered requests are sent once every request enqueued before the flush has completed.
+    /// Their [`Service::Future`] counts towards the concurrency limit, see
+    /// [`ResponseStream::with_concurrency`].
+    pub fn with_buffer_flush(mut self) -> Self {
+        self.flush_buffered = Some(Svc::flush_buffered);
+        self.flush_deadline = Some(Svc::flush_deadline);
+        self
+    }
+}
+
+impl<Request, Svc, Source, E> ResponseStream<Request, Svc, Source>
+where
+    Svc: Service<Request, Error = RequestError<Request, E>>,
+{
+    /// Takes failed requests back from the [`RequestError`]s returned by the [`Service`], so they
+    /// can be retried or spooled, rather than cloning each request before it is called.
+    ///
+    /// This only avoids the clone if the [`Service`] returns the request it was called with, a
+    /// [`Service`] whose error is `(Request, E)` can be wrapped in a
+    /// [`RecoverRequest`](crate::RecoverRequest) to do so. Middleware which drops requests on
+    /// failure can be wrapped in a [`ReturnRequest`](crate::ReturnRequest) to return them, but it
+    /// clones every request to do so.
+    pub fn with_request_recovery(mut self) -> Self {
+        self.recover_request = Some(RequestError::take_request);
+        self
+    }
 }
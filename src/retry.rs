@@ -0,0 +1,254 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::ready;
+use pin_project_lite::pin_project;
+
+use crate::spool::Replayed;
+
+type Classifier<Error> = Box<dyn Fn(&Error) -> bool + Send + Sync>;
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type Timer = Box<dyn Fn(Duration) -> Sleep + Send + Sync>;
+
+/// A policy for retrying failed [`Service`](tower::Service) calls, see
+/// [`ResponseStream::with_retry`](crate::ResponseStream::with_retry).
+///
+/// Retries wait for an exponentially increasing backoff. This uses the timer provided with
+/// [`RetryPolicy::with_timer`], falling back to that of the stream, see
+/// [`ResponseStream::with_timer`](crate::ResponseStream::with_timer), and then to the tokio timer
+/// with the `tokio` feature enabled. Without any timer failed calls are not retried.
+pub struct RetryPolicy<Error> {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+    is_retryable: Option<Classifier<Error>>,
+    timer: Option<Timer>,
+}
+
+impl<Error> RetryPolicy<Error> {
+    /// Constructs a `RetryPolicy` which calls the [`Service`](tower::Service) at most
+    /// `max_attempts` times per request, including the first call.
+    ///
+    /// The backoff defaults to 100ms, doubling on each attempt, up to a maximum of 10s. All errors
+    /// are retried by default.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2,
+            is_retryable: None,
+            timer: None,
+        }
+    }
+
+    /// Sets the backoff before the first retry, and the maximum backoff between retries.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets the factor the backoff is multiplied by after each retry.
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Only retries errors for which `is_retryable` returns `true`.
+    pub fn with_classifier<F>(mut self, is_retryable: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.is_retryable = Some(Box::new(is_retryable));
+        self
+    }
+
+    /// Waits out backoffs using the future returned by `sleep`, for example `async_io::Timer`
+    /// when running on `smol`.
+    ///
+    /// This takes precedence over the timer of the stream. Without the `tokio` feature, and
+    /// without either timer, failed calls are not retried rather than being retried without a
+    /// backoff.
+    pub fn with_timer<F, Fut>(mut self, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.timer = Some(Box::new(move |duration| Box::pin(sleep(duration))));
+        self
+    }
+
+    /// Whether a request, which has been attempted `attempts` times, should be retried.
+    pub(crate) fn should_retry(&self, error: &Error, attempts: u32) -> bool {
+        attempts < self.max_attempts
+            && match &self.is_retryable {
+                Some(is_retryable) => is_retryable(error),
+                None => true,
+            }
+    }
+
+    /// The backoff before retrying a request which has been attempted `attempts` times.
+    pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Waits out the backoff before retrying a request which has been attempted `attempts` times,
+    /// using the timer of the stream if the policy has none. Returns `None` if there is no timer
+    /// to wait with.
+    pub(crate) fn sleep(&self, attempts: u32, stream_timer: Option<&Timer>) -> Option<Sleep> {
+        sleep(self.timer.as_ref().or(stream_timer), self.backoff(attempts))
+    }
+}
+
+/// Sleeps for `duration` using `timer`, or the tokio timer if the `tokio` feature is enabled.
+/// Returns `None` if there is no timer.
+pub(crate) fn sleep(timer: Option<&Timer>, duration: Duration) -> Option<Sleep> {
+    match timer {
+        Some(timer) => Some(timer(duration)),
+        #[cfg(feature = "tokio")]
+        None => Some(Box::pin(tokio::time::sleep(duration))),
+        #[cfg(not(feature = "tokio"))]
+        None => None,
+    }
+}
+
+impl<Error> fmt::Debug for RetryPolicy<Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .finish_non_exhaustive()
+    }
+}
+
+pin_project! {
+    /// A [`Service::Future`](tower::Service::Future) along with a copy of its request, if it may be
+    /// retried or spooled, the spooled request it replays, if any, the sequence number it was
+    /// received with, and the generation of the circuit breaker it was admitted under.
+    pub(crate) struct Call<Request, Fut> {
+        #[pin]
+        future: Fut,
+        request: Option<Request>,
+        attempts: u32,
+        replayed: Option<Replayed>,
+        sequence: u64,
+        generation: u64,
+    }
+}
+
+impl<Request, Fut> Call<Request, Fut> {
+    pub(crate) fn new(
+        future: Fut,
+        request: Option<Request>,
+        attempts: u32,
+        replayed: Option<Replayed>,
+        sequence: u64,
+    ) -> Self {
+        Self {
+            future,
+            request,
+            attempts,
+            replayed,
+            sequence,
+            generation: 0,
+        }
+    }
+
+    /// Sets the generation of the circuit breaker the call was admitted under.
+    pub(crate) fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    /// The sequence number the request was received with.
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl<Request, Fut> Future for Call<Request, Fut>
+where
+    Fut: Future,
+{
+    type Output = (
+        Fut::Output,
+        Option<Request>,
+        u32,
+        Option<Replayed>,
+        u64,
+        u64,
+    );
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = ready!(this.future.poll(cx));
+        Poll::Ready((
+            output,
+            this.request.take(),
+            *this.attempts,
+            *this.replayed,
+            *this.sequence,
+            *this.generation,
+        ))
+    }
+}
+
+/// A request waiting out its backoff before being retried.
+pub(crate) struct Backoff<Request> {
+    sleep: Sleep,
+    request: Option<Request>,
+    attempts: u32,
+    replayed: Option<Replayed>,
+    sequence: u64,
+}
+
+impl<Request> Backoff<Request> {
+    pub(crate) fn new(
+        sleep: Sleep,
+        request: Request,
+        attempts: u32,
+        replayed: Option<Replayed>,
+        sequence: u64,
+    ) -> Self {
+        Self {
+            sleep,
+            request: Some(request),
+            attempts,
+            replayed,
+            sequence,
+        }
+    }
+
+    /// The sequence number the request was received with.
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+// The request is never pinned
+impl<Request> Unpin for Backoff<Request> {}
+
+impl<Request> Future for Backoff<Request> {
+    type Output = (Request, u32, Option<Replayed>, u64);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(self.sleep.as_mut().poll(cx));
+        let request = self
+            .request
+            .take()
+            .expect("Backoff polled after completion");
+        Poll::Ready((request, self.attempts, self.replayed, self.sequence))
+    }
+}
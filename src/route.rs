@@ -0,0 +1,164 @@
+use std::{any::TypeId, fmt};
+
+use tracing_core::{
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Dispatch, Event, LevelFilter, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context as LayerContext, Layer};
+
+type Predicate = Box<dyn Fn(&Event<'_>) -> bool + Send + Sync>;
+
+/// A [`Layer`] which dispatches each event to the first branch whose predicate matches it.
+///
+/// Each branch is typically a [`ServiceLayer`](crate::ServiceLayer), with its own `Request` type,
+/// [`Service`](tower::Service) and [`ResponseStream`](crate::ResponseStream). Events which match
+/// no predicate are discarded. Span notifications are passed to every branch.
+///
+/// ```
+/// # use tracing_core::Level;
+/// # use tracing_service::{Router, ServiceLayer};
+/// # use tracing_subscriber::{fmt::format::JsonVisitor, layer::SubscriberExt};
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # let webhook = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// # let aggregator = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let (errors, error_responses) = ServiceLayer::new(webhook, make_visitor);
+/// let (everything, responses) = ServiceLayer::new(aggregator, make_visitor);
+///
+/// let router = Router::new()
+///     .route(|event| *event.metadata().level() == Level::ERROR, errors)
+///     .fallback(everything);
+/// let subscriber = tracing_subscriber::registry().with(router);
+/// ```
+pub struct Router<S> {
+    predicates: Vec<Predicate>,
+    branches: Vec<Box<dyn Layer<S> + Send + Sync>>,
+}
+
+impl<S> Router<S>
+where
+    S: Subscriber,
+{
+    /// Constructs a `Router` without any branches.
+    pub fn new() -> Self {
+        Self {
+            predicates: Vec::new(),
+            branches: Vec::new(),
+        }
+    }
+
+    /// Adds a branch, receiving the events which match `predicate` and no earlier branch.
+    pub fn route<P, L>(mut self, predicate: P, layer: L) -> Self
+    where
+        P: Fn(&Event<'_>) -> bool + Send + Sync + 'static,
+        L: Layer<S> + Send + Sync + 'static,
+    {
+        self.predicates.push(Box::new(predicate));
+        self.branches.push(Box::new(layer));
+        self
+    }
+
+    /// Adds a branch, receiving the events which match no earlier branch.
+    pub fn fallback<L>(self, layer: L) -> Self
+    where
+        L: Layer<S> + Send + Sync + 'static,
+    {
+        self.route(|_| true, layer)
+    }
+}
+
+impl<S> Default for Router<S>
+where
+    S: Subscriber,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> fmt::Debug for Router<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("branches", &self.branches.len())
+            .finish_non_exhaustive()
+    }
+}
+
+// Everything other than `on_event` is delegated to the `Layer` implementation of `Vec`, which
+// notifies every branch.
+impl<S> Layer<S> for Router<S>
+where
+    S: Subscriber,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.branches.on_register_dispatch(subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.branches.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.branches.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: LayerContext<'_, S>) -> bool {
+        self.branches.enabled(metadata, ctx)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.branches.max_level_hint()
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        self.branches.on_record(id, values, ctx);
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_follows_from(id, follows, ctx);
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) -> bool {
+        self.branches.event_enabled(event, ctx)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let branch = self
+            .predicates
+            .iter()
+            .position(|predicate| predicate(event));
+        if let Some(branch) = branch {
+            self.branches[branch].on_event(event, ctx);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: LayerContext<'_, S>) {
+        self.branches.on_id_change(old, new, ctx);
+    }
+
+    #[doc(hidden)]
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const _ as *const ())
+        } else {
+            // Allows per-layer filters within the branches to be detected
+            unsafe { self.branches.downcast_raw(id) }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use tracing_core::Level;
+
+/// The fraction of events, at a given [`Level`], which are sent to the
+/// [`Service`](tower::Service), see [`ServiceLayer::with_sample_rate`](crate::ServiceLayer::with_sample_rate).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum SampleRate {
+    /// Every event is sent.
+    All,
+    /// Every `n`th event is sent, starting with the first.
+    OneIn(u64),
+    /// Each event is sent with the given probability, between `0.0` and `1.0`.
+    Probability(f64),
+}
+
+/// Decides which events are sent, according to the [`SampleRate`] of each level.
+#[derive(Debug, Default)]
+pub(crate) struct Sampler {
+    rates: RwLock<[Option<SampleRate>; 5]>,
+    counters: [AtomicU64; 5],
+}
+
+impl Sampler {
+    pub(crate) fn set(&self, level: Level, rate: Option<SampleRate>) {
+        let mut rates = self.rates.write().unwrap_or_else(|err| err.into_inner());
+        rates[index(level)] = rate;
+    }
+
+    /// Returns `true` if an event at `level` should be sent.
+    pub(crate) fn sample(&self, level: &Level) -> bool {
+        let index = index(*level);
+        let rate = self.rates.read().unwrap_or_else(|err| err.into_inner())[index];
+        match rate {
+            None | Some(SampleRate::All) => true,
+            Some(SampleRate::OneIn(n)) => {
+                let count = self.counters[index].fetch_add(1, Ordering::Relaxed);
+                count % n.max(1) == 0
+            }
+            Some(SampleRate::Probability(probability)) => random() < probability,
+        }
+    }
+}
+
+fn index(level: Level) -> usize {
+    match level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
+    }
+}
+
+/// Returns a number in `[0, 1)` from a per-thread xorshift generator.
+fn random() -> f64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::Waker,
+};
+
+use futures_util::task::AtomicWaker;
+
+use crate::{notify::Notify, LayerMetrics};
+
+/// The outcome of shutting down a [`ResponseStream`](crate::ResponseStream), see
+/// [`ShutdownHandle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ShutdownReport {
+    /// The number of requests which completed after the shutdown began.
+    pub flushed: u64,
+    /// The number of requests which were queued, in flight, or waiting to be retried when the
+    /// stream was aborted.
+    pub abandoned: u64,
+    /// Whether the stream was aborted before it finished draining.
+    pub aborted: bool,
+}
+
+/// A handle which gracefully shuts down a [`ResponseStream`](crate::ResponseStream), see
+/// [`ResponseStream::shutdown_handle`](crate::ResponseStream::shutdown_handle).
+///
+/// Shutting down stops the queue from accepting new events, while the stream continues to send
+/// the requests which are already queued or in flight. The stream must still be polled for this
+/// to make progress.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use tracing_service::ServiceLayer;
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # async fn run() {
+/// # let service = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+/// let shutdown = responses.shutdown_handle();
+/// tokio::spawn(responses.drain());
+///
+/// // On SIGTERM
+/// let report = shutdown.shutdown(Duration::from_secs(5)).await;
+/// eprintln!("flushed {}, abandoned {}", report.flushed, report.abandoned);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+    metrics: LayerMetrics,
+}
+
+/// The state shared between a [`ShutdownHandle`] and its stream.
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    close: AtomicBool,
+    aborted: AtomicBool,
+    finished: AtomicBool,
+    flushed: AtomicU64,
+    outstanding: AtomicU64,
+    waker: AtomicWaker,
+    notify: Notify,
+}
+
+impl State {
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    /// Returns `true`, once, if the stream has been asked to close.
+    pub(crate) fn take_close(&self) -> bool {
+        self.close.swap(false, Ordering::AcqRel)
+    }
+
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn record_flushed(&self) {
+        self.flushed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the number of requests which are in flight, or waiting to be retried or called.
+    pub(crate) fn set_outstanding(&self, outstanding: usize) {
+        self.outstanding
+            .store(outstanding as u64, Ordering::Relaxed);
+    }
+
+    /// Marks the stream as terminated, waking any tasks waiting on [`ShutdownHandle::drained`].
+    pub(crate) fn finish(&self) {
+        if !self.finished.swap(true, Ordering::AcqRel) {
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(state: Arc<State>, metrics: LayerMetrics) -> Self {
+        Self { state, metrics }
+    }
+
+    /// Stops the queue from accepting new events, the stream then terminates once the requests
+    /// already queued and in flight have been sent.
+    pub fn close(&self) {
+        self.state.close.store(true, Ordering::Release);
+        self.state.waker.wake();
+    }
+
+    /// Waits for the stream to terminate.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.state.notify.notified();
+            if self.state.finished.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Terminates the stream the next time it is polled, abandoning the requests which are still
+    /// queued, in flight, or waiting to be retried.
+    pub fn abort(&self) -> ShutdownReport {
+        let aborted = !self.state.finished.load(Ordering::Acquire);
+        if aborted {
+            self.state.aborted.store(true, Ordering::Release);
+            self.state.waker.wake();
+        }
+        self.report(aborted)
+    }
+
+    /// Closes the queue and waits up to `timeout` for the stream to drain, aborting it otherwise.
+    #[cfg(feature = "tokio")]
+    pub async fn shutdown(&self, timeout: std::time::Duration) -> ShutdownReport {
+        self.close();
+        match tokio::time::timeout(timeout, self.drained()).await {
+            Ok(()) => self.report(false),
+            Err(_) => self.abort(),
+        }
+    }
+
+    fn report(&self, aborted: bool) -> ShutdownReport {
+        let abandoned = if aborted {
+            self.state.outstanding.load(Ordering::Relaxed) + self.metrics.queue_depth()
+        } else {
+            0
+        };
+        ShutdownReport {
+            flushed: self.state.flushed.load(Ordering::Relaxed),
+            abandoned,
+            aborted,
+        }
+    }
+}
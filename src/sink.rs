@@ -0,0 +1,236 @@
+use std::{
+    fmt,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_util::StreamExt;
+#[cfg(feature = "tokio")]
+use tokio::sync::mpsc;
+use tracing_core::Level;
+
+use crate::LayerMetrics;
+
+/// The error returned by [`EventSink::try_send`].
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<Request> {
+    /// The queue is full.
+    Full(Request),
+    /// The queue is closed.
+    Closed(Request),
+}
+
+impl<Request> TrySendError<Request> {
+    /// Returns the request which failed to send.
+    pub fn into_inner(self) -> Request {
+        match self {
+            Self::Full(request) | Self::Closed(request) => request,
+        }
+    }
+
+    /// Maps the request which failed to send, keeping the reason.
+    pub(crate) fn map<T>(self, f: impl FnOnce(Request) -> T) -> TrySendError<T> {
+        match self {
+            Self::Full(request) => TrySendError::Full(f(request)),
+            Self::Closed(request) => TrySendError::Closed(f(request)),
+        }
+    }
+}
+
+impl<Request> fmt::Debug for TrySendError<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<Request> fmt::Display for TrySendError<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("queue is full"),
+            Self::Closed(_) => f.write_str("queue is closed"),
+        }
+    }
+}
+
+impl<Request> std::error::Error for TrySendError<Request> {}
+
+#[cfg(feature = "tokio")]
+impl<Request> From<mpsc::error::TrySendError<Request>> for TrySendError<Request> {
+    fn from(err: mpsc::error::TrySendError<Request>) -> Self {
+        match err {
+            mpsc::error::TrySendError::Full(request) => Self::Full(request),
+            mpsc::error::TrySendError::Closed(request) => Self::Closed(request),
+        }
+    }
+}
+
+/// The sending half of the queue between a [`ServiceLayer`](crate::ServiceLayer) and its
+/// [`ResponseStream`](crate::ResponseStream).
+///
+/// This is called from within [`Layer::on_event`](tracing_subscriber::Layer::on_event) and must
+/// not block.
+pub trait EventSink<Request> {
+    /// Attempts to immediately send a request.
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>>;
+
+    /// Attempts to immediately send a request constructed from an event at `level`.
+    ///
+    /// This allows sinks to prioritize events by severity, see
+    /// [`priority_channel`](crate::priority_channel). It defaults to [`EventSink::try_send`].
+    fn try_send_with_level(
+        &self,
+        request: Request,
+        level: &Level,
+    ) -> Result<(), TrySendError<Request>> {
+        let _ = level;
+        self.try_send(request)
+    }
+
+    /// Attempts to immediately send a request constructed from an event at `level` to each queue
+    /// behind the sink, passing the error for each queue which did not accept it to `on_error`.
+    ///
+    /// Returns `true` if any queue accepted the request. This allows sinks which send to several
+    /// queues, such as [`Tee`](crate::Tee), to report which of them failed. It defaults to
+    /// [`EventSink::try_send_with_level`].
+    fn try_send_each(
+        &self,
+        request: Request,
+        level: &Level,
+        on_error: &mut dyn FnMut(TrySendError<Request>),
+    ) -> bool {
+        match self.try_send_with_level(request, level) {
+            Ok(()) => true,
+            Err(err) => {
+                on_error(err);
+                false
+            }
+        }
+    }
+
+    /// The [`LayerMetrics`] of the queue behind the sink, if the sink counts the requests it
+    /// accepts in them.
+    ///
+    /// A [`ServiceLayer`](crate::ServiceLayer) constructed from the sink records into these
+    /// metrics, so [`Handle::flush`](crate::Handle::flush) can wait for the queue to drain. It
+    /// defaults to `None`, in which case the layer counts the requests itself and its metrics
+    /// should be passed to [`ResponseStream::with_metrics`](crate::ResponseStream::with_metrics).
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        None
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Request> EventSink<Request> for mpsc::Sender<Request> {
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        mpsc::Sender::try_send(self, request).map_err(Into::into)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Request> EventSink<Request> for mpsc::UnboundedSender<Request> {
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        self.send(request)
+            .map_err(|mpsc::error::SendError(request)| TrySendError::Closed(request))
+    }
+}
+
+/// The receiving half of the queue between a [`ServiceLayer`](crate::ServiceLayer) and its
+/// [`ResponseStream`](crate::ResponseStream).
+pub trait EventSource<Request> {
+    /// Polls for the next request, returning `None` once the queue is closed and empty.
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>>;
+
+    /// Stops the queue from accepting new requests, those already queued are still received.
+    fn close(&mut self);
+
+    /// The [`LayerMetrics`] of the queue, if its sink counts the requests it accepts in them, see
+    /// [`EventSink::metrics`].
+    ///
+    /// A [`ResponseStream`](crate::ResponseStream) constructed from the source records into
+    /// these metrics. It defaults to `None`.
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        None
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Request> EventSource<Request> for mpsc::Receiver<Request> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        mpsc::Receiver::poll_recv(self, cx)
+    }
+
+    fn close(&mut self) {
+        mpsc::Receiver::close(self)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Request> EventSource<Request> for mpsc::UnboundedReceiver<Request> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        mpsc::UnboundedReceiver::poll_recv(self, cx)
+    }
+
+    fn close(&mut self) {
+        mpsc::UnboundedReceiver::close(self)
+    }
+}
+
+/// An [`EventSource`] backed by a [`Stream`], such as the receiving half of a `flume` or
+/// `async-channel` channel.
+///
+/// A [`Stream`] cannot be closed, so once [`EventSource::close`] is called the requests which are
+/// immediately available are still received, and the source ends the first time the [`Stream`]
+/// has none ready. Requests sent after that are not received.
+#[derive(Debug)]
+pub struct StreamSource<S> {
+    stream: S,
+    closed: bool,
+    // Whether the stream had no requests ready after it was closed
+    drained: bool,
+}
+
+impl<S> StreamSource<S> {
+    /// Constructs a `StreamSource` from a [`Stream`] of requests.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            closed: false,
+            drained: false,
+        }
+    }
+
+    /// Returns the inner [`Stream`].
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<Request, S> EventSource<Request> for StreamSource<S>
+where
+    S: Stream<Item = Request> + Unpin,
+{
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        if self.drained {
+            return Poll::Ready(None);
+        }
+        match self.stream.poll_next_unpin(cx) {
+            // Once closed, the queue is drained when no more requests are ready
+            Poll::Pending if self.closed => {
+                self.drained = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(None) => {
+                self.drained = true;
+                Poll::Ready(None)
+            }
+            poll => poll,
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+
+use tracing_core::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Metadata,
+};
+
+/// A span in the scope of an event, see
+/// [`ServiceLayer::with_span_scope`](crate::ServiceLayer::with_span_scope).
+#[derive(Debug)]
+pub struct ScopeSpan<'a> {
+    pub(crate) id: Id,
+    pub(crate) metadata: &'static Metadata<'static>,
+    pub(crate) fields: Option<&'a SpanFields>,
+}
+
+impl ScopeSpan<'_> {
+    /// Returns the span's ID.
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Returns the span's name.
+    pub fn name(&self) -> &'static str {
+        self.metadata.name()
+    }
+
+    /// Returns the span's [`Metadata`].
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+
+    /// Visits the fields recorded on the span.
+    pub fn record(&self, visitor: &mut dyn Visit) {
+        if let Some(fields) = self.fields {
+            fields.record(visitor);
+        }
+    }
+}
+
+/// The fields recorded on a span, stored in its registry extensions.
+#[derive(Debug, Default)]
+pub(crate) struct SpanFields(Vec<(Field, FieldValue)>);
+
+#[derive(Debug)]
+enum FieldValue {
+    F64(f64),
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    Bool(bool),
+    Str(String),
+    Debug(String),
+}
+
+impl SpanFields {
+    pub(crate) fn new(attributes: &Attributes<'_>) -> Self {
+        let mut fields = Self::default();
+        attributes.record(&mut fields);
+        fields
+    }
+
+    pub(crate) fn update(&mut self, values: &Record<'_>) {
+        values.record(self);
+    }
+
+    pub(crate) fn record(&self, visitor: &mut dyn Visit) {
+        for (field, value) in &self.0 {
+            match value {
+                FieldValue::F64(value) => visitor.record_f64(field, *value),
+                FieldValue::I64(value) => visitor.record_i64(field, *value),
+                FieldValue::U64(value) => visitor.record_u64(field, *value),
+                FieldValue::I128(value) => visitor.record_i128(field, *value),
+                FieldValue::U128(value) => visitor.record_u128(field, *value),
+                FieldValue::Bool(value) => visitor.record_bool(field, *value),
+                FieldValue::Str(value) => visitor.record_str(field, value),
+                FieldValue::Debug(value) => visitor.record_debug(field, &format_args!("{value}")),
+            }
+        }
+    }
+
+    fn insert(&mut self, field: &Field, value: FieldValue) {
+        // Values recorded after construction replace the existing value
+        match self.0.iter_mut().find(|(existing, _)| existing == field) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((field.clone(), value)),
+        }
+    }
+}
+
+impl Visit for SpanFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, FieldValue::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, FieldValue::U64(value));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.insert(field, FieldValue::I128(value));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.insert(field, FieldValue::U128(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, FieldValue::Str(value.into()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, FieldValue::Debug(format!("{value:?}")));
+    }
+}
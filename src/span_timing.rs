@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use crate::ScopeSpan;
+
+/// A closed span, along with the time it spent entered and idle, see
+/// [`ServiceLayer::with_span_timing`](crate::ServiceLayer::with_span_timing).
+#[derive(Debug)]
+pub struct SpanTiming<'a> {
+    pub(crate) span: ScopeSpan<'a>,
+    pub(crate) busy: Duration,
+    pub(crate) idle: Duration,
+}
+
+impl<'a> SpanTiming<'a> {
+    /// Returns the span, whose name and fields can be recorded.
+    pub fn span(&self) -> &ScopeSpan<'a> {
+        &self.span
+    }
+
+    /// Returns the total time the span was entered.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    /// Returns the total time the span was open but not entered.
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    /// Returns the time between the span being created and closed.
+    pub fn elapsed(&self) -> Duration {
+        self.busy + self.idle
+    }
+}
+
+/// The time a span has spent entered and idle, stored in its registry extensions.
+///
+/// Each transition adds the time since the previous one, so the timings are not double counted
+/// when several layers update them.
+#[derive(Debug)]
+pub(crate) struct Timings {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+}
+
+impl Timings {
+    pub(crate) fn new() -> Self {
+        Self {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn enter(&mut self) {
+        let now = Instant::now();
+        self.idle += now.saturating_duration_since(self.last);
+        self.last = now;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        let now = Instant::now();
+        self.busy += now.saturating_duration_since(self.last);
+        self.last = now;
+    }
+
+    /// Returns the busy and idle time, once the span has closed.
+    pub(crate) fn close(&mut self) -> (Duration, Duration) {
+        self.enter();
+        (self.busy, self.idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn accumulates_busy_and_idle_time() {
+        let mut timings = Timings::new();
+        sleep(Duration::from_millis(5));
+        timings.enter();
+        sleep(Duration::from_millis(10));
+        timings.exit();
+        sleep(Duration::from_millis(5));
+
+        let (busy, idle) = timings.close();
+        assert!(busy >= Duration::from_millis(10));
+        assert!(idle >= Duration::from_millis(10));
+        assert!(busy < idle + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn repeated_transitions_are_not_double_counted() {
+        let mut timings = Timings::new();
+        timings.enter();
+        sleep(Duration::from_millis(10));
+        timings.exit();
+        // Another layer recording the same span
+        timings.exit();
+
+        let (busy, idle) = timings.close();
+        assert!(busy >= Duration::from_millis(10));
+        assert!(idle < Duration::from_millis(10));
+    }
+}
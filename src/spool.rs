@@ -0,0 +1,659 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Encodes and decodes requests written to a [`Spool`].
+pub trait SpoolCodec<Request> {
+    /// Encodes a request.
+    fn encode(&self, request: &Request) -> Vec<u8>;
+
+    /// Decodes a request, returning `None` if the bytes are invalid.
+    fn decode(&self, bytes: Vec<u8>) -> Option<Request>;
+}
+
+/// A [`SpoolCodec`] for [`String`] requests, which are spooled as UTF-8.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8Codec;
+
+impl SpoolCodec<String> for Utf8Codec {
+    fn encode(&self, request: &String) -> Vec<u8> {
+        request.as_bytes().to_vec()
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Option<String> {
+        String::from_utf8(bytes).ok()
+    }
+}
+
+/// A file backed queue which requests are written to when they cannot be delivered, and replayed
+/// from once the [`Service`](tower::Service) recovers.
+///
+/// A `Spool` is shared by cloning, see [`ServiceLayer::with_spool`](crate::ServiceLayer::with_spool)
+/// and [`ResponseStream::with_spool`](crate::ResponseStream::with_spool). Requests remaining in the
+/// spool file when the process exits are replayed by the next `Spool` opened at the same path.
+///
+/// A replayed request stays in the spool file until the [`ResponseStream`](crate::ResponseStream)
+/// has delivered it, or written it back to the spool after it failed. How far the spool file has
+/// been delivered is kept alongside it, in a file with an `.offset` suffix, so requests are not
+/// replayed twice. Requests which were being replayed when the process exited are replayed again.
+///
+/// Delivered requests are removed once every request in the spool file has been delivered, or by
+/// compacting the spool file once it grows to twice the maximum size. A record which was only
+/// partially written, for example because the process crashed, is truncated from the end of the
+/// spool file along with any records after it.
+///
+/// Spooling performs blocking file I/O.
+pub struct Spool<Request> {
+    file: Arc<Mutex<SpoolFile>>,
+    codec: Arc<dyn SpoolCodec<Request> + Send + Sync>,
+}
+
+/// A request replayed from a [`Spool`], which stays in the spool file until it is committed, see
+/// [`Spool::commit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Replayed(u64);
+
+struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    // Holds `generation` and `committed`, so replay resumes where it left off when the spool is
+    // reopened
+    offset_file: File,
+    // Changes each time the spool file is compacted, so an offset into an earlier spool file is
+    // not used
+    generation: u64,
+    // The offset of the first record which has yet to be delivered
+    committed: u64,
+    // The offset of the next record to replay
+    read_offset: u64,
+    // The end of each record being replayed, in the order they were read, and whether it has been
+    // committed
+    replaying: VecDeque<(u64, bool)>,
+    // The `Replayed` of the record at the front of `replaying`
+    first_replayed: u64,
+    len: u64,
+    max_len: u64,
+}
+
+/// The length of the prefix holding the length of each record.
+const HEADER_LEN: u64 = 4;
+
+/// The length of the generation at the start of the spool file.
+const GENERATION_LEN: u64 = 8;
+
+impl<Request> Spool<Request> {
+    /// Opens, or creates, the spool file at `path` which may hold at most `max_bytes` of requests
+    /// waiting to be replayed.
+    pub fn open<C>(path: impl AsRef<Path>, max_bytes: u64, codec: C) -> io::Result<Self>
+    where
+        C: SpoolCodec<Request> + Send + Sync + 'static,
+    {
+        let path = path.as_ref();
+        let file = open_spool_file(path)?;
+        let offset_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(suffixed(path, ".offset"))?;
+
+        let mut spool = SpoolFile {
+            path: path.to_owned(),
+            file,
+            offset_file,
+            generation: 0,
+            committed: GENERATION_LEN,
+            read_offset: GENERATION_LEN,
+            replaying: VecDeque::new(),
+            first_replayed: 0,
+            len: 0,
+            max_len: max_bytes,
+        };
+        spool.load_generation()?;
+        match spool.load_offset()? {
+            Some(offset) => {
+                spool.committed = offset;
+                spool.read_offset = offset;
+            }
+            // The offset belongs to an earlier spool file, or the spool file was truncated by
+            // something else
+            None => spool.store_offset(GENERATION_LEN)?,
+        }
+        spool.truncate_invalid_tail()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(spool)),
+            codec: Arc::new(codec),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpoolFile> {
+        self.file.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The number of bytes in the spool file which have yet to be replayed.
+    pub fn pending_bytes(&self) -> u64 {
+        let file = self.lock();
+        file.len - file.read_offset
+    }
+
+    /// Returns `true` if there are no requests waiting to be replayed.
+    pub fn is_empty(&self) -> bool {
+        self.pending_bytes() == 0
+    }
+
+    /// Appends a request, returning `false` if it would exceed the maximum size.
+    pub(crate) fn push(&self, request: &Request) -> io::Result<bool> {
+        let bytes = self.codec.encode(request);
+        let record_len = u32::try_from(bytes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "request is too large"))?;
+        let record_len = HEADER_LEN + u64::from(record_len);
+
+        let mut file = self.lock();
+        if file.len - file.read_offset + record_len > file.max_len {
+            return Ok(false);
+        }
+        if file.len + record_len > file.max_len.saturating_mul(2) {
+            file.compact()?;
+        }
+
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(&bytes);
+        if let Err(err) = file.file.write_all(&record) {
+            // Remove any part of the record which was written, so it is not read as a record
+            let previous = file.len;
+            let _ = file.file.set_len(previous);
+            return Err(err);
+        }
+        file.len += record_len;
+        Ok(true)
+    }
+
+    /// Reads the oldest request which has yet to be replayed.
+    ///
+    /// The request stays in the spool file, and is replayed again if the spool is reopened, until
+    /// it is committed using [`Spool::commit`].
+    pub(crate) fn pop(&self) -> io::Result<Option<(Request, Replayed)>> {
+        let mut file = self.lock();
+        loop {
+            if file.read_offset >= file.len {
+                return Ok(None);
+            }
+
+            let offset = file.read_offset;
+            let Some(bytes) = file.read_record(offset)? else {
+                // The rest of the spool file is invalid, discard it
+                file.truncate(offset)?;
+                continue;
+            };
+            file.read_offset = offset + HEADER_LEN + bytes.len() as u64;
+            let replayed = file.replaying();
+
+            // Skip over records which cannot be decoded
+            match self.codec.decode(bytes) {
+                Some(request) => return Ok(Some((request, replayed))),
+                None => file.commit(replayed)?,
+            }
+        }
+    }
+
+    /// Removes a replayed request from the spool file, once every request replayed before it has
+    /// also been committed.
+    pub(crate) fn commit(&self, replayed: Replayed) -> io::Result<()> {
+        self.lock().commit(replayed)
+    }
+}
+
+impl SpoolFile {
+    /// Reads the record at `offset`, returning `None` if it is incomplete or its length prefix is
+    /// invalid.
+    fn read_record(&mut self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let Some(record_len) = self.read_record_len(offset)? else {
+            return Ok(None);
+        };
+        let mut bytes = vec![0; record_len as usize];
+        match self.file.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the length prefix of the record at `offset`, returning `None` if the record does not
+    /// fit within the spool file.
+    ///
+    /// Records never extend past the end of the spool file, so a corrupt length prefix cannot
+    /// cause an arbitrarily large allocation.
+    fn read_record_len(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        if offset + HEADER_LEN > self.len {
+            return Ok(None);
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut record_len = [0; HEADER_LEN as usize];
+        self.file.read_exact(&mut record_len)?;
+        let record_len = u64::from(u32::from_le_bytes(record_len));
+        let fits = offset + HEADER_LEN + record_len <= self.len;
+        Ok(fits.then_some(record_len))
+    }
+
+    /// Walks the records which have yet to be replayed, truncating the spool file after the last
+    /// complete record.
+    fn truncate_invalid_tail(&mut self) -> io::Result<()> {
+        let mut offset = self.read_offset;
+        while offset < self.len {
+            match self.read_record_len(offset)? {
+                Some(record_len) => offset += HEADER_LEN + record_len,
+                None => break,
+            }
+        }
+        if offset < self.len {
+            self.truncate(offset)?;
+        }
+        Ok(())
+    }
+
+    /// Truncates the spool file to `len`, emptying it if every remaining record has been delivered.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        self.empty_if_delivered()
+    }
+
+    /// Empties the spool file once every record in it has been delivered.
+    fn empty_if_delivered(&mut self) -> io::Result<()> {
+        if self.committed < self.len || !self.replaying.is_empty() {
+            return Ok(());
+        }
+        self.file.set_len(GENERATION_LEN)?;
+        self.len = GENERATION_LEN;
+        self.read_offset = GENERATION_LEN;
+        self.store_offset(GENERATION_LEN)
+    }
+
+    /// Records that the record ending at `read_offset` is being replayed.
+    fn replaying(&mut self) -> Replayed {
+        self.replaying.push_back((self.read_offset, false));
+        Replayed(self.first_replayed + self.replaying.len() as u64 - 1)
+    }
+
+    fn commit(&mut self, replayed: Replayed) -> io::Result<()> {
+        let Some(index) = replayed.0.checked_sub(self.first_replayed) else {
+            return Ok(());
+        };
+        match self.replaying.get_mut(index as usize) {
+            Some((_, committed)) => *committed = true,
+            None => return Ok(()),
+        }
+
+        // Records are removed in order, so those replayed concurrently can complete in any order
+        let mut committed = self.committed;
+        while let Some(&(end, true)) = self.replaying.front() {
+            committed = end;
+            self.replaying.pop_front();
+            self.first_replayed += 1;
+        }
+        if committed != self.committed {
+            self.store_offset(committed)?;
+            self.empty_if_delivered()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the spool file with one holding only the records which have yet to be delivered.
+    ///
+    /// The records are written to a new file, which is renamed over the spool file, so the spool
+    /// file is intact if the process exits part way through. The new spool file has a new
+    /// generation, so the offset of the old spool file is not used if the process exits before
+    /// the offset is stored.
+    fn compact(&mut self) -> io::Result<()> {
+        let removed = self.committed - GENERATION_LEN;
+        if removed == 0 {
+            return Ok(());
+        }
+        let mut records = vec![0; (self.len - self.committed) as usize];
+        self.file.seek(SeekFrom::Start(self.committed))?;
+        self.file.read_exact(&mut records)?;
+
+        let generation = self.generation.wrapping_add(1);
+        let compact_path = suffixed(&self.path, ".compact");
+        let mut compacted = File::create(&compact_path)?;
+        compacted.write_all(&generation.to_le_bytes())?;
+        compacted.write_all(&records)?;
+        drop(compacted);
+        std::fs::rename(&compact_path, &self.path)?;
+
+        self.file = open_spool_file(&self.path)?;
+        self.generation = generation;
+        self.len -= removed;
+        self.read_offset -= removed;
+        for (end, _) in &mut self.replaying {
+            *end -= removed;
+        }
+        self.store_offset(GENERATION_LEN)
+    }
+
+    /// Reads the generation of the spool file, writing one if the spool file is new.
+    fn load_generation(&mut self) -> io::Result<()> {
+        self.len = self.file.metadata()?.len();
+        if self.len < GENERATION_LEN {
+            self.file.set_len(0)?;
+            self.file.write_all(&self.generation.to_le_bytes())?;
+            self.len = GENERATION_LEN;
+            return Ok(());
+        }
+        let mut generation = [0; GENERATION_LEN as usize];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut generation)?;
+        self.generation = u64::from_le_bytes(generation);
+        Ok(())
+    }
+
+    /// Reads the stored offset, returning `None` if it is missing or does not belong to the spool
+    /// file.
+    fn load_offset(&mut self) -> io::Result<Option<u64>> {
+        let mut stored = [0; 16];
+        self.offset_file.seek(SeekFrom::Start(0))?;
+        match self.offset_file.read_exact(&mut stored) {
+            Ok(()) => {}
+            // The offset file was just created, or was never fully written
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let (generation, offset) = stored.split_at(8);
+        let generation = u64::from_le_bytes(generation.try_into().unwrap());
+        let offset = u64::from_le_bytes(offset.try_into().unwrap());
+        let valid = generation == self.generation && (GENERATION_LEN..=self.len).contains(&offset);
+        Ok(valid.then_some(offset))
+    }
+
+    fn store_offset(&mut self, offset: u64) -> io::Result<()> {
+        self.committed = offset;
+        let mut stored = [0; 16];
+        stored[..8].copy_from_slice(&self.generation.to_le_bytes());
+        stored[8..].copy_from_slice(&offset.to_le_bytes());
+        self.offset_file.seek(SeekFrom::Start(0))?;
+        self.offset_file.write_all(&stored)
+    }
+}
+
+fn open_spool_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+impl<Request> Clone for Spool<Request> {
+    fn clone(&self) -> Self {
+        Self {
+            file: self.file.clone(),
+            codec: self.codec.clone(),
+        }
+    }
+}
+
+impl<Request> fmt::Debug for Spool<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spool")
+            .field("pending_bytes", &self.pending_bytes())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A spool file path, which is removed along with its offset file when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "tracing-service-{}-{name}.spool",
+                std::process::id()
+            ));
+            let path = Self(path);
+            path.remove();
+            path
+        }
+
+        fn offset_path(&self) -> PathBuf {
+            suffixed(&self.0, ".offset")
+        }
+
+        fn remove(&self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(self.offset_path());
+            let _ = fs::remove_file(suffixed(&self.0, ".compact"));
+        }
+
+        fn len(&self) -> u64 {
+            fs::metadata(&self.0).unwrap().len()
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn open(path: &TempPath) -> Spool<String> {
+        Spool::open(path, 1024, Utf8Codec).unwrap()
+    }
+
+    fn push(spool: &Spool<String>, requests: &[&str]) {
+        for request in requests {
+            assert!(spool.push(&request.to_string()).unwrap());
+        }
+    }
+
+    /// Replays and commits one request.
+    fn pop(spool: &Spool<String>) -> Option<String> {
+        let (request, replayed) = spool.pop().unwrap()?;
+        spool.commit(replayed).unwrap();
+        Some(request)
+    }
+
+    fn drain(spool: &Spool<String>) -> Vec<String> {
+        std::iter::from_fn(|| pop(spool)).collect()
+    }
+
+    #[test]
+    fn replays_requests_in_order() {
+        let path = TempPath::new("order");
+        let spool = open(&path);
+        assert!(spool.is_empty());
+        push(&spool, &["a", "bb", "ccc"]);
+        assert_eq!(spool.pending_bytes(), 3 * HEADER_LEN + 6);
+        assert_eq!(drain(&spool), ["a", "bb", "ccc"]);
+        assert!(spool.is_empty());
+        // The spool file is emptied once it has been fully delivered
+        assert_eq!(path.len(), GENERATION_LEN);
+    }
+
+    #[test]
+    fn rejects_requests_beyond_max_bytes() {
+        let path = TempPath::new("max-bytes");
+        let spool = Spool::open(&path, 2 * HEADER_LEN + 8, Utf8Codec).unwrap();
+        push(&spool, &["1234", "5678"]);
+        assert!(!spool.push(&"9".to_owned()).unwrap());
+
+        // Only requests waiting to be replayed count towards the maximum size
+        assert_eq!(pop(&spool).as_deref(), Some("1234"));
+        push(&spool, &["9"]);
+        assert_eq!(drain(&spool), ["5678", "9"]);
+    }
+
+    #[test]
+    fn resumes_replay_when_reopened() {
+        let path = TempPath::new("reopen");
+        let spool = open(&path);
+        push(&spool, &["a", "b", "c"]);
+        assert_eq!(pop(&spool).as_deref(), Some("a"));
+        drop(spool);
+
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["b", "c"]);
+    }
+
+    #[test]
+    fn replays_uncommitted_requests_again_when_reopened() {
+        let path = TempPath::new("uncommitted");
+        let spool = open(&path);
+        push(&spool, &["a", "b", "c"]);
+        let (a, _) = spool.pop().unwrap().unwrap();
+        let (b, replayed_b) = spool.pop().unwrap().unwrap();
+        assert_eq!([a, b], ["a", "b"]);
+        // Requests replayed concurrently may be delivered out of order
+        spool.commit(replayed_b).unwrap();
+        drop(spool);
+
+        let spool = open(&path);
+        let (a, replayed_a_again) = spool.pop().unwrap().unwrap();
+        assert_eq!(a, "a");
+        spool.commit(replayed_a_again).unwrap();
+        drop(spool);
+
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["b", "c"]);
+    }
+
+    #[test]
+    fn compacts_delivered_requests() {
+        let path = TempPath::new("compact");
+        let record_len = HEADER_LEN + 4;
+        let spool = Spool::open(&path, 2 * record_len, Utf8Codec).unwrap();
+        push(&spool, &["0000", "1111"]);
+        pop(&spool).unwrap();
+        push(&spool, &["2222"]);
+        assert_eq!(path.len(), GENERATION_LEN + 3 * record_len);
+
+        pop(&spool).unwrap();
+        let (replaying, _) = spool.pop().unwrap().unwrap();
+        assert_eq!(replaying, "2222");
+        // The spool file would exceed twice the maximum size, so the delivered requests are
+        // removed, keeping the request being replayed
+        push(&spool, &["3333"]);
+        assert_eq!(path.len(), GENERATION_LEN + 2 * record_len);
+        drop(spool);
+
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["2222", "3333"]);
+    }
+
+    #[test]
+    fn commits_replayed_requests_after_compaction() {
+        let path = TempPath::new("compact-commit");
+        let record_len = HEADER_LEN + 4;
+        let spool = Spool::open(&path, 2 * record_len, Utf8Codec).unwrap();
+        push(&spool, &["0000", "1111"]);
+        pop(&spool).unwrap();
+        push(&spool, &["2222"]);
+        pop(&spool).unwrap();
+        let (_, replayed) = spool.pop().unwrap().unwrap();
+        push(&spool, &["3333"]);
+
+        spool.commit(replayed).unwrap();
+        drop(spool);
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["3333"]);
+    }
+
+    #[test]
+    fn truncates_partially_written_records() {
+        let path = TempPath::new("partial");
+        let spool = open(&path);
+        push(&spool, &["complete"]);
+        drop(spool);
+
+        // A record whose length prefix claims more bytes than were written
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(b"torn").unwrap();
+        drop(file);
+
+        let spool = open(&path);
+        assert_eq!(spool.pending_bytes(), HEADER_LEN + 8);
+        assert_eq!(drain(&spool), ["complete"]);
+    }
+
+    #[test]
+    fn ignores_invalid_offsets() {
+        let path = TempPath::new("offset");
+        let spool = open(&path);
+        push(&spool, &["a"]);
+        drop(spool);
+
+        let mut offset = [0; 16];
+        offset[8..].copy_from_slice(&1_000u64.to_le_bytes());
+        fs::write(path.offset_path(), offset).unwrap();
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["a"]);
+    }
+
+    #[test]
+    fn ignores_offsets_of_other_generations() {
+        let path = TempPath::new("generation");
+        let spool = open(&path);
+        push(&spool, &["a", "b"]);
+        drop(spool);
+
+        // The offset of "b", in a spool file of another generation
+        let mut offset = [0; 16];
+        offset[..8].copy_from_slice(&1u64.to_le_bytes());
+        offset[8..].copy_from_slice(&(GENERATION_LEN + HEADER_LEN + 1).to_le_bytes());
+        fs::write(path.offset_path(), offset).unwrap();
+        let spool = open(&path);
+        assert_eq!(drain(&spool), ["a", "b"]);
+    }
+
+    #[test]
+    fn skips_records_which_cannot_be_decoded() {
+        let path = TempPath::new("decode");
+        let spool = open(&path);
+        push(&spool, &["before"]);
+        drop(spool);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&2u32.to_le_bytes()).unwrap();
+        file.write_all(&[0xff, 0xfe]).unwrap();
+        drop(file);
+
+        let spool = open(&path);
+        push(&spool, &["after"]);
+        assert_eq!(drain(&spool), ["before", "after"]);
+        assert_eq!(path.len(), GENERATION_LEN);
+    }
+
+    #[test]
+    fn clones_share_the_spool_file() {
+        let path = TempPath::new("clone");
+        let spool = open(&path);
+        let clone = spool.clone();
+        push(&spool, &["a"]);
+        assert_eq!(drain(&clone), ["a"]);
+        assert!(spool.is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+//! A preset which writes events to stdout as [structured JSON] understood by Google Cloud Logging.
+//!
+//! Serverless platforms such as Cloud Run and Cloud Functions collect stdout and parse the special
+//! `severity`, `time`, `message`, `logging.googleapis.com/trace` and
+//! `logging.googleapis.com/spanId` fields, so logs are correlated with their request trace.
+//!
+//! ```no_run
+//! use tracing_service::stackdriver::{self, Stackdriver};
+//! use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+//!
+//! # async fn run() {
+//! let (layer, responses) = stackdriver::layer(Stackdriver::new().with_project_id("my-project"));
+//! tokio::spawn(responses.drain());
+//! tracing_subscriber::registry().with(layer).init();
+//! # }
+//! ```
+//!
+//! [structured JSON]: https://cloud.google.com/logging/docs/structured-logging
+
+use std::{
+    fmt, future,
+    io::{self, Write},
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use serde_json::{Map, Value};
+use tower::Service;
+use tracing_core::{
+    field::{Field, Visit},
+    Level, Metadata,
+};
+use tracing_subscriber::field::{self, VisitOutput};
+
+use crate::{timestamp::rfc3339, RecordResource, Reset, Resource, ResponseStream, ServiceLayer};
+
+const TRACE_KEY: &str = "logging.googleapis.com/trace";
+const SPAN_ID_KEY: &str = "logging.googleapis.com/spanId";
+const SOURCE_LOCATION_KEY: &str = "logging.googleapis.com/sourceLocation";
+const LABELS_KEY: &str = "logging.googleapis.com/labels";
+
+/// Constructs a [`ServiceLayer`] which writes [`LogEntry`]s to stdout.
+pub fn layer(
+    stackdriver: Stackdriver,
+) -> (
+    ServiceLayer<LogEntry, Stackdriver>,
+    ResponseStream<LogEntry, Stdout>,
+) {
+    let (layer, responses) = ServiceLayer::new(Stdout, stackdriver);
+    (layer.with_metadata(record_metadata), responses)
+}
+
+/// Records the `severity`, `time` and `logging.googleapis.com/sourceLocation` of an event.
+///
+/// This is intended to be passed to [`ServiceLayer::with_metadata`].
+pub fn record_metadata(entry: &mut LogEntry, metadata: &Metadata<'_>, timestamp: SystemTime) {
+    entry
+        .0
+        .insert("severity".into(), severity(metadata.level()).into());
+    entry.0.insert("time".into(), rfc3339(timestamp).into());
+
+    let mut location = Map::new();
+    if let Some(file) = metadata.file() {
+        location.insert("file".into(), file.into());
+    }
+    if let Some(line) = metadata.line() {
+        // `LogEntrySourceLocation.line` is an int64, which is encoded as a JSON string
+        location.insert("line".into(), line.to_string().into());
+    }
+    if let Some(module_path) = metadata.module_path() {
+        location.insert("function".into(), module_path.into());
+    }
+    if !location.is_empty() {
+        entry.0.insert(SOURCE_LOCATION_KEY.into(), location.into());
+    }
+}
+
+fn severity(level: &Level) -> &'static str {
+    match *level {
+        Level::TRACE | Level::DEBUG => "DEBUG",
+        Level::INFO => "INFO",
+        Level::WARN => "WARNING",
+        Level::ERROR => "ERROR",
+    }
+}
+
+/// A structured log entry, formatted as a single line of JSON by its [`fmt::Display`]
+/// implementation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogEntry(Map<String, Value>);
+
+impl LogEntry {
+    /// Returns the fields of the entry.
+    pub fn as_map(&self) -> &Map<String, Value> {
+        &self.0
+    }
+}
+
+impl RecordResource for LogEntry {
+    /// Records the attributes as `logging.googleapis.com/labels`.
+    fn record_resource(&mut self, resource: &Resource) {
+        let labels = resource
+            .attributes()
+            .map(|(key, value)| (key.to_owned(), value.into()))
+            .collect();
+        self.0.insert(LABELS_KEY.into(), Value::Object(labels));
+    }
+}
+
+impl Reset for LogEntry {
+    fn reset(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl From<LogEntry> for Value {
+    fn from(entry: LogEntry) -> Self {
+        Value::Object(entry.0)
+    }
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(&self.0).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// A [`MakeVisitor`](field::MakeVisitor) which records event fields into a [`LogEntry`].
+///
+/// The `trace_id` and `span_id` fields are mapped onto `logging.googleapis.com/trace` and
+/// `logging.googleapis.com/spanId` respectively.
+#[derive(Debug, Clone, Default)]
+pub struct Stackdriver {
+    trace_prefix: Option<Arc<str>>,
+}
+
+impl Stackdriver {
+    /// Constructs a `Stackdriver` which records trace IDs as they were recorded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Formats trace IDs as `projects/{project_id}/traces/{trace_id}`, which is required for
+    /// Cloud Logging to correlate them with Cloud Trace.
+    pub fn with_project_id(mut self, project_id: impl fmt::Display) -> Self {
+        self.trace_prefix = Some(format!("projects/{project_id}/traces/").into());
+        self
+    }
+}
+
+impl<'a> field::MakeVisitor<&'a mut LogEntry> for Stackdriver {
+    type Visitor = Visitor<'a>;
+
+    fn make_visitor(&self, entry: &'a mut LogEntry) -> Self::Visitor {
+        Visitor {
+            entry,
+            trace_prefix: self.trace_prefix.clone(),
+        }
+    }
+}
+
+/// The [`Visit`] implementation constructed by [`Stackdriver`].
+#[derive(Debug)]
+pub struct Visitor<'a> {
+    entry: &'a mut LogEntry,
+    trace_prefix: Option<Arc<str>>,
+}
+
+impl Visitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "trace_id" => {
+                let trace_id = match value {
+                    Value::String(trace_id) => trace_id,
+                    value => value.to_string(),
+                };
+                let trace = match &self.trace_prefix {
+                    Some(prefix) => format!("{prefix}{trace_id}"),
+                    None => trace_id,
+                };
+                self.entry.0.insert(TRACE_KEY.into(), trace.into());
+            }
+            "span_id" => {
+                self.entry.0.insert(SPAN_ID_KEY.into(), value);
+            }
+            name => {
+                self.entry.0.insert(name.into(), value);
+            }
+        }
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}").into());
+    }
+}
+
+impl VisitOutput<Result<(), fmt::Error>> for Visitor<'_> {
+    fn finish(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+/// A [`Service`] which writes each request to stdout, followed by a newline.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stdout;
+
+impl<Request> Service<Request> for Stdout
+where
+    Request: fmt::Display,
+{
+    type Response = ();
+    type Error = io::Error;
+    type Future = future::Ready<Result<(), io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        future::ready(writeln!(io::stdout().lock(), "{request}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    /// Returns the entries constructed from the events emitted by `f`.
+    async fn entries(stackdriver: Stackdriver, f: impl FnOnce()) -> Vec<LogEntry> {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = entries.clone();
+        let service = tower::service_fn(move |entry: LogEntry| {
+            recorded.lock().unwrap().push(entry);
+            future::ready(Ok::<_, io::Error>(()))
+        });
+        let (layer, responses) = ServiceLayer::new(service, stackdriver);
+        let layer = layer.with_metadata(record_metadata);
+        tracing::subscriber::with_default(Registry::default().with(layer), f);
+        responses.for_each(|_| async {}).await;
+        let entries = entries.lock().unwrap().clone();
+        entries
+    }
+
+    #[tokio::test]
+    async fn maps_trace_fields() {
+        let stackdriver = Stackdriver::new().with_project_id("my-project");
+        let entries = entries(stackdriver, || {
+            tracing::info!(trace_id = "abc", span_id = "def", user = 7, "served");
+        })
+        .await;
+        let entry = entries[0].as_map();
+        assert_eq!(entry[TRACE_KEY], "projects/my-project/traces/abc");
+        assert_eq!(entry[SPAN_ID_KEY], "def");
+        assert_eq!(entry["user"], 7);
+        assert_eq!(entry["message"], "served");
+    }
+
+    #[tokio::test]
+    async fn records_trace_ids_as_recorded_without_a_project() {
+        let entries = entries(Stackdriver::new(), || tracing::info!(trace_id = 12)).await;
+        assert_eq!(entries[0].as_map()[TRACE_KEY], "12");
+    }
+
+    #[tokio::test]
+    async fn records_severity_and_source_location() {
+        let entries = entries(Stackdriver::new(), || {
+            tracing::warn!("warning");
+            tracing::trace!("trace");
+        })
+        .await;
+        let entry = entries[0].as_map();
+        assert_eq!(entry["severity"], "WARNING");
+        assert_eq!(entries[1].as_map()["severity"], "DEBUG");
+        assert_eq!(
+            entry[SOURCE_LOCATION_KEY]["function"],
+            "tracing_service::stackdriver::tests"
+        );
+        assert_eq!(entry[SOURCE_LOCATION_KEY]["file"], file!());
+        assert!(entry[SOURCE_LOCATION_KEY]["line"].is_string());
+        assert!(entry["time"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn displays_entries_as_a_line_of_json() {
+        let Value::Object(map) = json!({"message": "hi", "n": 1}) else {
+            unreachable!()
+        };
+        assert_eq!(LogEntry(map).to_string(), r#"{"message":"hi","n":1}"#);
+    }
+}
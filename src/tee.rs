@@ -0,0 +1,251 @@
+use std::fmt;
+
+use tower::Service;
+use tracing_core::Level;
+
+use crate::{
+    channel::{self, Sender},
+    EventSink, LayerMetrics, ResponseStream, Spool, TrySendError,
+};
+
+/// An [`EventSink`] which sends a clone of each request to every branch.
+///
+/// Each branch has its own queue and [`ResponseStream`], so a slow or failing branch does not
+/// hold up the others. This is useful for dual-writing to an old and new backend during a
+/// migration.
+///
+/// A request is enqueued if any branch accepts it. Each branch which does not is reported to the
+/// [`ServiceLayer`](crate::ServiceLayer) separately, so it counts one dropped event per failed
+/// branch. A branch added using [`Tee::branch_with_spool`] writes its overflow to its own
+/// [`Spool`], so only that branch replays it.
+///
+/// The [`LayerMetrics`] of each branch's queue are tracked alongside the `Tee`'s own, so
+/// [`Handle::flush`](crate::Handle::flush) waits for every branch to drain.
+///
+/// ```
+/// # use tracing_service::{ServiceLayer, Tee};
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// # fn make_visitor(value: &mut String) -> JsonVisitor<'_> { JsonVisitor::new(value) }
+/// # let old_backend = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// # let new_backend = tower::service_fn(|_: String| async { Ok::<_, ()>(()) });
+/// let mut tee = Tee::new();
+/// let old_responses = tee.branch(old_backend, 32);
+/// let new_responses = tee.branch(new_backend, 32);
+/// let layer = ServiceLayer::from_parts(tee, make_visitor);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Tee<Sink> {
+    sinks: Vec<Sink>,
+    metrics: LayerMetrics,
+}
+
+impl<Sink> Tee<Sink> {
+    /// Constructs a `Tee` without any branches.
+    pub fn new() -> Self {
+        Self {
+            sinks: Vec::new(),
+            metrics: LayerMetrics::default(),
+        }
+    }
+
+    /// Adds a branch which sends to `sink`.
+    ///
+    /// [`Handle::flush`](crate::Handle::flush) only waits for the branch if `sink` tracks the
+    /// [`LayerMetrics`] of its queue, see [`EventSink::metrics`].
+    pub fn push<Request>(&mut self, sink: Sink)
+    where
+        Sink: EventSink<Request>,
+    {
+        if let Some(metrics) = sink.metrics() {
+            self.metrics.add_branch(metrics.clone());
+        }
+        self.sinks.push(sink);
+    }
+}
+
+impl<Request> Tee<TeeBranch<Request>> {
+    /// Adds a branch with a bounded queue, of capacity `buffer`, being drained into `service`.
+    pub fn branch<Svc>(&mut self, service: Svc, buffer: usize) -> ResponseStream<Request, Svc>
+    where
+        Svc: Service<Request>,
+    {
+        let (sender, receiver) = channel::bounded(buffer);
+        let responses = ResponseStream::from_source(service, receiver);
+        self.push(TeeBranch {
+            sender,
+            spool: None,
+        });
+        responses
+    }
+
+    /// Adds a branch with a bounded queue, of capacity `buffer`, being drained into `service`.
+    ///
+    /// Requests which overflow the queue are written to `spool` and replayed by the branch's
+    /// [`ResponseStream`], along with those which fail to send, see
+    /// [`ResponseStream::with_spool`].
+    pub fn branch_with_spool<Svc>(
+        &mut self,
+        service: Svc,
+        buffer: usize,
+        spool: Spool<Request>,
+    ) -> ResponseStream<Request, Svc>
+    where
+        Request: Clone,
+        Svc: Service<Request>,
+    {
+        let (sender, receiver) = channel::bounded(buffer);
+        let responses = ResponseStream::from_source(service, receiver).with_spool(spool.clone());
+        self.push(TeeBranch {
+            sender,
+            spool: Some(spool),
+        });
+        responses
+    }
+}
+
+impl<Sink> Default for Tee<Sink> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Sink> Tee<Sink> {
+    /// Sends a clone of the request to every branch using `send`, returning `true`, and counting
+    /// the request, if any branch accepted it.
+    fn send_each<Request>(
+        &self,
+        request: Request,
+        send: impl Fn(&Sink, Request, &mut dyn FnMut(TrySendError<Request>)) -> bool,
+        on_error: &mut dyn FnMut(TrySendError<Request>),
+    ) -> bool
+    where
+        Request: Clone,
+    {
+        let Some((last, rest)) = self.sinks.split_last() else {
+            on_error(TrySendError::Closed(request));
+            return false;
+        };
+
+        let mut sent = false;
+        for sink in rest {
+            sent |= send(sink, request.clone(), on_error);
+        }
+        sent |= send(last, request, on_error);
+        if sent {
+            self.metrics.record_sent();
+        }
+        sent
+    }
+}
+
+impl<Request, Sink> EventSink<Request> for Tee<Sink>
+where
+    Request: Clone,
+    Sink: EventSink<Request>,
+{
+    /// Sends the request to every branch, returning the first error if no branch accepted it.
+    ///
+    /// The branches which failed while others accepted the request are only reported by
+    /// [`EventSink::try_send_each`].
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        let mut error = None;
+        let sent = self.send_each(
+            request,
+            |sink, request, on_error| match sink.try_send(request) {
+                Ok(()) => true,
+                Err(err) => {
+                    on_error(err);
+                    false
+                }
+            },
+            &mut |err| {
+                error.get_or_insert(err);
+            },
+        );
+        match error {
+            Some(err) if !sent => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn try_send_with_level(
+        &self,
+        request: Request,
+        level: &Level,
+    ) -> Result<(), TrySendError<Request>> {
+        let mut error = None;
+        let sent = self.try_send_each(request, level, &mut |err| {
+            error.get_or_insert(err);
+        });
+        match error {
+            Some(err) if !sent => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the request to every branch, passing the error for each branch which did not accept
+    /// it to `on_error`.
+    fn try_send_each(
+        &self,
+        request: Request,
+        level: &Level,
+        on_error: &mut dyn FnMut(TrySendError<Request>),
+    ) -> bool {
+        self.send_each(
+            request,
+            |sink, request, on_error| sink.try_send_each(request, level, on_error),
+            on_error,
+        )
+    }
+    /// The `Tee`'s own metrics, which track those of each branch.
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        Some(&self.metrics)
+    }
+}
+
+/// A branch of a [`Tee`], see [`Tee::branch`] and [`Tee::branch_with_spool`].
+pub struct TeeBranch<Request> {
+    sender: Sender<Request>,
+    spool: Option<Spool<Request>>,
+}
+
+impl<Request> Clone for TeeBranch<Request> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            spool: self.spool.clone(),
+        }
+    }
+}
+
+impl<Request> fmt::Debug for TeeBranch<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeeBranch")
+            .field("sender", &self.sender)
+            .field("spool", &self.spool)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Request> EventSink<Request> for TeeBranch<Request> {
+    /// Sends the request to the branch's queue, writing it to the branch's [`Spool`] if the queue
+    /// is full.
+    fn try_send(&self, request: Request) -> Result<(), TrySendError<Request>> {
+        match (self.sender.try_send(request), &self.spool) {
+            (Err(TrySendError::Full(request)), Some(spool)) => match spool.push(&request) {
+                Ok(true) => {
+                    if let Some(metrics) = self.metrics() {
+                        metrics.record_spooled();
+                    }
+                    Ok(())
+                }
+                _ => Err(TrySendError::Full(request)),
+            },
+            (result, _) => result,
+        }
+    }
+
+    fn metrics(&self) -> Option<&LayerMetrics> {
+        EventSink::metrics(&self.sender)
+    }
+}
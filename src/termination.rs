@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use crate::LayerMetrics;
+
+/// The reason a [`ResponseStream`](crate::ResponseStream) terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitReason {
+    /// Every [`ServiceLayer`](crate::ServiceLayer) sending to the stream was dropped.
+    Closed,
+    /// The queue was explicitly closed, for example by dropping a [`WorkerGuard`].
+    ///
+    /// [`WorkerGuard`]: crate::WorkerGuard
+    Shutdown,
+}
+
+/// A summary of a [`ResponseStream`](crate::ResponseStream), used to construct its final request.
+///
+/// See [`ResponseStream::with_final_request`](crate::ResponseStream::with_final_request).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Termination {
+    /// Why the stream terminated.
+    pub reason: ExitReason,
+    /// The time elapsed since the stream was constructed.
+    pub uptime: Duration,
+    /// The number of events dropped because the queue was full.
+    pub dropped_full: u64,
+    /// The number of events dropped because the queue was closed.
+    pub dropped_closed: u64,
+    /// The number of events dropped because they were rate limited.
+    pub dropped_rate_limited: u64,
+    /// The number of requests dropped because the circuit was open.
+    pub dropped_circuit_open: u64,
+}
+
+impl Termination {
+    pub(crate) fn new(reason: ExitReason, uptime: Duration, metrics: &LayerMetrics) -> Self {
+        Self {
+            reason,
+            uptime,
+            dropped_full: metrics.dropped_full(),
+            dropped_closed: metrics.dropped_closed(),
+            dropped_rate_limited: metrics.dropped_rate_limited(),
+            dropped_circuit_open: metrics.dropped_circuit_open(),
+        }
+    }
+
+    /// The total number of events dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped_full
+            + self.dropped_closed
+            + self.dropped_rate_limited
+            + self.dropped_circuit_open
+    }
+}
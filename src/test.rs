@@ -0,0 +1,451 @@
+//! Utilities for testing a [`ServiceLayer`] without a real [`Service`].
+//!
+//! A [`MockService`] records the requests delivered to it, and can be scripted to be slow, to
+//! stop being ready or to return errors. A [`TestPipeline`] installs a [`ServiceLayer`] backed by
+//! a [`MockService`] on a scoped subscriber, and drives its [`ResponseStream`] on the current
+//! tokio runtime, so tests can emit events and await the requests they produce.
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! use tracing_service::test::TestPipeline;
+//! use tracing_subscriber::fmt::format::JsonVisitor;
+//!
+//! fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+//!     JsonVisitor::new(value)
+//! }
+//!
+//! let pipeline = TestPipeline::new(make_visitor);
+//! pipeline.in_scope(|| tracing::info!(user_id = 7, "logged in"));
+//! let request = pipeline.next_request().await;
+//! assert!(request.contains("\"user_id\":7"));
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+use tower::Service;
+use tracing_core::{dispatcher, Dispatch};
+use tracing_subscriber::{field, layer::SubscriberExt, Layer, Registry};
+
+use crate::{notify::Notify, EventSource, EventVisitor, ResponseStream, ServiceLayer};
+
+/// The error returned by a [`MockService`] when it is scripted to fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockError {
+    message: String,
+}
+
+impl MockError {
+    /// Constructs a `MockError` with a message.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+
+    /// Returns the message of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl Error for MockError {}
+
+struct State<Request> {
+    delivered: VecDeque<Request>,
+    calls: usize,
+    ready: bool,
+    ready_waker: Option<Waker>,
+    ready_errors: VecDeque<MockError>,
+    call_errors: VecDeque<MockError>,
+    latency: Duration,
+}
+
+struct Shared<Request> {
+    state: Mutex<State<Request>>,
+    delivered_notify: Notify,
+}
+
+/// A [`Service`] which records the requests delivered to it.
+///
+/// Clones share their recorded requests and script, so a clone can be kept to make assertions
+/// after the original is passed to a [`ServiceLayer`]. Each call takes the latency set using
+/// [`MockService::set_latency`], then fails with the next error queued using
+/// [`MockService::fail_next_call`], if any, or delivers the request.
+pub struct MockService<Request> {
+    shared: Arc<Shared<Request>>,
+}
+
+impl<Request> MockService<Request> {
+    /// Constructs a `MockService` which is always ready and delivers every request immediately.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    delivered: VecDeque::new(),
+                    calls: 0,
+                    ready: true,
+                    ready_waker: None,
+                    ready_errors: VecDeque::new(),
+                    call_errors: VecDeque::new(),
+                    latency: Duration::ZERO,
+                }),
+                delivered_notify: Notify::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<Request>> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Sets whether [`Service::poll_ready`] reports the service as ready.
+    ///
+    /// While it is not ready, requests are held in the queue of the [`ServiceLayer`].
+    pub fn set_ready(&self, ready: bool) {
+        let waker = {
+            let mut state = self.lock();
+            state.ready = ready;
+            state.ready_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Sets the time each call takes before it completes.
+    pub fn set_latency(&self, latency: Duration) {
+        self.lock().latency = latency;
+    }
+
+    /// Queues an error to be returned by the next call to [`Service::poll_ready`].
+    pub fn fail_next_ready(&self, error: MockError) {
+        self.lock().ready_errors.push_back(error);
+    }
+
+    /// Queues an error to be returned by the next call, rather than delivering its request.
+    pub fn fail_next_call(&self, error: MockError) {
+        self.lock().call_errors.push_back(error);
+    }
+
+    /// The number of times the service has been called, including calls which failed.
+    pub fn calls(&self) -> usize {
+        self.lock().calls
+    }
+
+    /// Takes the requests delivered so far, in the order they were delivered.
+    pub fn take_requests(&self) -> Vec<Request> {
+        self.lock().delivered.drain(..).collect()
+    }
+
+    /// Waits for the next request to be delivered, returning it.
+    pub async fn next_request(&self) -> Request {
+        loop {
+            let notified = self.shared.delivered_notify.notified();
+            if let Some(request) = self.lock().delivered.pop_front() {
+                return request;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl<Request> Clone for MockService<Request> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Request> Default for MockService<Request> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Request> fmt::Debug for MockService<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("MockService")
+            .field("delivered", &state.delivered.len())
+            .field("calls", &state.calls)
+            .field("ready", &state.ready)
+            .field("latency", &state.latency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Request> Service<Request> for MockService<Request>
+where
+    Request: Send + 'static,
+{
+    type Response = ();
+    type Error = MockError;
+    type Future = MockFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut state = self.lock();
+        if let Some(error) = state.ready_errors.pop_front() {
+            return Poll::Ready(Err(error));
+        }
+        if state.ready {
+            Poll::Ready(Ok(()))
+        } else {
+            state.ready_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let (latency, error) = {
+            let mut state = self.lock();
+            state.calls += 1;
+            (state.latency, state.call_errors.pop_front())
+        };
+        let shared = self.shared.clone();
+        MockFuture(Box::pin(async move {
+            if !latency.is_zero() {
+                tokio::time::sleep(latency).await;
+            }
+            if let Some(error) = error {
+                return Err(error);
+            }
+            shared
+                .state
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .delivered
+                .push_back(request);
+            shared.delivered_notify.notify_waiters();
+            Ok(())
+        }))
+    }
+}
+
+/// The [`Future`] returned by [`MockService`].
+pub struct MockFuture(Pin<Box<dyn Future<Output = Result<(), MockError>> + Send>>);
+
+impl fmt::Debug for MockFuture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockFuture").finish_non_exhaustive()
+    }
+}
+
+impl Future for MockFuture {
+    type Output = Result<(), MockError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// A [`ServiceLayer`] backed by a [`MockService`], installed on a subscriber which is only the
+/// default within [`TestPipeline::in_scope`].
+///
+/// The [`ResponseStream`] is driven by a task spawned on the current tokio runtime, which is
+/// aborted when the `TestPipeline` is dropped, see [`TestPipeline::shutdown`] to wait for it to
+/// send the queued requests instead.
+pub struct TestPipeline<Request> {
+    service: MockService<Request>,
+    dispatch: Dispatch,
+    driver: Option<JoinHandle<()>>,
+}
+
+impl<Request> TestPipeline<Request>
+where
+    Request: Default + Send + Sync + 'static,
+{
+    /// Constructs a `TestPipeline` whose [`ServiceLayer`] constructs requests using
+    /// `make_visitor`, see [`ServiceLayer::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn new<MakeVisitor>(make_visitor: MakeVisitor) -> Self
+    where
+        for<'a> MakeVisitor: field::MakeVisitor<&'a mut Request>,
+        MakeVisitor: Send + Sync + 'static,
+        for<'a> <MakeVisitor as field::MakeVisitor<&'a mut Request>>::Visitor: EventVisitor,
+    {
+        Self::from_layer(|service| ServiceLayer::new(service, make_visitor))
+    }
+}
+
+impl<Request> TestPipeline<Request>
+where
+    Request: Send + 'static,
+{
+    /// Constructs a `TestPipeline` from the layer and [`ResponseStream`] returned by
+    /// `make_layer`, which is passed the [`MockService`].
+    ///
+    /// This allows the layer to be configured, for example using [`ServiceLayer::builder`], and
+    /// the [`MockService`] to be wrapped in other middleware.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn from_layer<F, L, Svc, Source>(make_layer: F) -> Self
+    where
+        F: FnOnce(MockService<Request>) -> (L, ResponseStream<Request, Svc, Source>),
+        L: Layer<Registry> + Send + Sync + 'static,
+        Svc: Service<Request> + Send + 'static,
+        Svc::Future: Send + 'static,
+        Source: EventSource<Request> + Send + 'static,
+    {
+        let service = MockService::new();
+        let (layer, responses) = make_layer(service.clone());
+        Self {
+            service,
+            dispatch: Dispatch::new(Registry::default().with(layer)),
+            driver: Some(tokio::spawn(responses.drain())),
+        }
+    }
+
+    /// Returns the [`MockService`], to script its behaviour or inspect its requests.
+    pub fn service(&self) -> &MockService<Request> {
+        &self.service
+    }
+
+    /// Calls `f` with the subscriber the layer is installed on set as the default, so the events
+    /// it emits are sent to the [`MockService`].
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        dispatcher::with_default(&self.dispatch, f)
+    }
+
+    /// Waits for the next request to be delivered to the [`MockService`], returning it.
+    pub async fn next_request(&self) -> Request {
+        self.service.next_request().await
+    }
+
+    /// Waits for the next `n` requests to be delivered to the [`MockService`], returning them in
+    /// the order they were delivered.
+    pub async fn requests(&self, n: usize) -> Vec<Request> {
+        let mut requests = Vec::with_capacity(n);
+        while requests.len() < n {
+            requests.push(self.service.next_request().await);
+        }
+        requests
+    }
+
+    /// Drops the layer, so its queue is closed, and waits for the queued requests to be sent,
+    /// returning the [`MockService`].
+    pub async fn shutdown(mut self) -> MockService<Request> {
+        self.dispatch = Dispatch::none();
+        if let Some(driver) = self.driver.take() {
+            if let Err(err) = driver.await {
+                if err.is_panic() {
+                    std::panic::resume_unwind(err.into_panic());
+                }
+            }
+        }
+        self.service.clone()
+    }
+}
+
+impl<Request> fmt::Debug for TestPipeline<Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestPipeline")
+            .field("service", &self.service)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Request> Drop for TestPipeline<Request> {
+    fn drop(&mut self) {
+        if let Some(driver) = &self.driver {
+            driver.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::format::JsonVisitor;
+
+    use super::*;
+
+    fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+        JsonVisitor::new(value)
+    }
+
+    #[tokio::test]
+    async fn mock_service_fails_scripted_calls() {
+        let service = MockService::new();
+        service.fail_next_ready(MockError::new("not ready"));
+        service.fail_next_call(MockError::new("failed"));
+
+        assert_eq!(
+            service.clone().ready().await.unwrap_err(),
+            MockError::new("not ready")
+        );
+        assert_eq!(
+            service.clone().oneshot("first").await,
+            Err(MockError::new("failed"))
+        );
+        assert_eq!(service.clone().oneshot("second").await, Ok(()));
+        assert_eq!(service.calls(), 2);
+        assert_eq!(service.take_requests(), ["second"]);
+    }
+
+    #[test]
+    fn mock_service_wakes_the_task_once_ready() {
+        let mut service = MockService::<&str>::new();
+        service.set_ready(false);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(service.poll_ready(&mut cx).is_pending());
+        assert!(service.lock().ready_waker.is_some());
+
+        service.set_ready(true);
+        assert!(service.lock().ready_waker.is_none());
+        assert_eq!(service.poll_ready(&mut cx), Poll::Ready(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn pipeline_delivers_events_emitted_in_scope() {
+        let pipeline = TestPipeline::new(make_visitor);
+        pipeline.in_scope(|| {
+            tracing::info!(i = 0);
+            tracing::info!(i = 1);
+        });
+        // Events outside of the scope are not sent
+        tracing::info!(i = 2);
+
+        assert_eq!(pipeline.requests(2).await, ["{\"i\":0}", "{\"i\":1}"]);
+        let service = pipeline.shutdown().await;
+        assert_eq!(service.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_queued_requests() {
+        let pipeline = TestPipeline::new(make_visitor);
+        pipeline.service().set_ready(false);
+        pipeline.in_scope(|| tracing::info!("queued"));
+        pipeline.service().set_ready(true);
+
+        let service = pipeline.shutdown().await;
+        assert_eq!(service.take_requests(), ["{\"message\":\"queued\"}"]);
+    }
+}
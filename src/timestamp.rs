@@ -0,0 +1,177 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{RecordResource, Reset, Resource};
+
+/// A `Request` along with the time its event was recorded.
+///
+/// Delivery is asynchronous, so a backend which stamps requests as they arrive skews the order of
+/// events queued under backpressure. The timestamp is captured within
+/// [`Layer::on_event`](tracing_subscriber::Layer::on_event), before the `Request` is constructed,
+/// see [`NewRequest::new_request_at`](crate::NewRequest::new_request_at). The `Request` is
+/// accessible through [`Deref`], so a [`MakeVisitor`](tracing_subscriber::field::MakeVisitor) can
+/// record into it:
+///
+/// ```
+/// # use tracing_service::{ServiceLayer, Timestamped};
+/// # use tracing_subscriber::fmt::format::JsonVisitor;
+/// fn make_visitor(request: &mut Timestamped<String>) -> JsonVisitor<'_> {
+///     JsonVisitor::new(&mut **request)
+/// }
+///
+/// let service = tower::service_fn(|request: Timestamped<String>| async move {
+///     let (timestamp, body) = request.into_parts();
+///     // Send `body`, recorded at `timestamp`
+///     Ok::<_, ()>(())
+/// });
+/// let (layer, responses) = ServiceLayer::new(service, make_visitor);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamped<Request> {
+    timestamp: SystemTime,
+    request: Request,
+}
+
+impl<Request> Timestamped<Request> {
+    /// Constructs a `Timestamped` from a `Request` recorded at `timestamp`.
+    pub fn new(timestamp: SystemTime, request: Request) -> Self {
+        Self { timestamp, request }
+    }
+
+    /// Returns the time the event was recorded.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Consumes the `Timestamped`, returning the `Request`.
+    pub fn into_inner(self) -> Request {
+        self.request
+    }
+
+    /// Consumes the `Timestamped`, returning the time the event was recorded and the `Request`.
+    pub fn into_parts(self) -> (SystemTime, Request) {
+        (self.timestamp, self.request)
+    }
+}
+
+impl<Request> Deref for Timestamped<Request> {
+    type Target = Request;
+
+    fn deref(&self) -> &Request {
+        &self.request
+    }
+}
+
+impl<Request> DerefMut for Timestamped<Request> {
+    fn deref_mut(&mut self) -> &mut Request {
+        &mut self.request
+    }
+}
+
+impl<Request> RecordResource for Timestamped<Request>
+where
+    Request: RecordResource,
+{
+    fn record_resource(&mut self, resource: &Resource) {
+        self.request.record_resource(resource);
+    }
+}
+
+impl<Request> Reset for Timestamped<Request>
+where
+    Request: Reset,
+{
+    fn reset(&mut self) {
+        self.timestamp = UNIX_EPOCH;
+        self.request.reset();
+    }
+}
+
+impl<Request> fmt::Display for Timestamped<Request>
+where
+    Request: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.request.fmt(f)
+    }
+}
+
+/// Formats a [`SystemTime`] as an RFC 3339 UTC timestamp with nanosecond precision.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = duration.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:09}Z",
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        duration.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{DefaultRequest, NewRequest};
+
+    fn at(secs: u64, nanos: u32) -> String {
+        rfc3339(UNIX_EPOCH + Duration::new(secs, nanos))
+    }
+
+    #[test]
+    fn formats_epoch() {
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn formats_nanoseconds() {
+        assert_eq!(at(1, 5), "1970-01-01T00:00:01.000000005Z");
+        assert_eq!(at(59, 999_999_999), "1970-01-01T00:00:59.999999999Z");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        // 2000 is a leap year as it is divisible by 400
+        assert_eq!(at(951_782_400, 0), "2000-02-29T00:00:00.000000000Z");
+        assert_eq!(at(1_709_164_800, 0), "2024-02-29T00:00:00.000000000Z");
+        // 2100 is not a leap year as it is divisible by 100
+        assert_eq!(at(4_107_542_400, 0), "2100-03-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn formats_end_of_year() {
+        assert_eq!(at(1_735_689_599, 0), "2024-12-31T23:59:59.000000000Z");
+        assert_eq!(at(1_735_689_600, 0), "2025-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn clamps_times_before_the_epoch() {
+        let time = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(rfc3339(time), "1970-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn default_request_is_timestamped_when_recorded() {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1);
+        let request: Timestamped<String> = DefaultRequest.new_request_at(timestamp);
+        assert_eq!(request.into_parts(), (timestamp, String::new()));
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use tracing_core::field::Visit;
+use tracing_subscriber::field::VisitOutput;
+
+/// A visitor which constructs a `Request` from the fields of an event, see
+/// [`ServiceLayer::new`](crate::ServiceLayer::new).
+///
+/// This is implemented for every [`VisitOutput<Result<(), fmt::Error>>`], so the visitors
+/// provided by `tracing-subscriber` can be used. A visitor which fails with another error, such as
+/// [`encode::Visitor`](crate::encode::Visitor), implements it directly. Requests whose visitor
+/// returns an error are counted by
+/// [`LayerMetrics::visitor_errors`](crate::LayerMetrics::visitor_errors), and still sent unless
+/// [`ServiceLayer::drop_visitor_errors`](crate::ServiceLayer::drop_visitor_errors) is used.
+pub trait EventVisitor: Visit {
+    /// The error returned if the request could not be constructed.
+    type Error;
+
+    /// Completes the request once every field of the event has been visited.
+    fn finish_request(self) -> Result<(), Self::Error>;
+}
+
+impl<V> EventVisitor for V
+where
+    V: VisitOutput<Result<(), fmt::Error>>,
+{
+    type Error = fmt::Error;
+
+    fn finish_request(self) -> Result<(), Self::Error> {
+        self.finish()
+    }
+}
@@ -0,0 +1,165 @@
+use std::{fmt, future::Future, sync::mpsc, task::Poll, thread, time::Duration};
+
+use futures_core::ready;
+use futures_util::{future::poll_fn, StreamExt};
+use tower::Service;
+
+use crate::{
+    channel::{self, Sender},
+    EventSource, ResponseStream,
+};
+
+/// A guard which, when dropped, stops the [`ServiceLayer`](crate::ServiceLayer) from accepting new
+/// events and waits for the queued events to be sent.
+///
+/// This is returned by [`ServiceLayer::spawn`](crate::ServiceLayer::spawn) and
+/// [`ServiceLayer::spawn_blocking_thread`](crate::ServiceLayer::spawn_blocking_thread). Dropping
+/// the guard blocks the current thread until the queue is drained or the drain timeout elapses. On
+/// a current-thread runtime a spawned driver cannot make progress while blocked, so the guard
+/// should be dropped outside of the runtime.
+#[must_use = "the queue is drained when the guard is dropped"]
+pub struct WorkerGuard {
+    close: Option<Sender<()>>,
+    done: mpsc::Receiver<()>,
+    timeout: Duration,
+}
+
+impl WorkerGuard {
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Sets the maximum time spent draining the queue when the guard is dropped, defaults to one
+    /// second.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl fmt::Debug for WorkerGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerGuard")
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        // Dropping the sender signals the driver to close the queue
+        self.close.take();
+
+        // This returns early if the driver has exited, or panicked
+        let _ = self.done.recv_timeout(self.timeout);
+    }
+}
+
+/// Spawns a task, on the current tokio runtime, which drives the [`ResponseStream`] to completion
+/// and discards its responses.
+#[cfg(feature = "tokio")]
+pub(crate) fn spawn<Request, Svc, Source>(
+    responses: ResponseStream<Request, Svc, Source>,
+) -> WorkerGuard
+where
+    Request: Send + 'static,
+    Svc: Service<Request> + Send + 'static,
+    Svc::Future: Send + 'static,
+    Source: EventSource<Request> + Send + 'static,
+{
+    let (driver, guard) = driver(responses);
+    tokio::spawn(driver);
+    guard
+}
+
+/// Spawns a thread which drives the [`ResponseStream`] to completion and discards its responses.
+///
+/// With the `tokio` feature enabled the thread runs a current-thread tokio runtime, otherwise it
+/// runs a minimal executor which parks the thread until it is woken.
+pub(crate) fn spawn_thread<Request, Svc, Source>(
+    responses: ResponseStream<Request, Svc, Source>,
+) -> WorkerGuard
+where
+    Request: Send + 'static,
+    Svc: Service<Request> + Send + 'static,
+    Svc::Future: Send + 'static,
+    Source: EventSource<Request> + Send + 'static,
+{
+    let (driver, guard) = driver(responses);
+    thread::Builder::new()
+        .name("tracing-service".into())
+        .spawn(move || block_on(driver))
+        .expect("failed to spawn thread");
+    guard
+}
+
+/// Constructs a future which drives the [`ResponseStream`] to completion, along with the
+/// [`WorkerGuard`] which closes it.
+fn driver<Request, Svc, Source>(
+    mut responses: ResponseStream<Request, Svc, Source>,
+) -> (impl Future<Output = ()> + Send + 'static, WorkerGuard)
+where
+    Request: Send + 'static,
+    Svc: Service<Request> + Send + 'static,
+    Svc::Future: Send + 'static,
+    Source: EventSource<Request> + Send + 'static,
+{
+    let (close_tx, mut close_rx) = channel::bounded(1);
+    let (done_tx, done_rx) = mpsc::sync_channel(1);
+
+    let mut closed = false;
+    let driver = poll_fn(move |cx| {
+        if !closed && close_rx.poll_recv(cx).is_ready() {
+            responses.close();
+            closed = true;
+        }
+
+        while ready!(responses.poll_next_unpin(cx)).is_some() {}
+        Poll::Ready(())
+    });
+    let driver = async move {
+        driver.await;
+        let _ = done_tx.send(());
+    };
+
+    let guard = WorkerGuard {
+        close: Some(close_tx),
+        done: done_rx,
+        timeout: WorkerGuard::DEFAULT_TIMEOUT,
+    };
+    (driver, guard)
+}
+
+#[cfg(feature = "tokio")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build runtime")
+        .block_on(future)
+}
+
+#[cfg(not(feature = "tokio"))]
+fn block_on<F: Future>(future: F) -> F::Output {
+    use std::{
+        pin::pin,
+        sync::Arc,
+        task::{Context, Wake, Waker},
+    };
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use tower::Service;
+use tracing_service::{
+    encode::{self, EncodeError, Encoded, Encoder, Fields, LengthDelimited, MessagePack},
+    Aggregation, ResponseStream, ServiceLayer,
+};
+use tracing_subscriber::{layer::SubscriberExt, Layer, Registry};
+
+type Delivered = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// A service which records the bytes of each request delivered to it.
+fn recorder() -> (impl Service<Encoded, Response = (), Error = ()>, Delivered) {
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let recorded = delivered.clone();
+    let service = tower::service_fn(move |request: Encoded| {
+        recorded.lock().unwrap().push(request.into_bytes());
+        future::ready(Ok(()))
+    });
+    (service, delivered)
+}
+
+/// Emits the events of `f` to `layer`, returning the bytes of each request once the stream has
+/// drained.
+async fn requests<Svc>(
+    layer: impl Layer<Registry> + Send + Sync + 'static,
+    responses: ResponseStream<Encoded, Svc>,
+    delivered: &Mutex<Vec<Vec<u8>>>,
+    f: impl FnOnce(),
+) -> Vec<Vec<u8>>
+where
+    Svc: Service<Encoded>,
+{
+    tracing::subscriber::with_default(Registry::default().with(layer), f);
+    tokio::time::timeout(Duration::from_secs(1), responses.for_each(|_| async {}))
+        .await
+        .expect("requests were not delivered in time");
+    delivered.lock().unwrap().clone()
+}
+
+/// Fails to encode every request.
+#[derive(Clone)]
+struct Failing;
+
+impl Encoder for Failing {
+    fn encode(&self, _fields: &Fields, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        buf.extend_from_slice(b"partial");
+        Err(EncodeError::new("failed"))
+    }
+}
+
+/// Reads a MessagePack fixstr, returning it and the rest of `bytes`.
+fn fixstr(bytes: &[u8]) -> (&str, &[u8]) {
+    assert_eq!(bytes[0] & 0xe0, 0xa0, "expected a fixstr");
+    let len = usize::from(bytes[0] & 0x1f);
+    let value = std::str::from_utf8(&bytes[1..=len]).unwrap();
+    (value, &bytes[1 + len..])
+}
+
+#[tokio::test]
+async fn sends_length_delimited_message_pack() {
+    let (service, delivered) = recorder();
+    let (layer, responses) = encode::layer(service, LengthDelimited::new(MessagePack));
+    let requests = requests(layer, responses, &delivered, || {
+        let span = tracing::info_span!("request", id = 9u8);
+        let _guard = span.enter();
+        tracing::warn!(n = 1, ok = true);
+    })
+    .await;
+    let bytes = &requests[0];
+
+    let len = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    assert_eq!(len as usize, bytes.len() - 4);
+    // timestamp, level, target, request.id, n and ok
+    assert_eq!(bytes[4], 0x86);
+
+    let (name, rest) = fixstr(&bytes[5..]);
+    assert_eq!(name, "timestamp");
+    let (timestamp, rest) = fixstr(rest);
+    assert_rfc3339(timestamp);
+
+    let (name, rest) = fixstr(rest);
+    assert_eq!(name, "level");
+    let (level, rest) = fixstr(rest);
+    assert_eq!(level, "WARN");
+
+    let (name, rest) = fixstr(rest);
+    assert_eq!(name, "target");
+    let (target, rest) = fixstr(rest);
+    assert_eq!(target, "encode");
+
+    let (name, rest) = fixstr(rest);
+    assert_eq!(name, "request.id");
+    assert_eq!(rest[0], 9);
+
+    let (name, rest) = fixstr(&rest[1..]);
+    assert_eq!(name, "n");
+    assert_eq!(rest[0], 1);
+    let (name, rest) = fixstr(&rest[1..]);
+    assert_eq!(name, "ok");
+    assert_eq!(rest, [0xc3]);
+}
+
+#[tokio::test]
+async fn sends_message_pack_without_framing() {
+    let (service, delivered) = recorder();
+    let (layer, responses) = encode::layer(service, MessagePack);
+    let requests = requests(layer, responses, &delivered, || {
+        tracing::info!(message = "hi")
+    })
+    .await;
+    let bytes = &requests[0];
+    // timestamp, level, target and message
+    assert_eq!(bytes[0], 0x84);
+    assert!(bytes.ends_with(&[0xa7, b'm', b'e', b's', b's', b'a', b'g', b'e', 0xa2, b'h', b'i']));
+}
+
+#[tokio::test]
+async fn drops_events_which_fail_to_encode() {
+    let (service, delivered) = recorder();
+    let (layer, responses) = encode::layer(service, Failing);
+    let metrics = layer.metrics();
+    let requests = requests(layer, responses, &delivered, || tracing::info!("lost")).await;
+
+    assert!(requests.is_empty());
+    assert_eq!(metrics.visitor_errors(), 1);
+}
+
+#[tokio::test]
+async fn does_not_aggregate_events_which_fail_to_encode() {
+    let (service, delivered) = recorder();
+    let (layer, responses) = encode::layer(service, Failing);
+    let layer = layer.with_aggregation(Aggregation::new(Duration::from_secs(60), |_, _| {}));
+    let metrics = layer.metrics();
+    let requests = requests(layer, responses, &delivered, || {
+        for _ in 0..3 {
+            tracing::info!("duplicate");
+        }
+    })
+    .await;
+
+    assert!(requests.is_empty());
+    assert_eq!(metrics.visitor_errors(), 3);
+    assert_eq!(metrics.aggregated(), 0);
+}
+
+#[tokio::test]
+async fn sends_events_which_fail_to_encode_unless_dropped() {
+    let (service, delivered) = recorder();
+    let (layer, responses) = ServiceLayer::new(service, encode::Encode::new(Failing));
+    let metrics = layer.metrics();
+    let requests = requests(layer, responses, &delivered, || tracing::info!("partial")).await;
+
+    // The partial frame is removed, leaving an empty request
+    assert_eq!(requests, [Vec::<u8>::new()]);
+    assert_eq!(metrics.visitor_errors(), 1);
+}
+
+/// Asserts that `timestamp` is an RFC 3339 UTC timestamp with nanosecond precision, such as
+/// `2024-02-29T12:34:56.123456789Z`.
+fn assert_rfc3339(timestamp: &str) {
+    let bytes = timestamp.as_bytes();
+    assert_eq!(bytes.len(), 30, "{timestamp}");
+    for (i, byte) in bytes.iter().enumerate() {
+        let expected = match i {
+            4 | 7 => b'-',
+            10 => b'T',
+            13 | 16 => b':',
+            19 => b'.',
+            29 => b'Z',
+            _ => {
+                assert!(byte.is_ascii_digit(), "{timestamp}");
+                continue;
+            }
+        };
+        assert_eq!(*byte, expected, "{timestamp}");
+    }
+    let month: u8 = timestamp[5..7].parse().unwrap();
+    let day: u8 = timestamp[8..10].parse().unwrap();
+    assert!(
+        (1..=12).contains(&month) && (1..=31).contains(&day),
+        "{timestamp}"
+    );
+    assert!(timestamp >= "2024", "{timestamp}");
+}
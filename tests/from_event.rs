@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+
+use futures_util::StreamExt;
+use tracing_core::{Event, Subscriber};
+use tracing_service::{FromEvent, ServiceLayer};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    Layer, Registry,
+};
+
+#[derive(Debug, Default, PartialEq, FromEvent)]
+struct AuditRecord {
+    user_id: u64,
+    action: String,
+    #[from_event(rename = "message")]
+    description: Option<String>,
+    #[from_event(level)]
+    level: String,
+    #[from_event(target)]
+    target: String,
+    #[from_event(module_path)]
+    module_path: String,
+    #[from_event(line)]
+    line: Option<u32>,
+    r#type: Option<String>,
+    missing: Option<bool>,
+}
+
+/// Sends the events of `f` through a [`ServiceLayer::typed`], returning the requests delivered.
+async fn records(f: impl FnOnce()) -> Vec<AuditRecord> {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let service = tower::service_fn({
+        let records = records.clone();
+        move |record: AuditRecord| {
+            records.lock().unwrap().push(record);
+            async { Ok::<_, ()>(()) }
+        }
+    });
+    let (layer, responses) = ServiceLayer::typed(service);
+    tracing::subscriber::with_default(Registry::default().with(layer), f);
+    responses.for_each(|_| async {}).await;
+
+    let records = std::mem::take(&mut *records.lock().unwrap());
+    records
+}
+
+#[tokio::test]
+async fn derives_requests_from_events() {
+    let line = line!() + 2;
+    let records = records(|| {
+        tracing::warn!(
+            user_id = 7,
+            action = "delete",
+            r#type = "user",
+            "deleted a user"
+        );
+    })
+    .await;
+
+    assert_eq!(
+        records,
+        [AuditRecord {
+            user_id: 7,
+            action: "delete".into(),
+            description: Some("deleted a user".into()),
+            level: "WARN".into(),
+            target: "from_event".into(),
+            module_path: "from_event".into(),
+            line: Some(line),
+            r#type: Some("user".into()),
+            missing: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn fields_missing_from_the_event_keep_their_default() {
+    let records = records(|| tracing::info!(user_id = 1)).await;
+
+    assert_eq!(records[0].user_id, 1);
+    assert_eq!(records[0].action, "");
+    assert_eq!(records[0].description, None);
+    assert_eq!(records[0].level, "INFO");
+}
+
+#[tokio::test]
+async fn fields_which_cannot_be_converted_are_ignored() {
+    let records = records(|| tracing::info!(user_id = -1, action = 5)).await;
+
+    assert_eq!(records[0].user_id, 0);
+}
+
+#[test]
+fn from_event_constructs_requests_directly() {
+    struct Capture(Arc<Mutex<Vec<AuditRecord>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let record = AuditRecord::from_event(event);
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(Capture(records.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(user_id = 3u64, action = "read");
+    });
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].user_id, 3);
+    assert_eq!(records[0].action, "read");
+    assert_eq!(records[0].level, "INFO");
+}
+
+#[test]
+fn derive_rejects_unsupported_input() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    cases.compile_fail("tests/ui/fail/*.rs");
+}
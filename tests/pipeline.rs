@@ -0,0 +1,1572 @@
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_util::StreamExt;
+use tower::Service;
+use tracing_core::Level;
+use tracing_service::{
+    channel, Aggregation, CircuitBreaker, ErrorClass, Flush, OverflowPolicy, Pool, Pooled,
+    RateLimit, RecoverRequest, ResponseStream, RetryPolicy, Router, SampleRate, ServiceLayer,
+    Spool, Tee, Timestamped, Utf8Codec,
+};
+use tracing_subscriber::{
+    field::VisitOutput, fmt::format::JsonVisitor, layer::SubscriberExt, Layer, Registry,
+};
+
+fn make_visitor(value: &mut String) -> JsonVisitor<'_> {
+    JsonVisitor::new(value)
+}
+
+/// Emits the events of `f` to a subscriber with `layer` installed, dropping it afterwards so the
+/// queue of the layer is closed.
+fn with_layer(layer: impl Layer<Registry> + Send + Sync + 'static, f: impl FnOnce()) {
+    tracing::subscriber::with_default(Registry::default().with(layer), f);
+}
+
+#[derive(Debug)]
+struct Unavailable;
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unavailable")
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+#[derive(Default)]
+struct State {
+    delivered: Vec<String>,
+    calls: usize,
+    failures: usize,
+    latency: Duration,
+    in_flight: usize,
+    max_in_flight: usize,
+}
+
+/// Returns a path for a spool file, removing any left over from an earlier run.
+fn spool_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "tracing-service-pipeline-{}-{name}.spool",
+        std::process::id()
+    ));
+    remove_spool(&path);
+    path
+}
+
+fn remove_spool(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(path.with_extension("spool.offset"));
+}
+
+/// A service which records the requests delivered to it, once its latency has elapsed, unless
+/// it was scripted to fail.
+#[derive(Clone, Default)]
+struct Recorder {
+    state: Arc<Mutex<State>>,
+}
+
+impl Recorder {
+    fn with_latency(self, latency: Duration) -> Self {
+        self.lock().latency = latency;
+        self
+    }
+
+    /// Fails the next `failures` calls.
+    fn fail_next(&self, failures: usize) {
+        self.lock().failures = failures;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn delivered(&self) -> Vec<String> {
+        self.lock().delivered.clone()
+    }
+
+    fn max_in_flight(&self) -> usize {
+        self.lock().max_in_flight
+    }
+
+    fn calls(&self) -> usize {
+        self.lock().calls
+    }
+}
+
+impl Service<String> for Recorder {
+    type Response = ();
+    type Error = Unavailable;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: String) -> Self::Future {
+        let (latency, fail) = {
+            let mut state = self.lock();
+            state.calls += 1;
+            state.in_flight += 1;
+            state.max_in_flight = state.max_in_flight.max(state.in_flight);
+            let fail = state.failures > 0;
+            state.failures = state.failures.saturating_sub(1);
+            (state.latency, fail)
+        };
+        let recorder = self.clone();
+        Box::pin(async move {
+            tokio::time::sleep(latency).await;
+            let mut state = recorder.lock();
+            state.in_flight -= 1;
+            if fail {
+                return Err(Unavailable);
+            }
+            state.delivered.push(request);
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn sends_one_request_at_a_time_by_default() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(5));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    driver.await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        [0, 1, 2].map(|i| format!("{{\"i\":{i}}}"))
+    );
+    assert_eq!(recorder.max_in_flight(), 1);
+}
+
+#[tokio::test]
+async fn sends_requests_concurrently() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(20));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let driver = tokio::spawn(responses.with_concurrency(3).for_each(|_| async {}));
+    with_layer(layer, || {
+        for i in 0..7 {
+            tracing::info!(i);
+        }
+    });
+    driver.await.unwrap();
+
+    assert_eq!(recorder.delivered().len(), 7);
+    assert_eq!(recorder.max_in_flight(), 3);
+}
+
+#[tokio::test]
+async fn sends_a_final_request_once_the_queue_closes() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new_with_buffer(recorder.clone(), make_visitor, 1);
+    let responses = responses.with_final_request(|termination| {
+        format!(
+            "{:?} dropped_full={}",
+            termination.reason, termination.dropped_full
+        )
+    });
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    driver.await.unwrap();
+
+    assert_eq!(recorder.delivered(), ["{\"i\":0}", "Closed dropped_full=2"]);
+}
+
+#[test]
+fn worker_guard_drains_the_queue_when_dropped() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let recorder = Recorder::default().with_latency(Duration::from_millis(10));
+    let (layer, guard) = {
+        let _enter = runtime.enter();
+        ServiceLayer::spawn(recorder.clone(), make_visitor)
+    };
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+        drop(guard.with_drain_timeout(Duration::from_secs(1)));
+        assert_eq!(recorder.delivered().len(), 3);
+
+        // The queue no longer accepts events
+        tracing::info!("closed");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(recorder.delivered().len(), 3);
+    });
+}
+
+#[test]
+fn blocking_thread_drains_the_queue_when_dropped() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(10));
+    let (layer, guard) = ServiceLayer::spawn_blocking_thread(recorder.clone(), make_visitor);
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+        drop(guard.with_drain_timeout(Duration::from_secs(1)));
+    });
+
+    assert_eq!(
+        recorder.delivered(),
+        [0, 1, 2].map(|i| format!("{{\"i\":{i}}}"))
+    );
+}
+
+#[test]
+fn worker_guard_stops_waiting_once_the_drain_timeout_elapses() {
+    let recorder = Recorder::default().with_latency(Duration::from_secs(10));
+    let (layer, guard) = ServiceLayer::spawn_blocking_thread(recorder.clone(), make_visitor);
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("slow");
+
+        let start = Instant::now();
+        drop(guard.with_drain_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    });
+
+    assert!(recorder.delivered().is_empty());
+}
+
+#[tokio::test]
+async fn records_the_span_scope_from_the_root() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_span_scope(|request: &mut String, span| {
+        request.push_str(span.name());
+        let mut visitor = JsonVisitor::new(request);
+        span.record(&mut visitor);
+        visitor.finish().unwrap();
+        request.push(' ');
+    });
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    with_layer(layer, || {
+        let root = tracing::info_span!("root", user = 7);
+        let leaf = tracing::info_span!(parent: &root, "leaf", status = tracing::field::Empty);
+        leaf.record("status", "ok");
+        leaf.in_scope(|| tracing::info!("event"));
+        // Events outside of a span are not affected
+        tracing::info!("unscoped");
+    });
+    driver.await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "root{\"user\":7} leaf{\"status\":\"ok\"} {\"message\":\"event\"}",
+            "{\"message\":\"unscoped\"}",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn retries_failed_calls_with_backoff() {
+    let recorder = Recorder::default();
+    recorder.fail_next(2);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(20), Duration::MAX);
+    let driver = tokio::spawn(responses.with_retry(policy).for_each(|_| async {}));
+
+    let start = Instant::now();
+    with_layer(layer, || tracing::info!("retried"));
+    driver.await.unwrap();
+
+    assert_eq!(recorder.delivered(), ["{\"message\":\"retried\"}"]);
+    assert_eq!(recorder.calls(), 3);
+    // The backoff doubles after each attempt
+    assert!(start.elapsed() >= Duration::from_millis(60));
+}
+
+#[tokio::test]
+async fn drops_requests_once_retries_are_exhausted() {
+    let recorder = Recorder::default();
+    recorder.fail_next(2);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let policy = RetryPolicy::new(2).with_backoff(Duration::ZERO, Duration::ZERO);
+    let driver = tokio::spawn(responses.with_retry(policy).collect::<Vec<_>>());
+
+    with_layer(layer, || tracing::info!("dropped"));
+    let responses = driver.await.unwrap();
+
+    // Only the last error is yielded, but every error is counted
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].is_err());
+    assert_eq!(recorder.calls(), 2);
+    assert!(recorder.delivered().is_empty());
+    assert_eq!(metrics.service_errors(), 2);
+}
+
+#[tokio::test]
+async fn only_retries_retryable_errors() {
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let policy = RetryPolicy::new(3)
+        .with_backoff(Duration::ZERO, Duration::ZERO)
+        .with_classifier(|_: &Unavailable| false);
+    let driver = tokio::spawn(responses.with_retry(policy).for_each(|_| async {}));
+
+    with_layer(layer, || tracing::info!("dropped"));
+    driver.await.unwrap();
+
+    assert_eq!(recorder.calls(), 1);
+    assert!(recorder.delivered().is_empty());
+}
+
+#[tokio::test]
+async fn waits_out_backoffs_using_the_provided_timer() {
+    let recorder = Recorder::default();
+    recorder.fail_next(2);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let sleeps = Arc::new(Mutex::new(Vec::new()));
+    let policy = RetryPolicy::new(3)
+        .with_backoff(Duration::from_secs(1), Duration::MAX)
+        .with_timer({
+            let sleeps = sleeps.clone();
+            move |duration| {
+                sleeps.lock().unwrap().push(duration);
+                std::future::ready(())
+            }
+        });
+    let driver = tokio::spawn(responses.with_retry(policy).for_each(|_| async {}));
+
+    with_layer(layer, || tracing::info!("retried"));
+    driver.await.unwrap();
+
+    assert_eq!(recorder.delivered(), ["{\"message\":\"retried\"}"]);
+    assert_eq!(
+        *sleeps.lock().unwrap(),
+        [Duration::from_secs(1), Duration::from_secs(2)]
+    );
+}
+
+#[tokio::test]
+async fn spools_overflowing_events_and_replays_them() {
+    let path = spool_path("overflow");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new_with_buffer(recorder.clone(), make_visitor, 1);
+    let layer = layer.with_spool(spool.clone());
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.with_spool(spool.clone()).for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for i in 0..5 {
+            tracing::info!(i);
+        }
+    });
+    assert_eq!(metrics.spooled(), 4);
+    driver.await.unwrap();
+
+    let mut delivered = recorder.delivered();
+    delivered.sort();
+    assert_eq!(delivered, [0, 1, 2, 3, 4].map(|i| format!("{{\"i\":{i}}}")));
+    assert!(spool.is_empty());
+    assert_eq!(metrics.dropped(), 0);
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn replays_failed_requests_once_the_service_recovers() {
+    let path = spool_path("failed");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.with_spool(spool.clone()).for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!("failed");
+        tracing::info!("recovered");
+    });
+    driver.await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        ["{\"message\":\"recovered\"}", "{\"message\":\"failed\"}"]
+    );
+    assert_eq!(metrics.spooled(), 1);
+    assert!(spool.is_empty());
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn classifier_drops_fatal_errors_without_retrying_or_spooling() {
+    let path = spool_path("fatal");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let policy = RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO);
+    let responses = responses
+        .with_retry(policy)
+        .with_spool(spool.clone())
+        .with_error_classifier(|_| ErrorClass::Fatal);
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!("fatal");
+        tracing::info!("delivered");
+    });
+    driver.await.unwrap();
+
+    assert_eq!(recorder.delivered(), ["{\"message\":\"delivered\"}"]);
+    assert_eq!(recorder.calls(), 2);
+    assert_eq!(metrics.spooled(), 0);
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn classifier_spools_overloaded_requests_rather_than_retrying() {
+    let path = spool_path("overloaded");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let policy = RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO);
+    let responses = responses
+        .with_retry(policy)
+        .with_spool(spool.clone())
+        .with_error_classifier(|_| ErrorClass::Overloaded);
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!("overloaded");
+        tracing::info!("delivered");
+    });
+    driver.await.unwrap();
+
+    // The shed request is replayed from the spool once a call succeeds
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"message\":\"delivered\"}",
+            "{\"message\":\"overloaded\"}"
+        ]
+    );
+    assert_eq!(metrics.spooled(), 1);
+    assert!(spool.is_empty());
+    remove_spool(&path);
+}
+
+/// A request which counts the number of times it is cloned.
+#[derive(Debug)]
+struct Counted(Arc<AtomicUsize>);
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Self(self.0.clone())
+    }
+}
+
+#[tokio::test]
+async fn recovers_requests_returned_alongside_errors_without_cloning() {
+    let clones = Arc::new(AtomicUsize::new(0));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let service = tower::service_fn({
+        let calls = calls.clone();
+        move |request: Counted| {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match call {
+                    0 => Err((request, Unavailable)),
+                    _ => Ok(()),
+                }
+            }
+        }
+    });
+    let (sender, receiver) = channel::bounded(8);
+    let policy = RetryPolicy::new(3).with_backoff(Duration::ZERO, Duration::ZERO);
+    let responses = ResponseStream::from_source(RecoverRequest::new(service), receiver)
+        .with_retry(policy)
+        .with_request_recovery();
+
+    sender.try_send(Counted(clones.clone())).unwrap();
+    drop(sender);
+    let responses: Vec<_> = within_a_second(responses.collect()).await;
+
+    // The failed call is retried rather than yielded
+    assert!(matches!(responses[..], [Ok(())]));
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+    assert_eq!(clones.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn router_sends_events_to_the_first_matching_branch() {
+    let (errors, warnings, everything) = (
+        Recorder::default(),
+        Recorder::default(),
+        Recorder::default(),
+    );
+    let (errors_layer, errors_responses) = ServiceLayer::new(errors.clone(), make_visitor);
+    let (warnings_layer, warnings_responses) = ServiceLayer::new(warnings.clone(), make_visitor);
+    let (everything_layer, responses) = ServiceLayer::new(everything.clone(), make_visitor);
+    let drivers = [
+        tokio::spawn(errors_responses.for_each(|_| async {})),
+        tokio::spawn(warnings_responses.for_each(|_| async {})),
+        tokio::spawn(responses.for_each(|_| async {})),
+    ];
+
+    let router = Router::new()
+        .route(
+            |event| *event.metadata().level() <= Level::WARN,
+            warnings_layer,
+        )
+        // Never reached, the warnings branch matches errors first
+        .route(
+            |event| *event.metadata().level() == Level::ERROR,
+            errors_layer,
+        )
+        .fallback(everything_layer);
+    with_layer(router, || {
+        tracing::error!("error");
+        tracing::warn!("warn");
+        tracing::info!("info");
+    });
+    for driver in drivers {
+        driver.await.unwrap();
+    }
+
+    assert!(errors.delivered().is_empty());
+    assert_eq!(
+        warnings.delivered(),
+        ["{\"message\":\"error\"}", "{\"message\":\"warn\"}"]
+    );
+    assert_eq!(everything.delivered(), ["{\"message\":\"info\"}"]);
+}
+
+#[tokio::test]
+async fn router_discards_unmatched_events_and_notifies_every_branch_of_spans() {
+    let (errors, everything) = (Recorder::default(), Recorder::default());
+    let record_span = |request: &mut String, span: &tracing_service::ScopeSpan<'_>| {
+        request.push_str(span.name());
+        request.push(' ');
+    };
+    let (errors_layer, errors_responses) = ServiceLayer::new(errors.clone(), make_visitor);
+    let (everything_layer, responses) = ServiceLayer::new(everything.clone(), make_visitor);
+    let drivers = [
+        tokio::spawn(errors_responses.for_each(|_| async {})),
+        tokio::spawn(responses.for_each(|_| async {})),
+    ];
+
+    let router = Router::new()
+        .route(
+            |event| *event.metadata().level() == Level::ERROR,
+            errors_layer.with_span_scope(record_span),
+        )
+        .route(
+            |event| event.metadata().target() == "audit",
+            everything_layer.with_span_scope(record_span),
+        );
+    with_layer(router, || {
+        tracing::info_span!("request").in_scope(|| {
+            tracing::error!("error");
+            tracing::info!(target: "audit", "audit");
+            tracing::info!("discarded");
+        });
+    });
+    for driver in drivers {
+        driver.await.unwrap();
+    }
+
+    assert_eq!(errors.delivered(), ["request {\"message\":\"error\"}"]);
+    assert_eq!(everything.delivered(), ["request {\"message\":\"audit\"}"]);
+}
+
+#[tokio::test]
+async fn tee_sends_each_request_to_every_branch() {
+    let (old, new) = (Recorder::default(), Recorder::default());
+    let mut tee = Tee::new();
+    let old_responses = tee.branch(old.clone(), 8);
+    let new_responses = tee.branch(new.clone(), 8);
+    let layer = ServiceLayer::from_parts(tee, make_visitor);
+    let drivers = [
+        tokio::spawn(old_responses.for_each(|_| async {})),
+        tokio::spawn(new_responses.for_each(|_| async {})),
+    ];
+
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    for driver in drivers {
+        driver.await.unwrap();
+    }
+
+    let expected = [0, 1, 2].map(|i| format!("{{\"i\":{i}}}"));
+    assert_eq!(old.delivered(), expected);
+    assert_eq!(new.delivered(), expected);
+}
+
+#[tokio::test]
+async fn tee_reports_and_spools_each_failed_branch() {
+    let path = spool_path("tee");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let (fast, slow, spooled) = (
+        Recorder::default(),
+        Recorder::default(),
+        Recorder::default(),
+    );
+    let mut tee = Tee::new();
+    let fast_responses = tee.branch(fast.clone(), 8);
+    let slow_responses = tee.branch(slow.clone(), 1);
+    let spooled_responses = tee.branch_with_spool(spooled.clone(), 1, spool.clone());
+    let spooled_metrics = spooled_responses.metrics();
+    let layer = ServiceLayer::from_parts(tee, make_visitor);
+    let metrics = layer.metrics();
+    let drivers = [
+        tokio::spawn(fast_responses.for_each(|_| async {})),
+        tokio::spawn(slow_responses.for_each(|_| async {})),
+        tokio::spawn(spooled_responses.for_each(|_| async {})),
+    ];
+
+    // The branches are not drained until the layer is dropped
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    assert_eq!(metrics.enqueued(), 3);
+    assert_eq!(metrics.dropped_full(), 2);
+    assert_eq!(spooled_metrics.spooled(), 2);
+    for driver in drivers {
+        driver.await.unwrap();
+    }
+
+    let expected = [0, 1, 2].map(|i| format!("{{\"i\":{i}}}"));
+    assert_eq!(fast.delivered(), expected);
+    assert_eq!(slow.delivered(), expected[..1]);
+    let mut delivered = spooled.delivered();
+    delivered.sort();
+    assert_eq!(delivered, expected);
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn ignores_events_emitted_by_the_service() {
+    let service = tower::service_fn(|request: String| {
+        tracing::info!(request, "sent");
+        async { Ok::<_, Unavailable>(()) }
+    });
+    let (layer, mut responses) = ServiceLayer::new(service, make_visitor);
+    let metrics = layer.metrics();
+
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("event");
+    assert!(responses.next().await.unwrap().is_ok());
+
+    assert_eq!(metrics.enqueued(), 1);
+    assert_eq!(metrics.queue_depth(), 0);
+}
+
+#[tokio::test]
+async fn ignores_events_from_excluded_targets() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.exclude_target("hyper");
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!(target: "hyper", "excluded");
+        tracing::info!(target: "hyper::client", "excluded");
+        tracing::info!(target: "hyperlocal", "included");
+        tracing::info!(target: "app::hyper", "included");
+    });
+    driver.await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        ["{\"message\":\"included\"}", "{\"message\":\"included\"}"]
+    );
+}
+
+#[tokio::test]
+async fn samples_events_per_level() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer
+        .with_sample_rate(Level::INFO, SampleRate::OneIn(3))
+        .with_sample_rate(Level::DEBUG, SampleRate::Probability(0.0));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for i in 0..7 {
+            tracing::info!(i);
+            tracing::debug!(i);
+            tracing::warn!(i);
+        }
+    });
+    driver.await.unwrap();
+
+    let delivered = recorder.delivered();
+    let sampled = [0, 3, 6].map(|i| format!("{{\"i\":{i}}}"));
+    assert_eq!(delivered.len(), 7 + sampled.len());
+    assert!(sampled.iter().all(|request| delivered.contains(request)));
+}
+
+fn rate_limit(per_second: u32, burst: u32) -> RateLimit<String> {
+    RateLimit::new(per_second, burst).with_summary(|request: &mut String, limited| {
+        request.push_str(&format!("{{\"rate_limited\":{limited}}}"));
+    })
+}
+
+#[tokio::test]
+async fn rate_limit_sends_a_summary_ahead_of_the_next_event() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_rate_limit(rate_limit(100, 2));
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for i in 0..5 {
+            tracing::info!(i);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        tracing::info!(i = 5);
+    });
+    driver.await.unwrap();
+
+    assert_eq!(metrics.dropped_rate_limited(), 3);
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"i\":0}",
+            "{\"i\":1}",
+            "{\"rate_limited\":3}",
+            "{\"i\":5}"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rate_limit_spools_limited_events() {
+    let path = spool_path("rate-limit");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer
+        .with_rate_limit(RateLimit::new(1, 1))
+        .with_spool(spool.clone());
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.with_spool(spool.clone()).for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    assert_eq!(metrics.spooled(), 2);
+    driver.await.unwrap();
+
+    let mut delivered = recorder.delivered();
+    delivered.sort();
+    assert_eq!(delivered, [0, 1, 2].map(|i| format!("{{\"i\":{i}}}")));
+    assert_eq!(metrics.dropped_rate_limited(), 0);
+    assert!(spool.is_empty());
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn rate_limit_drops_limited_events_under_the_drop_policy() {
+    let path = spool_path("rate-limit-drop");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer
+        .with_rate_limit(RateLimit::new(1, 1))
+        .with_spool(spool.clone());
+    let handle = layer.handle();
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.with_spool(spool.clone()).for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!(i = 0);
+        tracing::info!(i = 1);
+        handle.set_overflow_policy(OverflowPolicy::Drop);
+        tracing::info!(i = 2);
+    });
+    assert_eq!(metrics.spooled(), 1);
+    assert_eq!(metrics.dropped_rate_limited(), 1);
+    driver.await.unwrap();
+
+    let mut delivered = recorder.delivered();
+    delivered.sort();
+    assert_eq!(delivered, [0, 1].map(|i| format!("{{\"i\":{i}}}")));
+    remove_spool(&path);
+}
+
+#[tokio::test]
+async fn health_reports_the_queue_and_the_last_error() {
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, mut responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let health = responses.health();
+    assert!(health.is_alive());
+    assert_eq!(health.oldest_queued_age(), None);
+
+    with_layer(layer, || {
+        tracing::info!("failed");
+        tracing::info!("delivered");
+    });
+    assert_eq!(health.queue_depth(), 2);
+    assert!(health.oldest_queued_age().is_some());
+    assert!(health.last_error().is_none());
+
+    responses.for_each(|_| async {}).await;
+    assert!(!health.is_alive());
+    assert_eq!(health.queue_depth(), 0);
+    assert_eq!(health.oldest_queued_age(), None);
+    assert!(health.since_last_poll() < Duration::from_secs(1));
+    assert_eq!(health.last_error().unwrap().message, "Unavailable");
+    assert_eq!(recorder.delivered(), ["{\"message\":\"delivered\"}"]);
+}
+
+#[tokio::test]
+async fn health_reports_a_dropped_stream_as_not_alive() {
+    let (_layer, mut responses) = ServiceLayer::new(Recorder::default(), make_visitor);
+    let health = responses.health();
+    drop(responses);
+    assert!(!health.is_alive());
+    assert!(health.last_error().is_none());
+}
+
+#[tokio::test]
+async fn close_sends_queued_requests_and_rejects_new_events() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let mut responses = responses.with_final_request(|termination| {
+        format!(
+            "{:?} dropped_closed={}",
+            termination.reason, termination.dropped_closed
+        )
+    });
+
+    // The layer is still installed, so only closing the stream ends it
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("queued");
+    responses.close();
+    tracing::info!("rejected");
+    responses.for_each(|_| async {}).await;
+
+    assert_eq!(
+        recorder.delivered(),
+        ["{\"message\":\"queued\"}", "Shutdown dropped_closed=1"]
+    );
+    assert_eq!(metrics.dropped_closed(), 1);
+}
+
+#[tokio::test]
+async fn exposes_the_service_and_source() {
+    let recorder = Recorder::default();
+    let (layer, mut responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    with_layer(layer, || tracing::info!("queued"));
+
+    responses.get_mut().fail_next(1);
+    assert_eq!(responses.get_ref().calls(), 0);
+    assert_eq!(responses.source_ref().len(), 1);
+
+    let (service, mut source) = responses.into_parts();
+    assert_eq!(service.calls(), 0);
+    assert_eq!(
+        source.recv().await.as_deref(),
+        Some("{\"message\":\"queued\"}")
+    );
+    assert_eq!(source.recv().await, None);
+}
+
+#[tokio::test]
+async fn into_inner_drops_queued_requests() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("queued");
+
+    let service = responses.into_inner();
+    tracing::info!("closed");
+
+    assert_eq!(service.calls(), 0);
+    assert_eq!(metrics.dropped_closed(), 1);
+}
+
+/// Waits for `future`, failing the test if it takes longer than a second.
+async fn within_a_second<T>(future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_secs(1), future)
+        .await
+        .expect("future did not complete in time")
+}
+
+#[tokio::test]
+async fn shutdown_handle_closes_the_queue_and_waits_for_it_to_drain() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(10));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let shutdown = responses.shutdown_handle();
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for i in 0..3 {
+        tracing::info!(i);
+    }
+    let report = within_a_second(shutdown.shutdown(Duration::from_secs(1))).await;
+    assert!(!report.aborted);
+    assert_eq!(report.flushed, 3);
+    assert_eq!(report.abandoned, 0);
+    assert_eq!(recorder.delivered().len(), 3);
+    driver.await.unwrap();
+
+    // Events recorded once the queue is closed are dropped
+    tracing::info!("dropped");
+    assert_eq!(metrics.dropped_closed(), 1);
+    within_a_second(shutdown.drained()).await;
+}
+
+#[tokio::test]
+async fn abort_abandons_outstanding_requests() {
+    let recorder = Recorder::default().with_latency(Duration::from_secs(10));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let shutdown = responses.shutdown_handle();
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for i in 0..3 {
+        tracing::info!(i);
+    }
+    // Wait for the first request to be called
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(recorder.calls(), 1);
+
+    let report = shutdown.abort();
+    assert!(report.aborted);
+    assert_eq!(report.abandoned, 3);
+    within_a_second(shutdown.drained()).await;
+    within_a_second(driver).await.unwrap();
+    assert_eq!(recorder.calls(), 1);
+    assert!(recorder.delivered().is_empty());
+}
+
+#[tokio::test]
+async fn calls_the_response_and_error_callbacks_while_draining() {
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+    let driver = {
+        let (on_response, on_error) = (outcomes.clone(), outcomes.clone());
+        tokio::spawn(
+            responses
+                .on_response(move |_| on_response.lock().unwrap().push("response".to_string()))
+                .on_error(move |err| on_error.lock().unwrap().push(err.to_string()))
+                .drain(),
+        )
+    };
+    with_layer(layer, || {
+        tracing::info!("failed");
+        tracing::info!("delivered");
+    });
+    within_a_second(driver).await.unwrap();
+
+    assert_eq!(*outcomes.lock().unwrap(), ["unavailable", "response"]);
+    assert_eq!(recorder.delivered(), ["{\"message\":\"delivered\"}"]);
+}
+
+#[tokio::test]
+async fn for_each_response_yields_every_result() {
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    with_layer(layer, || {
+        tracing::info!("failed");
+        tracing::info!("delivered");
+    });
+
+    let mut results = Vec::new();
+    within_a_second(responses.for_each_response(|result| results.push(result.is_ok()))).await;
+    assert_eq!(results, [false, true]);
+}
+
+#[tokio::test]
+async fn spawned_stream_sends_queued_requests_when_the_guard_is_dropped() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(5));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let guard = responses.spawn();
+    with_layer(layer, || {
+        tracing::info!("first");
+        tracing::info!("second");
+    });
+    tokio::task::spawn_blocking(move || drop(guard))
+        .await
+        .unwrap();
+
+    assert_eq!(recorder.delivered().len(), 2);
+}
+
+#[tokio::test]
+async fn initializes_every_request_including_rate_limit_summaries() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer
+        .with_request_init(|request: &mut String| request.push_str("host=web-1 "))
+        .with_rate_limit(rate_limit(100, 1));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!(i = 0);
+        tracing::info!(i = 1);
+        std::thread::sleep(Duration::from_millis(20));
+        tracing::info!(i = 2);
+    });
+    driver.await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "host=web-1 {\"i\":0}",
+            "host=web-1 {\"rate_limited\":1}",
+            "host=web-1 {\"i\":2}"
+        ]
+    );
+}
+
+/// Waits up to a second for `condition` to hold.
+async fn eventually(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition was not met in time");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+fn aggregation(window: Duration) -> Aggregation<String> {
+    Aggregation::new(window, |request: &mut String, n| {
+        request.push_str(&format!(" x{n}"));
+    })
+}
+
+#[tokio::test]
+async fn aggregates_duplicates_within_a_window() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_aggregation(aggregation(Duration::from_millis(50)));
+    let metrics = layer.metrics();
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for _ in 0..4 {
+        tracing::warn!(code = 7, "duplicate");
+    }
+    tracing::warn!(code = 8, "duplicate");
+
+    // The coalesced request is sent by the stream once the window closes, while the layer is
+    // still installed
+    eventually(|| recorder.delivered().len() == 3).await;
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"code\":7,\"message\":\"duplicate\"}",
+            "{\"code\":8,\"message\":\"duplicate\"}",
+            "{\"code\":7,\"message\":\"duplicate\"} x3",
+        ]
+    );
+    assert_eq!(metrics.aggregated(), 3);
+    driver.abort();
+}
+
+#[tokio::test]
+async fn sends_coalesced_requests_when_the_layer_is_dropped() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_aggregation(aggregation(Duration::from_secs(60)));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for _ in 0..3 {
+            tracing::info!("duplicate");
+        }
+    });
+    within_a_second(driver).await.unwrap();
+
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"message\":\"duplicate\"}",
+            "{\"message\":\"duplicate\"} x2"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn sends_coalesced_requests_when_the_stream_is_closed() {
+    let recorder = Recorder::default();
+    let (layer, mut responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_aggregation(aggregation(Duration::from_secs(60)));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for _ in 0..2 {
+        tracing::info!("duplicate");
+    }
+    responses.close();
+    within_a_second(responses.for_each(|_| async {})).await;
+
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"message\":\"duplicate\"}",
+            "{\"message\":\"duplicate\"} x1"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn retries_using_the_stream_timer_unless_the_policy_has_one() {
+    fn counting_timer(
+        sleeps: &Arc<Mutex<usize>>,
+    ) -> impl Fn(Duration) -> std::future::Ready<()> + Send + Sync + 'static {
+        let sleeps = sleeps.clone();
+        move |_| {
+            *sleeps.lock().unwrap() += 1;
+            std::future::ready(())
+        }
+    }
+
+    let (stream_sleeps, policy_sleeps) = (Arc::default(), Arc::default());
+    for policy_timer in [false, true] {
+        let recorder = Recorder::default();
+        recorder.fail_next(1);
+        let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+        let mut policy = RetryPolicy::new(2).with_backoff(Duration::from_secs(60), Duration::MAX);
+        if policy_timer {
+            policy = policy.with_timer(counting_timer(&policy_sleeps));
+        }
+        let responses = responses
+            .with_timer(counting_timer(&stream_sleeps))
+            .with_retry(policy);
+        let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+        with_layer(layer, || tracing::info!("retried"));
+        within_a_second(driver).await.unwrap();
+        assert_eq!(recorder.delivered(), ["{\"message\":\"retried\"}"]);
+    }
+
+    assert_eq!(*stream_sleeps.lock().unwrap(), 1);
+    assert_eq!(*policy_sleeps.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn rate_limit_summary_is_sent_once_the_bucket_refills() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_rate_limit(rate_limit(100, 1));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for i in 0..3 {
+        tracing::info!(i);
+    }
+
+    // No further events are recorded, the stream sends the summary
+    eventually(|| recorder.delivered().len() == 2).await;
+    assert_eq!(recorder.delivered(), ["{\"i\":0}", "{\"rate_limited\":2}"]);
+    driver.abort();
+}
+
+#[tokio::test]
+async fn rate_limit_summary_is_sent_when_the_layer_is_dropped() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_rate_limit(rate_limit(1, 1));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+    });
+    within_a_second(driver).await.unwrap();
+
+    assert_eq!(recorder.delivered(), ["{\"i\":0}", "{\"rate_limited\":2}"]);
+}
+
+#[tokio::test]
+async fn returns_pooled_requests_once_the_service_drops_them() {
+    fn make_pooled_visitor(request: &mut Pooled<String>) -> JsonVisitor<'_> {
+        JsonVisitor::new(&mut **request)
+    }
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let service = tower::service_fn({
+        let delivered = delivered.clone();
+        move |request: Pooled<String>| {
+            delivered.lock().unwrap().push(request.to_string());
+            std::future::ready(Ok::<_, Unavailable>(()))
+        }
+    });
+    let pool = Pool::new(4);
+    let (layer, responses) = ServiceLayer::new(service, make_pooled_visitor);
+    let layer = layer.with_request_factory(pool.clone());
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+
+    with_layer(layer, || {
+        tracing::info!(i = 0);
+        tracing::info!(i = 1);
+    });
+    driver.await.unwrap();
+
+    assert_eq!(*delivered.lock().unwrap(), ["{\"i\":0}", "{\"i\":1}"]);
+    assert!(!pool.is_empty());
+}
+
+#[tokio::test]
+async fn circuit_breaker_sheds_requests_until_the_probe_succeeds() {
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let circuit_breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+    let driver = tokio::spawn(
+        responses
+            .with_circuit_breaker(circuit_breaker)
+            .for_each(|_| async {}),
+    );
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    tracing::info!("fails");
+    eventually(|| metrics.service_errors() == 1).await;
+
+    // The circuit is open
+    tracing::info!("shed");
+    eventually(|| metrics.dropped_circuit_open() == 1).await;
+    assert_eq!(recorder.calls(), 1);
+
+    // The cool-down has elapsed, so the next request probes the service
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    tracing::info!("probe");
+    tracing::info!("closed");
+    eventually(|| recorder.delivered().len() == 2).await;
+    assert_eq!(
+        recorder.delivered(),
+        ["{\"message\":\"probe\"}", "{\"message\":\"closed\"}"]
+    );
+    assert_eq!(metrics.dropped_circuit_open(), 1);
+    driver.abort();
+}
+
+#[tokio::test]
+async fn circuit_breaker_spools_shed_requests_and_replays_them_once_closed() {
+    let path = spool_path("circuit-breaker");
+    let spool = Spool::open(&path, 1024 * 1024, Utf8Codec).unwrap();
+    let recorder = Recorder::default();
+    recorder.fail_next(1);
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let metrics = layer.metrics();
+    let responses = responses
+        .with_spool(spool.clone())
+        .with_circuit_breaker(CircuitBreaker::new(1, Duration::from_millis(20)));
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    tracing::info!("fails");
+    eventually(|| metrics.service_errors() == 1).await;
+    tracing::info!("shed");
+    eventually(|| metrics.spooled() == 2).await;
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    tracing::info!("probe");
+    eventually(|| recorder.delivered().len() == 3).await;
+    let mut delivered = recorder.delivered();
+    delivered.sort();
+    assert_eq!(
+        delivered,
+        [
+            "{\"message\":\"fails\"}",
+            "{\"message\":\"probe\"}",
+            "{\"message\":\"shed\"}"
+        ]
+    );
+    assert_eq!(metrics.dropped_circuit_open(), 0);
+    driver.abort();
+    remove_spool(&path);
+}
+
+fn messages(delivered: &[String]) -> Vec<&str> {
+    delivered
+        .iter()
+        .map(|request| {
+            request
+                .trim_start_matches("{\"message\":\"")
+                .trim_end_matches("\"}")
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn priority_queue_sends_high_severity_events_first() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new_with_priority(recorder.clone(), make_visitor, 4, 2);
+
+    with_layer(layer, || {
+        tracing::info!("info");
+        tracing::debug!("debug");
+        tracing::error!("error");
+        tracing::warn!("warn");
+    });
+    within_a_second(responses.for_each(|_| async {})).await;
+
+    assert_eq!(
+        messages(&recorder.delivered()),
+        ["error", "warn", "info", "debug"]
+    );
+}
+
+#[tokio::test]
+async fn priority_queue_evicts_low_severity_events_to_admit_errors() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new_with_priority(recorder.clone(), make_visitor, 2, 1);
+    let metrics = layer.metrics();
+
+    with_layer(layer, || {
+        tracing::info!("info 1");
+        tracing::info!("info 2");
+        tracing::error!("error 1");
+        tracing::error!("error 2");
+    });
+    within_a_second(responses.for_each(|_| async {})).await;
+
+    assert_eq!(messages(&recorder.delivered()), ["error 1", "error 2"]);
+    assert_eq!(metrics.dropped_full(), 2);
+    // The evicted event was enqueued before it was dropped
+    assert_eq!(metrics.enqueued(), 3);
+    assert_eq!(metrics.queue_depth(), 0);
+}
+
+#[tokio::test]
+async fn sends_span_timing_requests_as_spans_close() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_span_timing(|request: &mut String, timing| {
+        let busy = timing.busy() >= Duration::from_millis(10);
+        request.push_str(&format!("{} busy={busy}", timing.span().name()));
+    });
+
+    with_layer(layer, || {
+        let span = tracing::info_span!("request");
+        {
+            let _entered = span.enter();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        tracing::info!("idle");
+        let _entered = tracing::info_span!("handler").entered();
+    });
+    within_a_second(responses.for_each(|_| async {})).await;
+
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"message\":\"idle\"}",
+            "handler busy=false",
+            "request busy=true"
+        ]
+    );
+}
+
+fn make_timestamped_visitor(request: &mut Timestamped<String>) -> JsonVisitor<'_> {
+    JsonVisitor::new(&mut **request)
+}
+
+#[tokio::test]
+async fn timestamps_requests_as_events_are_recorded() {
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let service = tower::service_fn({
+        let delivered = delivered.clone();
+        move |request: Timestamped<String>| {
+            delivered.lock().unwrap().push(request.into_parts());
+            std::future::ready(Ok::<_, Unavailable>(()))
+        }
+    });
+    let (layer, responses) = ServiceLayer::new(service, make_timestamped_visitor);
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let layer = layer.with_metadata({
+        let recorded = recorded.clone();
+        move |_: &mut Timestamped<String>, _: &tracing::Metadata<'_>, timestamp| {
+            recorded.lock().unwrap().push(timestamp);
+        }
+    });
+
+    let before = SystemTime::now();
+    with_layer(layer, || tracing::info!("recorded"));
+    let after = SystemTime::now();
+    // The requests wait in the queue before they are sent
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    responses.for_each(|_| async {}).await;
+
+    let delivered = delivered.lock().unwrap();
+    let [(timestamp, request)] = delivered.as_slice() else {
+        panic!("expected one request, got {delivered:?}");
+    };
+    assert_eq!(request, "{\"message\":\"recorded\"}");
+    assert!(before <= *timestamp && *timestamp <= after);
+    assert_eq!(*recorded.lock().unwrap(), [*timestamp]);
+}
+
+#[tokio::test]
+async fn flush_waits_for_queued_requests_to_be_delivered() {
+    let recorder = Recorder::default().with_latency(Duration::from_millis(20));
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let handle = layer.handle();
+    let driver = tokio::spawn(responses.with_concurrency(2).for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for i in 0..3 {
+        tracing::info!(i);
+    }
+    within_a_second(handle.flush()).await.unwrap();
+    assert_eq!(recorder.delivered().len(), 3);
+
+    // Nothing is queued, so the next flush completes immediately
+    within_a_second(handle.flush()).await.unwrap();
+    driver.abort();
+}
+
+#[tokio::test]
+async fn flush_fails_without_a_stream_draining_the_queue() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder, make_visitor);
+    let handle = layer.handle();
+    drop(responses);
+
+    assert!(within_a_second(handle.flush()).await.is_err());
+}
+
+#[tokio::test]
+async fn flush_waits_for_every_tee_branch() {
+    let (fast, slow) = (
+        Recorder::default(),
+        Recorder::default().with_latency(Duration::from_millis(20)),
+    );
+    let mut tee = Tee::new();
+    let fast_responses = tee.branch(fast.clone(), 8);
+    let slow_responses = tee.branch(slow.clone(), 8);
+    let layer = ServiceLayer::from_parts(tee, make_visitor);
+    let handle = layer.handle();
+    let drivers = [
+        tokio::spawn(fast_responses.for_each(|_| async {})),
+        tokio::spawn(slow_responses.for_each(|_| async {})),
+    ];
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for i in 0..3 {
+        tracing::info!(i);
+    }
+    within_a_second(handle.flush()).await.unwrap();
+    assert_eq!(fast.delivered().len(), 3);
+    assert_eq!(slow.delivered().len(), 3);
+    for driver in drivers {
+        driver.abort();
+    }
+}
+
+#[tokio::test]
+async fn flush_sends_held_coalesced_requests() {
+    let recorder = Recorder::default();
+    let (layer, responses) = ServiceLayer::new(recorder.clone(), make_visitor);
+    let layer = layer.with_aggregation(aggregation(Duration::from_secs(60)));
+    let handle = layer.handle();
+    let driver = tokio::spawn(responses.for_each(|_| async {}));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    for _ in 0..3 {
+        tracing::info!("duplicate");
+    }
+    within_a_second(handle.flush()).await.unwrap();
+    assert_eq!(
+        recorder.delivered(),
+        [
+            "{\"message\":\"duplicate\"}",
+            "{\"message\":\"duplicate\"} x2"
+        ]
+    );
+    driver.abort();
+}
+
+/// A service which buffers requests, delivering them to the [`Recorder`] as a single request
+/// when flushed.
+struct Buffered {
+    recorder: Recorder,
+    buffer: Vec<String>,
+}
+
+impl Service<String> for Buffered {
+    type Response = ();
+    type Error = Unavailable;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: String) -> Self::Future {
+        self.buffer.push(request);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Flush<String> for Buffered {
+    fn flush_buffered(&mut self) -> Option<Self::Future> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let batch = std::mem::take(&mut self.buffer).join(",");
+        Some(self.recorder.call(batch))
+    }
+}
+
+#[tokio::test]
+async fn flush_sends_requests_buffered_by_the_service() {
+    let recorder = Recorder::default();
+    let service = Buffered {
+        recorder: recorder.clone(),
+        buffer: Vec::new(),
+    };
+    let (layer, responses) = ServiceLayer::new(service, make_visitor);
+    let handle = layer.handle();
+    let driver = tokio::spawn(responses.with_buffer_flush().for_each(|_| async {}));
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    tracing::info!(i = 0);
+    tracing::info!(i = 1);
+    within_a_second(handle.flush()).await.unwrap();
+    assert_eq!(recorder.delivered(), ["{\"i\":0},{\"i\":1}"]);
+
+    // The requests buffered when the queue closes are sent before the stream terminates
+    tracing::info!(i = 2);
+    drop(guard);
+    within_a_second(driver).await.unwrap();
+    assert_eq!(recorder.delivered(), ["{\"i\":0},{\"i\":1}", "{\"i\":2}"]);
+}
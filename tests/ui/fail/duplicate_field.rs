@@ -0,0 +1,10 @@
+use tracing_service::FromEvent;
+
+#[derive(Default, FromEvent)]
+struct Record {
+    message: String,
+    #[from_event(rename = "message")]
+    description: String,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use tracing_service::FromEvent;
+
+#[derive(Default, FromEvent)]
+enum Record {
+    #[default]
+    Empty,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use tracing_service::FromEvent;
+
+#[derive(Default, FromEvent)]
+struct Record(u64, String);
+
+fn main() {}
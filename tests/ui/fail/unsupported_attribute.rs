@@ -0,0 +1,9 @@
+use tracing_service::FromEvent;
+
+#[derive(Default, FromEvent)]
+struct Record {
+    #[from_event(timestamp)]
+    time: String,
+}
+
+fn main() {}
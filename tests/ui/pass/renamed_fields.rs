@@ -0,0 +1,17 @@
+use tracing_service::FromEvent;
+
+#[derive(Default, FromEvent)]
+struct Record {
+    id: u64,
+    #[from_event(rename = "message")]
+    description: String,
+    #[from_event(level)]
+    level: String,
+    #[from_event(file)]
+    file: Option<String>,
+    r#type: Option<String>,
+}
+
+fn main() {
+    let _ = Record::default();
+}
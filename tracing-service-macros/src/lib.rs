@@ -0,0 +1,164 @@
+//! Derive macros for `tracing-service`, these are re-exported by `tracing-service` when its
+//! `derive` feature is enabled.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{ext::IdentExt, parse_macro_input, Data, DeriveInput, Error, Fields, LitStr};
+
+/// Derives `FromEvent` for a struct with named fields.
+///
+/// See the documentation of `tracing_service::FromEvent`.
+#[proc_macro_derive(FromEvent, attributes(from_event))]
+pub fn derive_from_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Where a struct field is read from.
+enum Source {
+    Field(String),
+    Level,
+    Target,
+    ModulePath,
+    File,
+    Line,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "`FromEvent` can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "`FromEvent` can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut metadata = Vec::new();
+    let mut arms = Vec::new();
+    let mut names = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("fields are named");
+        let source = source(field)?;
+        let assign = |value: TokenStream2| {
+            quote! {
+                if let ::std::option::Option::Some(value) =
+                    ::tracing_service::from_event::FromFieldValue::from_field_value(#value)
+                {
+                    self.#ident = value;
+                }
+            }
+        };
+        let str_value = quote!(::tracing_service::from_event::FieldValue::Str(value));
+        match source {
+            Source::Field(name) => {
+                // Only the first match arm for a name would ever be reached
+                if names.contains(&name) {
+                    return Err(Error::new_spanned(
+                        field,
+                        format!("duplicate `FromEvent` field name `{name}`"),
+                    ));
+                }
+                names.push(name.clone());
+                let assign = assign(quote!(value));
+                arms.push(quote!(#name => { #assign }));
+            }
+            Source::Level => {
+                let assign = assign(quote! {
+                    ::tracing_service::from_event::FieldValue::Str(metadata.level().as_str())
+                });
+                metadata.push(assign);
+            }
+            Source::Target => {
+                let assign = assign(quote! {
+                    ::tracing_service::from_event::FieldValue::Str(metadata.target())
+                });
+                metadata.push(assign);
+            }
+            Source::ModulePath => {
+                let assign = assign(str_value.clone());
+                metadata.push(quote! {
+                    if let ::std::option::Option::Some(value) = metadata.module_path() { #assign }
+                });
+            }
+            Source::File => {
+                let assign = assign(str_value.clone());
+                metadata.push(quote! {
+                    if let ::std::option::Option::Some(value) = metadata.file() { #assign }
+                });
+            }
+            Source::Line => {
+                let assign = assign(quote! {
+                    ::tracing_service::from_event::FieldValue::U64(::std::convert::From::from(value))
+                });
+                metadata.push(quote! {
+                    if let ::std::option::Option::Some(value) = metadata.line() { #assign }
+                });
+            }
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::tracing_service::FromEvent for #name #ty_generics #where_clause {
+            fn record_metadata(&mut self, metadata: &::tracing_service::__private::Metadata<'_>) {
+                let _ = metadata;
+                #(#metadata)*
+            }
+
+            fn record_field(
+                &mut self,
+                field: &::tracing_service::__private::Field,
+                value: ::tracing_service::from_event::FieldValue<'_>,
+            ) {
+                match field.name() {
+                    #(#arms)*
+                    _ => {}
+                }
+            }
+        }
+    })
+}
+
+/// Parses the `#[from_event(...)]` attribute of a struct field.
+fn source(field: &syn::Field) -> syn::Result<Source> {
+    let ident = field.ident.as_ref().expect("fields are named");
+    let mut source = Source::Field(ident.unraw().to_string());
+    for attr in &field.attrs {
+        if !attr.path().is_ident("from_event") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            source = if meta.path.is_ident("rename") {
+                Source::Field(meta.value()?.parse::<LitStr>()?.value())
+            } else if meta.path.is_ident("level") {
+                Source::Level
+            } else if meta.path.is_ident("target") {
+                Source::Target
+            } else if meta.path.is_ident("module_path") {
+                Source::ModulePath
+            } else if meta.path.is_ident("file") {
+                Source::File
+            } else if meta.path.is_ident("line") {
+                Source::Line
+            } else {
+                return Err(meta.error("unsupported `from_event` attribute"));
+            };
+            Ok(())
+        })?;
+    }
+    Ok(source)
+}